@@ -1,3 +1,5 @@
+-- ic-sql-migrate: allow-destructive
+-- The generated Chinook script drops its tables before recreating them
 
 /*******************************************************************************
    Chinook Database - Version 1.4.5
@@ -1,11 +1,16 @@
 use ic_cdk::{api::performance_counter, init, post_upgrade, pre_upgrade, query, update};
 use ic_rusqlite::{close_connection, with_connection, Connection};
-use ic_sql_migrate::{include_migrations, Migration};
+use ic_sql_migrate::{canister::Lifecycle, include_migrations, Migration};
 
 mod seeds;
 
 static MIGRATIONS: &[Migration] = include_migrations!();
 
+static LIFECYCLE: Lifecycle = Lifecycle::new(MIGRATIONS).with_seeds(seeds::SEEDS);
+
+// Controller-only methods to inspect and manage the migrations and seeds
+ic_sql_migrate::admin_interface!(LIFECYCLE);
+
 fn run_migrations_and_seeds() {
     with_connection(|mut conn| {
         let conn: &mut Connection = &mut conn;
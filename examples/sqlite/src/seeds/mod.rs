@@ -7,6 +7,6 @@ pub mod seed_002_more_data;
 use ic_sql_migrate::Seed;
 
 pub static SEEDS: &[Seed] = &[
-    Seed::new("seed_001_test", seed_001_test::seed),
+    Seed::new("seed_001_test", seed_001_test::seed).requires(seed_001_test::REQUIRES_MIGRATION),
     Seed::new("seed_002_more_data", seed_002_more_data::seed),
 ];
@@ -1,6 +1,8 @@
 use ic_rusqlite::Connection;
 use ic_sql_migrate::MigrateResult;
 
+pub const REQUIRES_MIGRATION: &str = "000_init";
+
 pub fn seed(conn: &Connection) -> MigrateResult<()> {
     conn.execute(
         "INSERT INTO Album (AlbumId, Title, ArtistId) VALUES (9999, 'Test Album from Seeds', 1)",
@@ -0,0 +1,621 @@
+//! `cargo ic-sql-migrate`: scaffold and inspect migrations from the command line.
+//!
+//! Migrations are discovered with the same [`Builder`] logic that the build script
+//! uses, so the CLI and the embedded migrations always agree on IDs and ordering.
+//!
+//! Install with `cargo install ic-sql-migrate --features cli` and run from the
+//! directory containing your canister's `Cargo.toml`.
+
+use ic_sql_migrate::{
+    Builder, Layout, MigrateOptions, Migration, MigrationCondition, MigrationFile,
+    MigrationHistory, MigrationOrder, VersionScheme,
+};
+use rusqlite::{Connection, OpenFlags};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type CliResult<T> = Result<T, Box<dyn Error>>;
+
+const USAGE: &str = "\
+Usage: cargo ic-sql-migrate <command> [options]
+
+Commands:
+  new <name>        Create a new migration file with the next version prefix
+  list              List migrations in execution order
+  status            Show applied, skipped and pending migrations of a database file
+  validate          Apply all migrations to an in-memory SQLite database
+  squash <name>     Replace all migrations with a single squashed migration
+  check-upgrade <f> Check the migrations against a history exported from a canister
+                    (file with the output of its migration_history query, - for stdin)
+
+Options:
+  --migrations-dir <dir>   Migrations directory relative to the manifest dir [default: migrations]
+  --manifest-dir <dir>     Directory containing Cargo.toml [default: .]
+  --version-scheme <s>     Version prefix format, sequential or timestamp [default: sequential]
+  --layout <l>             Naming convention, default, diesel, sqlx, refinery or flyway [default: default]
+  --recursive              Discover migrations in subdirectories
+  --order <o>              Order of nested migrations, version or directory [default: version]
+  --db <file>              SQLite database file (status)
+  --var <name=value>       Value of a ${name} variable in migrations (validate)
+  --timestamp              Use a YYYYMMDDHHMMSS prefix instead of the next number (new)
+  -h, --help               Print this help
+";
+
+/// Parsed command line options.
+#[derive(Debug, Default)]
+struct Options {
+    command: Option<String>,
+    args: Vec<String>,
+    migrations_dir: Option<String>,
+    manifest_dir: Option<PathBuf>,
+    version_scheme: VersionScheme,
+    layout: Layout,
+    recursive: bool,
+    order: MigrationOrder,
+    db: Option<PathBuf>,
+    vars: Vec<(String, String)>,
+    timestamp: bool,
+    help: bool,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> CliResult<Self> {
+        let mut options = Options::default();
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| {
+                args.next()
+                    .cloned()
+                    .ok_or_else(|| format!("missing value for {name}"))
+            };
+
+            match arg.as_str() {
+                "--migrations-dir" => options.migrations_dir = Some(value(arg)?),
+                "--manifest-dir" => options.manifest_dir = Some(value(arg)?.into()),
+                "--version-scheme" => {
+                    options.version_scheme = match value(arg)?.as_str() {
+                        "sequential" => VersionScheme::Sequential,
+                        "timestamp" => VersionScheme::Timestamp,
+                        other => return Err(format!("unknown version scheme '{other}'").into()),
+                    }
+                }
+                "--layout" => {
+                    options.layout = match value(arg)?.as_str() {
+                        "default" => Layout::Default,
+                        "diesel" => Layout::Diesel,
+                        "sqlx" => Layout::Sqlx,
+                        "refinery" => Layout::Refinery,
+                        "flyway" => Layout::Flyway,
+                        other => return Err(format!("unknown layout '{other}'").into()),
+                    }
+                }
+                "--recursive" => options.recursive = true,
+                "--order" => {
+                    options.order = match value(arg)?.as_str() {
+                        "version" => MigrationOrder::Version,
+                        "directory" => MigrationOrder::Directory,
+                        other => return Err(format!("unknown order '{other}'").into()),
+                    }
+                }
+                "--db" => options.db = Some(value(arg)?.into()),
+                "--var" => {
+                    let var = value(arg)?;
+                    let (name, value) = var
+                        .split_once('=')
+                        .ok_or_else(|| format!("invalid variable '{var}', expected name=value"))?;
+                    options.vars.push((name.to_string(), value.to_string()));
+                }
+                "--timestamp" => options.timestamp = true,
+                "-h" | "--help" => options.help = true,
+                flag if flag.starts_with('-') && flag != "-" => {
+                    return Err(format!("unknown option {flag}").into())
+                }
+                _ if options.command.is_none() => options.command = Some(arg.clone()),
+                _ => options.args.push(arg.clone()),
+            }
+        }
+
+        Ok(options)
+    }
+
+    fn builder(&self) -> Builder {
+        let mut builder = Builder::new()
+            .with_manifest_dir(self.manifest_dir.clone().unwrap_or_default())
+            .with_version_scheme(self.version_scheme)
+            .with_layout(self.layout)
+            .recursive(self.recursive)
+            .with_order(self.order);
+        if let Some(dir) = &self.migrations_dir {
+            builder = builder.with_migrations_dir(dir.clone());
+        }
+        builder
+    }
+
+    /// Fails for commands that write migration files, which only know the default layout.
+    fn require_default_layout(&self, command: &str) -> CliResult<()> {
+        if self.layout != Layout::Default {
+            return Err(format!("{command} only supports the default layout").into());
+        }
+        Ok(())
+    }
+
+    fn name_arg(&self, command: &str) -> CliResult<String> {
+        match self.args.as_slice() {
+            [name] => Ok(sanitize_name(name)),
+            _ => Err(format!("usage: cargo ic-sql-migrate {command} <name>").into()),
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    // When invoked as `cargo ic-sql-migrate`, cargo passes the subcommand name first
+    let args = match args.first() {
+        Some(first) if first == "ic-sql-migrate" => &args[1..],
+        _ => &args[..],
+    };
+
+    if let Err(e) = run(args) {
+        eprintln!("error: {e}");
+        std::process::exit(1);
+    }
+}
+
+fn run(args: &[String]) -> CliResult<()> {
+    let options = Options::parse(args)?;
+
+    if options.help {
+        print!("{USAGE}");
+        return Ok(());
+    }
+
+    match options.command.as_deref() {
+        Some("new") => new(&options),
+        Some("list") => list(&options),
+        Some("status") => status(&options),
+        Some("validate") => validate(&options),
+        Some("squash") => squash(&options),
+        Some("check-upgrade") => check_upgrade(&options),
+        Some(other) => Err(format!("unknown command '{other}'\n\n{USAGE}").into()),
+        None => {
+            print!("{USAGE}");
+            Ok(())
+        }
+    }
+}
+
+/// Creates a new, empty migration file.
+fn new(options: &Options) -> CliResult<()> {
+    options.require_default_layout("new")?;
+    let name = options.name_arg("new")?;
+    let builder = options.builder();
+    let migrations = builder.discover_migrations()?;
+
+    let prefix = if options.timestamp
+        || options.version_scheme == VersionScheme::Timestamp
+        || uses_timestamps(&migrations)
+    {
+        timestamp_prefix(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+    } else {
+        next_number_prefix(&migrations)
+    };
+
+    let dir = builder.migrations_path()?;
+    fs::create_dir_all(&dir)?;
+
+    let id = format!("{prefix}_{name}");
+    let path = dir.join(format!("{id}.sql"));
+    if path.exists() {
+        return Err(format!("{} already exists", path.display()).into());
+    }
+
+    fs::write(&path, format!("-- Migration: {id}\n\n"))?;
+    println!("Created {}", path.display());
+    Ok(())
+}
+
+/// Prints all migrations in execution order.
+fn list(options: &Options) -> CliResult<()> {
+    let migrations = options.builder().discover_migrations()?;
+    if migrations.is_empty() {
+        println!("No migrations found");
+    }
+    for migration in &migrations {
+        println!("{}", migration.id);
+    }
+    Ok(())
+}
+
+/// Compares the migrations on disk with the `_migrations` table of a database file.
+fn status(options: &Options) -> CliResult<()> {
+    let db = options
+        .db
+        .as_ref()
+        .ok_or("usage: cargo ic-sql-migrate status --db <file>")?;
+    let migrations = options.builder().discover_migrations()?;
+
+    let conn = Connection::open_with_flags(db, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut applied = applied_migrations(&conn)?;
+
+    for migration in &migrations {
+        match applied.remove(&migration.id) {
+            Some((_, true)) => println!("skipped  {}", migration.id),
+            Some((applied_at, false)) => println!("applied  {}  ({applied_at})", migration.id),
+            None => println!("pending  {}", migration.id),
+        }
+    }
+
+    // Applied migrations that no longer exist on disk
+    let mut unknown: Vec<_> = applied
+        .into_iter()
+        .filter(|(_, (_, skipped))| !skipped)
+        .collect();
+    unknown.sort();
+    for (id, (applied_at, _)) in unknown {
+        println!("unknown  {id}  ({applied_at})");
+    }
+
+    Ok(())
+}
+
+/// Reads the recorded migrations, mapped to when they were recorded and whether they
+/// were skipped, if the tracking table exists.
+fn applied_migrations(conn: &Connection) -> CliResult<HashMap<String, (String, bool)>> {
+    let has_table: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = '_migrations'",
+        [],
+        |row| row.get(0),
+    )?;
+    if !has_table {
+        return Ok(HashMap::new());
+    }
+
+    // Tables created by earlier versions have no `skipped` column, and the database is
+    // opened read-only
+    let has_skipped: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info('_migrations') WHERE name = 'skipped'",
+        [],
+        |row| row.get(0),
+    )?;
+    let sql = if has_skipped {
+        "SELECT id, applied_at, skipped FROM _migrations"
+    } else {
+        "SELECT id, applied_at, 0 FROM _migrations"
+    };
+    let mut statement = conn.prepare(sql)?;
+    let rows = statement.query_map([], |row| Ok((row.get(0)?, (row.get(1)?, row.get(2)?))))?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+/// Applies all migrations to a fresh in-memory database.
+fn validate(options: &Options) -> CliResult<()> {
+    let files = options.builder().discover_migrations()?;
+    let migrations = load_migrations(&files)?;
+
+    let mut conn = Connection::open_in_memory()?;
+    let migrate_options = MigrateOptions::new().with_vars(options.vars.clone());
+    ic_sql_migrate::sqlite::migrate_with(&mut conn, &migrations, &migrate_options)?;
+
+    println!("All {} migrations applied successfully", migrations.len());
+
+    // The build fails on these unless `Builder::allow_destructive` is set, which only
+    // the build script knows
+    for file in &files {
+        for change in file.destructive_changes()? {
+            println!(
+                "warning: {}:{}:{}: {} without `-- ic-sql-migrate: allow-destructive`",
+                change.path.display(),
+                change.line,
+                change.column,
+                change.operation
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Reads migration files into [`Migration`] values, with their checksums.
+///
+/// The CLI doesn't know the variables of the build script, so all variables are treated
+/// as runtime variables, set with `--var`. Nor does it know the enabled features or the
+/// predicates, so conditional migrations are treated as enabled: `validate` applies them
+/// and `check-upgrade` knows that they may be skipped.
+///
+/// The strings are leaked to get the `'static` lifetime that embedded migrations have,
+/// which is fine for a short-lived CLI process.
+fn load_migrations(files: &[MigrationFile]) -> CliResult<Vec<Migration>> {
+    files
+        .iter()
+        .map(|file| {
+            let id: &'static str = Box::leak(file.id.clone().into_boxed_str());
+            let sql: &'static str = Box::leak(fs::read_to_string(&file.path)?.into_boxed_str());
+            let checksum: &'static str = Box::leak(file.checksum()?.into_boxed_str());
+            let vars: Vec<&'static str> = file
+                .variables()?
+                .into_iter()
+                .map(|name| &*Box::leak(name.into_boxed_str()))
+                .collect();
+            let migration = Migration::new(id, sql)
+                .with_checksum(checksum)
+                .with_vars(vars.leak());
+            Ok(match file.condition()? {
+                Some(MigrationCondition::Feature(feature)) => {
+                    migration.when_feature(Box::leak(feature.into_boxed_str()), true)
+                }
+                Some(MigrationCondition::Predicate(_)) => migration.when(|| true),
+                None => migration,
+            })
+        })
+        .collect()
+}
+
+/// Compares the migrations on disk with the history of a deployed canister.
+///
+/// Fails if the migrations conflict with the history, so CI can run it before an
+/// upgrade.
+fn check_upgrade(options: &Options) -> CliResult<()> {
+    let source = match options.args.as_slice() {
+        [source] => source,
+        _ => return Err("usage: cargo ic-sql-migrate check-upgrade <history-file>".into()),
+    };
+    let text = if source == "-" {
+        let mut text = String::new();
+        std::io::stdin().read_to_string(&mut text)?;
+        text
+    } else {
+        fs::read_to_string(source)?
+    };
+    let history =
+        MigrationHistory::decode(find_history(&text).ok_or("no migration history found")?)?;
+
+    let files = options.builder().discover_migrations()?;
+    let check = ic_sql_migrate::check_upgrade(&history, &load_migrations(&files)?);
+
+    for id in &check.pending {
+        println!("pending   {id}");
+    }
+    for conflict in &check.conflicts {
+        println!("conflict  {conflict}");
+    }
+
+    if !check.is_compatible() {
+        return Err(format!("{} conflicting migrations", check.conflicts.len()).into());
+    }
+    println!(
+        "Upgrade is compatible, {} migrations pending",
+        check.pending.len()
+    );
+    Ok(())
+}
+
+/// Finds an encoded history in text, such as the Candid output of `dfx canister call`.
+fn find_history(text: &str) -> Option<&str> {
+    let start = text.find("ic-sql-migrate/")?;
+    let history = &text[start..];
+    let end = history
+        .find(|c: char| !(c.is_ascii_alphanumeric() || "_-./%;=".contains(c)))
+        .unwrap_or(history.len());
+    Some(&history[..end])
+}
+
+/// Concatenates all migrations into one file and removes the originals.
+///
+/// The squashed migration gets a new ID, so it must only be used for databases that
+/// have not applied any of the original migrations, e.g. before the first deployment.
+/// It has no down migration, so the down migrations of the originals are removed too.
+/// The squashed file is written before anything is removed, under a temporary name
+/// that is replaced afterwards, as it may replace one of the originals.
+fn squash(options: &Options) -> CliResult<()> {
+    options.require_default_layout("squash")?;
+    let name = options.name_arg("squash")?;
+    let builder = options.builder();
+    let files = builder.discover_migrations()?;
+
+    let (first, rest) = match files.split_first() {
+        Some((first, rest)) if !rest.is_empty() => (first, rest),
+        _ => return Err("at least two migrations are needed to squash".into()),
+    };
+
+    let prefix = version_prefix(&first.id).unwrap_or("000");
+    let id = format!("{prefix}_{name}");
+
+    let mut squashed = format!("-- Migration: {id}\n");
+    for file in std::iter::once(first).chain(rest) {
+        squashed.push_str(&format!("\n-- Squashed from {}\n", file.id));
+        squashed.push_str(fs::read_to_string(&file.path)?.trim_end());
+        squashed.push('\n');
+    }
+
+    let path = builder.migrations_path()?.join(format!("{id}.sql"));
+    let temp_path = path.with_extension("sql.tmp");
+    fs::write(&temp_path, squashed)?;
+
+    for file in &files {
+        fs::remove_file(&file.path)?;
+        if let Some(down_path) = &file.down_path {
+            fs::remove_file(down_path)?;
+        }
+    }
+    fs::rename(&temp_path, &path)?;
+
+    println!(
+        "Squashed {} migrations into {}",
+        files.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// Turns a free-form name into a file name friendly snake_case identifier.
+fn sanitize_name(name: &str) -> String {
+    let mut sanitized = String::new();
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            sanitized.push(c.to_ascii_lowercase());
+        } else if !sanitized.ends_with('_') {
+            sanitized.push('_');
+        }
+    }
+    sanitized.trim_matches('_').to_string()
+}
+
+/// Returns the leading digits of a migration ID.
+fn version_prefix(id: &str) -> Option<&str> {
+    // IDs of migrations in subdirectories start with the directory
+    let id = id.rsplit('/').next().unwrap_or(id);
+    let end = id.find(|c: char| !c.is_ascii_digit()).unwrap_or(id.len());
+    (end > 0).then(|| &id[..end])
+}
+
+/// Checks whether existing migrations use `YYYYMMDDHHMMSS` prefixes.
+fn uses_timestamps(migrations: &[MigrationFile]) -> bool {
+    migrations
+        .last()
+        .and_then(|m| version_prefix(&m.id))
+        .is_some_and(|prefix| prefix.len() == 14)
+}
+
+/// Returns the next sequential prefix, keeping the zero padding of existing migrations.
+fn next_number_prefix(migrations: &[MigrationFile]) -> String {
+    let width = migrations
+        .iter()
+        .filter_map(|m| version_prefix(&m.id))
+        .map(str::len)
+        .max()
+        .unwrap_or(3);
+    let next = migrations
+        .iter()
+        .map(|m| m.version)
+        .max()
+        .map_or(0, |max| max + 1);
+
+    format!("{next:0width$}")
+}
+
+/// Formats a UNIX timestamp as a `YYYYMMDDHHMMSS` prefix in UTC.
+fn timestamp_prefix(unix_secs: u64) -> String {
+    let days = (unix_secs / 86_400) as i64;
+    let secs = unix_secs % 86_400;
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}{month:02}{day:02}{:02}{:02}{:02}",
+        secs / 3_600,
+        secs % 3_600 / 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn files(ids: &[&str]) -> Vec<MigrationFile> {
+        ids.iter()
+            .map(|id| {
+                let (version, name) = VersionScheme::Sequential.parse(id).unwrap();
+                MigrationFile {
+                    id: id.to_string(),
+                    version,
+                    name: name.to_string(),
+                    path: Path::new("migrations").join(format!("{id}.sql")),
+                    down_path: None,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_squash() {
+        let dir =
+            std::env::temp_dir().join(format!("ic-sql-migrate-squash-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let migrations = dir.join("migrations");
+        fs::create_dir_all(&migrations).unwrap();
+        fs::write(
+            migrations.join("000_init.sql"),
+            "CREATE TABLE users (id INTEGER);",
+        )
+        .unwrap();
+        fs::write(
+            migrations.join("001_posts.sql"),
+            "CREATE TABLE posts (id INTEGER);",
+        )
+        .unwrap();
+        fs::write(migrations.join("001_posts.down.sql"), "DROP TABLE posts;").unwrap();
+
+        // The squashed migration replaces the first one, which has the same ID
+        let args: Vec<String> = ["squash", "init", "--manifest-dir"]
+            .into_iter()
+            .map(String::from)
+            .chain([dir.display().to_string()])
+            .collect();
+        squash(&Options::parse(&args).unwrap()).unwrap();
+
+        let mut names: Vec<_> = fs::read_dir(&migrations)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["000_init.sql"]);
+        let squashed = fs::read_to_string(migrations.join("000_init.sql")).unwrap();
+        assert!(squashed.contains("CREATE TABLE users"));
+        assert!(squashed.contains("CREATE TABLE posts"));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_next_number_prefix() {
+        assert_eq!(next_number_prefix(&[]), "000");
+        assert_eq!(
+            next_number_prefix(&files(&["000_init", "001_users"])),
+            "002"
+        );
+        assert_eq!(next_number_prefix(&files(&["9_a", "10_b"])), "11");
+        assert_eq!(next_number_prefix(&files(&["010_b", "9_a"])), "011");
+        assert_eq!(next_number_prefix(&files(&["0001_init"])), "0002");
+    }
+
+    #[test]
+    fn test_timestamp_prefix() {
+        assert_eq!(timestamp_prefix(0), "19700101000000");
+        assert_eq!(timestamp_prefix(1_760_745_600 + 3_723), "20251018010203");
+        assert!(uses_timestamps(&files(&["20251018010203_init"])));
+    }
+
+    #[test]
+    fn test_find_history() {
+        let output =
+            "(\n  variant {\n    Ok = \"ic-sql-migrate/1;001_users=0123;002_a%20b\"\n  },\n)\n";
+        assert_eq!(
+            find_history(output),
+            Some("ic-sql-migrate/1;001_users=0123;002_a%20b")
+        );
+        assert_eq!(find_history("ic-sql-migrate/1\n"), Some("ic-sql-migrate/1"));
+        assert_eq!(find_history("(variant { Err = \"denied\" })"), None);
+    }
+
+    #[test]
+    fn test_sanitize_name() {
+        assert_eq!(sanitize_name("Add users table"), "add_users_table");
+        assert_eq!(sanitize_name("--drop-index!"), "drop_index");
+    }
+}
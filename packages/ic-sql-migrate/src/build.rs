@@ -0,0 +1,1746 @@
+//! Build-time discovery of migration and seed files.
+//!
+//! Everything in this module runs in the build script of the user's crate (or in the
+//! `cargo-ic-sql-migrate` CLI), never inside the canister.
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::{sql, DestructiveChange, Error, MigrateResult};
+
+/// Directive that acknowledges destructive changes in a migration.
+const ALLOW_DESTRUCTIVE: &str = "allow-destructive";
+
+/// Directive that applies a migration only if a cargo feature is enabled.
+const FEATURE: &str = "feature";
+
+/// Directive that applies a migration only if a predicate returns `true`.
+const WHEN: &str = "when";
+
+/// Builder for configuring migration and seed discovery at compile time.
+///
+/// This builder allows you to customize the directories where migrations and seeds
+/// are located. By default, it looks for migrations in `migrations/` and seeds in `src/seeds/`.
+///
+/// # Example in build.rs
+/// ```no_run
+/// // Use defaults (migrations/ and src/seeds/)
+/// // If either directory doesn't exist, it will be skipped automatically
+/// ic_sql_migrate::Builder::new().build().unwrap();
+///
+/// // Custom directories
+/// ic_sql_migrate::Builder::new()
+///     .with_migrations_dir("db/migrations")
+///     .with_seeds_dir("src/db/seeds")
+///     .build()
+///     .unwrap();
+/// ```
+pub struct Builder {
+    migrations_dir: String,
+    seeds_dir: String,
+    manifest_dir: Option<PathBuf>,
+    version_scheme: VersionScheme,
+    recursive: bool,
+    order: MigrationOrder,
+    layout: Layout,
+    minify: bool,
+    #[cfg(feature = "compress")]
+    compress: bool,
+    allow_destructive: bool,
+    vars: BTreeMap<String, String>,
+    env_vars: BTreeMap<String, String>,
+    runtime_vars: BTreeSet<String>,
+}
+
+/// Naming convention of the migration files.
+///
+/// Besides this crate's own convention, the layouts of common Rust and JVM migration
+/// tools are understood, so existing migrations can be embedded without renaming them.
+/// IDs are derived from the original names, so they stay stable across tools.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Layout {
+    /// `<version>_<name>.sql`, e.g. `001_create_users.sql`, with an optional down
+    /// migration in `<version>_<name>.down.sql`. The ID is the file name without
+    /// extension.
+    #[default]
+    Default,
+    /// diesel: one directory per migration named `<version>_<name>` containing `up.sql`
+    /// and `down.sql`, e.g. `2024-01-31-120000_create_users/up.sql`. The version is the
+    /// digits of the timestamp and the ID is the directory name.
+    Diesel,
+    /// sqlx: `<version>_<name>.sql`, or `<version>_<name>.up.sql` and
+    /// `<version>_<name>.down.sql` for reversible migrations. The ID is the file name
+    /// without `.up` and extension.
+    Sqlx,
+    /// refinery: `V<version>__<name>.sql`, e.g. `V1__create_users.sql`. The ID is the
+    /// file name without extension.
+    Refinery,
+    /// Flyway: `V<version>__<description>.sql`, where the version can have several
+    /// parts, e.g. `V1.2__add_index.sql`. Undo migrations (`U<version>__<description>.sql`
+    /// with the same version and description) are used as down migrations. The ID is the
+    /// file name without extension.
+    Flyway,
+}
+
+impl Layout {
+    /// Parses a migration from an entry of a migrations directory.
+    ///
+    /// Returns `None` for entries that are not migrations in this layout, e.g. down
+    /// migrations or files with other extensions.
+    fn parse_entry(
+        self,
+        path: &Path,
+        scheme: VersionScheme,
+    ) -> MigrateResult<Option<ParsedMigration>> {
+        let Some(file_name) = path.file_name().and_then(|s| s.to_str()) else {
+            return Ok(None);
+        };
+
+        if self == Layout::Diesel {
+            if !path.is_dir() {
+                return Ok(None);
+            }
+            let up = path.join("up.sql");
+            if !up.is_file() {
+                return Err(Error::InvalidMigrationFile {
+                    path: path.to_path_buf(),
+                    reason: "diesel migration directory has no up.sql".to_string(),
+                });
+            }
+            let parsed = file_name.split_once('_').and_then(|(prefix, name)| {
+                let valid = !name.is_empty()
+                    && prefix.starts_with(|c: char| c.is_ascii_digit())
+                    && prefix.bytes().all(|b| b.is_ascii_digit() || b == b'-');
+                let digits: String = prefix.chars().filter(char::is_ascii_digit).collect();
+                Some((digits.parse().ok().filter(|_| valid)?, name))
+            });
+            let down = path.join("down.sql");
+            return match parsed {
+                Some((version, name)) => Ok(Some(ParsedMigration {
+                    id: file_name.to_string(),
+                    version: vec![version],
+                    name: name.to_string(),
+                    path: up,
+                    down: down.is_file().then_some(down),
+                })),
+                None => Err(self.naming_error(path, scheme)),
+            };
+        }
+
+        let Some(stem) = file_name.strip_suffix(".sql") else {
+            return Ok(None);
+        };
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        let down_file = match self {
+            Layout::Default => Some(format!("{stem}.down.sql")),
+            Layout::Sqlx => stem.strip_suffix(".up").map(|id| format!("{id}.down.sql")),
+            Layout::Flyway => stem.strip_prefix('V').map(|rest| format!("U{rest}.sql")),
+            Layout::Refinery | Layout::Diesel => None,
+        };
+        let down = down_file
+            .map(|file| path.with_file_name(file))
+            .filter(|down| down.is_file());
+
+        let parsed = match self {
+            Layout::Default => {
+                if stem.ends_with(".down") {
+                    return Ok(None);
+                }
+                scheme
+                    .parse(stem)
+                    .map(|(version, name)| (stem, vec![version], name))
+            }
+            Layout::Sqlx => {
+                if stem.ends_with(".down") {
+                    return Ok(None);
+                }
+                let id = stem.strip_suffix(".up").unwrap_or(stem);
+                scheme
+                    .parse(id)
+                    .map(|(version, name)| (id, vec![version], name))
+            }
+            Layout::Refinery => stem
+                .strip_prefix('V')
+                .and_then(|rest| rest.split_once("__"))
+                .filter(|(version, name)| {
+                    !version.is_empty()
+                        && !name.is_empty()
+                        && version.bytes().all(|b| b.is_ascii_digit())
+                })
+                .and_then(|(version, name)| Some((stem, vec![version.parse().ok()?], name))),
+            Layout::Flyway => {
+                if stem.starts_with('U') {
+                    return Ok(None);
+                }
+                if stem.starts_with("R__") {
+                    return Err(Error::InvalidMigrationFile {
+                        path: path.to_path_buf(),
+                        reason: "repeatable Flyway migrations are not supported".to_string(),
+                    });
+                }
+                stem.strip_prefix('V')
+                    .and_then(|rest| rest.split_once("__"))
+                    .filter(|(_, name)| !name.is_empty())
+                    .and_then(|(version, name)| {
+                        let mut parts = version
+                            .split(['.', '_'])
+                            .map(|part| part.parse::<u64>().ok())
+                            .collect::<Option<Vec<_>>>()?;
+                        // Flyway treats 1 and 1.0 as the same version
+                        while parts.len() > 1 && parts.last() == Some(&0) {
+                            parts.pop();
+                        }
+                        Some((stem, parts, name))
+                    })
+            }
+            Layout::Diesel => unreachable!(),
+        };
+
+        match parsed {
+            Some((id, version, name)) => Ok(Some(ParsedMigration {
+                id: id.to_string(),
+                version,
+                name: name.to_string(),
+                path: path.to_path_buf(),
+                down,
+            })),
+            None => Err(self.naming_error(path, scheme)),
+        }
+    }
+
+    fn naming_error(self, path: &Path, scheme: VersionScheme) -> Error {
+        let expected = match (self, scheme) {
+            (Layout::Default, VersionScheme::Sequential) => "e.g. 001_name.sql",
+            (Layout::Default, VersionScheme::Timestamp) => {
+                "e.g. 001_name.sql with a YYYYMMDDHHMMSS version"
+            }
+            (Layout::Sqlx, VersionScheme::Sequential) => "e.g. 001_name.sql or 001_name.up.sql",
+            (Layout::Sqlx, VersionScheme::Timestamp) => {
+                "e.g. 001_name.up.sql with a YYYYMMDDHHMMSS version"
+            }
+            (Layout::Diesel, _) => "e.g. 2024-01-31-120000_name/up.sql",
+            (Layout::Refinery, _) => "e.g. V1__name.sql",
+            (Layout::Flyway, _) => "e.g. V1.1__name.sql",
+        };
+        Error::InvalidMigrationFile {
+            path: path.to_path_buf(),
+            reason: format!(
+                "name does not start with a valid version prefix (expected {expected})"
+            ),
+        }
+    }
+}
+
+/// A migration parsed from a directory entry, before its final version is known.
+struct ParsedMigration {
+    id: String,
+    /// Version parts; only Flyway versions can have more than one
+    version: Vec<u64>,
+    name: String,
+    path: PathBuf,
+    down: Option<PathBuf>,
+}
+
+/// How migrations from nested directories are ordered when discovery is recursive.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MigrationOrder {
+    /// All migrations are sorted by version, regardless of their directory. Versions
+    /// must be unique across all directories.
+    #[default]
+    Version,
+    /// Migrations are grouped by directory, with directories in natural order (`v2`
+    /// before `v10`) after the files at the top level, and sorted by version within each
+    /// directory. Versions only need to be unique within a directory.
+    Directory,
+}
+
+/// How the version prefix of migration file names is parsed.
+///
+/// Every migration file name must start with a version prefix followed by `_` and a
+/// name, e.g. `001_create_users.sql`. Migrations are sorted numerically by version, so
+/// `10_x.sql` runs after `9_y.sql`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum VersionScheme {
+    /// Any non-negative integer, e.g. `1`, `001` or `20250101120000`.
+    #[default]
+    Sequential,
+    /// A `YYYYMMDDHHMMSS` timestamp, e.g. `20250101120000`.
+    Timestamp,
+}
+
+impl VersionScheme {
+    /// Parses the version prefix of a migration ID into its version and name.
+    ///
+    /// Returns `None` if the ID does not start with a valid version for this scheme
+    /// followed by `_` and a non-empty name.
+    pub fn parse<'a>(&self, id: &'a str) -> Option<(u64, &'a str)> {
+        let (prefix, name) = id.split_once('_')?;
+        if prefix.is_empty() || name.is_empty() || !prefix.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+
+        if *self == VersionScheme::Timestamp && !is_timestamp(prefix) {
+            return None;
+        }
+
+        Some((prefix.parse().ok()?, name))
+    }
+}
+
+/// Checks that a 14 digit prefix is a plausible `YYYYMMDDHHMMSS` timestamp.
+fn is_timestamp(prefix: &str) -> bool {
+    if prefix.len() != 14 {
+        return false;
+    }
+    let field = |range: std::ops::Range<usize>| prefix[range].parse::<u32>().unwrap_or(u32::MAX);
+    (1..=12).contains(&field(4..6))
+        && (1..=31).contains(&field(6..8))
+        && field(8..10) <= 23
+        && field(10..12) <= 59
+        && field(12..14) <= 59
+}
+
+/// A migration file discovered in the migrations directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationFile {
+    /// Migration ID, the file name without extension. For files in subdirectories the
+    /// ID is prefixed with the relative directory, e.g. `billing/001_invoices`.
+    pub id: String,
+    /// Numeric version parsed from the ID prefix. If any migration has a version with
+    /// several parts, as Flyway allows, this is the 1-based position in execution order
+    /// instead.
+    pub version: u64,
+    /// Descriptive name following the version prefix
+    pub name: String,
+    /// Absolute path of the SQL file
+    pub path: PathBuf,
+    /// Absolute path of the SQL file that reverts the migration, if there is one
+    pub down_path: Option<PathBuf>,
+}
+
+impl MigrationFile {
+    /// Computes the checksum of the file, as embedded by the build script.
+    ///
+    /// The embedded checksum of a migration using build-time variables also covers their
+    /// values, so it differs from this one.
+    ///
+    /// # Errors
+    /// Returns an error if the file cannot be read.
+    pub fn checksum(&self) -> MigrateResult<String> {
+        Ok(content_hash(&fs::read_to_string(&self.path)?))
+    }
+
+    /// Returns the names of the `${name}` variables the migration uses, in order of first
+    /// use.
+    ///
+    /// # Errors
+    /// Returns an error if the file cannot be read.
+    pub fn variables(&self) -> MigrateResult<Vec<String>> {
+        let sql = fs::read_to_string(&self.path)?;
+        Ok(sql::variables(&sql)
+            .into_iter()
+            .map(str::to_string)
+            .collect())
+    }
+
+    /// Returns the condition declared by a `-- ic-sql-migrate: feature = name` or
+    /// `-- ic-sql-migrate: when = path::to::predicate` directive, if there is one.
+    ///
+    /// # Errors
+    /// Returns an error if the file cannot be read, and [`Error::InvalidMigrationFile`]
+    /// if a directive is malformed or the file declares more than one condition.
+    pub fn condition(&self) -> MigrateResult<Option<MigrationCondition>> {
+        parse_condition(&self.path, &fs::read_to_string(&self.path)?)
+    }
+
+    /// Finds the statements of the migration that drop schema objects or delete data.
+    ///
+    /// Returns an empty list if the file carries the `-- ic-sql-migrate: allow-destructive`
+    /// directive. Down migrations are not checked, as they are destructive by nature.
+    ///
+    /// # Errors
+    /// Returns an error if the file cannot be read.
+    pub fn destructive_changes(&self) -> MigrateResult<Vec<DestructiveChange>> {
+        let sql = fs::read_to_string(&self.path)?;
+        if sql::directives(&sql).contains(&ALLOW_DESTRUCTIVE) {
+            return Ok(Vec::new());
+        }
+        Ok(sql::destructive_operations(&sql)
+            .into_iter()
+            .map(|(offset, operation)| {
+                let (line, column) = sql::line_column(&sql, offset);
+                DestructiveChange {
+                    path: self.path.clone(),
+                    line,
+                    column,
+                    operation,
+                }
+            })
+            .collect())
+    }
+}
+
+impl Builder {
+    /// Creates a new builder with default settings.
+    ///
+    /// Defaults:
+    /// - Migrations directory: `migrations/`
+    /// - Seeds directory: `src/seeds/`
+    pub fn new() -> Self {
+        Self {
+            migrations_dir: "migrations".to_string(),
+            seeds_dir: "src/seeds".to_string(),
+            manifest_dir: None,
+            version_scheme: VersionScheme::default(),
+            recursive: false,
+            order: MigrationOrder::default(),
+            layout: Layout::default(),
+            minify: false,
+            #[cfg(feature = "compress")]
+            compress: false,
+            allow_destructive: false,
+            vars: BTreeMap::new(),
+            env_vars: BTreeMap::new(),
+            runtime_vars: BTreeSet::new(),
+        }
+    }
+
+    /// Sets the directory where migration SQL files are located.
+    ///
+    /// # Arguments
+    /// * `dir` - Path relative to `Cargo.toml`
+    pub fn with_migrations_dir(mut self, dir: impl Into<String>) -> Self {
+        self.migrations_dir = dir.into();
+        self
+    }
+
+    /// Sets the directory where seed Rust files are located.
+    ///
+    /// # Arguments
+    /// * `dir` - Path relative to `Cargo.toml`
+    pub fn with_seeds_dir(mut self, dir: impl Into<String>) -> Self {
+        self.seeds_dir = dir.into();
+        self
+    }
+
+    /// Sets how version prefixes of migration file names are parsed.
+    ///
+    /// Defaults to [`VersionScheme::Sequential`].
+    pub fn with_version_scheme(mut self, scheme: VersionScheme) -> Self {
+        self.version_scheme = scheme;
+        self
+    }
+
+    /// Enables discovery of migrations in subdirectories of the migrations directory.
+    ///
+    /// Migrations in subdirectories get IDs that include their relative directory,
+    /// e.g. `v1/001_init`, so that files with the same name in different directories
+    /// don't collide. Files at the top level keep their plain IDs.
+    pub fn recursive(mut self, recursive: bool) -> Self {
+        self.recursive = recursive;
+        self
+    }
+
+    /// Sets how migrations from different directories are ordered.
+    ///
+    /// Only relevant together with [`Builder::recursive`]. Defaults to
+    /// [`MigrationOrder::Version`].
+    pub fn with_order(mut self, order: MigrationOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Sets the naming convention of the migration files.
+    ///
+    /// Use this to embed migrations written for diesel, sqlx, refinery or Flyway without
+    /// renaming them. Defaults to [`Layout::Default`]. Recursive discovery is not
+    /// available for [`Layout::Diesel`], whose subdirectories are the migrations.
+    pub fn with_layout(mut self, layout: Layout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Strips comments and redundant whitespace from migrations before embedding them.
+    ///
+    /// String literals, quoted identifiers and trigger bodies are preserved. The checksum
+    /// used to detect modified migrations is still computed from the original file, so
+    /// turning minification on or off doesn't affect already applied migrations.
+    pub fn minify(mut self, minify: bool) -> Self {
+        self.minify = minify;
+        self
+    }
+
+    /// Embeds migrations deflate-compressed instead of as plain text.
+    ///
+    /// This reduces the size of the canister wasm for large migrations. Compressed
+    /// migrations are decompressed only when they are applied. The `compress` feature
+    /// must be enabled both for the build dependency and for the regular dependency,
+    /// which does the decompression.
+    #[cfg(feature = "compress")]
+    pub fn compress(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    /// Allows migrations to drop tables, indexes, views or columns and to delete all rows
+    /// of a table.
+    ///
+    /// By default [`Builder::build`] fails with [`Error::DestructiveChanges`] if a
+    /// migration does so without carrying the `-- ic-sql-migrate: allow-destructive`
+    /// directive, so that data isn't lost by accident. Prefer the directive, which
+    /// acknowledges each destructive migration separately.
+    pub fn allow_destructive(mut self, allow: bool) -> Self {
+        self.allow_destructive = allow;
+        self
+    }
+
+    /// Sets the value of a build-time variable, substituted for `${name}` in migrations.
+    ///
+    /// Variables hold deploy-time values such as the environment name or a default admin.
+    /// They can be used anywhere in the SQL of migrations and down migrations, including
+    /// string literals; `$${` is a literal `${`. [`Builder::build`] fails with
+    /// [`Error::UnresolvedVariable`] if a migration uses a variable that is not set.
+    ///
+    /// Quotes in values substituted inside string literals and quoted identifiers are
+    /// escaped by doubling them. Values substituted anywhere else are inserted as raw
+    /// SQL.
+    ///
+    /// The values are included in the embedded checksums, so changing the value of a
+    /// variable used by an applied migration fails later runs with
+    /// [`Error::ChecksumMismatch`].
+    pub fn with_var(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.vars.insert(name.into(), value.into());
+        self
+    }
+
+    /// Sets a build-time variable to the value of the environment variable `env_var`.
+    ///
+    /// The value is read by [`Builder::build`], which fails with
+    /// [`Error::EnvVarNotFound`] if it is not set, and cargo rebuilds when it changes.
+    pub fn with_env_var(mut self, name: impl Into<String>, env_var: impl Into<String>) -> Self {
+        self.env_vars.insert(name.into(), env_var.into());
+        self
+    }
+
+    /// Declares a variable whose value is only set when migrations are applied.
+    ///
+    /// Use this for values only known in the canister, such as its own principal. The
+    /// placeholders are embedded as they are and substituted with the values of
+    /// [`MigrateOptions::with_var`](crate::MigrateOptions::with_var). Down migrations
+    /// can't use runtime variables, as rolling back takes no options.
+    pub fn with_runtime_var(mut self, name: impl Into<String>) -> Self {
+        self.runtime_vars.insert(name.into());
+        self
+    }
+
+    /// Sets the directory that the migrations and seeds directories are relative to.
+    ///
+    /// Build scripts don't need this, as it defaults to `CARGO_MANIFEST_DIR`. It is
+    /// used by tooling such as the `cargo-ic-sql-migrate` CLI that runs outside of cargo.
+    pub fn with_manifest_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.manifest_dir = Some(dir.into());
+        self
+    }
+
+    /// Returns the absolute path of the migrations directory.
+    ///
+    /// # Errors
+    /// Returns [`Error::EnvVarNotFound`] if no manifest directory was set and
+    /// `CARGO_MANIFEST_DIR` is not set.
+    pub fn migrations_path(&self) -> MigrateResult<PathBuf> {
+        Ok(self.resolve_manifest_dir()?.join(&self.migrations_dir))
+    }
+
+    /// Discovers the migration files that [`Builder::build`] would embed, in execution order.
+    ///
+    /// Returns an empty list if the migrations directory does not exist.
+    ///
+    /// # Errors
+    /// Returns [`Error::Io`] if the migrations directory cannot be read,
+    /// [`Error::InvalidMigrationFile`] if a file name does not follow the configured
+    /// [`Layout`] and [`Error::DuplicateVersion`] if two files share the same version.
+    pub fn discover_migrations(&self) -> MigrateResult<Vec<MigrationFile>> {
+        let migrations_dir = self.migrations_path()?;
+        if !migrations_dir.exists() {
+            return Ok(Vec::new());
+        }
+        collect_migration_files(&migrations_dir, self)
+    }
+
+    /// Executes the builder, discovering and generating code for migrations and seeds.
+    ///
+    /// This method automatically handles missing directories by generating empty arrays.
+    /// You don't need to specify whether directories exist or not.
+    ///
+    /// # Errors
+    /// Returns [`Error::Io`] if file system operations fail, [`Error::EnvVarNotFound`] if
+    /// required environment variables are not set, and the errors of
+    /// [`Builder::discover_migrations`] for invalid migration files. Returns
+    /// [`Error::DestructiveChanges`] if migrations drop schema objects or delete data
+    /// without acknowledging it, unless [`Builder::allow_destructive`] is set, and
+    /// [`Error::UnresolvedVariable`] if migrations use variables that are not set.
+    pub fn build(self) -> MigrateResult<()> {
+        let manifest_dir = self.resolve_manifest_dir()?;
+
+        let out_dir =
+            std::env::var("OUT_DIR").map_err(|_| Error::EnvVarNotFound("OUT_DIR".to_string()))?;
+
+        // Process migrations
+        let migrations_dir = manifest_dir.join(&self.migrations_dir);
+        println!("cargo:rerun-if-changed={}", migrations_dir.display());
+        if self.recursive && self.layout != Layout::Diesel && migrations_dir.exists() {
+            // Ensure cargo rebuilds when files are added to or removed from any subdirectory
+            for dir in collect_subdirs(&migrations_dir)? {
+                println!("cargo:rerun-if-changed={}", dir.display());
+            }
+        }
+
+        let migrations_dest = Path::new(&out_dir).join("migrations_gen.rs");
+
+        let migration_files = self.discover_migrations()?;
+        for file in &migration_files {
+            // Ensure cargo rebuilds when this specific file changes
+            println!("cargo:rerun-if-changed={}", file.path.display());
+            if let Some(down_path) = &file.down_path {
+                println!("cargo:rerun-if-changed={}", down_path.display());
+            }
+        }
+        for env_var in self.env_vars.values() {
+            println!("cargo:rerun-if-env-changed={env_var}");
+        }
+        self.check_destructive(&migration_files)?;
+        if migration_files.is_empty() {
+            fs::write(migrations_dest, "&[]")?;
+        } else {
+            let embedded = self.prepare_migrations(
+                &migration_files,
+                &Path::new(&out_dir).join("embedded_migrations"),
+            )?;
+            let generated_code = generate_migrations_code(&migration_files, &embedded);
+            fs::write(migrations_dest, generated_code)?;
+        }
+
+        // Process seeds - generate mod.rs in the seeds directory
+        let seeds_dir = manifest_dir.join(&self.seeds_dir);
+        println!("cargo:rerun-if-changed={}", seeds_dir.display());
+
+        if seeds_dir.exists() {
+            let seed_files = collect_seed_files(&seeds_dir)?;
+            if !seed_files.is_empty() {
+                let generated_code = generate_seeds_code(&seed_files);
+                let mod_file = seeds_dir.join("mod.rs");
+                fs::write(mod_file, generated_code)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fails if migrations contain unacknowledged destructive changes, listing all of
+    /// them, unless destructive changes are allowed.
+    fn check_destructive(&self, files: &[MigrationFile]) -> MigrateResult<()> {
+        if self.allow_destructive {
+            return Ok(());
+        }
+        let mut changes = Vec::new();
+        for file in files {
+            changes.extend(file.destructive_changes()?);
+        }
+        if changes.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::DestructiveChanges { changes })
+        }
+    }
+
+    /// Prepares the SQL of each migration for embedding.
+    ///
+    /// Build-time variables are substituted, and SQL that was changed by that, minified
+    /// or compressed is written to `dir`, with files named by position as migration IDs
+    /// can contain `/`. Otherwise the original files are embedded.
+    fn prepare_migrations(
+        &self,
+        migration_files: &[MigrationFile],
+        dir: &Path,
+    ) -> MigrateResult<Vec<EmbeddedMigration>> {
+        #[cfg(feature = "compress")]
+        let compress = self.compress;
+        #[cfg(not(feature = "compress"))]
+        let compress = false;
+
+        let vars = self.variables()?;
+        fs::create_dir_all(dir)?;
+
+        migration_files
+            .iter()
+            .enumerate()
+            .map(|(index, file)| {
+                let original = fs::read_to_string(&file.path)?;
+                let condition = parse_condition(&file.path, &original)?;
+                let resolved = self.resolve_variables(&file.id, &original, &vars, true)?;
+                let checksum = checksum_with_vars(&content_hash(&original), &resolved.values);
+                let runtime_vars = resolved
+                    .runtime_vars
+                    .iter()
+                    .map(|v| v.to_string())
+                    .collect();
+
+                let down_path = match &file.down_path {
+                    Some(path) => {
+                        let down = fs::read_to_string(path)?;
+                        match self.resolve_variables(&file.id, &down, &vars, false)?.sql {
+                            Cow::Borrowed(_) => Some(path.clone()),
+                            Cow::Owned(down) => {
+                                let path = dir.join(format!("{index:04}.down.sql"));
+                                fs::write(&path, down)?;
+                                Some(path)
+                            }
+                        }
+                    }
+                    None => None,
+                };
+
+                let sql = match resolved.sql {
+                    Cow::Borrowed(_) if !self.minify && !compress => {
+                        return Ok(EmbeddedMigration {
+                            checksum,
+                            path: file.path.clone(),
+                            compressed: false,
+                            statement_positions: None,
+                            down_path,
+                            runtime_vars,
+                            condition,
+                        });
+                    }
+                    sql => sql,
+                };
+
+                let (sql, statement_positions) = if self.minify {
+                    let positions = sql::split_statements(&sql)
+                        .iter()
+                        .map(|statement| sql::line_column(&sql, statement.offset()))
+                        .collect();
+                    (sql::minify(&sql), Some(positions))
+                } else {
+                    (sql.into_owned(), None)
+                };
+
+                #[cfg(feature = "compress")]
+                if compress {
+                    let path = dir.join(format!("{index:04}.sql.deflate"));
+                    fs::write(
+                        &path,
+                        miniz_oxide::deflate::compress_to_vec(sql.as_bytes(), 10),
+                    )?;
+                    return Ok(EmbeddedMigration {
+                        checksum,
+                        path,
+                        compressed: true,
+                        statement_positions,
+                        down_path,
+                        runtime_vars,
+                        condition,
+                    });
+                }
+
+                let path = dir.join(format!("{index:04}.sql"));
+                fs::write(&path, sql)?;
+                Ok(EmbeddedMigration {
+                    checksum,
+                    path,
+                    compressed: false,
+                    statement_positions,
+                    down_path,
+                    runtime_vars,
+                    condition,
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the values of the build-time variables, reading those set from
+    /// environment variables.
+    fn variables(&self) -> MigrateResult<BTreeMap<String, String>> {
+        let mut vars = self.vars.clone();
+        for (name, env_var) in &self.env_vars {
+            let value =
+                std::env::var(env_var).map_err(|_| Error::EnvVarNotFound(env_var.clone()))?;
+            vars.insert(name.clone(), value);
+        }
+        Ok(vars)
+    }
+
+    /// Substitutes the build-time variables of a migration's SQL.
+    ///
+    /// Runtime variables are kept if `runtime` is set. Fails with
+    /// [`Error::UnresolvedVariable`] for any other variable that is not set.
+    fn resolve_variables<'a>(
+        &self,
+        id: &str,
+        sql: &'a str,
+        vars: &'a BTreeMap<String, String>,
+        runtime: bool,
+    ) -> MigrateResult<ResolvedSql<'a>> {
+        let mut values = Vec::new();
+        let mut runtime_vars = Vec::new();
+        for name in sql::variables(sql) {
+            if let Some((name, value)) = vars.get_key_value(name) {
+                values.push((name.as_str(), value.as_str()));
+            } else if runtime && self.runtime_vars.contains(name) {
+                runtime_vars.push(name);
+            } else {
+                return Err(Error::UnresolvedVariable {
+                    id: id.to_string(),
+                    name: name.to_string(),
+                });
+            }
+        }
+
+        let sql = sql::substitute_variables(
+            sql,
+            |name| vars.get(name).map(String::as_str),
+            runtime_vars.is_empty(),
+        );
+        Ok(ResolvedSql {
+            sql,
+            values,
+            runtime_vars,
+        })
+    }
+
+    fn resolve_manifest_dir(&self) -> MigrateResult<PathBuf> {
+        match &self.manifest_dir {
+            Some(dir) => Ok(dir.clone()),
+            None => std::env::var("CARGO_MANIFEST_DIR")
+                .map(PathBuf::from)
+                .map_err(|_| Error::EnvVarNotFound("CARGO_MANIFEST_DIR".to_string())),
+        }
+    }
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Collects all SQL migration files from the specified directory.
+///
+/// Returns the migration files in execution order, as configured on the builder.
+fn collect_migration_files(
+    migrations_dir: &Path,
+    builder: &Builder,
+) -> MigrateResult<Vec<MigrationFile>> {
+    let mut dirs = vec![migrations_dir.to_path_buf()];
+    if builder.recursive && builder.layout != Layout::Diesel {
+        dirs.extend(collect_subdirs(migrations_dir)?);
+    }
+
+    // Migrations paired with the directory they are in, relative to the migrations directory
+    let mut migrations = Vec::new();
+
+    for dir in dirs {
+        let relative_dir = dir
+            .strip_prefix(migrations_dir)
+            .unwrap_or(Path::new(""))
+            .to_string_lossy()
+            .replace('\\', "/");
+
+        let entries = fs::read_dir(&dir)?;
+        for entry in entries {
+            let path = entry?.path();
+            let Some(mut migration) = builder.layout.parse_entry(&path, builder.version_scheme)?
+            else {
+                continue;
+            };
+
+            if !relative_dir.is_empty() {
+                migration.id = format!("{relative_dir}/{}", migration.id);
+            }
+            migrations.push((relative_dir.clone(), migration));
+        }
+    }
+
+    // Sort numerically by version to ensure consistent ordering
+    match builder.order {
+        MigrationOrder::Version => migrations
+            .sort_by(|(_, a), (_, b)| a.version.cmp(&b.version).then_with(|| a.id.cmp(&b.id))),
+        MigrationOrder::Directory => migrations.sort_by(|(dir_a, a), (dir_b, b)| {
+            natural_cmp(dir_a, dir_b)
+                .then_with(|| a.version.cmp(&b.version))
+                .then_with(|| a.id.cmp(&b.id))
+        }),
+    }
+
+    // Versions must be unique within the scope they are ordered in
+    if let Some(pair) = migrations.windows(2).find(|pair| {
+        pair[0].1.version == pair[1].1.version
+            && (builder.order == MigrationOrder::Version || pair[0].0 == pair[1].0)
+    }) {
+        let version: Vec<_> = pair[0].1.version.iter().map(u64::to_string).collect();
+        return Err(Error::DuplicateVersion {
+            version: version.join("."),
+            first: pair[0].1.path.clone(),
+            second: pair[1].1.path.clone(),
+        });
+    }
+
+    // Versions with several parts don't fit in a u64, so fall back to the position
+    let positional = migrations.iter().any(|(_, m)| m.version.len() > 1);
+
+    Ok(migrations
+        .into_iter()
+        .enumerate()
+        .map(|(index, (_, migration))| MigrationFile {
+            id: migration.id,
+            version: if positional {
+                index as u64 + 1
+            } else {
+                migration.version[0]
+            },
+            name: migration.name,
+            path: migration.path,
+            down_path: migration.down,
+        })
+        .collect())
+}
+
+/// Recursively collects all subdirectories of a directory, sorted by path.
+fn collect_subdirs(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut subdirs = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            subdirs.extend(collect_subdirs(&path)?);
+            subdirs.push(path);
+        }
+    }
+    subdirs.sort();
+    Ok(subdirs)
+}
+
+/// Compares two strings treating runs of digits as numbers, so that `v2` < `v10`.
+fn natural_cmp(a: &str, b: &str) -> std::cmp::Ordering {
+    fn chunks(s: &str) -> Vec<(bool, &str)> {
+        let mut chunks = Vec::new();
+        let mut rest = s;
+        while let Some(first) = rest.chars().next() {
+            let digit = first.is_ascii_digit();
+            let end = rest
+                .find(|c: char| c.is_ascii_digit() != digit)
+                .unwrap_or(rest.len());
+            chunks.push((digit, &rest[..end]));
+            rest = &rest[end..];
+        }
+        chunks
+    }
+
+    let (a, b) = (chunks(a), chunks(b));
+    for (x, y) in a.iter().zip(&b) {
+        let ordering = match (x, y) {
+            ((true, x), (true, y)) => {
+                let (x, y) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+                x.len().cmp(&y.len()).then_with(|| x.cmp(y))
+            }
+            ((_, x), (_, y)) => x.cmp(y),
+        };
+        if ordering.is_ne() {
+            return ordering;
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+/// The SQL of a migration with its build-time variables substituted.
+struct ResolvedSql<'a> {
+    sql: Cow<'a, str>,
+    /// Names and values of the substituted variables, in order of first use
+    values: Vec<(&'a str, &'a str)>,
+    /// Names of the runtime variables kept in the SQL, in order of first use
+    runtime_vars: Vec<&'a str>,
+}
+
+/// The SQL of a migration as it is embedded in the generated code.
+struct EmbeddedMigration {
+    /// Checksum of the original file content and the values of its build-time variables
+    checksum: String,
+    /// File to include, with `include_bytes!` if compressed and `include_str!` otherwise
+    path: PathBuf,
+    compressed: bool,
+    /// Line and column of each statement in the original file, set if the SQL was minified
+    statement_positions: Option<Vec<(usize, usize)>>,
+    /// Down migration file to include, if there is one
+    down_path: Option<PathBuf>,
+    /// Names of the runtime variables in the SQL
+    runtime_vars: Vec<String>,
+    /// Condition declared by a directive
+    condition: Option<MigrationCondition>,
+}
+
+/// The condition of a conditional migration, declared by a directive in its file.
+///
+/// See [`Migration::when_feature`](crate::Migration::when_feature) and
+/// [`Migration::when`](crate::Migration::when).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MigrationCondition {
+    /// `-- ic-sql-migrate: feature = name`: applied if the cargo feature is enabled
+    Feature(String),
+    /// `-- ic-sql-migrate: when = path::to::predicate`: applied if the predicate, a
+    /// `fn() -> bool` in the canister crate, returns `true`
+    Predicate(String),
+}
+
+/// Parses the condition directive of a migration file.
+fn parse_condition(path: &Path, sql: &str) -> MigrateResult<Option<MigrationCondition>> {
+    let invalid = |reason: String| Error::InvalidMigrationFile {
+        path: path.to_path_buf(),
+        reason,
+    };
+
+    let mut condition = None;
+    for directive in sql::directives(sql) {
+        let Some((key, value)) = directive.split_once('=') else {
+            continue;
+        };
+        let value = value.trim();
+        let parsed = match key.trim() {
+            FEATURE => {
+                let name = value
+                    .strip_prefix('"')
+                    .and_then(|v| v.strip_suffix('"'))
+                    .unwrap_or(value);
+                let valid = !name.is_empty()
+                    && name
+                        .chars()
+                        .all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '+' | '.'));
+                if !valid {
+                    return Err(invalid(format!("invalid feature name '{name}'")));
+                }
+                MigrationCondition::Feature(name.to_string())
+            }
+            WHEN => {
+                let valid = value.split("::").all(|segment| {
+                    segment.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                        && segment
+                            .bytes()
+                            .all(|b| b.is_ascii_alphanumeric() || b == b'_')
+                });
+                if !valid {
+                    return Err(invalid(format!("invalid predicate path '{value}'")));
+                }
+                MigrationCondition::Predicate(value.to_string())
+            }
+            _ => continue,
+        };
+        if condition.replace(parsed).is_some() {
+            return Err(invalid("more than one condition directive".to_string()));
+        }
+    }
+    Ok(condition)
+}
+
+/// Generates Rust code for including migration files.
+///
+/// Creates a static array initialization with all migration files. A migration gated by
+/// a cargo feature is only included if the feature is enabled; otherwise an entry
+/// without SQL takes its place, so that it is recorded as skipped.
+fn generate_migrations_code(
+    migration_files: &[MigrationFile],
+    embedded: &[EmbeddedMigration],
+) -> String {
+    let mut code = String::from("&[\n");
+
+    for (file, embedded) in migration_files.iter().zip(embedded) {
+        if let Some(MigrationCondition::Feature(feature)) = &embedded.condition {
+            code.push_str(&format!(
+                "    #[cfg(not(feature = \"{feature}\"))]\n    \
+                 ic_sql_migrate::Migration::new(\"{}\", \"\").with_version({}, \"{}\")\
+                 .when_feature(\"{feature}\", false),\n",
+                file.id, file.version, file.name
+            ));
+            code.push_str(&format!("    #[cfg(feature = \"{feature}\")]\n"));
+        }
+        let migration = if embedded.compressed {
+            format!(
+                "ic_sql_migrate::Migration::compressed(\"{}\", include_bytes!(\"{}\"))",
+                file.id,
+                embedded.path.display()
+            )
+        } else {
+            format!(
+                "ic_sql_migrate::Migration::new(\"{}\", include_str!(\"{}\"))",
+                file.id,
+                embedded.path.display()
+            )
+        };
+        code.push_str(&format!(
+            "    {migration}.with_version({}, \"{}\").with_checksum(\"{}\")",
+            file.version, file.name, embedded.checksum
+        ));
+        if let Some(down_path) = &embedded.down_path {
+            code.push_str(&format!(
+                ".with_down(include_str!(\"{}\"))",
+                down_path.display()
+            ));
+        }
+        if !embedded.runtime_vars.is_empty() {
+            let vars: Vec<_> = embedded
+                .runtime_vars
+                .iter()
+                .map(|name| format!("\"{name}\""))
+                .collect();
+            code.push_str(&format!(".with_vars(&[{}])", vars.join(", ")));
+        }
+        if let Some(positions) = &embedded.statement_positions {
+            let positions: Vec<_> = positions
+                .iter()
+                .map(|(line, column)| format!("({line}, {column})"))
+                .collect();
+            code.push_str(&format!(
+                ".with_statement_positions(&[{}])",
+                positions.join(", ")
+            ));
+        }
+        match &embedded.condition {
+            Some(MigrationCondition::Feature(feature)) => {
+                code.push_str(&format!(".when_feature(\"{feature}\", true)"));
+            }
+            Some(MigrationCondition::Predicate(predicate)) => {
+                code.push_str(&format!(".when({predicate})"));
+            }
+            None => {}
+        }
+        code.push_str(",\n");
+    }
+
+    code.push_str("]\n");
+    code
+}
+
+/// A seed module discovered at build time.
+struct SeedFile {
+    /// Module name, also used as the seed ID
+    id: String,
+    /// Content hash, set when the module declares a `VERSIONED` constant
+    hash: Option<String>,
+    /// Whether the module declares a `REQUIRES_MIGRATION` constant
+    requires_migration: bool,
+}
+
+/// Collects all Rust seed files from the specified directory.
+///
+/// Returns a list of seed files sorted by ID.
+/// Excludes mod.rs as it's the module declaration file.
+fn collect_seed_files(seeds_dir: &Path) -> io::Result<Vec<SeedFile>> {
+    let mut seed_files = Vec::new();
+
+    let entries = fs::read_dir(seeds_dir)?;
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path();
+
+        if path.extension().and_then(|s| s.to_str()) != Some("rs") {
+            continue;
+        }
+
+        if let Some(file_stem) = path.file_stem().and_then(|s| s.to_str()) {
+            // Skip mod.rs as it's the generated module file
+            if file_stem == "mod" {
+                continue;
+            }
+
+            let content = fs::read_to_string(&path)?;
+            let versioned = seed_const(&content, "VERSIONED").is_some();
+
+            seed_files.push(SeedFile {
+                id: file_stem.to_string(),
+                hash: versioned.then(|| content_hash(&content)),
+                requires_migration: seed_const(&content, "REQUIRES_MIGRATION").is_some(),
+            });
+
+            println!("cargo:rerun-if-changed={}", path.display());
+        }
+    }
+
+    seed_files.sort_by(|a, b| a.id.cmp(&b.id));
+
+    Ok(seed_files)
+}
+
+/// Looks up the value of a `pub const NAME: Type = value;` declaration in a seed module.
+///
+/// Seed modules are not compiled at build time, so the declaration is found by scanning
+/// the source text. Returns the value expression with surrounding whitespace trimmed.
+fn seed_const(content: &str, name: &str) -> Option<String> {
+    content.lines().find_map(|line| {
+        let rest = line.trim().strip_prefix("pub const ")?.strip_prefix(name)?;
+        let rest = rest.trim_start().strip_prefix(':')?;
+        let (_, value) = rest.split_once('=')?;
+        Some(value.trim().trim_end_matches(';').trim().to_string())
+    })
+}
+
+/// Generates a mod.rs file for the seeds module.
+///
+/// Creates a module file that:
+/// 1. Declares all seed submodules in alphabetical order
+/// 2. Exports a SEEDS constant with all seed functions in order
+///
+/// This function is feature-agnostic and generates generic code.
+/// The actual type checking happens at compile time when the user's
+/// crate is built with the appropriate feature.
+fn generate_seeds_code(seed_files: &[SeedFile]) -> String {
+    let mut code = String::new();
+
+    code.push_str("// This file is auto-generated by ic-sql-migrate\n");
+    code.push_str("// Do not edit manually\n\n");
+
+    // Declare all submodules
+    for seed in seed_files {
+        code.push_str(&format!("pub mod {};\n", seed.id));
+    }
+
+    code.push('\n');
+    code.push_str("use ic_sql_migrate::Seed;\n\n");
+
+    // Create the SEEDS array
+    code.push_str("pub static SEEDS: &[Seed] = &[\n");
+    for seed in seed_files {
+        let seed_id = &seed.id;
+        // Versioned seeds are chosen by the module's `VERSIONED` constant, so that
+        // setting it to `false` turns the seed back into a run-once seed
+        let mut expr = match &seed.hash {
+            Some(hash) => format!(
+                "(if {seed_id}::VERSIONED {{ Seed::versioned(\"{seed_id}\", \"{hash}\", {seed_id}::seed) }} \
+                 else {{ Seed::new(\"{seed_id}\", {seed_id}::seed) }})"
+            ),
+            None => format!("Seed::new(\"{seed_id}\", {seed_id}::seed)"),
+        };
+        if seed.requires_migration {
+            expr.push_str(&format!(".requires({seed_id}::REQUIRES_MIGRATION)"));
+        }
+        code.push_str(&format!("    {expr},\n"));
+    }
+    code.push_str("];\n");
+
+    code
+}
+
+/// Computes the content hash used to track versioned seeds and migration checksums.
+///
+/// This is a 64-bit FNV-1a hash rendered as 16 lowercase hex characters. It is
+/// only used to detect changes, not for any security purpose.
+fn content_hash(content: &str) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in content.bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{hash:016x}")
+}
+
+/// Combines the checksum of a migration with the values of the variables substituted in
+/// it, so that changing a value is detected like changing the file.
+///
+/// The checksum is returned unchanged if no variables were substituted.
+pub(crate) fn checksum_with_vars(checksum: &str, values: &[(&str, &str)]) -> String {
+    if values.is_empty() {
+        return checksum.to_string();
+    }
+    let mut content = checksum.to_string();
+    for (name, value) in values {
+        content.push_str(&format!("\n{name}={value}"));
+    }
+    content_hash(&content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates an empty, uniquely named directory under the system temp dir.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("ic-sql-migrate-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn ids(files: &[MigrationFile]) -> Vec<&str> {
+        files.iter().map(|f| f.id.as_str()).collect()
+    }
+
+    #[test]
+    fn test_version_scheme_parse() {
+        assert_eq!(
+            VersionScheme::Sequential.parse("001_init"),
+            Some((1, "init"))
+        );
+        assert_eq!(
+            VersionScheme::Sequential.parse("10_add_users"),
+            Some((10, "add_users"))
+        );
+        assert_eq!(VersionScheme::Sequential.parse("init"), None);
+        assert_eq!(VersionScheme::Sequential.parse("001_"), None);
+        assert_eq!(VersionScheme::Sequential.parse("v1_init"), None);
+
+        assert_eq!(
+            VersionScheme::Timestamp.parse("20250101120000_init"),
+            Some((20250101120000, "init"))
+        );
+        assert_eq!(VersionScheme::Timestamp.parse("001_init"), None);
+        assert_eq!(VersionScheme::Timestamp.parse("20251301120000_init"), None);
+    }
+
+    #[test]
+    fn test_migrations_sorted_numerically() {
+        let dir = temp_dir("sorted");
+        for file in ["9_y.sql", "10_x.sql", "1_a.sql", "notes.txt"] {
+            fs::write(dir.join(file), "").unwrap();
+        }
+
+        let files = collect_migration_files(&dir, &Builder::new()).unwrap();
+        assert_eq!(ids(&files), ["1_a", "9_y", "10_x"]);
+        assert_eq!(files[2].version, 10);
+        assert_eq!(files[2].name, "x");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_and_duplicate_versions_rejected() {
+        let dir = temp_dir("invalid");
+        fs::write(dir.join("001_init.sql"), "").unwrap();
+        fs::write(dir.join("init.sql"), "").unwrap();
+
+        let err = collect_migration_files(&dir, &Builder::new()).unwrap_err();
+        assert!(
+            matches!(err, Error::InvalidMigrationFile { path, .. } if path.ends_with("init.sql"))
+        );
+
+        fs::remove_file(dir.join("init.sql")).unwrap();
+        fs::write(dir.join("1_other.sql"), "").unwrap();
+
+        let err = collect_migration_files(&dir, &Builder::new()).unwrap_err();
+        assert!(matches!(err, Error::DuplicateVersion { version, .. } if version == "1"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_recursive_discovery() {
+        let dir = temp_dir("recursive");
+        fs::create_dir_all(dir.join("v2")).unwrap();
+        fs::create_dir_all(dir.join("v10")).unwrap();
+        fs::write(dir.join("000_base.sql"), "").unwrap();
+        fs::write(dir.join("v10").join("003_c.sql"), "").unwrap();
+        fs::write(dir.join("v2").join("001_a.sql"), "").unwrap();
+        fs::write(dir.join("v2").join("004_d.sql"), "").unwrap();
+
+        // Subdirectories are ignored unless discovery is recursive
+        let files = collect_migration_files(&dir, &Builder::new()).unwrap();
+        assert_eq!(ids(&files), ["000_base"]);
+
+        let builder = Builder::new().recursive(true);
+        let files = collect_migration_files(&dir, &builder).unwrap();
+        assert_eq!(
+            ids(&files),
+            ["000_base", "v2/001_a", "v10/003_c", "v2/004_d"]
+        );
+
+        let builder = builder.with_order(MigrationOrder::Directory);
+        let files = collect_migration_files(&dir, &builder).unwrap();
+        assert_eq!(
+            ids(&files),
+            ["000_base", "v2/001_a", "v2/004_d", "v10/003_c"]
+        );
+
+        // The same version in different directories is only allowed when ordering by directory
+        fs::write(dir.join("v10").join("001_x.sql"), "").unwrap();
+        assert!(collect_migration_files(&dir, &builder).is_ok());
+        let builder = builder.with_order(MigrationOrder::Version);
+        assert!(collect_migration_files(&dir, &builder).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_layouts() {
+        let dir = temp_dir("layouts");
+
+        let discover = |layout: Layout, files: &[&str]| {
+            let _ = fs::remove_dir_all(&dir);
+            for file in files {
+                let path = dir.join(file);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, "").unwrap();
+            }
+            collect_migration_files(&dir, &Builder::new().with_layout(layout))
+        };
+
+        let files = discover(
+            Layout::Default,
+            &[
+                "001_create_users.sql",
+                "001_create_users.down.sql",
+                "002_add_email.sql",
+            ],
+        )
+        .unwrap();
+        assert_eq!(ids(&files), ["001_create_users", "002_add_email"]);
+        assert!(files[0]
+            .down_path
+            .as_ref()
+            .is_some_and(|p| p.ends_with("001_create_users.down.sql")));
+        assert_eq!(files[1].down_path, None);
+
+        let files = discover(
+            Layout::Diesel,
+            &[
+                "2024-02-01-000000_add_email/up.sql",
+                "2024-02-01-000000_add_email/down.sql",
+                "2024-01-31-120000_create_users/up.sql",
+                "2024-01-31-120000_create_users/down.sql",
+                ".keep",
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            ids(&files),
+            [
+                "2024-01-31-120000_create_users",
+                "2024-02-01-000000_add_email"
+            ]
+        );
+        assert_eq!(files[0].version, 20240131120000);
+        assert_eq!(files[0].name, "create_users");
+        assert!(files[0].path.ends_with("up.sql"));
+        assert!(files[0]
+            .down_path
+            .as_ref()
+            .is_some_and(|p| p.ends_with("down.sql")));
+        assert!(discover(Layout::Diesel, &["2024-01-31-120000_x/down.sql"]).is_err());
+
+        let files = discover(
+            Layout::Sqlx,
+            &[
+                "2_add_email.up.sql",
+                "2_add_email.down.sql",
+                "1_create_users.sql",
+            ],
+        )
+        .unwrap();
+        assert_eq!(ids(&files), ["1_create_users", "2_add_email"]);
+        assert_eq!(files[0].down_path, None);
+        assert!(files[1]
+            .down_path
+            .as_ref()
+            .is_some_and(|p| p.ends_with("2_add_email.down.sql")));
+
+        let files = discover(
+            Layout::Refinery,
+            &[
+                "V10__add_index.sql",
+                "V2__add_email.sql",
+                "V1__create_users.sql",
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            ids(&files),
+            ["V1__create_users", "V2__add_email", "V10__add_index"]
+        );
+        assert_eq!(files[2].version, 10);
+        assert_eq!(files[2].name, "add_index");
+        assert!(discover(Layout::Refinery, &["U1__unversioned.sql"]).is_err());
+
+        let files = discover(
+            Layout::Flyway,
+            &[
+                "V2__add_index.sql",
+                "V1.1__add_email.sql",
+                "V1__create_users.sql",
+                "U1.1__add_email.sql",
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            ids(&files),
+            ["V1__create_users", "V1.1__add_email", "V2__add_index"]
+        );
+        assert_eq!(files[1].version, 2);
+        assert!(files[1]
+            .down_path
+            .as_ref()
+            .is_some_and(|p| p.ends_with("U1.1__add_email.sql")));
+        assert_eq!(files[0].down_path, None);
+        assert!(discover(Layout::Flyway, &["V1__a.sql", "V1.0__b.sql"]).is_err());
+        assert!(discover(Layout::Flyway, &["R__views.sql"]).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "compress")]
+    #[test]
+    fn test_compressed_migrations() {
+        let dir = temp_dir("compressed");
+        let sql = "CREATE TABLE users (id INTEGER PRIMARY KEY);\n".repeat(50);
+        fs::write(dir.join("001_users.sql"), &sql).unwrap();
+
+        let builder = Builder::new().compress(true);
+        let files = collect_migration_files(&dir, &builder).unwrap();
+        let embedded = builder
+            .prepare_migrations(&files, &dir.join("out"))
+            .unwrap();
+
+        let code = generate_migrations_code(&files, &embedded);
+        assert!(code.contains("Migration::compressed(\"001_users\", include_bytes!("));
+
+        let compressed = fs::read(&embedded[0].path).unwrap();
+        assert!(compressed.len() < sql.len());
+        let migration = crate::Migration::compressed("001_users", compressed.leak());
+        assert_eq!(migration.load_sql().unwrap(), sql);
+
+        let corrupt = crate::Migration::compressed("002_corrupt", b"not deflate");
+        assert!(matches!(
+            corrupt.load_sql(),
+            Err(crate::Error::Decompression { .. })
+        ));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_minified_migrations() {
+        let dir = temp_dir("minified");
+        let sql = "-- Users\nCREATE TABLE users (\n    id INTEGER PRIMARY KEY\n);\n";
+        fs::write(dir.join("001_users.sql"), sql).unwrap();
+
+        let files = collect_migration_files(&dir, &Builder::new()).unwrap();
+        let out = dir.join("out");
+
+        // The original file is embedded unless minification is enabled
+        let embedded = Builder::new().prepare_migrations(&files, &out).unwrap();
+        assert_eq!(embedded[0].path, files[0].path);
+
+        let minified = Builder::new()
+            .minify(true)
+            .prepare_migrations(&files, &out)
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(&minified[0].path).unwrap(),
+            "CREATE TABLE users(id INTEGER PRIMARY KEY);"
+        );
+
+        // The checksum is always computed from the original file
+        assert_eq!(minified[0].checksum, content_hash(sql));
+        assert_eq!(minified[0].checksum, embedded[0].checksum);
+        let code = generate_migrations_code(&files, &minified);
+        assert!(code.contains(&format!(
+            ".with_checksum(\"{}\").with_statement_positions(&[(2, 1)])",
+            content_hash(sql)
+        )));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_variables() {
+        let dir = temp_dir("variables");
+        fs::write(
+            dir.join("001_admins.sql"),
+            "INSERT INTO admins VALUES ('${admin}', '${canister}', '$${literal}');",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("001_admins.down.sql"),
+            "DELETE FROM admins WHERE id = '${admin}';",
+        )
+        .unwrap();
+        fs::write(dir.join("002_plain.sql"), "SELECT 1;").unwrap();
+
+        let files = collect_migration_files(&dir, &Builder::new()).unwrap();
+        let out = dir.join("out");
+
+        // Every variable must be set or declared as a runtime variable
+        let result = Builder::new()
+            .with_var("admin", "aaaaa-aa")
+            .prepare_migrations(&files, &out);
+        assert!(matches!(
+            result,
+            Err(Error::UnresolvedVariable { id, name }) if id == "001_admins" && name == "canister"
+        ));
+        let result = Builder::new()
+            .with_env_var("admin", "IC_SQL_MIGRATE_TEST_UNSET")
+            .prepare_migrations(&files, &out);
+        assert!(matches!(
+            result,
+            Err(Error::EnvVarNotFound(name)) if name == "IC_SQL_MIGRATE_TEST_UNSET"
+        ));
+
+        let builder = Builder::new()
+            .with_var("admin", "aaaaa-aa")
+            .with_runtime_var("canister");
+        let embedded = builder.prepare_migrations(&files, &out).unwrap();
+        assert_eq!(
+            fs::read_to_string(&embedded[0].path).unwrap(),
+            "INSERT INTO admins VALUES ('aaaaa-aa', '${canister}', '$${literal}');"
+        );
+        assert_eq!(
+            fs::read_to_string(embedded[0].down_path.as_ref().unwrap()).unwrap(),
+            "DELETE FROM admins WHERE id = 'aaaaa-aa';"
+        );
+        assert_eq!(embedded[0].runtime_vars, ["canister"]);
+        let code = generate_migrations_code(&files, &embedded);
+        assert!(code.contains(".with_vars(&[\"canister\"])"));
+
+        // Migrations without variables are embedded as they are
+        assert_eq!(embedded[1].path, files[1].path);
+        assert_eq!(embedded[1].checksum, files[1].checksum().unwrap());
+
+        // The values of build-time variables are part of the checksum
+        let other = Builder::new()
+            .with_var("admin", "bbbbb-bb")
+            .with_runtime_var("canister")
+            .prepare_migrations(&files, &out)
+            .unwrap();
+        assert_ne!(embedded[0].checksum, files[0].checksum().unwrap());
+        assert_ne!(embedded[0].checksum, other[0].checksum);
+
+        // Down migrations can't use runtime variables
+        fs::write(
+            dir.join("001_admins.down.sql"),
+            "DELETE FROM admins WHERE id = '${canister}';",
+        )
+        .unwrap();
+        assert!(matches!(
+            builder.prepare_migrations(&files, &out),
+            Err(Error::UnresolvedVariable { name, .. }) if name == "canister"
+        ));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_conditions() {
+        let dir = temp_dir("conditions");
+        fs::write(
+            dir.join("001_users.sql"),
+            "CREATE TABLE users (id INTEGER);",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("002_audit.sql"),
+            "-- ic-sql-migrate: feature = \"audit\"\nCREATE TABLE audit (id INTEGER);",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("003_events.sql"),
+            "/* ic-sql-migrate: when = crate::flags::events */ CREATE TABLE events (id INTEGER);",
+        )
+        .unwrap();
+
+        let files = collect_migration_files(&dir, &Builder::new()).unwrap();
+        assert_eq!(files[0].condition().unwrap(), None);
+        assert_eq!(
+            files[1].condition().unwrap(),
+            Some(MigrationCondition::Feature("audit".to_string()))
+        );
+        assert_eq!(
+            files[2].condition().unwrap(),
+            Some(MigrationCondition::Predicate(
+                "crate::flags::events".to_string()
+            ))
+        );
+
+        let embedded = Builder::new()
+            .prepare_migrations(&files, &dir.join("out"))
+            .unwrap();
+        let code = generate_migrations_code(&files, &embedded);
+        let lines: Vec<&str> = code.lines().collect();
+        assert_eq!(lines[2], "    #[cfg(not(feature = \"audit\"))]");
+        assert_eq!(
+            lines[3],
+            "    ic_sql_migrate::Migration::new(\"002_audit\", \"\").with_version(2, \"audit\")\
+             .when_feature(\"audit\", false),"
+        );
+        assert_eq!(lines[4], "    #[cfg(feature = \"audit\")]");
+        assert!(lines[5].ends_with(".when_feature(\"audit\", true),"));
+        assert!(lines[6].ends_with(".when(crate::flags::events),"));
+
+        for directive in [
+            "feature = \"audit\\\"\"",
+            "when = crate::flags::events()",
+            "feature = audit */ /* ic-sql-migrate: when = events",
+        ] {
+            fs::write(
+                dir.join("002_audit.sql"),
+                format!("/* ic-sql-migrate: {directive} */ CREATE TABLE audit (id INTEGER);"),
+            )
+            .unwrap();
+            assert!(matches!(
+                files[1].condition(),
+                Err(Error::InvalidMigrationFile { path, .. }) if path == files[1].path
+            ));
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_destructive_changes_require_acknowledgement() {
+        let dir = temp_dir("destructive");
+        fs::write(
+            dir.join("001_users.sql"),
+            "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT);",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("002_cleanup.sql"),
+            "DELETE FROM sessions WHERE expired = 1;\n\nDROP INDEX IF EXISTS idx_name;\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("003_drop_name.sql"),
+            "-- ic-sql-migrate: allow-destructive\nALTER TABLE users DROP COLUMN name;",
+        )
+        .unwrap();
+        fs::write(dir.join("003_drop_name.down.sql"), "DROP TABLE users;").unwrap();
+
+        let files = collect_migration_files(&dir, &Builder::new()).unwrap();
+        let changes = files[1].destructive_changes().unwrap();
+        assert_eq!(
+            changes,
+            [DestructiveChange {
+                path: dir.join("002_cleanup.sql"),
+                line: 3,
+                column: 1,
+                operation: crate::DestructiveOperation::DropIndex {
+                    index: "idx_name".to_string()
+                },
+            }]
+        );
+        // Acknowledged migrations and down migrations are not reported
+        assert!(files[2].destructive_changes().unwrap().is_empty());
+
+        let err = Builder::new().check_destructive(&files).unwrap_err();
+        assert!(matches!(&err, Error::DestructiveChanges { changes: c } if *c == changes));
+        assert!(err
+            .to_string()
+            .ends_with("002_cleanup.sql:3:1: drops index 'idx_name'"));
+
+        assert!(Builder::new()
+            .allow_destructive(true)
+            .check_destructive(&files)
+            .is_ok());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
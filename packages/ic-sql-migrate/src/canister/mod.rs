@@ -0,0 +1,75 @@
+//! Canister lifecycle integration for `ic-cdk` (feature: `ic-cdk`).
+//!
+//! Every canister using this library needs the same lifecycle hooks: `#[init]` and
+//! `#[post_upgrade]` apply pending migrations and seeds, and `#[pre_upgrade]` releases
+//! the database connection. The [`lifecycle!`](crate::lifecycle) macro generates these
+//! hooks from a [`Lifecycle`], which lists the migrations and seeds to apply and the
+//! canister's own logic to run before or after them.
+//!
+//! With the `turso` feature the generated hooks also mount the database file in stable
+//! memory, and the connection they open is available through [`connection`].
+//!
+//! With the `admin` feature the [`admin_interface!`](crate::admin_interface) macro adds
+//! canister methods to inspect migrations, migrate or roll back to a given migration and
+//! re-run seeds. Their Candid interface is [`ADMIN_CANDID`].
+//!
+//! # Example
+//! ```ignore
+//! use ic_sql_migrate::canister::Lifecycle;
+//!
+//! mod seeds;
+//!
+//! static LIFECYCLE: Lifecycle = Lifecycle::new(ic_sql_migrate::include_migrations!())
+//!     .with_seeds(seeds::SEEDS)
+//!     .after_init(load_settings);
+//!
+//! ic_sql_migrate::lifecycle!(LIFECYCLE);
+//! ```
+
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(feature = "turso")]
+mod turso;
+
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::Lifecycle;
+#[cfg(feature = "turso")]
+pub use self::turso::{
+    close_connection, connection, not_migrating, with_memory_manager, Lifecycle,
+    DEFAULT_DATABASE_PATH, DEFAULT_MEMORY_ID,
+};
+
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+use crate::MigrateResult;
+
+/// Function returning the values of runtime variables, see [`Lifecycle::with_vars`].
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+pub type VarsFn = fn() -> Vec<(String, String)>;
+
+/// Traps with the error message if a lifecycle hook failed, which makes the canister
+/// install or upgrade fail instead of leaving the database half set up.
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+fn trap_on_error(hook: &str, result: MigrateResult<()>) {
+    if let Err(e) = result {
+        ic_cdk::trap(format!("Canister {hook} failed: {e}"));
+    }
+}
+
+/// Candid interface of the methods generated by [`admin_interface!`](crate::admin_interface),
+/// to merge into the canister's `.did` file.
+#[cfg(feature = "admin")]
+pub const ADMIN_CANDID: &str = include_str!("admin.did");
+
+/// The default guard of the admin methods: only controllers of the canister may call them.
+///
+/// # Errors
+/// Returns an error message if the caller is not a controller.
+#[cfg(feature = "admin")]
+pub fn is_controller() -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    if ic_cdk::api::is_controller(&caller) {
+        Ok(())
+    } else {
+        Err(format!("{caller} is not a controller of the canister"))
+    }
+}
@@ -0,0 +1,474 @@
+use rusqlite::Connection;
+
+use super::{trap_on_error, VarsFn};
+use crate::{
+    sqlite, MigrateOptions, MigrateResult, Migration, MigrationReport, Seed, SqliteSeedFn,
+};
+
+/// Configuration of the lifecycle hooks generated by [`lifecycle!`](crate::lifecycle).
+///
+/// The hooks apply `migrations` and, if configured, `seeds` on install and upgrade. Custom
+/// init and upgrade logic is added with the `before_*` and `after_*` methods; apart from
+/// [`Lifecycle::before_upgrade`] it receives the database connection, just like a seed.
+///
+/// # Example
+/// ```ignore
+/// use ic_sql_migrate::canister::Lifecycle;
+///
+/// fn load_settings(conn: &rusqlite::Connection) -> ic_sql_migrate::MigrateResult<()> {
+///     conn.execute("INSERT OR IGNORE INTO settings (key, value) VALUES ('mode', 'live')", [])?;
+///     Ok(())
+/// }
+///
+/// static LIFECYCLE: Lifecycle = Lifecycle::new(ic_sql_migrate::include_migrations!())
+///     .with_seeds(seeds::SEEDS)
+///     .after_init(load_settings);
+///
+/// ic_sql_migrate::lifecycle!(LIFECYCLE);
+/// ```
+#[derive(Clone, Copy)]
+pub struct Lifecycle {
+    migrations: &'static [Migration],
+    seeds: &'static [Seed],
+    backup_path: Option<&'static str>,
+    vars: Option<VarsFn>,
+    before_init: Option<SqliteSeedFn>,
+    after_init: Option<SqliteSeedFn>,
+    before_upgrade: Option<fn()>,
+    after_upgrade: Option<SqliteSeedFn>,
+}
+
+impl Lifecycle {
+    pub const fn new(migrations: &'static [Migration]) -> Self {
+        Self {
+            migrations,
+            seeds: &[],
+            backup_path: None,
+            vars: None,
+            before_init: None,
+            after_init: None,
+            before_upgrade: None,
+            after_upgrade: None,
+        }
+    }
+
+    /// Sets the seeds to apply together with the migrations, see [`sqlite::run`].
+    pub const fn with_seeds(mut self, seeds: &'static [Seed]) -> Self {
+        self.seeds = seeds;
+        self
+    }
+
+    /// Backs up the database to `path` before applying pending migrations, and restores
+    /// the backup if they fail, see [`MigrateOptions::with_backup`].
+    pub const fn with_backup(mut self, path: &'static str) -> Self {
+        self.backup_path = Some(path);
+        self
+    }
+
+    /// Sets the values of the runtime variables of the migrations, see
+    /// [`MigrateOptions::with_var`].
+    ///
+    /// `vars` is called whenever migrations are applied, so it can return values that
+    /// are only known in the canister, such as its own principal.
+    pub const fn with_vars(mut self, vars: VarsFn) -> Self {
+        self.vars = Some(vars);
+        self
+    }
+
+    /// Runs `hook` in `#[init]` before migrations are applied.
+    pub const fn before_init(mut self, hook: SqliteSeedFn) -> Self {
+        self.before_init = Some(hook);
+        self
+    }
+
+    /// Runs `hook` in `#[init]` after migrations and seeds are applied.
+    pub const fn after_init(mut self, hook: SqliteSeedFn) -> Self {
+        self.after_init = Some(hook);
+        self
+    }
+
+    /// Runs `hook` in `#[pre_upgrade]` before the connection is closed.
+    pub const fn before_upgrade(mut self, hook: fn()) -> Self {
+        self.before_upgrade = Some(hook);
+        self
+    }
+
+    /// Runs `hook` in `#[post_upgrade]` after migrations and seeds are applied.
+    pub const fn after_upgrade(mut self, hook: SqliteSeedFn) -> Self {
+        self.after_upgrade = Some(hook);
+        self
+    }
+
+    /// The migrations applied by the hooks.
+    pub const fn migrations(&self) -> &'static [Migration] {
+        self.migrations
+    }
+
+    /// The seeds applied by the hooks.
+    pub const fn seeds(&self) -> &'static [Seed] {
+        self.seeds
+    }
+
+    /// The options migrations are applied with, including the backup and runtime
+    /// variables set with [`Lifecycle::with_backup`] and [`Lifecycle::with_vars`].
+    pub fn options(&self) -> MigrateOptions {
+        let mut options = MigrateOptions::new();
+        if let Some(path) = self.backup_path {
+            options = options.with_backup(path);
+        }
+        if let Some(vars) = self.vars {
+            options = options.with_vars(vars());
+        }
+        options
+    }
+
+    /// Applies pending migrations and seeds, and returns what was applied.
+    ///
+    /// # Errors
+    /// Returns an error if a migration or seed fails, see [`sqlite::migrate`] and
+    /// [`sqlite::run`].
+    pub fn apply(&self, conn: &mut Connection) -> MigrateResult<MigrationReport> {
+        let options = self.options();
+        if self.seeds.is_empty() {
+            sqlite::migrate_with(conn, self.migrations, &options)
+        } else {
+            sqlite::run_with(conn, self.migrations, self.seeds, &options)
+        }
+    }
+
+    /// The `#[init]` hook: applies migrations and seeds between the init hooks.
+    ///
+    /// Traps if any step fails.
+    pub fn init(&self, conn: &mut Connection) {
+        trap_on_error("init", self.run(conn, self.before_init, self.after_init));
+    }
+
+    /// The `#[pre_upgrade]` hook: runs the `before_upgrade` hook.
+    ///
+    /// The generated hook closes the connection afterwards.
+    pub fn pre_upgrade(&self) {
+        if let Some(hook) = self.before_upgrade {
+            hook();
+        }
+    }
+
+    /// The `#[post_upgrade]` hook: applies migrations and seeds, then runs the
+    /// `after_upgrade` hook.
+    ///
+    /// Traps if any step fails.
+    pub fn post_upgrade(&self, conn: &mut Connection) {
+        trap_on_error("post_upgrade", self.run(conn, None, self.after_upgrade));
+    }
+
+    fn run(
+        &self,
+        conn: &mut Connection,
+        before: Option<SqliteSeedFn>,
+        after: Option<SqliteSeedFn>,
+    ) -> MigrateResult<()> {
+        if let Some(hook) = before {
+            hook(conn)?;
+        }
+        self.apply(conn)?;
+        if let Some(hook) = after {
+            hook(conn)?;
+        }
+        Ok(())
+    }
+}
+
+/// Generates the `#[init]`, `#[pre_upgrade]` and `#[post_upgrade]` hooks of a canister
+/// from a [`Lifecycle`](crate::canister::Lifecycle).
+///
+/// The hooks use the connection managed by `ic-rusqlite`, which the canister must depend
+/// on along with `ic-cdk`. The canister must not define these hooks itself; custom logic
+/// goes into the `before_*` and `after_*` hooks of the [`Lifecycle`](crate::canister::Lifecycle).
+///
+/// # Example
+/// ```ignore
+/// static LIFECYCLE: ic_sql_migrate::canister::Lifecycle =
+///     ic_sql_migrate::canister::Lifecycle::new(ic_sql_migrate::include_migrations!());
+///
+/// ic_sql_migrate::lifecycle!(LIFECYCLE);
+/// ```
+#[macro_export]
+macro_rules! lifecycle {
+    ($lifecycle:expr) => {
+        #[::ic_cdk::init]
+        fn __ic_sql_migrate_init() {
+            ::ic_rusqlite::with_connection(|mut conn| $lifecycle.init(&mut conn));
+        }
+
+        #[::ic_cdk::pre_upgrade]
+        fn __ic_sql_migrate_pre_upgrade() {
+            $lifecycle.pre_upgrade();
+            ::ic_rusqlite::close_connection();
+        }
+
+        #[::ic_cdk::post_upgrade]
+        fn __ic_sql_migrate_post_upgrade() {
+            ::ic_rusqlite::with_connection(|mut conn| $lifecycle.post_upgrade(&mut conn));
+        }
+    };
+}
+
+/// Generates canister methods to manage migrations and seeds of a
+/// [`Lifecycle`](crate::canister::Lifecycle) (feature: `admin`).
+///
+/// The generated methods are the `migration_status` and `migration_history` queries and
+/// the `migrate_to`, `rollback_to` and `run_seed` updates, which call [`sqlite::status`],
+/// [`sqlite::history`], [`sqlite::migrate_to_with`], [`sqlite::rollback_to`] and
+/// [`sqlite::run_seed`] on the connection managed by `ic-rusqlite`. The history is
+/// returned encoded, see [`MigrationHistory::encode`](crate::MigrationHistory::encode).
+/// The Candid interface of the methods is [`ADMIN_CANDID`](crate::canister::ADMIN_CANDID).
+///
+/// `migrate_to` applies migrations with the options of the lifecycle, see
+/// [`Lifecycle::options`](crate::canister::Lifecycle::options), so it makes the
+/// configured backup and resolves runtime variables just like the lifecycle hooks.
+///
+/// Every method first calls the guard, a `fn() -> Result<(), String>`, and returns its
+/// error to the caller. The guard defaults to
+/// [`is_controller`](crate::canister::is_controller).
+///
+/// # Example
+/// ```ignore
+/// ic_sql_migrate::admin_interface!(LIFECYCLE);
+///
+/// // Or with a custom guard
+/// fn is_admin() -> Result<(), String> {
+///     // ...
+/// }
+///
+/// ic_sql_migrate::admin_interface!(LIFECYCLE, guard = is_admin);
+/// ```
+#[cfg(feature = "admin")]
+#[macro_export]
+macro_rules! admin_interface {
+    // Generates the methods with the function giving access to the connection, which
+    // is `ic_rusqlite::with_connection` outside of tests
+    (@methods $lifecycle:expr, $guard:path, $with_connection:path) => {
+        #[::ic_cdk::query]
+        fn migration_status() -> Result<Vec<$crate::MigrationStatus>, String> {
+            $guard()?;
+            $with_connection(|conn| {
+                $crate::sqlite::status(&conn, $lifecycle.migrations())
+            })
+            .map_err(|e| e.to_string())
+        }
+
+        #[::ic_cdk::query]
+        fn migration_history() -> Result<String, String> {
+            $guard()?;
+            $with_connection(|conn| $crate::sqlite::history(&conn))
+                .map(|history| history.encode())
+                .map_err(|e| e.to_string())
+        }
+
+        #[::ic_cdk::update]
+        fn migrate_to(target: String) -> Result<Vec<String>, String> {
+            $guard()?;
+            $with_connection(|mut conn| {
+                let options = $lifecycle.options();
+                $crate::sqlite::migrate_to_with(
+                    &mut conn,
+                    $lifecycle.migrations(),
+                    &target,
+                    &options,
+                )
+            })
+            .map_err(|e| e.to_string())
+        }
+
+        #[::ic_cdk::update]
+        fn rollback_to(target: Option<String>) -> Result<Vec<String>, String> {
+            $guard()?;
+            $with_connection(|mut conn| {
+                $crate::sqlite::rollback_to(&mut conn, $lifecycle.migrations(), target.as_deref())
+            })
+            .map_err(|e| e.to_string())
+        }
+
+        #[::ic_cdk::update]
+        fn run_seed(id: String) -> Result<(), String> {
+            $guard()?;
+            $with_connection(|mut conn| {
+                $crate::sqlite::run_seed(&mut conn, $lifecycle.seeds(), &id)
+            })
+            .map_err(|e| e.to_string())
+        }
+    };
+    ($lifecycle:expr) => {
+        $crate::admin_interface!($lifecycle, guard = $crate::canister::is_controller);
+    };
+    ($lifecycle:expr, guard = $guard:path) => {
+        $crate::admin_interface!(@methods $lifecycle, $guard, ::ic_rusqlite::with_connection);
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static MIGRATIONS: &[Migration] = &[Migration::new(
+        "001_settings",
+        "CREATE TABLE settings (key TEXT PRIMARY KEY, value TEXT);",
+    )];
+
+    static SEEDS: &[Seed] = &[Seed::new("001_settings", seed_settings)];
+
+    fn seed_settings(conn: &Connection) -> MigrateResult<()> {
+        conn.execute("INSERT INTO settings VALUES ('mode', 'test')", [])?;
+        Ok(())
+    }
+
+    fn override_settings(conn: &Connection) -> MigrateResult<()> {
+        conn.execute("UPDATE settings SET value = 'live'", [])?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_hooks_run_around_migrations() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let lifecycle = Lifecycle::new(MIGRATIONS)
+            .with_seeds(SEEDS)
+            .after_init(override_settings);
+
+        lifecycle.init(&mut conn);
+        let value: String = conn
+            .query_row("SELECT value FROM settings", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(value, "live");
+
+        // Upgrades don't run the init hooks and skip applied migrations and seeds
+        lifecycle.post_upgrade(&mut conn);
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM settings", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_seeds_are_optional() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        Lifecycle::new(MIGRATIONS).apply(&mut conn).unwrap();
+
+        let seeds_table: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE name = '_seeds'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(seeds_table, 0);
+    }
+
+    #[cfg(feature = "admin")]
+    mod admin {
+        use std::cell::{Cell, RefCell, RefMut};
+
+        use super::*;
+        use crate::MigrationStatus;
+
+        static MIGRATIONS: &[Migration] = &[
+            Migration::new(
+                "001_settings",
+                "CREATE TABLE settings (key TEXT PRIMARY KEY, value TEXT);",
+            )
+            .with_down("DROP TABLE settings;"),
+            Migration::new(
+                "002_flags",
+                "CREATE TABLE flags (name TEXT PRIMARY KEY);
+                 INSERT INTO flags VALUES ('${flag}');",
+            )
+            .with_down("DROP TABLE flags;")
+            .with_vars(&["flag"]),
+        ];
+
+        static LIFECYCLE: Lifecycle = Lifecycle::new(MIGRATIONS).with_seeds(SEEDS).with_vars(vars);
+
+        fn vars() -> Vec<(String, String)> {
+            vec![("flag".to_string(), "beta".to_string())]
+        }
+
+        thread_local! {
+            static CONNECTION: RefCell<Connection> =
+                RefCell::new(Connection::open_in_memory().unwrap());
+            static CONNECTIONS: Cell<usize> = const { Cell::new(0) };
+            static IS_ADMIN: Cell<bool> = const { Cell::new(false) };
+        }
+
+        fn with_connection<R>(f: impl FnOnce(RefMut<'_, Connection>) -> R) -> R {
+            CONNECTIONS.set(CONNECTIONS.get() + 1);
+            CONNECTION.with(|conn| f(conn.borrow_mut()))
+        }
+
+        fn is_admin() -> Result<(), String> {
+            if IS_ADMIN.get() {
+                Ok(())
+            } else {
+                Err("Not an admin".to_string())
+            }
+        }
+
+        crate::admin_interface!(@methods LIFECYCLE, is_admin, with_connection);
+
+        fn status(id: &str, applied: bool) -> MigrationStatus {
+            let status = migration_status().unwrap();
+            let status = status.into_iter().find(|s| s.id == id).unwrap();
+            assert_eq!(status.applied_at.is_some(), applied);
+            status
+        }
+
+        #[test]
+        fn test_guard_runs_before_database_access() {
+            let denied = "Not an admin".to_string();
+            assert_eq!(migration_status().unwrap_err(), denied);
+            assert_eq!(migration_history().unwrap_err(), denied);
+            assert_eq!(migrate_to("002_flags".to_string()).unwrap_err(), denied);
+            assert_eq!(rollback_to(None).unwrap_err(), denied);
+            assert_eq!(run_seed("001_settings".to_string()).unwrap_err(), denied);
+            assert_eq!(CONNECTIONS.get(), 0);
+        }
+
+        #[test]
+        fn test_admin_methods() {
+            IS_ADMIN.set(true);
+
+            assert!(status("001_settings", false).reversible);
+            assert_eq!(
+                migrate_to("001_settings".to_string()),
+                Ok(vec!["001_settings".to_string()])
+            );
+            status("001_settings", true);
+            status("002_flags", false);
+            assert!(migrate_to("003_missing".to_string())
+                .unwrap_err()
+                .contains("003_missing"));
+
+            assert_eq!(run_seed("001_settings".to_string()), Ok(()));
+            let count: i64 = CONNECTION.with_borrow(|conn| {
+                conn.query_row("SELECT COUNT(*) FROM settings", [], |row| row.get(0))
+                    .unwrap()
+            });
+            assert_eq!(count, 1);
+
+            assert_eq!(
+                migrate_to("002_flags".to_string()),
+                Ok(vec!["002_flags".to_string()])
+            );
+            // The runtime variables of the lifecycle are set
+            let flag: String = CONNECTION.with_borrow(|conn| {
+                conn.query_row("SELECT name FROM flags", [], |row| row.get(0))
+                    .unwrap()
+            });
+            assert_eq!(flag, "beta");
+            assert!(migration_history().unwrap().contains("002_flags"));
+            assert_eq!(
+                rollback_to(Some("001_settings".to_string())),
+                Ok(vec!["002_flags".to_string()])
+            );
+            status("001_settings", true);
+            status("002_flags", false);
+        }
+    }
+}
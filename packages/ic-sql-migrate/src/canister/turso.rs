@@ -0,0 +1,598 @@
+use std::cell::{Cell, RefCell};
+use std::path::Path;
+
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
+use ic_stable_structures::DefaultMemoryImpl;
+use turso_crate::Connection;
+
+use super::{trap_on_error, VarsFn};
+use crate::{
+    turso, turso_crate, Error, MigrateOptions, MigrateResult, Migration, MigrationReport, Seed,
+    TursoSeedFn,
+};
+
+/// Path of the database file used unless [`Lifecycle::with_database`] sets another one.
+pub const DEFAULT_DATABASE_PATH: &str = "/DB/main.db";
+
+/// Stable memory ID the database file is mounted on unless [`Lifecycle::with_database`]
+/// sets another one.
+pub const DEFAULT_MEMORY_ID: u8 = 20;
+
+thread_local! {
+    static MEMORY_MANAGER: MemoryManager<DefaultMemoryImpl> =
+        MemoryManager::init(DefaultMemoryImpl::default());
+
+    static DATABASE_PATH: Cell<&'static str> = const { Cell::new(DEFAULT_DATABASE_PATH) };
+
+    static CONNECTION: RefCell<Option<Connection>> = const { RefCell::new(None) };
+}
+
+/// Gives access to the memory manager that the database file is mounted from.
+///
+/// Canisters that keep other data in stable memory must allocate it from this memory
+/// manager instead of creating their own, avoiding memory IDs 200 to 209, which are
+/// used by the file system, and the database memory ID.
+pub fn with_memory_manager<R>(f: impl FnOnce(&MemoryManager<DefaultMemoryImpl>) -> R) -> R {
+    MEMORY_MANAGER.with(f)
+}
+
+/// Returns the connection to the database, opening it on first use.
+///
+/// # Errors
+/// Returns an error if the database cannot be opened.
+pub async fn connection() -> MigrateResult<Connection> {
+    if let Some(conn) = CONNECTION.with_borrow(|c| c.clone()) {
+        return Ok(conn);
+    }
+
+    let db = turso_crate::Builder::new_local(DATABASE_PATH.get())
+        .build()
+        .await?;
+    let conn = db.connect()?;
+    CONNECTION.set(Some(conn.clone()));
+    Ok(conn)
+}
+
+/// Drops the cached database connection, as done before an upgrade.
+pub fn close_connection() {
+    CONNECTION.set(None);
+}
+
+/// Guard for canister methods that must not run while migrations are applied.
+///
+/// The hooks and admin methods share the connection with the canister's own methods,
+/// so a method running while a migration is suspended at an await point would see a
+/// partially migrated schema, see [`turso::migration_in_progress`].
+///
+/// # Example
+/// ```ignore
+/// #[ic_cdk::update(guard = "ic_sql_migrate::canister::not_migrating")]
+/// async fn add_user(name: String) { /* ... */ }
+/// ```
+///
+/// # Errors
+/// Returns an error message while migrations are in progress.
+pub fn not_migrating() -> Result<(), String> {
+    if turso::migration_in_progress() {
+        Err(Error::MigrationInProgress.to_string())
+    } else {
+        Ok(())
+    }
+}
+
+/// Mounts stable memory as the database file.
+///
+/// Mounting the file in its own virtual memory makes database operations much faster
+/// than going through the file system, and lets the database survive upgrades.
+fn mount_database(path: &'static str, memory_id: u8) {
+    MEMORY_MANAGER.with(|m| {
+        ic_wasi_polyfill::init_with_memory_manager(&[0u8; 32], &[], m, 200..210);
+
+        // Unmount the file in case it was mounted before
+        ic_wasi_polyfill::unmount_memory_file(path);
+
+        let memory = m.get(MemoryId::new(memory_id));
+        ic_wasi_polyfill::mount_memory_file(
+            path,
+            Box::new(memory),
+            ic_wasi_polyfill::MountedFileSizePolicy::MemoryPages,
+        );
+
+        // Remove a lock left behind by the previous canister version
+        let _ = std::fs::remove_dir_all(format!("{path}.lock"));
+
+        // The containing folder must exist before the database is opened
+        if let Some(parent) = Path::new(path).parent() {
+            let _ = std::fs::create_dir_all(parent);
+        }
+    });
+    DATABASE_PATH.set(path);
+}
+
+/// Configuration of the lifecycle hooks generated by [`lifecycle!`](crate::lifecycle).
+///
+/// The hooks mount the database file in stable memory and apply `migrations` and, if
+/// configured, `seeds` on install and upgrade. Custom init and upgrade logic is added
+/// with the `before_*` and `after_*` methods; apart from [`Lifecycle::before_upgrade`]
+/// it receives the database connection, just like a seed.
+///
+/// # Example
+/// ```ignore
+/// use ic_sql_migrate::canister::Lifecycle;
+///
+/// static LIFECYCLE: Lifecycle = Lifecycle::new(ic_sql_migrate::include_migrations!())
+///     .with_database("/data/app.db", 30);
+///
+/// ic_sql_migrate::lifecycle!(LIFECYCLE);
+///
+/// #[ic_cdk::query]
+/// async fn user_count() -> i64 {
+///     let conn = ic_sql_migrate::canister::connection().await.unwrap();
+///     // ...
+/// }
+/// ```
+#[derive(Clone, Copy)]
+pub struct Lifecycle {
+    migrations: &'static [Migration],
+    seeds: &'static [Seed],
+    database_path: &'static str,
+    memory_id: u8,
+    backup_path: Option<&'static str>,
+    vars: Option<VarsFn>,
+    before_init: Option<TursoSeedFn>,
+    after_init: Option<TursoSeedFn>,
+    before_upgrade: Option<fn()>,
+    after_upgrade: Option<TursoSeedFn>,
+}
+
+impl Lifecycle {
+    pub const fn new(migrations: &'static [Migration]) -> Self {
+        Self {
+            migrations,
+            seeds: &[],
+            database_path: DEFAULT_DATABASE_PATH,
+            memory_id: DEFAULT_MEMORY_ID,
+            backup_path: None,
+            vars: None,
+            before_init: None,
+            after_init: None,
+            before_upgrade: None,
+            after_upgrade: None,
+        }
+    }
+
+    /// Sets the seeds to apply together with the migrations, see [`turso::run`].
+    pub const fn with_seeds(mut self, seeds: &'static [Seed]) -> Self {
+        self.seeds = seeds;
+        self
+    }
+
+    /// Sets the path of the database file and the stable memory ID it is mounted on.
+    ///
+    /// Defaults to [`DEFAULT_DATABASE_PATH`] and [`DEFAULT_MEMORY_ID`]. The memory ID
+    /// must not change between upgrades, as the database would be lost.
+    pub const fn with_database(mut self, path: &'static str, memory_id: u8) -> Self {
+        self.database_path = path;
+        self.memory_id = memory_id;
+        self
+    }
+
+    /// Backs up the database file to `path` before applying pending migrations, and
+    /// restores the backup if they fail, see [`MigrateOptions::with_backup`].
+    pub const fn with_backup(mut self, path: &'static str) -> Self {
+        self.backup_path = Some(path);
+        self
+    }
+
+    /// Sets the values of the runtime variables of the migrations, see
+    /// [`MigrateOptions::with_var`].
+    ///
+    /// `vars` is called whenever migrations are applied, so it can return values that
+    /// are only known in the canister, such as its own principal.
+    pub const fn with_vars(mut self, vars: VarsFn) -> Self {
+        self.vars = Some(vars);
+        self
+    }
+
+    /// Runs `hook` in `#[init]` before migrations are applied.
+    pub const fn before_init(mut self, hook: TursoSeedFn) -> Self {
+        self.before_init = Some(hook);
+        self
+    }
+
+    /// Runs `hook` in `#[init]` after migrations and seeds are applied.
+    pub const fn after_init(mut self, hook: TursoSeedFn) -> Self {
+        self.after_init = Some(hook);
+        self
+    }
+
+    /// Runs `hook` in `#[pre_upgrade]` before the connection is closed.
+    pub const fn before_upgrade(mut self, hook: fn()) -> Self {
+        self.before_upgrade = Some(hook);
+        self
+    }
+
+    /// Runs `hook` in `#[post_upgrade]` after migrations and seeds are applied.
+    pub const fn after_upgrade(mut self, hook: TursoSeedFn) -> Self {
+        self.after_upgrade = Some(hook);
+        self
+    }
+
+    /// The migrations applied by the hooks.
+    pub const fn migrations(&self) -> &'static [Migration] {
+        self.migrations
+    }
+
+    /// The seeds applied by the hooks.
+    pub const fn seeds(&self) -> &'static [Seed] {
+        self.seeds
+    }
+
+    /// The options migrations are applied with, including the database path, and the
+    /// backup and runtime variables set with [`Lifecycle::with_backup`] and
+    /// [`Lifecycle::with_vars`].
+    pub fn options(&self) -> MigrateOptions {
+        let mut options = MigrateOptions::new().with_database_path(self.database_path);
+        if let Some(path) = self.backup_path {
+            options = options.with_backup(path);
+        }
+        if let Some(vars) = self.vars {
+            options = options.with_vars(vars());
+        }
+        options
+    }
+
+    /// Applies pending migrations and seeds, and returns what was applied.
+    ///
+    /// # Errors
+    /// Returns an error if a migration or seed fails, see [`turso::migrate`] and
+    /// [`turso::run`].
+    pub async fn apply(&self, conn: &mut Connection) -> MigrateResult<MigrationReport> {
+        let options = self.options();
+        if self.seeds.is_empty() {
+            turso::migrate_with(conn, self.migrations, &options).await
+        } else {
+            turso::run_with(conn, self.migrations, self.seeds, &options).await
+        }
+    }
+
+    /// The `#[init]` hook: mounts the database file and applies migrations and seeds
+    /// between the init hooks.
+    ///
+    /// Traps if any step fails.
+    pub async fn init(&self) {
+        mount_database(self.database_path, self.memory_id);
+        let result = self.run(self.before_init, self.after_init).await;
+        trap_on_error("init", result);
+    }
+
+    /// The `#[pre_upgrade]` hook: runs the `before_upgrade` hook and closes the
+    /// connection.
+    pub fn pre_upgrade(&self) {
+        if let Some(hook) = self.before_upgrade {
+            hook();
+        }
+        close_connection();
+    }
+
+    /// The `#[post_upgrade]` hook: mounts the database file, applies migrations and
+    /// seeds, then runs the `after_upgrade` hook.
+    ///
+    /// Traps if any step fails.
+    pub async fn post_upgrade(&self) {
+        mount_database(self.database_path, self.memory_id);
+        let result = self.run(None, self.after_upgrade).await;
+        trap_on_error("post_upgrade", result);
+    }
+
+    async fn run(
+        &self,
+        before: Option<TursoSeedFn>,
+        after: Option<TursoSeedFn>,
+    ) -> MigrateResult<()> {
+        let mut conn = connection().await?;
+        // Nothing can be migrating in a freshly installed or upgraded canister, so a lock
+        // is left over from a call that trapped after an await point
+        turso::release_migration_lock(&conn).await?;
+        if let Some(hook) = before {
+            hook(&conn).await?;
+        }
+        self.apply(&mut conn).await?;
+        if let Some(hook) = after {
+            hook(&conn).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Generates the `#[init]`, `#[pre_upgrade]` and `#[post_upgrade]` hooks of a canister
+/// from a [`Lifecycle`](crate::canister::Lifecycle).
+///
+/// The canister must depend on `ic-cdk` and must not define these hooks itself; custom
+/// logic goes into the `before_*` and `after_*` hooks of the
+/// [`Lifecycle`](crate::canister::Lifecycle).
+///
+/// # Example
+/// ```ignore
+/// static LIFECYCLE: ic_sql_migrate::canister::Lifecycle =
+///     ic_sql_migrate::canister::Lifecycle::new(ic_sql_migrate::include_migrations!());
+///
+/// ic_sql_migrate::lifecycle!(LIFECYCLE);
+/// ```
+#[macro_export]
+macro_rules! lifecycle {
+    ($lifecycle:expr) => {
+        #[::ic_cdk::init]
+        async fn __ic_sql_migrate_init() {
+            $lifecycle.init().await;
+        }
+
+        #[::ic_cdk::pre_upgrade]
+        fn __ic_sql_migrate_pre_upgrade() {
+            $lifecycle.pre_upgrade();
+        }
+
+        #[::ic_cdk::post_upgrade]
+        async fn __ic_sql_migrate_post_upgrade() {
+            $lifecycle.post_upgrade().await;
+        }
+    };
+}
+
+/// Generates canister methods to manage migrations and seeds of a
+/// [`Lifecycle`](crate::canister::Lifecycle) (feature: `admin`).
+///
+/// The generated methods are the `migration_status` and `migration_history` queries and
+/// the `migrate_to`, `rollback_to` and `run_seed` updates, which call [`turso::status`],
+/// [`turso::history`], [`turso::migrate_to_with`], [`turso::rollback_to`] and
+/// [`turso::run_seed`] on the connection returned by
+/// [`connection`](crate::canister::connection). The history is returned encoded, see
+/// [`MigrationHistory::encode`](crate::MigrationHistory::encode). The Candid interface
+/// of the methods is [`ADMIN_CANDID`](crate::canister::ADMIN_CANDID).
+///
+/// `migrate_to` applies migrations with the options of the lifecycle, see
+/// [`Lifecycle::options`](crate::canister::Lifecycle::options), so it makes the
+/// configured backup and resolves runtime variables just like the lifecycle hooks.
+///
+/// Every method first calls the guard, a `fn() -> Result<(), String>`, and returns its
+/// error to the caller. The guard defaults to
+/// [`is_controller`](crate::canister::is_controller).
+///
+/// # Example
+/// ```ignore
+/// ic_sql_migrate::admin_interface!(LIFECYCLE);
+///
+/// // Or with a custom guard
+/// fn is_admin() -> Result<(), String> {
+///     // ...
+/// }
+///
+/// ic_sql_migrate::admin_interface!(LIFECYCLE, guard = is_admin);
+/// ```
+#[cfg(feature = "admin")]
+#[macro_export]
+macro_rules! admin_interface {
+    ($lifecycle:expr) => {
+        $crate::admin_interface!($lifecycle, guard = $crate::canister::is_controller);
+    };
+    ($lifecycle:expr, guard = $guard:path) => {
+        #[::ic_cdk::query]
+        async fn migration_status() -> Result<Vec<$crate::MigrationStatus>, String> {
+            $guard()?;
+            let conn = $crate::canister::connection()
+                .await
+                .map_err(|e| e.to_string())?;
+            $crate::turso::status(&conn, $lifecycle.migrations())
+                .await
+                .map_err(|e| e.to_string())
+        }
+
+        #[::ic_cdk::query]
+        async fn migration_history() -> Result<String, String> {
+            $guard()?;
+            let conn = $crate::canister::connection()
+                .await
+                .map_err(|e| e.to_string())?;
+            $crate::turso::history(&conn)
+                .await
+                .map(|history| history.encode())
+                .map_err(|e| e.to_string())
+        }
+
+        #[::ic_cdk::update]
+        async fn migrate_to(target: String) -> Result<Vec<String>, String> {
+            $guard()?;
+            let mut conn = $crate::canister::connection()
+                .await
+                .map_err(|e| e.to_string())?;
+            let options = $lifecycle.options();
+            $crate::turso::migrate_to_with(&mut conn, $lifecycle.migrations(), &target, &options)
+                .await
+                .map_err(|e| e.to_string())
+        }
+
+        #[::ic_cdk::update]
+        async fn rollback_to(target: Option<String>) -> Result<Vec<String>, String> {
+            $guard()?;
+            let mut conn = $crate::canister::connection()
+                .await
+                .map_err(|e| e.to_string())?;
+            $crate::turso::rollback_to(&mut conn, $lifecycle.migrations(), target.as_deref())
+                .await
+                .map_err(|e| e.to_string())
+        }
+
+        #[::ic_cdk::update]
+        async fn run_seed(id: String) -> Result<(), String> {
+            $guard()?;
+            let mut conn = $crate::canister::connection()
+                .await
+                .map_err(|e| e.to_string())?;
+            $crate::turso::run_seed(&mut conn, $lifecycle.seeds(), &id)
+                .await
+                .map_err(|e| e.to_string())
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static MIGRATIONS: &[Migration] = &[Migration::new(
+        "001_settings",
+        "CREATE TABLE settings (key TEXT PRIMARY KEY, value TEXT);",
+    )];
+
+    static SEEDS: &[Seed] = &[Seed::new("001_settings", seed_settings)];
+
+    fn seed_settings(
+        conn: &Connection,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = MigrateResult<()>> + Send>> {
+        let conn = conn.clone();
+        Box::pin(async move {
+            conn.execute("INSERT INTO settings VALUES ('mode', 'test')", ())
+                .await?;
+            Ok(())
+        })
+    }
+
+    async fn count(conn: &Connection, sql: &str) -> i64 {
+        let mut rows = conn.query(sql, ()).await.unwrap();
+        let row = rows.next().await.unwrap().unwrap();
+        *row.get_value(0).unwrap().as_integer().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_apply_with_and_without_seeds() {
+        let db = turso_crate::Builder::new_local(":memory:")
+            .build()
+            .await
+            .unwrap();
+        let mut conn = db.connect().unwrap();
+
+        Lifecycle::new(MIGRATIONS).apply(&mut conn).await.unwrap();
+        assert_eq!(
+            count(
+                &conn,
+                "SELECT COUNT(*) FROM sqlite_master WHERE name = '_seeds'"
+            )
+            .await,
+            0
+        );
+
+        let lifecycle = Lifecycle::new(MIGRATIONS).with_seeds(SEEDS);
+        lifecycle.apply(&mut conn).await.unwrap();
+        lifecycle.apply(&mut conn).await.unwrap();
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM settings").await, 1);
+    }
+
+    #[cfg(feature = "admin")]
+    mod admin {
+        use super::*;
+        use crate::MigrationStatus;
+
+        static MIGRATIONS: &[Migration] = &[
+            Migration::new(
+                "001_settings",
+                "CREATE TABLE settings (key TEXT PRIMARY KEY, value TEXT);",
+            )
+            .with_down("DROP TABLE settings;"),
+            Migration::new(
+                "002_flags",
+                "CREATE TABLE flags (name TEXT PRIMARY KEY);
+                 INSERT INTO flags VALUES ('${flag}');",
+            )
+            .with_down("DROP TABLE flags;")
+            .with_vars(&["flag"]),
+        ];
+
+        static LIFECYCLE: Lifecycle = Lifecycle::new(MIGRATIONS).with_seeds(SEEDS).with_vars(vars);
+
+        fn vars() -> Vec<(String, String)> {
+            vec![("flag".to_string(), "beta".to_string())]
+        }
+
+        thread_local! {
+            static IS_ADMIN: Cell<bool> = const { Cell::new(false) };
+        }
+
+        fn is_admin() -> Result<(), String> {
+            if IS_ADMIN.get() {
+                Ok(())
+            } else {
+                Err("Not an admin".to_string())
+            }
+        }
+
+        crate::admin_interface!(LIFECYCLE, guard = is_admin);
+
+        async fn status(id: &str, applied: bool) -> MigrationStatus {
+            let status = migration_status().await.unwrap();
+            let status = status.into_iter().find(|s| s.id == id).unwrap();
+            assert_eq!(status.applied_at.is_some(), applied);
+            status
+        }
+
+        #[tokio::test]
+        async fn test_guard_runs_before_database_access() {
+            let denied = "Not an admin".to_string();
+            assert_eq!(migration_status().await.unwrap_err(), denied);
+            assert_eq!(migration_history().await.unwrap_err(), denied);
+            assert_eq!(
+                migrate_to("002_flags".to_string()).await.unwrap_err(),
+                denied
+            );
+            assert_eq!(rollback_to(None).await.unwrap_err(), denied);
+            assert_eq!(
+                run_seed("001_settings".to_string()).await.unwrap_err(),
+                denied
+            );
+            // The connection is opened on first use
+            assert!(CONNECTION.with_borrow(|c| c.is_none()));
+        }
+
+        #[tokio::test]
+        async fn test_admin_methods() {
+            let db = turso_crate::Builder::new_local(":memory:")
+                .build()
+                .await
+                .unwrap();
+            let conn = db.connect().unwrap();
+            CONNECTION.set(Some(conn.clone()));
+            IS_ADMIN.set(true);
+
+            assert!(status("001_settings", false).await.reversible);
+            assert_eq!(
+                migrate_to("001_settings".to_string()).await,
+                Ok(vec!["001_settings".to_string()])
+            );
+            status("001_settings", true).await;
+            status("002_flags", false).await;
+            assert!(migrate_to("003_missing".to_string())
+                .await
+                .unwrap_err()
+                .contains("003_missing"));
+
+            assert_eq!(run_seed("001_settings".to_string()).await, Ok(()));
+            assert_eq!(count(&conn, "SELECT COUNT(*) FROM settings").await, 1);
+
+            assert_eq!(
+                migrate_to("002_flags".to_string()).await,
+                Ok(vec!["002_flags".to_string()])
+            );
+            // The runtime variables of the lifecycle are set
+            assert_eq!(
+                count(&conn, "SELECT COUNT(*) FROM flags WHERE name = 'beta'").await,
+                1
+            );
+            assert!(migration_history().await.unwrap().contains("002_flags"));
+            assert_eq!(
+                rollback_to(Some("001_settings".to_string())).await,
+                Ok(vec!["002_flags".to_string()])
+            );
+            status("001_settings", true).await;
+            status("002_flags", false).await;
+        }
+    }
+}
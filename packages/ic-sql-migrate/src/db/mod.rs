@@ -2,3 +2,360 @@
 pub mod sqlite;
 #[cfg(feature = "turso")]
 pub mod turso;
+
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+use std::collections::{BTreeMap, HashMap, HashSet};
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+use std::time::Instant;
+
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+use crate::sql::{self, Statement};
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+use crate::{
+    DatabaseError, Error, FailedStatement, MigrateResult, Migration, MigrationStats,
+    MigrationStatus, Seed,
+};
+
+/// Measures the time and, in a canister, the instructions a migration takes.
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+pub(crate) struct Stopwatch {
+    started: Instant,
+    instructions: Option<u64>,
+}
+
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+impl Stopwatch {
+    pub(crate) fn start() -> Self {
+        Self {
+            started: Instant::now(),
+            instructions: instruction_counter(),
+        }
+    }
+
+    /// Returns the statistics of `migration`, measured since the stopwatch was started.
+    pub(crate) fn stats(
+        &self,
+        migration: &Migration,
+        statements: usize,
+        rows_changed: u64,
+    ) -> MigrationStats {
+        MigrationStats {
+            id: migration.id.to_string(),
+            elapsed: self.started.elapsed(),
+            instructions: self
+                .instructions
+                .zip(instruction_counter())
+                .map(|(started, now)| now.saturating_sub(started)),
+            statements,
+            rows_changed,
+        }
+    }
+}
+
+/// Returns the instructions executed in the current call context, in a canister.
+#[cfg(all(
+    any(feature = "sqlite", feature = "turso"),
+    feature = "ic-cdk",
+    target_family = "wasm"
+))]
+fn instruction_counter() -> Option<u64> {
+    Some(ic_cdk::api::call_context_instruction_counter())
+}
+
+/// Returns `None`, as instructions are only counted in a canister.
+#[cfg(all(
+    any(feature = "sqlite", feature = "turso"),
+    not(all(feature = "ic-cdk", target_family = "wasm"))
+))]
+fn instruction_counter() -> Option<u64> {
+    None
+}
+
+/// Checks whether a seed needs to run given the seeds recorded in `_seeds`.
+///
+/// A seed is pending if it has never been applied, or if it is a versioned seed whose
+/// hash differs from the hash recorded when it last ran.
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+pub(crate) fn seed_is_pending(
+    seed: &Seed,
+    applied_seeds: &HashMap<String, Option<String>>,
+) -> bool {
+    match applied_seeds.get(seed.id) {
+        None => true,
+        Some(applied_hash) => seed.hash.is_some() && applied_hash.as_deref() != seed.hash,
+    }
+}
+
+/// Returns the IDs of the migrations that are not applied and whose condition is not met.
+///
+/// Each condition is evaluated once, so a predicate gives the same answer for the whole
+/// call. These migrations are recorded as skipped instead of being applied.
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+pub(crate) fn disabled_migrations<'a>(
+    migrations: &'a [Migration],
+    applied: &HashSet<String>,
+) -> HashSet<&'a str> {
+    migrations
+        .iter()
+        .filter(|m| !applied.contains(m.id) && !m.is_enabled())
+        .map(|m| m.id)
+        .collect()
+}
+
+/// Checks whether a migration needs to be applied or recorded as skipped.
+///
+/// A migration is pending if it has not been applied, unless its condition is not met
+/// and it has already been recorded as skipped.
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+pub(crate) fn migration_is_pending(
+    migration: &Migration,
+    applied: &HashSet<String>,
+    skipped: &HashSet<String>,
+    disabled: &HashSet<&str>,
+) -> bool {
+    if applied.contains(migration.id) {
+        return false;
+    }
+    !disabled.contains(migration.id) || !skipped.contains(migration.id)
+}
+
+/// Verifies that applied migrations have not been modified since they were applied.
+///
+/// Only migrations with both an embedded and a recorded checksum are compared, so
+/// migrations applied by earlier versions of the library are not checked. The embedded
+/// checksum includes the values of the runtime variables in `vars`; migrations whose
+/// runtime variables are not all set are not checked either, e.g. in `migrate_to`.
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+pub(crate) fn verify_checksums(
+    migrations: &[Migration],
+    recorded_checksums: &HashMap<String, String>,
+    vars: &BTreeMap<String, String>,
+) -> MigrateResult<()> {
+    for migration in migrations {
+        let Some(recorded) = recorded_checksums.get(migration.id) else {
+            continue;
+        };
+        let Ok(Some(embedded)) = migration.resolved_checksum(vars) else {
+            continue;
+        };
+        if embedded != *recorded {
+            return Err(Error::ChecksumMismatch {
+                id: migration.id.to_string(),
+                recorded: recorded.clone(),
+                embedded,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Builds the error for a migration statement that failed to execute.
+///
+/// `position` is the position of the statement in the original file if the build script
+/// embedded it, as minified SQL no longer matches the original file. Otherwise the
+/// position is taken from `sql`.
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+pub(crate) fn statement_failed(
+    migration: &Migration,
+    sql: &str,
+    index: usize,
+    statement: &Statement,
+    position: Option<(usize, usize)>,
+    source: DatabaseError,
+) -> Error {
+    let (line, column) = position.unwrap_or_else(|| sql::line_column(sql, statement.offset()));
+
+    Error::MigrationFailed {
+        id: migration.id.to_string(),
+        statement: Some(Box::new(FailedStatement {
+            index,
+            sql: statement.text(sql).to_string(),
+            line,
+            column,
+        })),
+        source,
+    }
+}
+
+/// Returns the migrations to roll back to reach `target`, latest first.
+///
+/// These are the applied migrations after `target`, or all applied migrations if
+/// `target` is `None`. Fails if `target` is unknown or a migration to roll back has no
+/// down migration, before anything is changed.
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+pub(crate) fn rollback_plan<'a>(
+    migrations: &'a [Migration],
+    applied: &HashSet<String>,
+    target: Option<&str>,
+) -> MigrateResult<Vec<&'a Migration>> {
+    let start = match target {
+        Some(target) => {
+            migrations
+                .iter()
+                .position(|m| m.id == target)
+                .ok_or_else(|| Error::UnknownMigration {
+                    id: target.to_string(),
+                })?
+                + 1
+        }
+        None => 0,
+    };
+
+    let to_roll_back: Vec<&Migration> = migrations[start..]
+        .iter()
+        .rev()
+        .filter(|m| applied.contains(m.id))
+        .collect();
+
+    if let Some(migration) = to_roll_back.iter().find(|m| m.down().is_none()) {
+        return Err(Error::IrreversibleMigration {
+            id: migration.id.to_string(),
+        });
+    }
+    Ok(to_roll_back)
+}
+
+/// Builds the status of each migration from the applied migrations and when they were
+/// applied, and the migrations recorded as skipped.
+///
+/// Applied migrations that are not embedded, e.g. because they were removed from the
+/// canister, are listed after the embedded ones.
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+pub(crate) fn migration_status(
+    migrations: &[Migration],
+    mut applied: HashMap<String, String>,
+    skipped: &HashSet<String>,
+) -> Vec<MigrationStatus> {
+    let mut status: Vec<MigrationStatus> = migrations
+        .iter()
+        .map(|m| {
+            let applied_at = applied.remove(m.id);
+            MigrationStatus {
+                id: m.id.to_string(),
+                skipped: applied_at.is_none() && skipped.contains(m.id),
+                applied_at,
+                reversible: m.down().is_some(),
+            }
+        })
+        .collect();
+
+    let mut unknown: Vec<_> = applied.into_iter().collect();
+    unknown.sort();
+    status.extend(unknown.into_iter().map(|(id, applied_at)| MigrationStatus {
+        id,
+        applied_at: Some(applied_at),
+        reversible: false,
+        skipped: false,
+    }));
+    status
+}
+
+/// A single step of a combined migration and seed pipeline.
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+pub(crate) enum Step<'a> {
+    Migration(&'a Migration),
+    Seed(&'a Seed),
+}
+
+/// Merges migrations and seeds into a single ordered pipeline.
+///
+/// A seed that requires a migration from `migrations` runs directly after that
+/// migration. All other seeds are merged in by version (see [`Seed::version`]), running
+/// after the migrations with the same or a lower version and before the first migration
+/// with a higher one. Seeds without a version run after all migrations. Migrations are
+/// expected to be sorted by version, which is what the build script generates.
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+pub(crate) fn plan<'a>(migrations: &'a [Migration], seeds: &'a [Seed]) -> Vec<Step<'a>> {
+    let anchored = |seed: &Seed| {
+        seed.requires_migration
+            .is_some_and(|id| migrations.iter().any(|m| m.id == id))
+    };
+
+    // Sorting is stable, so seeds with the same version keep their order
+    let mut free_seeds: Vec<_> = seeds.iter().filter(|s| !anchored(s)).collect();
+    free_seeds.sort_by_key(|s| s.version().unwrap_or(u64::MAX));
+    let mut free_seeds = free_seeds.into_iter().peekable();
+    let mut steps = Vec::with_capacity(migrations.len() + seeds.len());
+
+    for migration in migrations {
+        while let Some(seed) = free_seeds.next_if(|s| {
+            s.version()
+                .zip(migration.version())
+                .is_some_and(|(seed, migration)| seed < migration)
+        }) {
+            steps.push(Step::Seed(seed));
+        }
+
+        steps.push(Step::Migration(migration));
+
+        steps.extend(
+            seeds
+                .iter()
+                .filter(|s| s.requires_migration == Some(migration.id))
+                .map(Step::Seed),
+        );
+    }
+
+    steps.extend(free_seeds.map(Step::Seed));
+    steps
+}
+
+/// Quotes an identifier with double quotes, escaping embedded quotes.
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+pub(crate) fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Lists the indexes and triggers of a table, which are dropped with the table and
+/// recreated after a rebuild. Automatic indexes of constraints have no SQL.
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+pub(crate) const TABLE_SCHEMA_SQL: &str = "SELECT sql FROM sqlite_master \
+     WHERE tbl_name = ?1 AND type IN ('index', 'trigger') AND sql IS NOT NULL \
+     ORDER BY type, rowid";
+
+/// Lists the other tables with foreign keys referencing a table.
+#[cfg(feature = "sqlite")]
+pub(crate) const REFERENCING_TABLES_SQL: &str = "SELECT DISTINCT m.name \
+     FROM sqlite_master AS m, pragma_foreign_key_list(m.name) AS f \
+     WHERE m.type = 'table' AND f.\"table\" = ?1 COLLATE NOCASE AND m.name <> ?1";
+
+/// Returns the `PRAGMA foreign_key_check` statement for one table, or for all tables.
+#[cfg(feature = "sqlite")]
+pub(crate) fn foreign_key_check_sql(table: Option<&str>) -> String {
+    match table {
+        Some(table) => format!("PRAGMA foreign_key_check({})", quote_identifier(table)),
+        None => "PRAGMA foreign_key_check".to_string(),
+    }
+}
+
+/// Returns the statements that copy a table into a table with a new definition, in the
+/// order they run: create the new table, copy the rows, drop the old table and rename
+/// the new table to the old name.
+///
+/// `columns` maps each column of the new table to an expression over the columns of
+/// the old table. Table names are written with `quote_table`.
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+pub(crate) fn rebuild_statements(
+    table: &str,
+    definition: &str,
+    columns: &[(&str, &str)],
+    quote_table: fn(&str) -> String,
+) -> [String; 4] {
+    let quoted = quote_table(table);
+    let new_table = quote_table(&format!("_rebuild_{table}"));
+    let (names, expressions): (Vec<_>, Vec<_>) = columns
+        .iter()
+        .map(|(name, expression)| (quote_identifier(name), *expression))
+        .unzip();
+
+    [
+        format!("CREATE TABLE {new_table} {definition}"),
+        format!(
+            "INSERT INTO {new_table} ({}) SELECT {} FROM {quoted}",
+            names.join(", "),
+            expressions.join(", ")
+        ),
+        format!("DROP TABLE {quoted}"),
+        format!("ALTER TABLE {new_table} RENAME TO {quoted}"),
+    ]
+}
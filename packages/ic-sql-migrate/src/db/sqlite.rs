@@ -41,31 +41,66 @@
 //! ```
 
 use rusqlite::Connection;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
-use crate::{Error, MigrateResult, Migration, Seed};
+use super::{
+    disabled_migrations, foreign_key_check_sql, migration_is_pending, migration_status, plan,
+    quote_identifier, rebuild_statements, rollback_plan, seed_is_pending, statement_failed,
+    verify_checksums, Step, Stopwatch, REFERENCING_TABLES_SQL, TABLE_SCHEMA_SQL,
+};
+use crate::sql::split_statements;
+use crate::{
+    AppliedMigration, Error, ForeignKeyViolation, Maintenance, MigrateOptions, MigrateResult,
+    Migration, MigrationHistory, MigrationReport, MigrationStats, MigrationStatus, Seed,
+};
 
 /// Ensures the migrations tracking table exists in the database.
 ///
 /// Creates a `_migrations` table if it doesn't exist, which tracks:
 /// - `id`: The unique identifier of each applied migration
 /// - `applied_at`: Timestamp when the migration was applied
-fn ensure_migrations_table(conn: &mut Connection) -> MigrateResult<()> {
+/// - `checksum`: Checksum of the migration file, `NULL` if it was not embedded
+/// - `skipped`: Whether the migration was skipped because its condition was not met,
+///   rather than applied
+///
+/// Tables created by earlier versions of the library are upgraded in place. Returns
+/// whether the table was created.
+fn ensure_migrations_table(conn: &mut Connection) -> MigrateResult<bool> {
+    let created = !table_exists(conn, "_migrations")?;
     conn.execute(
         "CREATE TABLE IF NOT EXISTS _migrations (
             id TEXT PRIMARY KEY,
-            applied_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            applied_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            checksum TEXT,
+            skipped INTEGER NOT NULL DEFAULT 0
         )",
         [],
-    )?;
-    Ok(())
+    )
+    .map_err(Error::tracking("_migrations", None))?;
+    ensure_column(conn, "_migrations", "checksum", "TEXT")?;
+    ensure_column(conn, "_migrations", "skipped", "INTEGER NOT NULL DEFAULT 0")?;
+    Ok(created)
 }
 
 /// Retrieves the set of already applied migration IDs from the database.
 fn get_applied_migrations(conn: &Connection) -> MigrateResult<HashSet<String>> {
-    let mut statement = conn.prepare("SELECT id FROM _migrations")?;
+    get_migration_ids(conn, false)
+}
 
-    let migration_ids = statement.query_map([], |row| row.get::<_, String>(0))?;
+/// Retrieves the set of migration IDs recorded as skipped.
+fn get_skipped_migrations(conn: &Connection) -> MigrateResult<HashSet<String>> {
+    get_migration_ids(conn, true)
+}
+
+/// Retrieves the IDs of the migrations recorded as applied, or as skipped.
+fn get_migration_ids(conn: &Connection, skipped: bool) -> MigrateResult<HashSet<String>> {
+    let mut statement = conn
+        .prepare("SELECT id FROM _migrations WHERE skipped = ?1")
+        .map_err(Error::tracking("_migrations", None))?;
+
+    let migration_ids = statement
+        .query_map([skipped], |row| row.get::<_, String>(0))
+        .map_err(Error::tracking("_migrations", None))?;
 
     let mut applied_set = HashSet::new();
     for id in migration_ids.into_iter().flatten() {
@@ -75,6 +110,241 @@ fn get_applied_migrations(conn: &Connection) -> MigrateResult<HashSet<String>> {
     Ok(applied_set)
 }
 
+/// Retrieves the applied migration IDs, mapped to when they were applied.
+fn get_applied_at(conn: &Connection) -> MigrateResult<HashMap<String, String>> {
+    let mut statement = conn
+        .prepare("SELECT id, applied_at FROM _migrations WHERE skipped = 0")
+        .map_err(Error::tracking("_migrations", None))?;
+
+    let applied = statement
+        .query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })
+        .map_err(Error::tracking("_migrations", None))?;
+
+    Ok(applied.into_iter().flatten().collect())
+}
+
+/// Retrieves the recorded checksums of applied migrations that have one.
+fn get_migration_checksums(conn: &Connection) -> MigrateResult<HashMap<String, String>> {
+    let mut statement = conn
+        .prepare("SELECT id, checksum FROM _migrations WHERE checksum IS NOT NULL")
+        .map_err(Error::tracking("_migrations", None))?;
+
+    let checksums = statement
+        .query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })
+        .map_err(Error::tracking("_migrations", None))?;
+
+    Ok(checksums.into_iter().flatten().collect())
+}
+
+/// Records a migration as applied, along with its checksum, replacing the record of a
+/// skipped migration.
+fn record_migration(
+    conn: &Connection,
+    migration: &Migration,
+    vars: &BTreeMap<String, String>,
+) -> MigrateResult<()> {
+    conn.execute(
+        "DELETE FROM _migrations WHERE id = ?1 AND skipped = 1",
+        [migration.id],
+    )
+    .map_err(Error::tracking("_migrations", Some(migration.id)))?;
+    conn.execute(
+        "INSERT INTO _migrations(id, checksum) VALUES (?1, ?2)",
+        rusqlite::params![migration.id, migration.resolved_checksum(vars)?],
+    )
+    .map_err(Error::tracking("_migrations", Some(migration.id)))?;
+    Ok(())
+}
+
+/// Records a migration as skipped because its condition was not met.
+fn record_skipped(conn: &Connection, migration: &Migration) -> MigrateResult<()> {
+    conn.execute(
+        "INSERT INTO _migrations(id, skipped) VALUES (?1, 1)",
+        [migration.id],
+    )
+    .map_err(Error::tracking("_migrations", Some(migration.id)))?;
+    Ok(())
+}
+
+/// Executes the statements of a migration one by one.
+///
+/// Running statements individually lets a failure be traced to the statement that
+/// caused it. Returns the statistics of the migration.
+fn execute_migration(
+    conn: &Connection,
+    migration: &Migration,
+    vars: &BTreeMap<String, String>,
+) -> MigrateResult<MigrationStats> {
+    let stopwatch = Stopwatch::start();
+    let total_changes = conn.total_changes();
+    let sql = migration.resolve_sql(vars)?;
+    let statements = split_statements(&sql);
+    for (index, statement) in statements.iter().enumerate() {
+        conn.execute_batch(statement.text(&sql)).map_err(|e| {
+            let position = migration.statement_position(index);
+            statement_failed(migration, &sql, index, statement, position, e)
+        })?;
+    }
+    Ok(stopwatch.stats(
+        migration,
+        statements.len(),
+        conn.total_changes() - total_changes,
+    ))
+}
+
+/// Executes the down migration of a migration and removes it from `_migrations`.
+fn revert_migration(conn: &Connection, migration: &Migration) -> MigrateResult<()> {
+    let sql = migration.down().unwrap_or_default();
+    for (index, statement) in split_statements(sql).iter().enumerate() {
+        conn.execute_batch(statement.text(sql))
+            .map_err(|e| statement_failed(migration, sql, index, statement, None, e))?;
+    }
+
+    conn.execute("DELETE FROM _migrations WHERE id = ?1", [migration.id])
+        .map_err(Error::tracking("_migrations", Some(migration.id)))?;
+    Ok(())
+}
+
+/// Backs up the database if `options` enable backups.
+fn backup(conn: &Connection, options: &MigrateOptions) -> MigrateResult<()> {
+    if let Some(path) = options.backup_path() {
+        conn.backup(rusqlite::MAIN_DB, path, None)
+            .map_err(|e| Error::BackupFailed {
+                path: path.to_path_buf(),
+                source: Box::new(e.into()),
+            })?;
+    }
+    Ok(())
+}
+
+/// Restores the backup taken by [`backup`] after `error` occurred, and returns `error`.
+fn restore(conn: &mut Connection, options: &MigrateOptions, error: Error) -> Error {
+    let Some(path) = options.backup_path() else {
+        return error;
+    };
+    match conn.restore(
+        rusqlite::MAIN_DB,
+        path,
+        None::<fn(rusqlite::backup::Progress)>,
+    ) {
+        Ok(()) => error,
+        Err(e) => Error::RestoreFailed {
+            path: path.to_path_buf(),
+            error: Box::new(error),
+            source: Box::new(e.into()),
+        },
+    }
+}
+
+/// Disables foreign keys if `options` ask for it and they are enabled.
+///
+/// Returns whether they were disabled and have to be enabled again.
+fn disable_foreign_keys(conn: &Connection, options: &MigrateOptions) -> MigrateResult<bool> {
+    if !options.foreign_keys_disabled() {
+        return Ok(false);
+    }
+    let enabled: bool = conn.query_row("PRAGMA foreign_keys", [], |row| row.get(0))?;
+    if enabled {
+        conn.execute_batch("PRAGMA foreign_keys = OFF")?;
+    }
+    Ok(enabled)
+}
+
+/// Enables foreign keys again after [`disable_foreign_keys`].
+fn enable_foreign_keys(conn: &Connection) -> MigrateResult<()> {
+    Ok(conn.execute_batch("PRAGMA foreign_keys = ON")?)
+}
+
+/// Runs the checks configured in `options` and returns the checks that ran.
+///
+/// Checks foreign keys if they were disabled, failing with
+/// [`Error::ForeignKeyViolations`], and runs the integrity check, failing with
+/// [`Error::IntegrityCheckFailed`].
+fn check(conn: &Connection, options: &MigrateOptions) -> MigrateResult<Vec<Maintenance>> {
+    let mut checks = Vec::new();
+
+    if options.foreign_keys_disabled() {
+        let violations = foreign_key_violations(conn, None)?;
+        if !violations.is_empty() {
+            return Err(Error::ForeignKeyViolations { violations });
+        }
+        checks.push(Maintenance::ForeignKeyCheck);
+    }
+
+    if let Some(integrity_check) = options.integrity_check() {
+        let problems = conn
+            .prepare(integrity_check.pragma())?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        if problems != ["ok"] {
+            return Err(Error::IntegrityCheckFailed { problems });
+        }
+        checks.push(Maintenance::IntegrityCheck(integrity_check));
+    }
+
+    Ok(checks)
+}
+
+/// Runs the maintenance steps configured in `options` and returns the steps that ran.
+///
+/// The migrations are committed by then, so a step that fails is recorded as
+/// [`Maintenance::Failed`] and the remaining steps still run.
+fn maintain(conn: &Connection, options: &MigrateOptions) -> Vec<Maintenance> {
+    let mut steps = Vec::new();
+
+    if options.optimize() {
+        steps.push(maintenance_step(
+            conn,
+            "PRAGMA optimize",
+            Maintenance::Optimize,
+        ));
+    }
+    if options.analyze() {
+        steps.push(maintenance_step(conn, "ANALYZE", Maintenance::Analyze));
+    }
+    if let Some(threshold) = options.vacuum_threshold() {
+        match conn.query_row("PRAGMA freelist_count", [], |row| row.get::<_, u64>(0)) {
+            Ok(free_pages) if free_pages > threshold => steps.push(maintenance_step(
+                conn,
+                "VACUUM",
+                Maintenance::Vacuum { free_pages },
+            )),
+            Ok(_) => {}
+            Err(e) => steps.push(Maintenance::Failed {
+                statement: "PRAGMA freelist_count".to_string(),
+                error: e.to_string(),
+            }),
+        }
+    }
+
+    steps
+}
+
+/// Executes a maintenance `statement` and returns `step`, or the failure.
+fn maintenance_step(conn: &Connection, statement: &str, step: Maintenance) -> Maintenance {
+    match conn.execute_batch(statement) {
+        Ok(()) => step,
+        Err(e) => Maintenance::Failed {
+            statement: statement.to_string(),
+            error: e.to_string(),
+        },
+    }
+}
+
+/// Checks whether a table exists in the database.
+fn table_exists(conn: &Connection, table: &str) -> MigrateResult<bool> {
+    let exists = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = ?1",
+        [table],
+        |row| row.get(0),
+    )?;
+    Ok(exists)
+}
+
 /// Executes all pending migrations in order.
 ///
 /// This function:
@@ -91,8 +361,8 @@ fn get_applied_migrations(conn: &Connection) -> MigrateResult<HashSet<String>> {
 /// * `migrations` - Slice of migrations to apply in order
 ///
 /// # Returns
-/// * `Ok(())` - If all pending migrations were successfully applied or if there were no pending migrations
-/// * `Err(Error)` - If any migration failed to execute
+/// A [`MigrationReport`] of the applied migrations, which is empty if there were no
+/// pending migrations.
 ///
 /// # Errors
 /// Returns an error if:
@@ -117,39 +387,240 @@ fn get_applied_migrations(conn: &Connection) -> MigrateResult<HashSet<String>> {
 ///     });
 /// }
 /// ```
-pub fn migrate(conn: &mut Connection, migrations: &[Migration]) -> MigrateResult<()> {
-    ensure_migrations_table(conn)?;
+pub fn migrate(conn: &mut Connection, migrations: &[Migration]) -> MigrateResult<MigrationReport> {
+    migrate_with(conn, migrations, &MigrateOptions::default())
+}
+
+/// Executes all pending migrations in order, with the given options.
+///
+/// Works like [`migrate`], with additional steps configured by [`MigrateOptions`].
+///
+/// # Errors
+/// Returns the errors of [`migrate`], and an error if:
+/// - The database cannot be backed up ([`Error::BackupFailed`])
+/// - Migrations failed and the backup cannot be restored ([`Error::RestoreFailed`])
+///
+/// # Example
+/// ```ignore
+/// let options = MigrateOptions::new().with_backup("/backup/main.db");
+/// sqlite::migrate_with(conn, MIGRATIONS, &options)?;
+/// ```
+pub fn migrate_with(
+    conn: &mut Connection,
+    migrations: &[Migration],
+    options: &MigrateOptions,
+) -> MigrateResult<MigrationReport> {
+    apply_migrations(conn, migrations, options)
+}
+
+/// Applies the pending migrations in a single transaction.
+fn apply_migrations(
+    conn: &mut Connection,
+    migrations: &[Migration],
+    options: &MigrateOptions,
+) -> MigrateResult<MigrationReport> {
+    let tracking_table_created = ensure_migrations_table(conn)?;
     let applied_migrations = get_applied_migrations(conn)?;
+    let skipped_migrations = get_skipped_migrations(conn)?;
+    verify_checksums(migrations, &get_migration_checksums(conn)?, options.vars())?;
+    let disabled = disabled_migrations(migrations, &applied_migrations);
 
-    // Check if there are any migrations to apply
+    // Check if there are any migrations to apply or skip
     let pending_migrations: Vec<&Migration> = migrations
         .iter()
-        .filter(|m| !applied_migrations.contains(m.id))
+        .filter(|m| migration_is_pending(m, &applied_migrations, &skipped_migrations, &disabled))
         .collect();
 
     if pending_migrations.is_empty() {
-        return Ok(());
+        return Ok(MigrationReport {
+            tracking_table_created,
+            ..MigrationReport::default()
+        });
     }
 
+    backup(conn, options)?;
+    let reenable_foreign_keys = disable_foreign_keys(conn, options)?;
+    let mut result = execute_migrations(conn, &pending_migrations, &disabled, options);
+    if reenable_foreign_keys {
+        let enabled = enable_foreign_keys(conn);
+        result = result.and_then(|report| enabled.map(|()| report));
+    }
+    let mut report = result.map_err(|e| restore(conn, options, e))?;
+    report.maintenance.extend(maintain(conn, options));
+    report.tracking_table_created = tracking_table_created;
+
+    Ok(report)
+}
+
+/// Executes and records migrations in a single transaction.
+///
+/// Migrations in `disabled` are recorded as skipped instead. Runs the checks configured
+/// in `options` before committing, and returns a report of the migrations and the
+/// checks.
+fn execute_migrations(
+    conn: &mut Connection,
+    migrations: &[&Migration],
+    disabled: &HashSet<&str>,
+    options: &MigrateOptions,
+) -> MigrateResult<MigrationReport> {
+    let mut report = MigrationReport::default();
+
     // Start transaction for all migrations
     let tx = conn.transaction()?;
 
-    for migration in pending_migrations {
+    for migration in migrations {
+        if disabled.contains(migration.id) {
+            record_skipped(&tx, migration)?;
+            report.skipped.push(migration.id.to_string());
+            continue;
+        }
+
         // Execute the migration SQL
-        tx.execute_batch(migration.sql)
-            .map_err(|e| Error::MigrationFailed {
-                id: migration.id.to_string(),
-                message: e.to_string(),
-            })?;
+        let stats = execute_migration(&tx, migration, options.vars())?;
 
         // Record migration as applied
-        tx.execute("INSERT INTO _migrations(id) VALUES (?)", [migration.id])?;
+        record_migration(&tx, migration, options.vars())?;
+        report.push_migration(stats);
     }
 
+    report.maintenance = check(&tx, options)?;
+
     // Commit all migrations atomically
-    tx.commit()?;
+    tx.commit().map_err(|source| Error::Commit { source })?;
 
-    Ok(())
+    Ok(report)
+}
+
+/// Applies pending migrations up to and including `target`.
+///
+/// Like [`migrate`], but migrations after `target` are left pending. All migrations are
+/// applied in a single transaction.
+///
+/// # Returns
+/// The IDs of the migrations that were applied, in order.
+///
+/// # Errors
+/// Returns [`Error::UnknownMigration`] if `target` is not in `migrations`, and the
+/// errors of [`migrate`].
+pub fn migrate_to(
+    conn: &mut Connection,
+    migrations: &[Migration],
+    target: &str,
+) -> MigrateResult<Vec<String>> {
+    migrate_to_with(conn, migrations, target, &MigrateOptions::default())
+}
+
+/// Applies pending migrations up to and including `target`, with the given options.
+///
+/// Works like [`migrate_to`], with additional steps configured by [`MigrateOptions`].
+/// Migrations using runtime variables can only be applied with their values set, see
+/// [`MigrateOptions::with_var`].
+///
+/// # Errors
+/// Returns the errors of [`migrate_to`] and [`migrate_with`].
+pub fn migrate_to_with(
+    conn: &mut Connection,
+    migrations: &[Migration],
+    target: &str,
+    options: &MigrateOptions,
+) -> MigrateResult<Vec<String>> {
+    let position = migrations
+        .iter()
+        .position(|m| m.id == target)
+        .ok_or_else(|| Error::UnknownMigration {
+            id: target.to_string(),
+        })?;
+    apply_migrations(conn, &migrations[..=position], options).map(|report| report.applied)
+}
+
+/// Rolls back applied migrations until `target` is the latest applied migration.
+///
+/// The down migrations (see [`Migration::with_down`]) of the applied migrations after
+/// `target` are executed latest first, and the migrations are removed from
+/// `_migrations`. With `None` as the target, all applied migrations are rolled back.
+/// Everything runs in a single transaction, so if a down migration fails nothing is
+/// rolled back.
+///
+/// Only migrations in `migrations` are rolled back; applied migrations that are not
+/// embedded are left alone.
+///
+/// # Returns
+/// The IDs of the migrations that were rolled back, latest first.
+///
+/// # Errors
+/// Returns an error if:
+/// - `target` is not in `migrations` ([`Error::UnknownMigration`])
+/// - A migration to roll back has no down migration ([`Error::IrreversibleMigration`])
+/// - A down migration fails ([`Error::MigrationFailed`])
+/// - Transaction cannot be committed
+pub fn rollback_to(
+    conn: &mut Connection,
+    migrations: &[Migration],
+    target: Option<&str>,
+) -> MigrateResult<Vec<String>> {
+    ensure_migrations_table(conn)?;
+    let applied_migrations = get_applied_migrations(conn)?;
+    let to_roll_back = rollback_plan(migrations, &applied_migrations, target)?;
+
+    if to_roll_back.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let tx = conn.transaction()?;
+    for migration in &to_roll_back {
+        revert_migration(&tx, migration)?;
+    }
+    tx.commit().map_err(|source| Error::Commit { source })?;
+
+    Ok(to_roll_back.iter().map(|m| m.id.to_string()).collect())
+}
+
+/// Returns the status of every migration.
+///
+/// Lists the migrations in `migrations` in order, followed by applied migrations that
+/// are not in `migrations`. The database is not modified, so this can be called from
+/// a query.
+///
+/// # Errors
+/// Returns an error if `_migrations` cannot be read.
+pub fn status(conn: &Connection, migrations: &[Migration]) -> MigrateResult<Vec<MigrationStatus>> {
+    let (applied, skipped) = if table_exists(conn, "_migrations")? {
+        (get_applied_at(conn)?, get_skipped_migrations(conn)?)
+    } else {
+        (HashMap::new(), HashSet::new())
+    };
+    Ok(migration_status(migrations, applied, &skipped))
+}
+
+/// Returns the applied migrations in the order they were applied.
+///
+/// The history can be encoded with [`MigrationHistory::encode`], e.g. to return it from
+/// a query, and checked against the migrations of a new build with
+/// [`check_upgrade`](crate::check_upgrade) before upgrading. The database is not
+/// modified.
+///
+/// # Errors
+/// Returns an error if `_migrations` cannot be read.
+pub fn history(conn: &Connection) -> MigrateResult<MigrationHistory> {
+    if !table_exists(conn, "_migrations")? {
+        return Ok(MigrationHistory::default());
+    }
+
+    let mut statement = conn
+        .prepare("SELECT id, checksum FROM _migrations WHERE skipped = 0 ORDER BY rowid")
+        .map_err(Error::tracking("_migrations", None))?;
+    let migrations = statement
+        .query_map([], |row| {
+            Ok(AppliedMigration {
+                id: row.get(0)?,
+                checksum: row.get(1)?,
+            })
+        })
+        .map_err(Error::tracking("_migrations", None))?
+        .collect::<Result<_, _>>()
+        .map_err(Error::tracking("_migrations", None))?;
+
+    Ok(MigrationHistory { migrations })
 }
 
 /// Ensures the seeds tracking table exists in the database.
@@ -157,29 +628,102 @@ pub fn migrate(conn: &mut Connection, migrations: &[Migration]) -> MigrateResult
 /// Creates a `_seeds` table if it doesn't exist, which tracks:
 /// - `id`: The unique identifier of each applied seed
 /// - `applied_at`: Timestamp when the seed was applied
-fn ensure_seeds_table(conn: &mut Connection) -> MigrateResult<()> {
+/// - `hash`: Content hash of versioned seeds, `NULL` for run-once seeds
+///
+/// Tables created by earlier versions of the library are upgraded in place. Returns
+/// whether the table was created.
+fn ensure_seeds_table(conn: &mut Connection) -> MigrateResult<bool> {
+    let created = !table_exists(conn, "_seeds")?;
     conn.execute(
         "CREATE TABLE IF NOT EXISTS _seeds (
             id TEXT PRIMARY KEY,
-            applied_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            applied_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            hash TEXT
         )",
         [],
-    )?;
+    )
+    .map_err(Error::tracking("_seeds", None))?;
+    ensure_column(conn, "_seeds", "hash", "TEXT")?;
+    Ok(created)
+}
+
+/// Adds a column to a tracking table if it is missing.
+fn ensure_column(
+    conn: &Connection,
+    table: &'static str,
+    column: &str,
+    decl: &str,
+) -> MigrateResult<()> {
+    let exists: bool = conn
+        .query_row(
+            "SELECT COUNT(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+            [table, column],
+            |row| row.get(0),
+        )
+        .map_err(Error::tracking(table, None))?;
+    if !exists {
+        conn.execute_batch(&format!("ALTER TABLE {table} ADD COLUMN {column} {decl}"))
+            .map_err(Error::tracking(table, None))?;
+    }
     Ok(())
 }
 
-/// Retrieves the set of already applied seed IDs from the database.
-fn get_applied_seeds(conn: &Connection) -> MigrateResult<HashSet<String>> {
-    let mut statement = conn.prepare("SELECT id FROM _seeds")?;
+/// Retrieves the already applied seed IDs from the database, mapped to their recorded hash.
+fn get_applied_seeds(conn: &Connection) -> MigrateResult<HashMap<String, Option<String>>> {
+    let mut statement = conn
+        .prepare("SELECT id, hash FROM _seeds")
+        .map_err(Error::tracking("_seeds", None))?;
 
-    let seed_ids = statement.query_map([], |row| row.get::<_, String>(0))?;
+    let seeds = statement
+        .query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?))
+        })
+        .map_err(Error::tracking("_seeds", None))?;
 
-    let mut applied_set = HashSet::new();
-    for id in seed_ids.into_iter().flatten() {
-        applied_set.insert(id);
+    let mut applied = HashMap::new();
+    for (id, hash) in seeds.into_iter().flatten() {
+        applied.insert(id, hash);
     }
 
-    Ok(applied_set)
+    Ok(applied)
+}
+
+/// Records a seed as applied, updating the stored hash if the seed was re-run.
+fn record_seed(conn: &Connection, seed: &Seed, reapplied: bool) -> MigrateResult<()> {
+    let sql = if reapplied {
+        "UPDATE _seeds SET hash = ?2, applied_at = CURRENT_TIMESTAMP WHERE id = ?1"
+    } else {
+        "INSERT INTO _seeds(id, hash) VALUES (?1, ?2)"
+    };
+    conn.execute(sql, rusqlite::params![seed.id, seed.hash])
+        .map_err(Error::tracking("_seeds", Some(seed.id)))?;
+    Ok(())
+}
+
+/// Verifies that the migrations required by the given seeds have been applied.
+fn check_seed_dependencies(conn: &Connection, seeds: &[&Seed]) -> MigrateResult<()> {
+    if seeds.iter().all(|s| s.requires_migration.is_none()) {
+        return Ok(());
+    }
+
+    let applied_migrations = if table_exists(conn, "_migrations")? {
+        get_applied_migrations(conn)?
+    } else {
+        HashSet::new()
+    };
+
+    for seed in seeds {
+        if let Some(migration) = seed.requires_migration {
+            if !applied_migrations.contains(migration) {
+                return Err(Error::SeedDependencyMissing {
+                    seed: seed.id.to_string(),
+                    migration: migration.to_string(),
+                });
+            }
+        }
+    }
+
+    Ok(())
 }
 
 /// Executes all pending seeds in order.
@@ -190,20 +734,26 @@ fn get_applied_seeds(conn: &Connection) -> MigrateResult<HashSet<String>> {
 /// 3. Executes pending seeds in the order they appear in the slice
 /// 4. Records each seed as applied
 ///
-/// All seeds are executed within a single transaction for atomicity.
-/// If any seed fails, all changes are rolled back.
+/// A seed is pending if it has never been applied, or if it is a versioned seed whose
+/// hash differs from the hash recorded when it last ran. Before any seed runs, the
+/// migrations required by pending seeds are checked to be applied.
+///
+/// Each seed is executed within its own transaction for atomicity.
+/// If any seed fails, changes for that seed are rolled back.
 ///
 /// # Arguments
 /// * `conn` - Mutable reference to the SQLite connection
 /// * `seeds` - Slice of seeds to apply in order
 ///
 /// # Returns
-/// * `Ok(())` - If all pending seeds were successfully applied or if there were no pending seeds
-/// * `Err(Error)` - If any seed failed to execute
+/// A [`MigrationReport`] of the applied seeds, which is empty if there were no pending
+/// seeds.
 ///
 /// # Errors
 /// Returns an error if:
 /// - Database operations fail
+/// - A pending seed requires a migration that has not been applied
+///   ([`Error::SeedDependencyMissing`])
 /// - Seed function returns an error
 /// - Transaction cannot be committed
 ///
@@ -228,89 +778,541 @@ fn get_applied_seeds(conn: &Connection) -> MigrateResult<HashSet<String>> {
 ///     });
 /// }
 /// ```
-pub fn seed(conn: &mut Connection, seeds: &[Seed]) -> MigrateResult<()> {
-    ensure_seeds_table(conn)?;
+pub fn seed(conn: &mut Connection, seeds: &[Seed]) -> MigrateResult<MigrationReport> {
+    let mut report = MigrationReport {
+        tracking_table_created: ensure_seeds_table(conn)?,
+        ..MigrationReport::default()
+    };
     let applied_seeds = get_applied_seeds(conn)?;
 
     let pending_seeds: Vec<&Seed> = seeds
         .iter()
-        .filter(|s| !applied_seeds.contains(s.id))
+        .filter(|s| seed_is_pending(s, &applied_seeds))
         .collect();
 
     if pending_seeds.is_empty() {
-        return Ok(());
+        return Ok(report);
     }
 
+    check_seed_dependencies(conn, &pending_seeds)?;
+
     for seed in pending_seeds {
         let tx = conn.transaction()?;
+        let total_changes = tx.total_changes();
 
-        (seed.seed_fn)(&tx).map_err(|e| Error::MigrationFailed {
+        (seed.seed_fn)(&tx).map_err(|e| Error::SeedFailed {
             id: seed.id.to_string(),
-            message: e.to_string(),
+            source: Box::new(e),
         })?;
+        report.rows_changed += tx.total_changes() - total_changes;
 
-        tx.execute("INSERT INTO _seeds(id) VALUES (?)", [seed.id])?;
+        record_seed(&tx, seed, applied_seeds.contains_key(seed.id))?;
 
-        tx.commit()?;
+        tx.commit().map_err(|source| Error::Commit { source })?;
+        report.seeds.push(seed.id.to_string());
     }
 
+    Ok(report)
+}
+
+/// Runs a single seed, even if it has already been applied.
+///
+/// Use this to re-run a seed on demand, e.g. from an admin endpoint. The seed is
+/// recorded in `_seeds` as if it had been run by [`seed`].
+///
+/// # Errors
+/// Returns an error if:
+/// - `id` is not in `seeds` ([`Error::UnknownSeed`])
+/// - The seed requires a migration that has not been applied
+///   ([`Error::SeedDependencyMissing`])
+/// - The seed function returns an error ([`Error::SeedFailed`])
+/// - Transaction cannot be committed
+pub fn run_seed(conn: &mut Connection, seeds: &[Seed], id: &str) -> MigrateResult<()> {
+    let seed = seeds
+        .iter()
+        .find(|s| s.id == id)
+        .ok_or_else(|| Error::UnknownSeed { id: id.to_string() })?;
+
+    ensure_seeds_table(conn)?;
+    let applied_seeds = get_applied_seeds(conn)?;
+    check_seed_dependencies(conn, &[seed])?;
+
+    let tx = conn.transaction()?;
+    (seed.seed_fn)(&tx).map_err(|e| Error::SeedFailed {
+        id: seed.id.to_string(),
+        source: Box::new(e),
+    })?;
+    record_seed(&tx, seed, applied_seeds.contains_key(seed.id))?;
+    tx.commit().map_err(|source| Error::Commit { source })?;
+
     Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use rusqlite::Connection;
+/// Executes pending migrations and seeds as a single ordered pipeline.
+///
+/// Use this instead of separate [`migrate`] and [`seed`] calls when data setup has to
+/// happen between schema steps, for example to populate a new column before a later
+/// migration builds on it. Seeds that declare a required migration (see
+/// [`Seed::requires`]) run directly after that migration; all other seeds are merged
+/// in by version, so `seed_002_prices` runs after migration `002_create_prices` and
+/// before `003_...`. Seeds without a version run after all migrations.
+///
+/// Migrations and seeds are tracked in `_migrations` and `_seeds` exactly as with
+/// [`migrate`] and [`seed`], and the whole pipeline runs in a single transaction.
+/// If any step fails, all changes are rolled back.
+///
+/// # Arguments
+/// * `conn` - Mutable reference to the SQLite connection
+/// * `migrations` - Slice of migrations, sorted by version
+/// * `seeds` - Slice of seeds
+///
+/// # Errors
+/// Returns an error if:
+/// - Database operations fail
+/// - Migration SQL is invalid or a seed function returns an error
+/// - A pending seed requires a migration that is neither applied nor part of `migrations`
+///   ([`Error::SeedDependencyMissing`])
+/// - Transaction cannot be committed
+///
+/// # Example
+/// ```ignore
+/// use ic_rusqlite::{with_connection, Connection};
+///
+/// static MIGRATIONS: &[ic_sql_migrate::Migration] = ic_sql_migrate::include_migrations!();
+///
+/// fn run_migrations_and_seeds() {
+///     with_connection(|mut conn| {
+///         let conn: &mut Connection = &mut conn;
+///         ic_sql_migrate::sqlite::run(conn, MIGRATIONS, seeds::SEEDS).unwrap();
+///     });
+/// }
+/// ```
+pub fn run(
+    conn: &mut Connection,
+    migrations: &[Migration],
+    seeds: &[Seed],
+) -> MigrateResult<MigrationReport> {
+    run_with(conn, migrations, seeds, &MigrateOptions::default())
+}
 
-    #[test]
-    fn test_migration_creation() {
-        let migration = Migration::new("001_test", "CREATE TABLE test (id INTEGER);");
-        assert_eq!(migration.id, "001_test");
-        assert_eq!(migration.sql, "CREATE TABLE test (id INTEGER);");
+/// Executes pending migrations and seeds as a single ordered pipeline, with the given
+/// options.
+///
+/// Works like [`run`], with additional steps configured by [`MigrateOptions`].
+///
+/// # Errors
+/// Returns the errors of [`run`] and [`migrate_with`].
+pub fn run_with(
+    conn: &mut Connection,
+    migrations: &[Migration],
+    seeds: &[Seed],
+    options: &MigrateOptions,
+) -> MigrateResult<MigrationReport> {
+    let tracking_table_created = ensure_migrations_table(conn)?;
+    ensure_seeds_table(conn)?;
+    let applied_migrations = get_applied_migrations(conn)?;
+    let skipped_migrations = get_skipped_migrations(conn)?;
+    let applied_seeds = get_applied_seeds(conn)?;
+    verify_checksums(migrations, &get_migration_checksums(conn)?, options.vars())?;
+    let disabled = disabled_migrations(migrations, &applied_migrations);
+
+    // Seeds that require a skipped migration are left pending until it is applied
+    let pending_steps: Vec<Step> = plan(migrations, seeds)
+        .into_iter()
+        .filter(|step| match step {
+            Step::Migration(m) => {
+                migration_is_pending(m, &applied_migrations, &skipped_migrations, &disabled)
+            }
+            Step::Seed(s) => {
+                seed_is_pending(s, &applied_seeds)
+                    && !s.requires_migration.is_some_and(|m| disabled.contains(m))
+            }
+        })
+        .collect();
+
+    if pending_steps.is_empty() {
+        return Ok(MigrationReport {
+            tracking_table_created,
+            ..MigrationReport::default()
+        });
     }
 
-    #[test]
-    fn test_ensure_migrations_table() {
-        let mut conn = Connection::open_in_memory().unwrap();
-        ensure_migrations_table(&mut conn).unwrap();
+    // Seeds may only depend on migrations that are applied or run as part of this pipeline
+    for step in &pending_steps {
+        if let Step::Seed(seed) = step {
+            if let Some(migration) = seed.requires_migration {
+                if !applied_migrations.contains(migration)
+                    && !migrations.iter().any(|m| m.id == migration)
+                {
+                    return Err(Error::SeedDependencyMissing {
+                        seed: seed.id.to_string(),
+                        migration: migration.to_string(),
+                    });
+                }
+            }
+        }
+    }
 
-        // Verify table exists
-        let count: i64 = conn
-            .query_row(
-                "SELECT COUNT(*) FROM sqlite_master WHERE type='table' AND name='_migrations'",
-                [],
-                |row| row.get(0),
-            )
-            .unwrap();
-        assert_eq!(count, 1);
+    backup(conn, options)?;
+    let reenable_foreign_keys = disable_foreign_keys(conn, options)?;
+    let mut result = execute_steps(conn, &pending_steps, &applied_seeds, &disabled, options);
+    if reenable_foreign_keys {
+        let enabled = enable_foreign_keys(conn);
+        result = result.and_then(|report| enabled.map(|()| report));
     }
+    let mut report = result.map_err(|e| restore(conn, options, e))?;
+    report.maintenance.extend(maintain(conn, options));
+    report.tracking_table_created = tracking_table_created;
 
-    #[test]
-    fn test_up_migrations() {
-        let mut conn = Connection::open_in_memory().unwrap();
+    Ok(report)
+}
 
-        let migrations = &[
-            Migration::new(
-                "001_create_users",
-                "CREATE TABLE users (id INTEGER PRIMARY KEY);",
-            ),
-            Migration::new("002_add_email", "ALTER TABLE users ADD COLUMN email TEXT;"),
-        ];
+/// Executes and records pipeline steps in a single transaction.
+///
+/// Migrations in `disabled` are recorded as skipped instead. Runs the checks configured
+/// in `options` before committing, and returns a report of the steps and the checks.
+fn execute_steps(
+    conn: &mut Connection,
+    steps: &[Step],
+    applied_seeds: &HashMap<String, Option<String>>,
+    disabled: &HashSet<&str>,
+    options: &MigrateOptions,
+) -> MigrateResult<MigrationReport> {
+    let mut report = MigrationReport::default();
+    let tx = conn.transaction()?;
 
-        // Run migrations
-        migrate(&mut conn, migrations).unwrap();
+    for step in steps {
+        match step {
+            Step::Migration(migration) if disabled.contains(migration.id) => {
+                record_skipped(&tx, migration)?;
+                report.skipped.push(migration.id.to_string());
+            }
+            Step::Migration(migration) => {
+                let stats = execute_migration(&tx, migration, options.vars())?;
 
-        // Verify migrations were applied
-        let applied = get_applied_migrations(&conn).unwrap();
-        assert!(applied.contains("001_create_users"));
-        assert!(applied.contains("002_add_email"));
+                record_migration(&tx, migration, options.vars())?;
+                report.push_migration(stats);
+            }
+            Step::Seed(seed) => {
+                let total_changes = tx.total_changes();
+                (seed.seed_fn)(&tx).map_err(|e| Error::SeedFailed {
+                    id: seed.id.to_string(),
+                    source: Box::new(e),
+                })?;
+                report.rows_changed += tx.total_changes() - total_changes;
 
-        // Verify table structure
-        let count: i64 = conn
-            .query_row(
-                "SELECT COUNT(*) FROM pragma_table_info('users') WHERE name='email'",
-                [],
-                |row| row.get(0),
+                record_seed(&tx, seed, applied_seeds.contains_key(seed.id))?;
+                report.seeds.push(seed.id.to_string());
+            }
+        }
+    }
+
+    report.maintenance = check(&tx, options)?;
+
+    tx.commit().map_err(|source| Error::Commit { source })?;
+
+    Ok(report)
+}
+
+/// Inserts a row, or updates it if a row with the same key already exists.
+///
+/// Intended for versioned seeds, which must be safe to re-run. Columns in
+/// `conflict_columns` must be covered by a primary key or unique index and be listed
+/// in `columns`, otherwise [`Error::UnknownConflictColumn`] is returned; all other
+/// columns in `columns` are overwritten with the new values on conflict.
+///
+/// # Arguments
+/// * `conn` - The SQLite connection (or transaction) to execute on
+/// * `table` - Name of the table to upsert into
+/// * `conflict_columns` - Columns identifying the row
+/// * `columns` - All columns to insert, in the same order as `params`
+/// * `params` - Values for `columns`
+///
+/// # Returns
+/// The number of rows inserted or updated.
+///
+/// # Example
+/// ```
+/// use ic_sql_migrate::sqlite::upsert;
+///
+/// fn seed(conn: &rusqlite::Connection) -> ic_sql_migrate::MigrateResult<()> {
+///     for (code, name) in [("USD", "US Dollar"), ("EUR", "Euro")] {
+///         upsert(conn, "currency", &["code"], &["code", "name"], rusqlite::params![code, name])?;
+///     }
+///     Ok(())
+/// }
+/// ```
+pub fn upsert(
+    conn: &Connection,
+    table: &str,
+    conflict_columns: &[&str],
+    columns: &[&str],
+    params: impl rusqlite::Params,
+) -> MigrateResult<u64> {
+    if let Some(column) = conflict_columns.iter().find(|c| !columns.contains(c)) {
+        return Err(Error::UnknownConflictColumn {
+            table: table.to_string(),
+            column: column.to_string(),
+        });
+    }
+
+    let sql = upsert_sql(table, conflict_columns, columns);
+    Ok(conn.execute(&sql, params)? as u64)
+}
+
+/// Builds the `INSERT ... ON CONFLICT DO UPDATE` statement of [`upsert`].
+///
+/// Columns listed in `conflict_columns` identify the row, all other columns are
+/// overwritten with the inserted values on conflict.
+fn upsert_sql(table: &str, conflict_columns: &[&str], columns: &[&str]) -> String {
+    let quote = quote_identifier;
+
+    let column_list = columns.iter().map(|c| quote(c)).collect::<Vec<_>>();
+    let placeholders = (1..=columns.len())
+        .map(|i| format!("?{i}"))
+        .collect::<Vec<_>>();
+    let conflict_list = conflict_columns
+        .iter()
+        .map(|c| quote(c))
+        .collect::<Vec<_>>();
+    let updates = columns
+        .iter()
+        .filter(|c| !conflict_columns.contains(c))
+        .map(|c| format!("{0} = excluded.{0}", quote(c)))
+        .collect::<Vec<_>>();
+
+    let action = if updates.is_empty() {
+        "DO NOTHING".to_string()
+    } else {
+        format!("DO UPDATE SET {}", updates.join(", "))
+    };
+
+    format!(
+        "INSERT INTO {} ({}) VALUES ({}) ON CONFLICT ({}) {}",
+        quote(table),
+        column_list.join(", "),
+        placeholders.join(", "),
+        conflict_list.join(", "),
+        action
+    )
+}
+
+/// Rebuilds a table with a new definition, keeping its rows, indexes and triggers.
+///
+/// SQLite's `ALTER TABLE` cannot change column types or constraints, and cannot drop
+/// columns that are part of a constraint. This performs the documented workaround:
+/// it creates a table with the new definition, copies the rows, drops the old table,
+/// renames the new one and recreates the indexes and triggers of the old table.
+/// Indexes and triggers that refer to removed columns must be dropped beforehand.
+///
+/// Foreign keys can only be disabled outside of a transaction. Called outside of a
+/// transaction, the rebuild runs in its own transaction with foreign keys disabled,
+/// and rows violating foreign keys afterwards are reported as
+/// [`Error::ForeignKeyViolations`]. Migrations and seeds run inside a transaction, so
+/// there the rebuild fails with [`Error::TableRebuild`] if foreign keys are enabled and
+/// other tables reference the rebuilt table, as dropping it would delete or orphan
+/// their rows. Apply them with [`MigrateOptions::with_foreign_keys_disabled`] instead.
+///
+/// # Arguments
+/// * `conn` - The SQLite connection (or transaction) to execute on
+/// * `table` - Name of the table to rebuild
+/// * `definition` - The new table definition, as it follows the table name in
+///   `CREATE TABLE`
+/// * `columns` - Columns of the new table, each with the expression over the columns
+///   of the old table that fills it
+///
+/// # Example
+/// ```
+/// use ic_sql_migrate::sqlite::rebuild_table;
+///
+/// fn make_email_required(conn: &rusqlite::Connection) -> ic_sql_migrate::MigrateResult<()> {
+///     rebuild_table(
+///         conn,
+///         "users",
+///         "(id INTEGER PRIMARY KEY, email TEXT NOT NULL UNIQUE)",
+///         &[("id", "id"), ("email", "coalesce(email, id || '@example.com')")],
+///     )
+/// }
+/// ```
+pub fn rebuild_table(
+    conn: &Connection,
+    table: &str,
+    definition: &str,
+    columns: &[(&str, &str)],
+) -> MigrateResult<()> {
+    let foreign_keys: bool = conn.query_row("PRAGMA foreign_keys", [], |row| row.get(0))?;
+    let own_transaction = conn.is_autocommit();
+
+    if foreign_keys {
+        if own_transaction {
+            conn.execute_batch("PRAGMA foreign_keys = OFF")?;
+        } else if let Some(referencing) = referencing_tables(conn, table)?.first() {
+            return Err(Error::TableRebuild {
+                table: table.to_string(),
+                reason: format!(
+                    "table '{referencing}' references it and foreign keys cannot be disabled \
+                     inside a transaction"
+                ),
+            });
+        }
+    }
+
+    let result = if own_transaction {
+        conn.execute_batch("BEGIN")
+            .map_err(Error::from)
+            .and_then(|()| execute_rebuild(conn, table, definition, columns, foreign_keys))
+            .and_then(|()| conn.execute_batch("COMMIT").map_err(Error::from))
+    } else {
+        execute_rebuild(conn, table, definition, columns, foreign_keys)
+    };
+    if result.is_err() && own_transaction && !conn.is_autocommit() {
+        let _ = conn.execute_batch("ROLLBACK");
+    }
+
+    if foreign_keys && own_transaction {
+        conn.execute_batch("PRAGMA foreign_keys = ON")?;
+    }
+    result
+}
+
+/// Runs the statements of [`rebuild_table`], checking foreign keys afterwards if
+/// `check_foreign_keys` is set.
+fn execute_rebuild(
+    conn: &Connection,
+    table: &str,
+    definition: &str,
+    columns: &[(&str, &str)],
+    check_foreign_keys: bool,
+) -> MigrateResult<()> {
+    let schema = conn
+        .prepare(TABLE_SCHEMA_SQL)?
+        .query_map([table], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Views referring to the table would fail the rename, as the old table is gone by
+    // then. Legacy mode renames without checking them.
+    let legacy_alter_table: bool =
+        conn.query_row("PRAGMA legacy_alter_table", [], |row| row.get(0))?;
+    conn.execute_batch("PRAGMA legacy_alter_table = ON")?;
+    let result = rebuild_statements(table, definition, columns, quote_identifier)
+        .iter()
+        .try_for_each(|sql| conn.execute_batch(sql));
+    if !legacy_alter_table {
+        conn.execute_batch("PRAGMA legacy_alter_table = OFF")?;
+    }
+    result?;
+
+    for sql in &schema {
+        conn.execute_batch(sql)?;
+    }
+
+    if check_foreign_keys {
+        let mut violations = foreign_key_violations(conn, Some(table))?;
+        for referencing in referencing_tables(conn, table)? {
+            violations.extend(foreign_key_violations(conn, Some(&referencing))?);
+        }
+        if !violations.is_empty() {
+            return Err(Error::ForeignKeyViolations { violations });
+        }
+    }
+    Ok(())
+}
+
+/// Returns the other tables with foreign keys referencing `table`.
+fn referencing_tables(conn: &Connection, table: &str) -> MigrateResult<Vec<String>> {
+    Ok(conn
+        .prepare(REFERENCING_TABLES_SQL)?
+        .query_map([table], |row| row.get(0))?
+        .collect::<Result<_, _>>()?)
+}
+
+/// Returns the rows of `table`, or of all tables, that violate foreign keys.
+fn foreign_key_violations(
+    conn: &Connection,
+    table: Option<&str>,
+) -> MigrateResult<Vec<ForeignKeyViolation>> {
+    Ok(conn
+        .prepare(&foreign_key_check_sql(table))?
+        .query_map([], |row| {
+            Ok(ForeignKeyViolation {
+                table: row.get(0)?,
+                rowid: row.get(1)?,
+                parent: row.get(2)?,
+                foreign_key: row.get(3)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::IntegrityCheck;
+    use rusqlite::Connection;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn test_migration_creation() {
+        let migration = Migration::new("001_test", "CREATE TABLE test (id INTEGER);");
+        assert_eq!(migration.id, "001_test");
+        assert_eq!(migration.sql, "CREATE TABLE test (id INTEGER);");
+    }
+
+    #[test]
+    fn test_migration_version_and_name() {
+        let migration = Migration::new("010_add_users", "");
+        assert_eq!(migration.version(), Some(10));
+        assert_eq!(migration.name(), "add_users");
+
+        let migration = Migration::new("V2__legacy", "").with_version(2, "legacy");
+        assert_eq!(migration.version(), Some(2));
+        assert_eq!(migration.name(), "legacy");
+
+        assert_eq!(Migration::new("init", "").version(), None);
+    }
+
+    #[test]
+    fn test_ensure_migrations_table() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        ensure_migrations_table(&mut conn).unwrap();
+
+        // Verify table exists
+        let count: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE type='table' AND name='_migrations'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_up_migrations() {
+        let mut conn = Connection::open_in_memory().unwrap();
+
+        let migrations = &[
+            Migration::new(
+                "001_create_users",
+                "CREATE TABLE users (id INTEGER PRIMARY KEY);",
+            ),
+            Migration::new("002_add_email", "ALTER TABLE users ADD COLUMN email TEXT;"),
+        ];
+
+        // Run migrations
+        migrate(&mut conn, migrations).unwrap();
+
+        // Verify migrations were applied
+        let applied = get_applied_migrations(&conn).unwrap();
+        assert!(applied.contains("001_create_users"));
+        assert!(applied.contains("002_add_email"));
+
+        // Verify table structure
+        let count: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM pragma_table_info('users') WHERE name='email'",
+                [],
+                |row| row.get(0),
             )
             .unwrap();
         assert_eq!(count, 1);
@@ -407,8 +1409,8 @@ mod tests {
         seed(&mut conn, seeds).unwrap();
 
         let applied = get_applied_seeds(&conn).unwrap();
-        assert!(applied.contains("001_initial"));
-        assert!(applied.contains("002_more"));
+        assert!(applied.contains_key("001_initial"));
+        assert!(applied.contains_key("002_more"));
 
         let count: i64 = conn
             .query_row("SELECT COUNT(*) FROM test_users", [], |row| row.get(0))
@@ -439,4 +1441,935 @@ mod tests {
             .unwrap();
         assert_eq!(user_count, 2);
     }
+
+    fn seed_currencies(conn: &Connection) -> MigrateResult<()> {
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS currency (code TEXT PRIMARY KEY, name TEXT NOT NULL)",
+            [],
+        )?;
+        for (code, name) in [("USD", "US Dollar"), ("EUR", "Euro")] {
+            upsert(conn, "currency", &["code"], &["code", "name"], [code, name])?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_upsert() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute("CREATE TABLE tags (name TEXT PRIMARY KEY, color TEXT)", [])
+            .unwrap();
+
+        let columns = &["name", "color"];
+        assert_eq!(
+            upsert(&conn, "tags", &["name"], columns, ["a", "red"]).unwrap(),
+            1
+        );
+        assert_eq!(
+            upsert(&conn, "tags", &["name"], columns, ["a", "blue"]).unwrap(),
+            1
+        );
+        assert_eq!(
+            upsert(&conn, "tags", &["name"], &["name"], ["a"]).unwrap(),
+            0
+        );
+        let blue: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM tags WHERE color = 'blue'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(blue, 1);
+
+        let result = upsert(&conn, "tags", &["id"], columns, ["b", "red"]);
+        assert!(matches!(
+            result,
+            Err(Error::UnknownConflictColumn { ref column, .. }) if column == "id"
+        ));
+    }
+
+    #[test]
+    fn test_versioned_seed_reruns_on_hash_change() {
+        let mut conn = Connection::open_in_memory().unwrap();
+
+        seed(
+            &mut conn,
+            &[Seed::versioned("001_currency", "aaaa", seed_currencies)],
+        )
+        .unwrap();
+        conn.execute("UPDATE currency SET name = 'Changed'", [])
+            .unwrap();
+
+        // Same hash: the seed is not re-run
+        seed(
+            &mut conn,
+            &[Seed::versioned("001_currency", "aaaa", seed_currencies)],
+        )
+        .unwrap();
+        let changed: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM currency WHERE name = 'Changed'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(changed, 2);
+
+        // New hash: the seed is re-run and the recorded hash updated
+        seed(
+            &mut conn,
+            &[Seed::versioned("001_currency", "bbbb", seed_currencies)],
+        )
+        .unwrap();
+        let changed: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM currency WHERE name = 'Changed'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(changed, 0);
+
+        let applied = get_applied_seeds(&conn).unwrap();
+        assert_eq!(applied["001_currency"].as_deref(), Some("bbbb"));
+
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM currency", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn test_seeds_table_upgraded_with_hash_column() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE _seeds (
+                id TEXT PRIMARY KEY,
+                applied_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            );
+            INSERT INTO _seeds(id) VALUES ('001_old');",
+        )
+        .unwrap();
+
+        ensure_seeds_table(&mut conn).unwrap();
+
+        let applied = get_applied_seeds(&conn).unwrap();
+        assert_eq!(applied.get("001_old"), Some(&None));
+    }
+
+    #[test]
+    fn test_seed_dependency_missing() {
+        let mut conn = Connection::open_in_memory().unwrap();
+
+        let seeds = &[Seed::new("001_initial", seed_test_data).requires("001_create_users")];
+
+        // Migrations have not been run at all
+        let result = seed(&mut conn, seeds);
+        assert!(matches!(
+            result,
+            Err(Error::SeedDependencyMissing { ref seed, ref migration })
+                if seed == "001_initial" && migration == "001_create_users"
+        ));
+        assert!(get_applied_seeds(&conn).unwrap().is_empty());
+
+        // Once the migration is applied the seed runs
+        let migrations = &[Migration::new(
+            "001_create_users",
+            "CREATE TABLE users (id INTEGER PRIMARY KEY);",
+        )];
+        migrate(&mut conn, migrations).unwrap();
+        seed(&mut conn, seeds).unwrap();
+        assert!(get_applied_seeds(&conn)
+            .unwrap()
+            .contains_key("001_initial"));
+    }
+
+    fn seed_items(conn: &Connection) -> MigrateResult<()> {
+        conn.execute_batch("INSERT INTO items (name) VALUES ('a'), ('b'), ('c');")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_interleaves_seeds_and_migrations() {
+        let mut conn = Connection::open_in_memory().unwrap();
+
+        let migrations = &[
+            Migration::new("001_items", "CREATE TABLE items (name TEXT);"),
+            Migration::new(
+                "002_item_count",
+                "CREATE TABLE item_count AS SELECT COUNT(*) AS n FROM items;",
+            ),
+        ];
+        let seeds = &[Seed::new("seed_001_items", seed_items).requires("001_items")];
+
+        run(&mut conn, migrations, seeds).unwrap();
+
+        // The seed ran between the two migrations
+        let n: i64 = conn
+            .query_row("SELECT n FROM item_count", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(n, 3);
+        assert_eq!(get_applied_migrations(&conn).unwrap().len(), 2);
+        assert!(get_applied_seeds(&conn)
+            .unwrap()
+            .contains_key("seed_001_items"));
+
+        // Nothing is pending on a second run
+        run(&mut conn, migrations, seeds).unwrap();
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM items", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    fn test_run_merges_seeds_by_version() {
+        let mut conn = Connection::open_in_memory().unwrap();
+
+        let migrations = &[
+            Migration::new("9_items", "CREATE TABLE items (name TEXT);"),
+            Migration::new(
+                "10_item_count",
+                "CREATE TABLE item_count AS SELECT COUNT(*) AS n FROM items;",
+            ),
+        ];
+        let seeds = &[Seed::new("seed_9_items", seed_items)];
+
+        run(&mut conn, migrations, seeds).unwrap();
+
+        // The seed ran after migration 9 and before migration 10
+        let n: i64 = conn
+            .query_row("SELECT n FROM item_count", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(n, 3);
+    }
+
+    #[test]
+    fn test_run_failure_rolls_back_seeds() {
+        let mut conn = Connection::open_in_memory().unwrap();
+
+        let migrations = &[
+            Migration::new("001_items", "CREATE TABLE items (name TEXT);"),
+            Migration::new("002_invalid", "INVALID SQL STATEMENT;"),
+        ];
+        let seeds = &[Seed::new("seed_001_items", seed_items).requires("001_items")];
+
+        assert!(run(&mut conn, migrations, seeds).is_err());
+
+        assert!(get_applied_migrations(&conn).unwrap().is_empty());
+        assert!(get_applied_seeds(&conn).unwrap().is_empty());
+        assert!(!table_exists(&conn, "items").unwrap());
+    }
+
+    #[test]
+    fn test_modified_migration_detected() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE _migrations (
+                id TEXT PRIMARY KEY,
+                applied_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            );
+            INSERT INTO _migrations(id) VALUES ('000_legacy');",
+        )
+        .unwrap();
+
+        let sql = "CREATE TABLE users (id INTEGER PRIMARY KEY);";
+        let migrations =
+            &[Migration::new("001_create_users", sql).with_checksum("0123456789abcdef")];
+        migrate(&mut conn, migrations).unwrap();
+        assert_eq!(
+            get_migration_checksums(&conn)
+                .unwrap()
+                .get("001_create_users"),
+            Some(&"0123456789abcdef".to_string())
+        );
+
+        // Migrations applied without a checksum are not verified
+        let migrations = &[
+            Migration::new("000_legacy", "").with_checksum("fedcba9876543210"),
+            Migration::new("001_create_users", sql).with_checksum("0123456789abcdef"),
+        ];
+        migrate(&mut conn, migrations).unwrap();
+
+        let migrations =
+            &[Migration::new("001_create_users", sql).with_checksum("fedcba9876543210")];
+        let err = migrate(&mut conn, migrations).unwrap_err();
+        assert!(matches!(err, Error::ChecksumMismatch { id, .. } if id == "001_create_users"));
+        assert!(run(&mut conn, migrations, &[]).is_err());
+    }
+
+    #[test]
+    fn test_failed_statement_reported() {
+        let mut conn = Connection::open_in_memory().unwrap();
+
+        let sql = "-- Users\nCREATE TABLE users (id INTEGER PRIMARY KEY);\n\n  \
+                   INSERT INTO missing VALUES (1);\n";
+        let migrations = &[Migration::new("001_users", sql)];
+
+        let Err(Error::MigrationFailed {
+            id,
+            statement: Some(statement),
+            source,
+        }) = migrate(&mut conn, migrations)
+        else {
+            panic!("expected a failed statement");
+        };
+        assert_eq!(id, "001_users");
+        assert!(source.to_string().contains("missing"));
+        assert_eq!(statement.index, 1);
+        assert_eq!(statement.sql, "INSERT INTO missing VALUES (1);");
+        assert_eq!((statement.line, statement.column), (4, 3));
+
+        // Positions embedded for minified SQL take precedence
+        let migrations = &[Migration::new(
+            "001_users",
+            "CREATE TABLE users(id INTEGER PRIMARY KEY);\nINSERT INTO missing VALUES(1);",
+        )
+        .with_statement_positions(&[(2, 1), (4, 3)])];
+        let err = run(&mut conn, migrations, &[]).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Migration '001_users' failed at statement 2 (line 4, column 3)"));
+    }
+
+    #[test]
+    fn test_seed_failure_chains_source() {
+        use std::error::Error as _;
+
+        fn seed_missing_table(conn: &Connection) -> MigrateResult<()> {
+            conn.execute("INSERT INTO missing VALUES (1)", [])?;
+            Ok(())
+        }
+
+        let mut conn = Connection::open_in_memory().unwrap();
+        let seeds = &[Seed::new("001_missing", seed_missing_table)];
+
+        let err = seed(&mut conn, seeds).unwrap_err();
+        let Error::SeedFailed { id, source } = &err else {
+            panic!("expected a seed failure, got {err:?}");
+        };
+        assert_eq!(id, "001_missing");
+        assert!(matches!(**source, Error::Database(_)));
+        assert!(err.source().unwrap().to_string().contains("missing"));
+        assert!(get_applied_seeds(&conn).unwrap().is_empty());
+
+        let err = run(&mut conn, &[], seeds).unwrap_err();
+        assert!(matches!(err, Error::SeedFailed { id, .. } if id == "001_missing"));
+    }
+
+    fn reversible_migrations() -> [Migration; 3] {
+        [
+            Migration::new("001_users", "CREATE TABLE users (id INTEGER PRIMARY KEY);")
+                .with_down("DROP TABLE users;"),
+            Migration::new("002_email", "ALTER TABLE users ADD COLUMN email TEXT;")
+                .with_down("ALTER TABLE users DROP COLUMN email;"),
+            Migration::new("003_posts", "CREATE TABLE posts (id INTEGER PRIMARY KEY);")
+                .with_down("DROP TABLE posts;"),
+        ]
+    }
+
+    #[test]
+    fn test_migrate_to_and_rollback_to() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let migrations = &reversible_migrations();
+
+        assert_eq!(
+            migrate_to(&mut conn, migrations, "002_email").unwrap(),
+            ["001_users", "002_email"]
+        );
+        assert!(table_exists(&conn, "users").unwrap());
+        assert!(!table_exists(&conn, "posts").unwrap());
+        assert!(matches!(
+            migrate_to(&mut conn, migrations, "004_missing"),
+            Err(Error::UnknownMigration { id }) if id == "004_missing"
+        ));
+
+        migrate(&mut conn, migrations).unwrap();
+        assert_eq!(
+            rollback_to(&mut conn, migrations, Some("001_users")).unwrap(),
+            ["003_posts", "002_email"]
+        );
+        assert!(!table_exists(&conn, "posts").unwrap());
+        assert_eq!(
+            get_applied_migrations(&conn).unwrap(),
+            HashSet::from(["001_users".to_string()])
+        );
+
+        // Nothing is left to roll back after the target
+        assert!(rollback_to(&mut conn, migrations, Some("001_users"))
+            .unwrap()
+            .is_empty());
+
+        assert_eq!(
+            rollback_to(&mut conn, migrations, None).unwrap(),
+            ["001_users"]
+        );
+        assert!(!table_exists(&conn, "users").unwrap());
+        assert!(get_applied_migrations(&conn).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_rollback_requires_down_migrations() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let [users, email, _] = reversible_migrations();
+        let migrations = &[
+            users,
+            email,
+            Migration::new("003_posts", "CREATE TABLE posts (id INTEGER PRIMARY KEY);"),
+        ];
+        migrate(&mut conn, migrations).unwrap();
+
+        let err = rollback_to(&mut conn, migrations, Some("001_users")).unwrap_err();
+        assert!(matches!(err, Error::IrreversibleMigration { id } if id == "003_posts"));
+        assert_eq!(get_applied_migrations(&conn).unwrap().len(), 3);
+
+        // A failing down migration rolls back the whole rollback
+        let migrations = &[
+            migrations[0].clone(),
+            migrations[1].clone(),
+            migrations[2].clone().with_down("DROP TABLE missing;"),
+        ];
+        let err = rollback_to(&mut conn, migrations, None).unwrap_err();
+        assert!(matches!(err, Error::MigrationFailed { id, .. } if id == "003_posts"));
+        assert_eq!(get_applied_migrations(&conn).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_status() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let [users, email, posts] = reversible_migrations();
+        let migrations = &[users, email, Migration::new("003_posts", posts.sql)];
+        assert!(status(&conn, migrations)
+            .unwrap()
+            .iter()
+            .all(|s| s.applied_at.is_none()));
+
+        migrate_to(&mut conn, migrations, "001_users").unwrap();
+        conn.execute("INSERT INTO _migrations(id) VALUES ('000_removed')", [])
+            .unwrap();
+
+        let status = status(&conn, migrations).unwrap();
+        let summary: Vec<_> = status
+            .iter()
+            .map(|s| (s.id.as_str(), s.applied_at.is_some(), s.reversible))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("001_users", true, true),
+                ("002_email", false, true),
+                ("003_posts", false, false),
+                ("000_removed", true, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_run_seed() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let seeds = &[Seed::new("001_initial", seed_test_data)];
+
+        run_seed(&mut conn, seeds, "001_initial").unwrap();
+        // Seeds can be re-run on demand
+        run_seed(&mut conn, seeds, "001_initial").unwrap();
+
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM test_users", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 4);
+        assert_eq!(get_applied_seeds(&conn).unwrap().len(), 1);
+        assert!(matches!(
+            run_seed(&mut conn, seeds, "002_missing"),
+            Err(Error::UnknownSeed { id }) if id == "002_missing"
+        ));
+    }
+
+    #[test]
+    fn test_history() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        assert!(history(&conn).unwrap().migrations.is_empty());
+
+        let [users, email, posts] = reversible_migrations();
+        let migrations = &[users.with_checksum("aaaa"), email, posts];
+        migrate_to(&mut conn, migrations, "002_email").unwrap();
+
+        let history = history(&conn).unwrap();
+        assert_eq!(
+            history.encode(),
+            "ic-sql-migrate/1;001_users=aaaa;002_email"
+        );
+
+        let check = crate::check_upgrade(&history, migrations);
+        assert!(check.is_compatible());
+        assert_eq!(check.pending, ["003_posts"]);
+    }
+
+    /// Creates an empty, uniquely named directory under the system temp dir.
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("ic-sql-migrate-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_backup_before_migrations() {
+        let dir = temp_dir("backup");
+        let backup_path = dir.join("backup.db");
+        let options = MigrateOptions::new().with_backup(&backup_path);
+        let mut conn = Connection::open(dir.join("main.db")).unwrap();
+        let [users, email, _] = reversible_migrations();
+
+        migrate_with(&mut conn, std::slice::from_ref(&users), &options).unwrap();
+        conn.execute("INSERT INTO users (id) VALUES (1)", [])
+            .unwrap();
+
+        // A failed migration leaves the database as it was before
+        let failing = Migration::new("003_fail", "INSERT INTO missing VALUES (1);");
+        let err = migrate_with(&mut conn, &[users.clone(), email, failing], &options).unwrap_err();
+        assert!(matches!(err, Error::MigrationFailed { id, .. } if id == "003_fail"));
+        assert_eq!(
+            get_applied_migrations(&conn).unwrap(),
+            HashSet::from(["001_users".to_string()])
+        );
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM users", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+
+        // The backup holds the state before the failed migrations
+        let backup = Connection::open(&backup_path).unwrap();
+        assert_eq!(get_applied_migrations(&backup).unwrap().len(), 1);
+
+        // Without pending migrations no backup is taken
+        std::fs::remove_file(&backup_path).unwrap();
+        migrate_with(&mut conn, &[users], &options).unwrap();
+        assert!(!backup_path.exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_backup_failure() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let path = std::env::temp_dir().join("ic-sql-migrate-missing-dir/backup.db");
+        let options = MigrateOptions::new().with_backup(&path);
+
+        let err = run_with(&mut conn, &reversible_migrations(), &[], &options).unwrap_err();
+        assert!(matches!(&err, Error::BackupFailed { path: p, .. } if *p == path));
+        assert!(!table_exists(&conn, "users").unwrap());
+    }
+
+    fn rebuild_schema() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "PRAGMA foreign_keys = ON;
+             CREATE TABLE users (id INTEGER PRIMARY KEY, email TEXT);
+             CREATE INDEX idx_users_email ON users (email);
+             CREATE TRIGGER users_lower AFTER INSERT ON users BEGIN
+                 UPDATE users SET email = lower(email) WHERE id = new.id;
+             END;
+             CREATE VIEW user_emails AS SELECT email FROM users;
+             CREATE TABLE posts (id INTEGER PRIMARY KEY, user_id INTEGER REFERENCES users (id));
+             INSERT INTO users VALUES (1, 'a@example.com'), (2, NULL);
+             INSERT INTO posts VALUES (1, 1), (2, 2);",
+        )
+        .unwrap();
+        conn
+    }
+
+    #[test]
+    fn test_rebuild_table() {
+        let conn = rebuild_schema();
+
+        rebuild_table(
+            &conn,
+            "users",
+            "(id INTEGER PRIMARY KEY, email TEXT NOT NULL)",
+            &[("id", "id"), ("email", "coalesce(email, 'user' || id)")],
+        )
+        .unwrap();
+
+        let emails: String = conn
+            .query_row("SELECT group_concat(email) FROM user_emails", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(emails, "a@example.com,user2");
+        assert!(conn
+            .execute("INSERT INTO users (id, email) VALUES (3, NULL)", [])
+            .is_err());
+
+        // Indexes and triggers are recreated
+        let schema: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE tbl_name = 'users' \
+                 AND name IN ('idx_users_email', 'users_lower')",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(schema, 2);
+        conn.execute("INSERT INTO users VALUES (3, 'C@EXAMPLE.COM')", [])
+            .unwrap();
+        let email: String = conn
+            .query_row("SELECT email FROM users WHERE id = 3", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(email, "c@example.com");
+
+        // Foreign keys are enabled again and still refer to the rebuilt table
+        assert!(conn
+            .execute("INSERT INTO posts VALUES (3, 99)", [])
+            .is_err());
+        assert!(conn.is_autocommit());
+    }
+
+    #[test]
+    fn test_rebuild_table_foreign_key_violations() {
+        let conn = rebuild_schema();
+
+        let err = rebuild_table(
+            &conn,
+            "users",
+            "(id INTEGER PRIMARY KEY, email TEXT)",
+            &[("id", "id + 10"), ("email", "email")],
+        )
+        .unwrap_err();
+        let Error::ForeignKeyViolations { violations } = &err else {
+            panic!("unexpected error: {err}");
+        };
+        assert_eq!(violations.len(), 2);
+        assert_eq!(
+            violations[0],
+            ForeignKeyViolation {
+                table: "posts".to_string(),
+                rowid: Some(1),
+                parent: "users".to_string(),
+                foreign_key: 0,
+            }
+        );
+
+        // The rebuild is rolled back
+        let ids: String = conn
+            .query_row("SELECT group_concat(id) FROM users", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(ids, "1,2");
+        let foreign_keys: bool = conn
+            .query_row("PRAGMA foreign_keys", [], |row| row.get(0))
+            .unwrap();
+        assert!(foreign_keys);
+    }
+
+    #[test]
+    fn test_rebuild_referenced_table_in_transaction() {
+        let mut conn = rebuild_schema();
+        let tx = conn.transaction().unwrap();
+
+        let err =
+            rebuild_table(&tx, "users", "(id INTEGER PRIMARY KEY)", &[("id", "id")]).unwrap_err();
+        assert!(matches!(&err, Error::TableRebuild { table, .. } if table == "users"));
+
+        // Tables that aren't referenced can be rebuilt with foreign keys enabled
+        rebuild_table(
+            &tx,
+            "posts",
+            "(id INTEGER PRIMARY KEY, user_id INTEGER NOT NULL REFERENCES users (id))",
+            &[("id", "id"), ("user_id", "user_id")],
+        )
+        .unwrap();
+        tx.commit().unwrap();
+    }
+
+    #[test]
+    fn test_migrate_with_foreign_keys_disabled() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("PRAGMA foreign_keys = ON").unwrap();
+        let schema = Migration::new(
+            "001_schema",
+            "CREATE TABLE users (id INTEGER PRIMARY KEY, email TEXT);
+             CREATE TABLE posts (id INTEGER PRIMARY KEY, user_id INTEGER REFERENCES users (id));
+             INSERT INTO users VALUES (1, 'a@example.com');
+             INSERT INTO posts VALUES (1, 1);",
+        );
+        let rebuild = Migration::new(
+            "002_email_required",
+            "CREATE TABLE new_users (id INTEGER PRIMARY KEY, email TEXT NOT NULL);
+             INSERT INTO new_users SELECT id, email FROM users;
+             DROP TABLE users;
+             ALTER TABLE new_users RENAME TO users;",
+        );
+        migrate(&mut conn, std::slice::from_ref(&schema)).unwrap();
+
+        // Dropping the referenced table fails while foreign keys are enforced
+        let err = migrate(&mut conn, &[schema.clone(), rebuild.clone()]).unwrap_err();
+        assert!(matches!(err, Error::MigrationFailed { id, .. } if id == "002_email_required"));
+
+        let options = MigrateOptions::new().with_foreign_keys_disabled();
+        migrate_with(&mut conn, &[schema.clone(), rebuild.clone()], &options).unwrap();
+        let foreign_keys: bool = conn
+            .query_row("PRAGMA foreign_keys", [], |row| row.get(0))
+            .unwrap();
+        assert!(foreign_keys);
+
+        // Violations are reported and roll the migrations back
+        let orphan = Migration::new("003_orphan", "INSERT INTO posts VALUES (2, 42);");
+        let err = run_with(&mut conn, &[schema, rebuild, orphan], &[], &options).unwrap_err();
+        let Error::ForeignKeyViolations { violations } = &err else {
+            panic!("unexpected error: {err}");
+        };
+        assert_eq!(
+            violations,
+            &[ForeignKeyViolation {
+                table: "posts".to_string(),
+                rowid: Some(2),
+                parent: "users".to_string(),
+                foreign_key: 0,
+            }]
+        );
+        assert_eq!(
+            err.to_string(),
+            "Foreign key check failed:\n  row 2 of table 'posts' references a missing row of table 'users'"
+        );
+        assert!(!get_applied_migrations(&conn)
+            .unwrap()
+            .contains("003_orphan"));
+        let foreign_keys: bool = conn
+            .query_row("PRAGMA foreign_keys", [], |row| row.get(0))
+            .unwrap();
+        assert!(foreign_keys);
+    }
+
+    #[test]
+    fn test_migrate_with_maintenance() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let fill = Migration::new(
+            "001_fill",
+            "CREATE TABLE logs (id INTEGER PRIMARY KEY, message TEXT);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 1000)
+             INSERT INTO logs SELECT i, hex(randomblob(100)) FROM n;",
+        );
+        let drop = Migration::new("002_drop", "DROP TABLE logs;");
+        let options = MigrateOptions::new()
+            .with_integrity_check(IntegrityCheck::Quick)
+            .with_optimize()
+            .with_analyze()
+            .with_vacuum_threshold(0);
+
+        let report = migrate_with(&mut conn, std::slice::from_ref(&fill), &options).unwrap();
+        assert_eq!(report.applied, ["001_fill"]);
+        assert_eq!(
+            report.maintenance,
+            [
+                Maintenance::IntegrityCheck(IntegrityCheck::Quick),
+                Maintenance::Optimize,
+                Maintenance::Analyze,
+            ]
+        );
+
+        // Dropping the table frees pages, which exceed the threshold
+        let report = run_with(&mut conn, &[fill.clone(), drop.clone()], &[], &options).unwrap();
+        assert_eq!(report.applied, ["002_drop"]);
+        assert!(matches!(
+            report.maintenance.as_slice(),
+            [
+                Maintenance::IntegrityCheck(IntegrityCheck::Quick),
+                Maintenance::Optimize,
+                Maintenance::Analyze,
+                Maintenance::Vacuum { free_pages },
+            ] if *free_pages > 0
+        ));
+        let free_pages: u64 = conn
+            .query_row("PRAGMA freelist_count", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(free_pages, 0);
+
+        // Nothing runs when there is nothing to apply
+        let report = migrate_with(&mut conn, &[fill, drop], &options).unwrap();
+        assert_eq!(report, MigrationReport::default());
+    }
+
+    #[test]
+    fn test_maintenance_failure_is_recorded() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE logs (id INTEGER PRIMARY KEY, message TEXT);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 1000)
+             INSERT INTO logs SELECT i, hex(randomblob(100)) FROM n;
+             DROP TABLE logs;",
+        )
+        .unwrap();
+        let options = MigrateOptions::new()
+            .with_analyze()
+            .with_vacuum_threshold(0);
+
+        // VACUUM cannot run inside a transaction, which does not stop the other steps
+        conn.execute_batch("BEGIN").unwrap();
+        let steps = maintain(&conn, &options);
+        assert!(matches!(
+            steps.as_slice(),
+            [Maintenance::Analyze, Maintenance::Failed { statement, .. }] if statement == "VACUUM"
+        ));
+    }
+
+    #[test]
+    fn test_migration_report() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let migrations = [
+            Migration::new(
+                "001_users",
+                "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT);
+                 INSERT INTO users (name) VALUES ('Alice'), ('Bob');",
+            ),
+            Migration::new("002_upper", "UPDATE users SET name = upper(name);"),
+        ];
+        let seeds = [
+            Seed::new("001_initial", seed_test_data),
+            Seed::new("002_more", seed_more_data),
+        ];
+
+        let report = migrate(&mut conn, &migrations[..1]).unwrap();
+        assert_eq!(report.applied, ["001_users"]);
+        assert!(report.tracking_table_created);
+        assert_eq!(report.rows_changed, 2);
+        let [stats] = report.stats.as_slice() else {
+            panic!("unexpected stats: {:?}", report.stats);
+        };
+        assert_eq!(stats.id, "001_users");
+        assert_eq!(stats.statements, 2);
+        assert_eq!(stats.rows_changed, 2);
+        assert_eq!(stats.instructions, None);
+
+        let report = seed(&mut conn, &seeds[..1]).unwrap();
+        assert_eq!(report.seeds, ["001_initial"]);
+        assert!(report.applied.is_empty());
+        assert!(report.tracking_table_created);
+        assert_eq!(report.rows_changed, 2);
+
+        let report = run(&mut conn, &migrations, &seeds).unwrap();
+        assert_eq!(report.applied, ["002_upper"]);
+        assert_eq!(report.seeds, ["002_more"]);
+        assert!(!report.tracking_table_created);
+        assert_eq!(report.rows_changed, 3);
+        assert_eq!(report.stats[0].statements, 1);
+
+        // Nothing is reported when there is nothing to apply
+        let report = migrate(&mut conn, &migrations).unwrap();
+        assert_eq!(report, MigrationReport::default());
+    }
+
+    #[test]
+    fn test_runtime_variables() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let migrations = [Migration::new(
+            "001_admins",
+            "CREATE TABLE admins (principal TEXT, note TEXT);
+             INSERT INTO admins VALUES ('${admin}', '$${admin}');",
+        )
+        .with_checksum("0123456789abcdef")
+        .with_vars(&["admin"])];
+
+        let err = migrate(&mut conn, &migrations).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::UnresolvedVariable { id, name } if id == "001_admins" && name == "admin"
+        ));
+
+        let options = MigrateOptions::new().with_var("admin", "aaaaa-aa");
+        migrate_with(&mut conn, &migrations, &options).unwrap();
+        let admin: (String, String) = conn
+            .query_row("SELECT principal, note FROM admins", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(admin, ("aaaaa-aa".to_string(), "${admin}".to_string()));
+
+        // The recorded checksum covers the value, which must not change afterwards
+        let checksums = get_migration_checksums(&conn).unwrap();
+        assert_ne!(checksums["001_admins"], "0123456789abcdef");
+        assert!(migrate_with(&mut conn, &migrations, &options).is_ok());
+        // Without the value the checksum can't be verified, but nothing has to be applied
+        assert!(migrate(&mut conn, &migrations).is_ok());
+        let options = MigrateOptions::new().with_var("admin", "bbbbb-bb");
+        let err = migrate_with(&mut conn, &migrations, &options).unwrap_err();
+        assert!(matches!(err, Error::ChecksumMismatch { id, .. } if id == "001_admins"));
+    }
+
+    #[test]
+    fn test_migrate_to_with_runtime_variables() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let migrations = [
+            Migration::new("001_admins", "CREATE TABLE admins (principal TEXT);"),
+            Migration::new("002_admin", "INSERT INTO admins VALUES ('${admin}');")
+                .with_vars(&["admin"]),
+        ];
+
+        let err = migrate_to(&mut conn, &migrations, "002_admin").unwrap_err();
+        assert!(matches!(err, Error::UnresolvedVariable { name, .. } if name == "admin"));
+
+        // Quotes in the value can't end the string literal
+        let value = "aaaaa-aa'); DROP TABLE admins; --";
+        let options = MigrateOptions::new().with_var("admin", value);
+        let applied = migrate_to_with(&mut conn, &migrations, "002_admin", &options).unwrap();
+        assert_eq!(applied, ["001_admins", "002_admin"]);
+        let admin: String = conn
+            .query_row("SELECT principal FROM admins", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(admin, value);
+    }
+
+    static EVENTS_ENABLED: AtomicBool = AtomicBool::new(false);
+
+    fn events_enabled() -> bool {
+        EVENTS_ENABLED.load(Ordering::Relaxed)
+    }
+
+    fn seed_events(conn: &Connection) -> MigrateResult<()> {
+        conn.execute_batch("INSERT INTO events (name) VALUES ('created');")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_conditional_migrations() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let migrations = [
+            Migration::new("001_users", "CREATE TABLE users (id INTEGER PRIMARY KEY);"),
+            Migration::new("002_audit", "").when_feature("audit", false),
+            Migration::new("003_events", "CREATE TABLE events (name TEXT);").when(events_enabled),
+            Migration::new("004_posts", "CREATE TABLE posts (id INTEGER PRIMARY KEY);"),
+        ];
+        let seeds = [Seed::new("seed_003_events", seed_events).requires("003_events")];
+
+        let report = run(&mut conn, &migrations, &seeds).unwrap();
+        assert_eq!(report.applied, ["001_users", "004_posts"]);
+        assert_eq!(report.skipped, ["002_audit", "003_events"]);
+        assert!(report.seeds.is_empty());
+        assert!(!table_exists(&conn, "events").unwrap());
+
+        // Skipped migrations are recorded once, and are not part of the history
+        let report = run(&mut conn, &migrations, &seeds).unwrap();
+        assert_eq!(report, MigrationReport::default());
+        assert_eq!(history(&conn).unwrap().migrations.len(), 2);
+        let skipped: Vec<_> = status(&conn, &migrations)
+            .unwrap()
+            .into_iter()
+            .filter(|s| s.skipped)
+            .map(|s| s.id)
+            .collect();
+        assert_eq!(skipped, ["002_audit", "003_events"]);
+
+        // Once the condition is met, the skipped migration and its seed are applied
+        EVENTS_ENABLED.store(true, Ordering::Relaxed);
+        let report = run(&mut conn, &migrations, &seeds).unwrap();
+        assert_eq!(report.applied, ["003_events"]);
+        assert_eq!(report.seeds, ["seed_003_events"]);
+        let status = status(&conn, &migrations).unwrap();
+        assert!(status[2].applied_at.is_some() && !status[2].skipped);
+        assert!(status[1].skipped);
+        assert_eq!(
+            get_applied_migrations(&conn).unwrap().len()
+                + get_skipped_migrations(&conn).unwrap().len(),
+            4
+        );
+    }
 }
@@ -9,6 +9,7 @@
 //! - Transactional migration execution (all-or-nothing)
 //! - Idempotent migrations (safe to run multiple times)
 //! - Ordered execution of pending migrations
+//! - Migration lock against concurrent calls
 //!
 //! # Usage in ICP Canisters
 //! ```ignore
@@ -50,35 +51,79 @@
 //! }
 //! ```
 
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::poll_fn;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::task::{Poll, Waker};
 use turso::Connection;
 
-use crate::{Error, MigrateResult, Migration, Seed};
+use super::{
+    disabled_migrations, migration_is_pending, migration_status, plan, rebuild_statements,
+    rollback_plan, seed_is_pending, statement_failed, verify_checksums, Step, Stopwatch,
+    TABLE_SCHEMA_SQL,
+};
+use crate::sql::split_statements;
+use crate::{
+    AppliedMigration, Error, IntegrityCheck, Maintenance, MigrateOptions, MigrateResult, Migration,
+    MigrationHistory, MigrationReport, MigrationStats, MigrationStatus, Seed,
+};
 
 /// Ensures the migrations tracking table exists in the database.
 ///
 /// Creates a `_migrations` table if it doesn't exist, which tracks:
 /// - `id`: The unique identifier of each applied migration
 /// - `applied_at`: Timestamp when the migration was applied
-async fn ensure_migrations_table(conn: &Connection) -> MigrateResult<()> {
+/// - `checksum`: Checksum of the migration file, `NULL` if it was not embedded
+/// - `skipped`: Whether the migration was skipped because its condition was not met,
+///   rather than applied
+///
+/// Tables created by earlier versions of the library are upgraded in place. Returns
+/// whether the table was created.
+async fn ensure_migrations_table(conn: &Connection) -> MigrateResult<bool> {
+    let created = !table_exists(conn, "_migrations").await?;
     conn.execute(
         "CREATE TABLE IF NOT EXISTS _migrations (
             id TEXT PRIMARY KEY,
-            applied_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            applied_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            checksum TEXT,
+            skipped INTEGER NOT NULL DEFAULT 0
         )",
         (),
     )
-    .await?;
-    Ok(())
+    .await
+    .map_err(Error::tracking("_migrations", None))?;
+    ensure_column(conn, "_migrations", "checksum", "TEXT").await?;
+    ensure_column(conn, "_migrations", "skipped", "INTEGER NOT NULL DEFAULT 0").await?;
+    Ok(created)
 }
 
 /// Retrieves the set of already applied migration IDs from the database.
 async fn get_applied_migrations(conn: &Connection) -> MigrateResult<HashSet<String>> {
-    let mut rows = conn.query("SELECT id FROM _migrations", ()).await?;
+    get_migration_ids(conn, "SELECT id FROM _migrations WHERE skipped = 0").await
+}
+
+/// Retrieves the set of migration IDs recorded as skipped.
+async fn get_skipped_migrations(conn: &Connection) -> MigrateResult<HashSet<String>> {
+    get_migration_ids(conn, "SELECT id FROM _migrations WHERE skipped = 1").await
+}
+
+/// Retrieves the migration IDs selected by `sql` from `_migrations`.
+async fn get_migration_ids(conn: &Connection, sql: &str) -> MigrateResult<HashSet<String>> {
+    let mut rows = conn
+        .query(sql, ())
+        .await
+        .map_err(Error::tracking("_migrations", None))?;
 
     let mut applied_set = HashSet::new();
-    while let Some(row) = rows.next().await? {
-        let value = row.get_value(0)?;
+    while let Some(row) = rows
+        .next()
+        .await
+        .map_err(Error::tracking("_migrations", None))?
+    {
+        let value = row
+            .get_value(0)
+            .map_err(Error::tracking("_migrations", None))?;
         if let Some(text) = value.as_text() {
             applied_set.insert(text.to_string());
         }
@@ -87,6 +132,446 @@ async fn get_applied_migrations(conn: &Connection) -> MigrateResult<HashSet<Stri
     Ok(applied_set)
 }
 
+/// Retrieves the applied migration IDs, mapped to when they were applied.
+async fn get_applied_at(conn: &Connection) -> MigrateResult<HashMap<String, String>> {
+    let mut rows = conn
+        .query(
+            "SELECT id, applied_at FROM _migrations WHERE skipped = 0",
+            (),
+        )
+        .await
+        .map_err(Error::tracking("_migrations", None))?;
+
+    let mut applied = HashMap::new();
+    while let Some(row) = rows
+        .next()
+        .await
+        .map_err(Error::tracking("_migrations", None))?
+    {
+        let id = row
+            .get_value(0)
+            .map_err(Error::tracking("_migrations", None))?;
+        let applied_at = row
+            .get_value(1)
+            .map_err(Error::tracking("_migrations", None))?;
+        if let (Some(id), Some(applied_at)) = (id.as_text(), applied_at.as_text()) {
+            applied.insert(id.to_string(), applied_at.to_string());
+        }
+    }
+
+    Ok(applied)
+}
+
+/// Retrieves the recorded checksums of applied migrations that have one.
+async fn get_migration_checksums(conn: &Connection) -> MigrateResult<HashMap<String, String>> {
+    let mut rows = conn
+        .query(
+            "SELECT id, checksum FROM _migrations WHERE checksum IS NOT NULL",
+            (),
+        )
+        .await
+        .map_err(Error::tracking("_migrations", None))?;
+
+    let mut checksums = HashMap::new();
+    while let Some(row) = rows
+        .next()
+        .await
+        .map_err(Error::tracking("_migrations", None))?
+    {
+        let id = row
+            .get_value(0)
+            .map_err(Error::tracking("_migrations", None))?;
+        let checksum = row
+            .get_value(1)
+            .map_err(Error::tracking("_migrations", None))?;
+        if let (Some(id), Some(checksum)) = (id.as_text(), checksum.as_text()) {
+            checksums.insert(id.to_string(), checksum.to_string());
+        }
+    }
+
+    Ok(checksums)
+}
+
+/// Records a migration as applied, along with its checksum, replacing the record of a
+/// skipped migration.
+async fn record_migration(
+    conn: &Connection,
+    migration: &Migration,
+    vars: &BTreeMap<String, String>,
+) -> MigrateResult<()> {
+    conn.execute(
+        "DELETE FROM _migrations WHERE id = ?1 AND skipped = 1",
+        [migration.id],
+    )
+    .await
+    .map_err(Error::tracking("_migrations", Some(migration.id)))?;
+    let result = match migration.resolved_checksum(vars)? {
+        Some(checksum) => {
+            conn.execute(
+                "INSERT INTO _migrations(id, checksum) VALUES (?1, ?2)",
+                [migration.id, checksum.as_str()],
+            )
+            .await
+        }
+        None => {
+            conn.execute("INSERT INTO _migrations(id) VALUES (?)", [migration.id])
+                .await
+        }
+    };
+    result.map_err(Error::tracking("_migrations", Some(migration.id)))?;
+    Ok(())
+}
+
+/// Records a migration as skipped because its condition was not met.
+async fn record_skipped(conn: &Connection, migration: &Migration) -> MigrateResult<()> {
+    conn.execute(
+        "INSERT INTO _migrations(id, skipped) VALUES (?1, 1)",
+        [migration.id],
+    )
+    .await
+    .map_err(Error::tracking("_migrations", Some(migration.id)))?;
+    Ok(())
+}
+
+/// Executes the statements of a migration one by one.
+///
+/// Running statements individually lets a failure be traced to the statement that
+/// caused it. Returns the statistics of the migration.
+async fn execute_migration(
+    conn: &Connection,
+    migration: &Migration,
+    vars: &BTreeMap<String, String>,
+) -> MigrateResult<MigrationStats> {
+    let stopwatch = Stopwatch::start();
+    let sql = migration.resolve_sql(vars)?;
+    let statements = split_statements(&sql);
+    let mut rows_changed = 0;
+    for (index, statement) in statements.iter().enumerate() {
+        match execute_statement(conn, statement.text(&sql)).await {
+            Ok(changes) => rows_changed += changes,
+            Err(e) => {
+                let position = migration.statement_position(index);
+                return Err(statement_failed(
+                    migration, &sql, index, statement, position, e,
+                ));
+            }
+        }
+    }
+    Ok(stopwatch.stats(migration, statements.len(), rows_changed))
+}
+
+/// Executes a single statement and returns the number of rows it changed.
+///
+/// Turso does not update `total_changes()` inside a transaction, so the changes are
+/// counted per statement. `execute` stops at the first row of statements that return
+/// rows, such as `SELECT`s and some `PRAGMA`s, so the remaining rows are stepped through
+/// without restarting the statement, and those statements count as changing no rows.
+async fn execute_statement(conn: &Connection, sql: &str) -> Result<u64, turso::Error> {
+    let mut statement = conn.prepare(sql).await?;
+    match statement.execute(()).await {
+        Err(turso::Error::SqlExecutionFailure(message))
+            if message == "unexpected row during execution" =>
+        {
+            let mut rows = statement.query(()).await?;
+            while rows.next().await?.is_some() {}
+            Ok(0)
+        }
+        result => result,
+    }
+}
+
+/// Executes the down migration of a migration and removes it from `_migrations`.
+async fn revert_migration(conn: &Connection, migration: &Migration) -> MigrateResult<()> {
+    let sql = migration.down().unwrap_or_default();
+    for (index, statement) in split_statements(sql).iter().enumerate() {
+        if let Err(e) = conn.execute_batch(statement.text(sql)).await {
+            return Err(statement_failed(migration, sql, index, statement, None, e));
+        }
+    }
+
+    conn.execute("DELETE FROM _migrations WHERE id = ?1", [migration.id])
+        .await
+        .map_err(Error::tracking("_migrations", Some(migration.id)))?;
+    Ok(())
+}
+
+/// Backs up the database file if `options` enable backups.
+///
+/// The write-ahead log is checkpointed first, so that the database file holds all
+/// committed changes.
+async fn backup(conn: &Connection, options: &MigrateOptions) -> MigrateResult<()> {
+    let Some(path) = options.backup_path() else {
+        return Ok(());
+    };
+    let backup_failed = |source: Error| Error::BackupFailed {
+        path: path.to_path_buf(),
+        source: Box::new(source),
+    };
+
+    let database_path = options.database_path().ok_or_else(|| {
+        backup_failed(Error::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "the database path must be set with MigrateOptions::with_database_path",
+        )))
+    })?;
+
+    let mut rows = conn
+        .query("PRAGMA wal_checkpoint(TRUNCATE)", ())
+        .await
+        .map_err(|e| backup_failed(e.into()))?;
+    while rows
+        .next()
+        .await
+        .map_err(|e| backup_failed(e.into()))?
+        .is_some()
+    {}
+
+    std::fs::copy(database_path, path).map_err(|e| backup_failed(e.into()))?;
+    release_backup_lock(path).await.map_err(backup_failed)
+}
+
+/// Removes the lock row taken by [`lock_database`] from the backup at `path`, so that
+/// restoring the backup does not leave the database locked.
+async fn release_backup_lock(path: &Path) -> MigrateResult<()> {
+    let path = path.to_str().ok_or_else(|| {
+        Error::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "the backup path must be valid UTF-8",
+        ))
+    })?;
+    let conn = turso::Builder::new_local(path).build().await?.connect()?;
+    release_migration_lock(&conn).await?;
+    let mut rows = conn.query("PRAGMA wal_checkpoint(TRUNCATE)", ()).await?;
+    while rows.next().await?.is_some() {}
+    Ok(())
+}
+
+/// Restores the backup taken by [`backup`] after `error` occurred, and returns `error`.
+///
+/// The backup is copied over the database file and the write-ahead log is removed.
+fn restore(options: &MigrateOptions, error: Error) -> Error {
+    let (Some(path), Some(database_path)) = (options.backup_path(), options.database_path()) else {
+        return error;
+    };
+
+    let mut wal = database_path.as_os_str().to_owned();
+    wal.push("-wal");
+    let result = std::fs::copy(path, database_path).and_then(|_| match std::fs::remove_file(wal) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    });
+
+    match result {
+        Ok(()) => error,
+        Err(e) => Error::RestoreFailed {
+            path: path.to_path_buf(),
+            error: Box::new(error),
+            source: Box::new(e.into()),
+        },
+    }
+}
+
+/// Fails with [`Error::Unsupported`] if `options` ask for something Turso cannot do.
+///
+/// Turso ignores `PRAGMA foreign_keys` and does not support `PRAGMA foreign_key_check`,
+/// `PRAGMA quick_check`, `PRAGMA optimize`, `ANALYZE` or `VACUUM`, so of the checks and
+/// maintenance steps only the full integrity check can run.
+fn check_options(options: &MigrateOptions) -> MigrateResult<()> {
+    let unsupported = if options.foreign_keys_disabled() {
+        Some("Disabling foreign keys while migrating")
+    } else if options.integrity_check() == Some(IntegrityCheck::Quick) {
+        Some("PRAGMA quick_check")
+    } else if options.optimize() {
+        Some("PRAGMA optimize")
+    } else if options.analyze() {
+        Some("ANALYZE")
+    } else if options.vacuum_threshold().is_some() {
+        Some("VACUUM")
+    } else {
+        None
+    };
+
+    match unsupported {
+        Some(operation) => Err(Error::Unsupported {
+            operation: operation.to_string(),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Whether a call in this process is applying migrations, and the calls waiting for it.
+struct LockState {
+    locked: bool,
+    waiters: Vec<Waker>,
+}
+
+static LOCK: Mutex<LockState> = Mutex::new(LockState {
+    locked: false,
+    waiters: Vec::new(),
+});
+
+fn lock_state() -> MutexGuard<'static, LockState> {
+    LOCK.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The in-process migration lock taken by [`lock`], released when dropped.
+struct ProcessLock;
+
+impl Drop for ProcessLock {
+    fn drop(&mut self) {
+        let waiters = {
+            let mut state = lock_state();
+            state.locked = false;
+            std::mem::take(&mut state.waiters)
+        };
+        for waker in waiters {
+            waker.wake();
+        }
+    }
+}
+
+/// Takes the in-process migration lock.
+///
+/// Migration functions are async, so another call can start while one is suspended at
+/// an await point. The second call waits until the first one releases the lock, or
+/// fails with [`Error::MigrationInProgress`] if `options` ask to fail fast.
+async fn lock(options: &MigrateOptions) -> MigrateResult<ProcessLock> {
+    poll_fn(|cx| {
+        let mut state = lock_state();
+        if !state.locked {
+            state.locked = true;
+            Poll::Ready(Ok(ProcessLock))
+        } else if options.fail_fast() {
+            Poll::Ready(Err(Error::MigrationInProgress))
+        } else {
+            state.waiters.push(cx.waker().clone());
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+/// Returns whether a call in this process is applying migrations or seeds.
+///
+/// While it is, other canister methods sharing the connection can observe a partially
+/// migrated schema. Use this, or the `canister::not_migrating` guard with the `ic-cdk`
+/// feature, to reject their calls until migrations are done.
+pub fn migration_in_progress() -> bool {
+    lock_state().locked
+}
+
+/// Ensures the migration lock table exists in the database.
+///
+/// The `_migrations_lock` table holds a single row while a connection applies
+/// migrations or seeds, with the time it took the lock in `locked_at`.
+async fn ensure_lock_table(conn: &Connection) -> MigrateResult<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS _migrations_lock (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            locked_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+        )",
+        (),
+    )
+    .await
+    .map_err(Error::tracking("_migrations_lock", None))?;
+    Ok(())
+}
+
+/// Takes the lock row in `_migrations_lock`.
+///
+/// Fails with [`Error::MigrationLocked`] if another connection holds it.
+async fn lock_database(conn: &Connection) -> MigrateResult<()> {
+    ensure_lock_table(conn).await?;
+    // Turso does not support `INSERT OR IGNORE`, so a lock held by another connection
+    // shows as a primary key violation
+    match conn
+        .execute("INSERT INTO _migrations_lock (id) VALUES (1)", ())
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(turso::Error::SqlExecutionFailure(message))
+            if message.contains("constraint failed") =>
+        {
+            let mut rows = conn
+                .query("SELECT locked_at FROM _migrations_lock", ())
+                .await
+                .map_err(Error::tracking("_migrations_lock", None))?;
+            let locked_at = match rows.next().await? {
+                Some(row) => row
+                    .get_value(0)?
+                    .as_text()
+                    .map(|t| t.to_string())
+                    .unwrap_or_default(),
+                None => String::new(),
+            };
+            Err(Error::MigrationLocked { locked_at })
+        }
+        Err(e) => Err(Error::tracking("_migrations_lock", None)(e)),
+    }
+}
+
+/// Releases the lock row taken by [`lock_database`] and returns `result`.
+///
+/// A failure to release the lock is only returned if `result` is `Ok`.
+async fn unlock_database<T>(conn: &Connection, result: MigrateResult<T>) -> MigrateResult<T> {
+    let unlocked = release_migration_lock(conn).await;
+    let value = result?;
+    unlocked?;
+    Ok(value)
+}
+
+/// Removes the lock row from `_migrations_lock`.
+///
+/// The row is removed when a call finishes, but stays behind if the call is
+/// interrupted, e.g. by a trap after an await point in a canister, and makes later
+/// calls fail with [`Error::MigrationLocked`]. Only call this when no other connection
+/// can be applying migrations; the hooks generated by [`lifecycle!`](crate::lifecycle)
+/// do so on install and upgrade.
+///
+/// # Errors
+/// Returns an error if `_migrations_lock` cannot be written.
+pub async fn release_migration_lock(conn: &Connection) -> MigrateResult<()> {
+    ensure_lock_table(conn).await?;
+    conn.execute("DELETE FROM _migrations_lock", ())
+        .await
+        .map_err(Error::tracking("_migrations_lock", None))?;
+    Ok(())
+}
+
+/// Runs the checks configured in `options` and returns the checks that ran.
+///
+/// Runs the integrity check, failing with [`Error::IntegrityCheckFailed`].
+async fn check(conn: &Connection, options: &MigrateOptions) -> MigrateResult<Vec<Maintenance>> {
+    let mut checks = Vec::new();
+
+    if let Some(integrity_check) = options.integrity_check() {
+        let mut rows = conn.query(integrity_check.pragma(), ()).await?;
+        let mut problems = Vec::new();
+        while let Some(row) = rows.next().await? {
+            if let Some(problem) = row.get_value(0)?.as_text() {
+                problems.push(problem.to_string());
+            }
+        }
+        if problems != ["ok"] {
+            return Err(Error::IntegrityCheckFailed { problems });
+        }
+        checks.push(Maintenance::IntegrityCheck(integrity_check));
+    }
+
+    Ok(checks)
+}
+
+/// Checks whether a table exists in the database.
+async fn table_exists(conn: &Connection, table: &str) -> MigrateResult<bool> {
+    let mut rows = conn
+        .query(
+            "SELECT name FROM sqlite_master WHERE type = 'table' AND name = ?1",
+            [table],
+        )
+        .await?;
+    Ok(rows.next().await?.is_some())
+}
+
 /// Executes all pending migrations in order.
 ///
 /// This function:
@@ -98,19 +583,25 @@ async fn get_applied_migrations(conn: &Connection) -> MigrateResult<HashSet<Stri
 /// All migrations are executed within a single transaction for atomicity.
 /// If any migration fails, all changes are rolled back.
 ///
+/// Calls that apply migrations or seeds are serialized: while one runs, another call
+/// in the same process waits for it to finish, and `_migrations_lock` holds a row so
+/// that other connections fail with [`Error::MigrationLocked`] instead of applying the
+/// same migrations twice.
+///
 /// # Arguments
 /// * `conn` - Mutable reference to the Turso connection
 /// * `migrations` - Slice of migrations to apply in order
 ///
 /// # Returns
-/// * `Ok(())` - If all pending migrations were successfully applied or if there were no pending migrations
-/// * `Err(Error)` - If any migration failed to execute
+/// A [`MigrationReport`] of the applied migrations, which is empty if there were no
+/// pending migrations.
 ///
 /// # Errors
 /// Returns an error if:
 /// - Database operations fail
 /// - Migration SQL is invalid
 /// - Transaction cannot be committed
+/// - Another connection holds the migration lock ([`Error::MigrationLocked`])
 ///
 /// # Example in ICP Canister
 /// ```no_run
@@ -126,81 +617,445 @@ async fn get_applied_migrations(conn: &Connection) -> MigrateResult<HashSet<Stri
 ///     ic_sql_migrate::turso::migrate(conn, MIGRATIONS).await.unwrap();
 /// }
 /// ```
-pub async fn migrate(conn: &mut Connection, migrations: &[Migration]) -> MigrateResult<()> {
-    ensure_migrations_table(conn).await?;
-    let applied_migrations = get_applied_migrations(conn).await?;
+pub async fn migrate(
+    conn: &mut Connection,
+    migrations: &[Migration],
+) -> MigrateResult<MigrationReport> {
+    migrate_with(conn, migrations, &MigrateOptions::default()).await
+}
+
+/// Executes all pending migrations in order, with the given options.
+///
+/// Works like [`migrate`], with additional steps configured by [`MigrateOptions`].
+///
+/// # Errors
+/// Returns the errors of [`migrate`], and an error if:
+/// - The database cannot be backed up ([`Error::BackupFailed`])
+/// - Migrations failed and the backup cannot be restored ([`Error::RestoreFailed`])
+/// - The integrity check finds problems ([`Error::IntegrityCheckFailed`])
+/// - `options` ask for a check or maintenance step Turso does not support, such as
+///   disabling foreign keys or `VACUUM` ([`Error::Unsupported`])
+/// - Another call is applying migrations and `options` ask to fail fast
+///   ([`Error::MigrationInProgress`])
+///
+/// # Example
+/// ```ignore
+/// let options = MigrateOptions::new()
+///     .with_database_path("/DB/main.db")
+///     .with_backup("/DB/backup.db");
+/// turso::migrate_with(&mut conn, MIGRATIONS, &options).await?;
+/// ```
+pub async fn migrate_with(
+    conn: &mut Connection,
+    migrations: &[Migration],
+    options: &MigrateOptions,
+) -> MigrateResult<MigrationReport> {
+    apply_migrations(conn, migrations, options).await
+}
 
-    // Check if there are any migrations to apply
+/// Applies the pending migrations in a single transaction.
+async fn apply_migrations(
+    conn: &mut Connection,
+    migrations: &[Migration],
+    options: &MigrateOptions,
+) -> MigrateResult<MigrationReport> {
+    check_options(options)?;
+    let _lock = lock(options).await?;
+    let tracking_table_created = ensure_migrations_table(conn).await?;
+    let applied_migrations = get_applied_migrations(conn).await?;
+    let skipped_migrations = get_skipped_migrations(conn).await?;
+    verify_checksums(
+        migrations,
+        &get_migration_checksums(conn).await?,
+        options.vars(),
+    )?;
+    let disabled = disabled_migrations(migrations, &applied_migrations);
+
+    // Check if there are any migrations to apply or skip
     let pending_migrations: Vec<&Migration> = migrations
         .iter()
-        .filter(|m| !applied_migrations.contains(m.id))
+        .filter(|m| migration_is_pending(m, &applied_migrations, &skipped_migrations, &disabled))
         .collect();
 
     if pending_migrations.is_empty() {
-        return Ok(());
+        return Ok(MigrationReport {
+            tracking_table_created,
+            ..MigrationReport::default()
+        });
     }
 
+    lock_database(conn).await?;
+    if let Err(e) = backup(conn, options).await {
+        return unlock_database(conn, Err(e)).await;
+    }
+    let result = execute_migrations(conn, &pending_migrations, &disabled, options).await;
+    let mut report = unlock_database(conn, result)
+        .await
+        .map_err(|e| restore(options, e))?;
+    report.tracking_table_created = tracking_table_created;
+
+    Ok(report)
+}
+
+/// Executes and records migrations in a single transaction.
+///
+/// Migrations in `disabled` are recorded as skipped instead. Runs the checks configured
+/// in `options` before committing, and returns a report of the migrations and the
+/// checks.
+async fn execute_migrations(
+    conn: &mut Connection,
+    migrations: &[&Migration],
+    disabled: &HashSet<&str>,
+    options: &MigrateOptions,
+) -> MigrateResult<MigrationReport> {
+    let mut report = MigrationReport::default();
+
     // Start transaction for all migrations
     let tx = conn.transaction().await?;
 
-    for migration in pending_migrations {
-        if let Err(e) = tx.execute_batch(migration.sql).await {
-            tx.rollback().await?;
-            return Err(Error::MigrationFailed {
-                id: migration.id.to_string(),
-                message: e.to_string(),
-            });
+    for migration in migrations {
+        if disabled.contains(migration.id) {
+            if let Err(e) = record_skipped(&tx, migration).await {
+                tx.rollback().await?;
+                return Err(e);
+            }
+            report.skipped.push(migration.id.to_string());
+            continue;
         }
 
+        let stats = match execute_migration(&tx, migration, options.vars()).await {
+            Ok(stats) => stats,
+            Err(e) => {
+                tx.rollback().await?;
+                return Err(e);
+            }
+        };
+
         // Record migration as applied
-        if let Err(e) = tx
-            .execute("INSERT INTO _migrations(id) VALUES (?)", [migration.id])
-            .await
-        {
+        if let Err(e) = record_migration(&tx, migration, options.vars()).await {
             tx.rollback().await?;
-            return Err(Error::MigrationFailed {
-                id: migration.id.to_string(),
-                message: e.to_string(),
-            });
+            return Err(e);
         };
+        report.push_migration(stats);
     }
 
+    report.maintenance = match check(&tx, options).await {
+        Ok(checks) => checks,
+        Err(e) => {
+            tx.rollback().await?;
+            return Err(e);
+        }
+    };
+
     // Commit all migrations atomically
-    tx.commit().await?;
+    tx.commit()
+        .await
+        .map_err(|source| Error::Commit { source })?;
+
+    Ok(report)
+}
+
+/// Applies pending migrations up to and including `target`.
+///
+/// Like [`migrate`], but migrations after `target` are left pending. All migrations are
+/// applied in a single transaction.
+///
+/// # Returns
+/// The IDs of the migrations that were applied, in order.
+///
+/// # Errors
+/// Returns [`Error::UnknownMigration`] if `target` is not in `migrations`, and the
+/// errors of [`migrate`].
+pub async fn migrate_to(
+    conn: &mut Connection,
+    migrations: &[Migration],
+    target: &str,
+) -> MigrateResult<Vec<String>> {
+    migrate_to_with(conn, migrations, target, &MigrateOptions::default()).await
+}
+
+/// Applies pending migrations up to and including `target`, with the given options.
+///
+/// Works like [`migrate_to`], with additional steps configured by [`MigrateOptions`].
+/// Migrations using runtime variables can only be applied with their values set, see
+/// [`MigrateOptions::with_var`].
+///
+/// # Errors
+/// Returns the errors of [`migrate_to`] and [`migrate_with`].
+pub async fn migrate_to_with(
+    conn: &mut Connection,
+    migrations: &[Migration],
+    target: &str,
+    options: &MigrateOptions,
+) -> MigrateResult<Vec<String>> {
+    let position = migrations
+        .iter()
+        .position(|m| m.id == target)
+        .ok_or_else(|| Error::UnknownMigration {
+            id: target.to_string(),
+        })?;
+    apply_migrations(conn, &migrations[..=position], options)
+        .await
+        .map(|report| report.applied)
+}
+
+/// Rolls back applied migrations until `target` is the latest applied migration.
+///
+/// The down migrations (see [`Migration::with_down`]) of the applied migrations after
+/// `target` are executed latest first, and the migrations are removed from
+/// `_migrations`. With `None` as the target, all applied migrations are rolled back.
+/// Everything runs in a single transaction, so if a down migration fails nothing is
+/// rolled back.
+///
+/// Only migrations in `migrations` are rolled back; applied migrations that are not
+/// embedded are left alone.
+///
+/// # Returns
+/// The IDs of the migrations that were rolled back, latest first.
+///
+/// # Errors
+/// Returns an error if:
+/// - `target` is not in `migrations` ([`Error::UnknownMigration`])
+/// - A migration to roll back has no down migration ([`Error::IrreversibleMigration`])
+/// - A down migration fails ([`Error::MigrationFailed`])
+/// - Transaction cannot be committed
+pub async fn rollback_to(
+    conn: &mut Connection,
+    migrations: &[Migration],
+    target: Option<&str>,
+) -> MigrateResult<Vec<String>> {
+    let _lock = lock(&MigrateOptions::default()).await?;
+    ensure_migrations_table(conn).await?;
+    let applied_migrations = get_applied_migrations(conn).await?;
+    let to_roll_back = rollback_plan(migrations, &applied_migrations, target)?;
+
+    if to_roll_back.is_empty() {
+        return Ok(Vec::new());
+    }
 
+    lock_database(conn).await?;
+    let result = revert_migrations(conn, &to_roll_back).await;
+    unlock_database(conn, result).await?;
+
+    Ok(to_roll_back.iter().map(|m| m.id.to_string()).collect())
+}
+
+/// Executes down migrations and removes them from `_migrations` in a single
+/// transaction.
+async fn revert_migrations(conn: &mut Connection, migrations: &[&Migration]) -> MigrateResult<()> {
+    let tx = conn.transaction().await?;
+    for migration in migrations {
+        if let Err(e) = revert_migration(&tx, migration).await {
+            tx.rollback().await?;
+            return Err(e);
+        }
+    }
+    tx.commit()
+        .await
+        .map_err(|source| Error::Commit { source })?;
     Ok(())
 }
 
+/// Returns the status of every migration.
+///
+/// Lists the migrations in `migrations` in order, followed by applied migrations that
+/// are not in `migrations`. The database is not modified, so this can be called from
+/// a query.
+///
+/// # Errors
+/// Returns an error if `_migrations` cannot be read.
+pub async fn status(
+    conn: &Connection,
+    migrations: &[Migration],
+) -> MigrateResult<Vec<MigrationStatus>> {
+    let (applied, skipped) = if table_exists(conn, "_migrations").await? {
+        (
+            get_applied_at(conn).await?,
+            get_skipped_migrations(conn).await?,
+        )
+    } else {
+        (HashMap::new(), HashSet::new())
+    };
+    Ok(migration_status(migrations, applied, &skipped))
+}
+
+/// Returns the applied migrations in the order they were applied.
+///
+/// The history can be encoded with [`MigrationHistory::encode`], e.g. to return it from
+/// a query, and checked against the migrations of a new build with
+/// [`check_upgrade`](crate::check_upgrade) before upgrading. The database is not
+/// modified.
+///
+/// # Errors
+/// Returns an error if `_migrations` cannot be read.
+pub async fn history(conn: &Connection) -> MigrateResult<MigrationHistory> {
+    if !table_exists(conn, "_migrations").await? {
+        return Ok(MigrationHistory::default());
+    }
+
+    let mut rows = conn
+        .query(
+            "SELECT id, checksum FROM _migrations WHERE skipped = 0 ORDER BY rowid",
+            (),
+        )
+        .await
+        .map_err(Error::tracking("_migrations", None))?;
+
+    let mut migrations = Vec::new();
+    while let Some(row) = rows
+        .next()
+        .await
+        .map_err(Error::tracking("_migrations", None))?
+    {
+        let id = row
+            .get_value(0)
+            .map_err(Error::tracking("_migrations", None))?;
+        let checksum = row
+            .get_value(1)
+            .map_err(Error::tracking("_migrations", None))?;
+        if let Some(id) = id.as_text() {
+            migrations.push(AppliedMigration {
+                id: id.to_string(),
+                checksum: checksum.as_text().map(|c| c.to_string()),
+            });
+        }
+    }
+
+    Ok(MigrationHistory { migrations })
+}
+
 /// Ensures the seeds tracking table exists in the database.
 ///
 /// Creates a `_seeds` table if it doesn't exist, which tracks:
 /// - `id`: The unique identifier of each applied seed
 /// - `applied_at`: Timestamp when the seed was applied
-async fn ensure_seeds_table(conn: &Connection) -> MigrateResult<()> {
+/// - `hash`: Content hash of versioned seeds, `NULL` for run-once seeds
+///
+/// Tables created by earlier versions of the library are upgraded in place. Returns
+/// whether the table was created.
+async fn ensure_seeds_table(conn: &Connection) -> MigrateResult<bool> {
+    let created = !table_exists(conn, "_seeds").await?;
     conn.execute(
         "CREATE TABLE IF NOT EXISTS _seeds (
             id TEXT PRIMARY KEY,
-            applied_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            applied_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            hash TEXT
         )",
         (),
     )
-    .await?;
+    .await
+    .map_err(Error::tracking("_seeds", None))?;
+    ensure_column(conn, "_seeds", "hash", "TEXT").await?;
+    Ok(created)
+}
+
+/// Adds a column to a tracking table if it is missing.
+async fn ensure_column(
+    conn: &Connection,
+    table: &'static str,
+    column: &str,
+    decl: &str,
+) -> MigrateResult<()> {
+    let mut rows = conn
+        .query(&format!("PRAGMA table_info({table})"), ())
+        .await
+        .map_err(Error::tracking(table, None))?;
+
+    let mut exists = false;
+    while let Some(row) = rows.next().await.map_err(Error::tracking(table, None))? {
+        let value = row.get_value(1).map_err(Error::tracking(table, None))?;
+        if value.as_text().map(|name| name.as_str()) == Some(column) {
+            exists = true;
+        }
+    }
+
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE {table} ADD COLUMN {column} {decl}"),
+            (),
+        )
+        .await
+        .map_err(Error::tracking(table, None))?;
+    }
     Ok(())
 }
 
-/// Retrieves the set of already applied seed IDs from the database.
-async fn get_applied_seeds(conn: &Connection) -> MigrateResult<HashSet<String>> {
-    let mut rows = conn.query("SELECT id FROM _seeds", ()).await?;
+/// Retrieves the already applied seed IDs from the database, mapped to their recorded hash.
+async fn get_applied_seeds(conn: &Connection) -> MigrateResult<HashMap<String, Option<String>>> {
+    let mut rows = conn
+        .query("SELECT id, hash FROM _seeds", ())
+        .await
+        .map_err(Error::tracking("_seeds", None))?;
+
+    let mut applied = HashMap::new();
+    while let Some(row) = rows.next().await.map_err(Error::tracking("_seeds", None))? {
+        let id = row.get_value(0).map_err(Error::tracking("_seeds", None))?;
+        let hash = row.get_value(1).map_err(Error::tracking("_seeds", None))?;
+        if let Some(id) = id.as_text() {
+            applied.insert(id.to_string(), hash.as_text().map(|h| h.to_string()));
+        }
+    }
 
-    let mut applied_set = HashSet::new();
-    while let Some(row) = rows.next().await? {
-        let value = row.get_value(0)?;
-        if let Some(text) = value.as_text() {
-            applied_set.insert(text.to_string());
+    Ok(applied)
+}
+
+/// Records a seed as applied, updating the stored hash if the seed was re-run.
+async fn record_seed(conn: &Connection, seed: &Seed, reapplied: bool) -> MigrateResult<()> {
+    let result = match (reapplied, seed.hash) {
+        (true, Some(hash)) => {
+            conn.execute(
+                "UPDATE _seeds SET hash = ?2, applied_at = CURRENT_TIMESTAMP WHERE id = ?1",
+                [seed.id, hash],
+            )
+            .await
+        }
+        (false, Some(hash)) => {
+            conn.execute(
+                "INSERT INTO _seeds(id, hash) VALUES (?1, ?2)",
+                [seed.id, hash],
+            )
+            .await
+        }
+        (true, None) => {
+            conn.execute(
+                "UPDATE _seeds SET applied_at = CURRENT_TIMESTAMP WHERE id = ?1",
+                [seed.id],
+            )
+            .await
         }
+        (false, None) => {
+            conn.execute("INSERT INTO _seeds(id) VALUES (?)", [seed.id])
+                .await
+        }
+    };
+    result.map_err(Error::tracking("_seeds", Some(seed.id)))?;
+    Ok(())
+}
+
+/// Verifies that the migrations required by the given seeds have been applied.
+async fn check_seed_dependencies(conn: &Connection, seeds: &[&Seed]) -> MigrateResult<()> {
+    if seeds.iter().all(|s| s.requires_migration.is_none()) {
+        return Ok(());
     }
 
-    Ok(applied_set)
+    let applied_migrations = if table_exists(conn, "_migrations").await? {
+        get_applied_migrations(conn).await?
+    } else {
+        HashSet::new()
+    };
+
+    for seed in seeds {
+        if let Some(migration) = seed.requires_migration {
+            if !applied_migrations.contains(migration) {
+                return Err(Error::SeedDependencyMissing {
+                    seed: seed.id.to_string(),
+                    migration: migration.to_string(),
+                });
+            }
+        }
+    }
+
+    Ok(())
 }
 
 /// Executes all pending seeds in order.
@@ -211,6 +1066,10 @@ async fn get_applied_seeds(conn: &Connection) -> MigrateResult<HashSet<String>>
 /// 3. Executes pending seeds in the order they appear in the slice
 /// 4. Records each seed as applied
 ///
+/// A seed is pending if it has never been applied, or if it is a versioned seed whose
+/// hash differs from the hash recorded when it last ran. Before any seed runs, the
+/// migrations required by pending seeds are checked to be applied.
+///
 /// All seeds are executed within individual transactions for atomicity.
 /// If any seed fails, changes for that seed are rolled back.
 ///
@@ -219,12 +1078,14 @@ async fn get_applied_seeds(conn: &Connection) -> MigrateResult<HashSet<String>>
 /// * `seeds` - Slice of seeds to apply in order
 ///
 /// # Returns
-/// * `Ok(())` - If all pending seeds were successfully applied or if there were no pending seeds
-/// * `Err(Error)` - If any seed failed to execute
+/// A [`MigrationReport`] of the applied seeds, which is empty if there were no pending
+/// seeds.
 ///
 /// # Errors
 /// Returns an error if:
 /// - Database operations fail
+/// - A pending seed requires a migration that has not been applied
+///   ([`Error::SeedDependencyMissing`])
 /// - Seed function returns an error
 /// - Transaction cannot be committed
 ///
@@ -243,60 +1104,509 @@ async fn get_applied_seeds(conn: &Connection) -> MigrateResult<HashSet<String>>
 ///     // ic_sql_migrate::turso::seed(conn, SEEDS).await.unwrap();
 /// }
 /// ```
-pub async fn seed(conn: &mut Connection, seeds: &[Seed]) -> MigrateResult<()> {
-    ensure_seeds_table(conn).await?;
+pub async fn seed(conn: &mut Connection, seeds: &[Seed]) -> MigrateResult<MigrationReport> {
+    let _lock = lock(&MigrateOptions::default()).await?;
+    let tracking_table_created = ensure_seeds_table(conn).await?;
     let applied_seeds = get_applied_seeds(conn).await?;
 
     let pending_seeds: Vec<&Seed> = seeds
         .iter()
-        .filter(|s| !applied_seeds.contains(s.id))
+        .filter(|s| seed_is_pending(s, &applied_seeds))
         .collect();
 
     if pending_seeds.is_empty() {
-        return Ok(());
+        return Ok(MigrationReport {
+            tracking_table_created,
+            ..MigrationReport::default()
+        });
     }
 
-    for seed in pending_seeds {
+    check_seed_dependencies(conn, &pending_seeds).await?;
+
+    lock_database(conn).await?;
+    let result = apply_seeds(conn, &pending_seeds, &applied_seeds).await;
+    let mut report = unlock_database(conn, result).await?;
+    report.tracking_table_created = tracking_table_created;
+    Ok(report)
+}
+
+/// Executes and records seeds, each in its own transaction, and returns a report of
+/// them.
+async fn apply_seeds(
+    conn: &mut Connection,
+    seeds: &[&Seed],
+    applied_seeds: &HashMap<String, Option<String>>,
+) -> MigrateResult<MigrationReport> {
+    let mut report = MigrationReport::default();
+
+    for seed in seeds {
         let tx = conn.transaction().await?;
 
-        if let Err(e) = (seed.seed_fn)(&tx).await {
+        if let Err(e) = execute_seed(&tx, seed, applied_seeds).await {
             tx.rollback().await?;
-            return Err(Error::MigrationFailed {
-                id: seed.id.to_string(),
-                message: e.to_string(),
-            });
+            return Err(e);
         }
 
-        if let Err(e) = tx
-            .execute("INSERT INTO _seeds(id) VALUES (?)", [seed.id])
+        tx.commit()
             .await
-        {
-            tx.rollback().await?;
-            return Err(Error::MigrationFailed {
-                id: seed.id.to_string(),
-                message: e.to_string(),
-            });
-        }
-
-        tx.commit().await?;
+            .map_err(|source| Error::Commit { source })?;
+        report.seeds.push(seed.id.to_string());
     }
 
-    Ok(())
+    Ok(report)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[tokio::test]
-    async fn test_migration_creation() {
-        let migration = Migration::new("001_test", "CREATE TABLE test (id INTEGER);");
-        assert_eq!(migration.id, "001_test");
-        assert_eq!(migration.sql, "CREATE TABLE test (id INTEGER);");
-    }
+/// Runs a seed function and records the seed as applied.
+///
+/// The rows a seed changes are not counted: Turso does not update `total_changes()`
+/// inside a transaction, and the seed function executes its statements itself.
+async fn execute_seed(
+    conn: &Connection,
+    seed: &Seed,
+    applied_seeds: &HashMap<String, Option<String>>,
+) -> MigrateResult<()> {
+    (seed.seed_fn)(conn).await.map_err(|e| Error::SeedFailed {
+        id: seed.id.to_string(),
+        source: Box::new(e),
+    })?;
+    record_seed(conn, seed, applied_seeds.contains_key(seed.id)).await
+}
 
-    #[tokio::test]
-    async fn test_ensure_migrations_table() {
+/// Runs a single seed, even if it has already been applied.
+///
+/// Use this to re-run a seed on demand, e.g. from an admin endpoint. The seed is
+/// recorded in `_seeds` as if it had been run by [`seed`].
+///
+/// # Errors
+/// Returns an error if:
+/// - `id` is not in `seeds` ([`Error::UnknownSeed`])
+/// - The seed requires a migration that has not been applied
+///   ([`Error::SeedDependencyMissing`])
+/// - The seed function returns an error ([`Error::SeedFailed`])
+/// - Transaction cannot be committed
+pub async fn run_seed(conn: &mut Connection, seeds: &[Seed], id: &str) -> MigrateResult<()> {
+    let seed = seeds
+        .iter()
+        .find(|s| s.id == id)
+        .ok_or_else(|| Error::UnknownSeed { id: id.to_string() })?;
+
+    let _lock = lock(&MigrateOptions::default()).await?;
+    ensure_seeds_table(conn).await?;
+    let applied_seeds = get_applied_seeds(conn).await?;
+    check_seed_dependencies(conn, &[seed]).await?;
+
+    lock_database(conn).await?;
+    let result = apply_seeds(conn, &[seed], &applied_seeds).await;
+    unlock_database(conn, result).await?;
+    Ok(())
+}
+
+/// Executes pending migrations and seeds as a single ordered pipeline.
+///
+/// Use this instead of separate [`migrate`] and [`seed`] calls when data setup has to
+/// happen between schema steps, for example to populate a new column before a later
+/// migration builds on it. Seeds that declare a required migration (see
+/// [`Seed::requires`]) run directly after that migration; all other seeds are merged
+/// in by version, so `seed_002_prices` runs after migration `002_create_prices` and
+/// before `003_...`. Seeds without a version run after all migrations.
+///
+/// Migrations and seeds are tracked in `_migrations` and `_seeds` exactly as with
+/// [`migrate`] and [`seed`], and the whole pipeline runs in a single transaction.
+/// If any step fails, all changes are rolled back.
+///
+/// # Arguments
+/// * `conn` - Mutable reference to the Turso connection
+/// * `migrations` - Slice of migrations, sorted by version
+/// * `seeds` - Slice of seeds
+///
+/// # Errors
+/// Returns an error if:
+/// - Database operations fail
+/// - Migration SQL is invalid or a seed function returns an error
+/// - A pending seed requires a migration that is neither applied nor part of `migrations`
+///   ([`Error::SeedDependencyMissing`])
+/// - Transaction cannot be committed
+///
+/// # Example
+/// ```ignore
+/// static MIGRATIONS: &[ic_sql_migrate::Migration] = ic_sql_migrate::include_migrations!();
+///
+/// async fn run_migrations_and_seeds() {
+///     let mut conn = get_connection().await;
+///     ic_sql_migrate::turso::run(&mut conn, MIGRATIONS, seeds::SEEDS)
+///         .await
+///         .unwrap();
+/// }
+/// ```
+pub async fn run(
+    conn: &mut Connection,
+    migrations: &[Migration],
+    seeds: &[Seed],
+) -> MigrateResult<MigrationReport> {
+    run_with(conn, migrations, seeds, &MigrateOptions::default()).await
+}
+
+/// Executes pending migrations and seeds as a single ordered pipeline, with the given
+/// options.
+///
+/// Works like [`run`], with additional steps configured by [`MigrateOptions`].
+///
+/// # Errors
+/// Returns the errors of [`run`] and [`migrate_with`].
+pub async fn run_with(
+    conn: &mut Connection,
+    migrations: &[Migration],
+    seeds: &[Seed],
+    options: &MigrateOptions,
+) -> MigrateResult<MigrationReport> {
+    check_options(options)?;
+    let _lock = lock(options).await?;
+    let tracking_table_created = ensure_migrations_table(conn).await?;
+    ensure_seeds_table(conn).await?;
+    let applied_migrations = get_applied_migrations(conn).await?;
+    let skipped_migrations = get_skipped_migrations(conn).await?;
+    let applied_seeds = get_applied_seeds(conn).await?;
+    verify_checksums(
+        migrations,
+        &get_migration_checksums(conn).await?,
+        options.vars(),
+    )?;
+    let disabled = disabled_migrations(migrations, &applied_migrations);
+
+    // Seeds that require a skipped migration are left pending until it is applied
+    let pending_steps: Vec<Step> = plan(migrations, seeds)
+        .into_iter()
+        .filter(|step| match step {
+            Step::Migration(m) => {
+                migration_is_pending(m, &applied_migrations, &skipped_migrations, &disabled)
+            }
+            Step::Seed(s) => {
+                seed_is_pending(s, &applied_seeds)
+                    && !s.requires_migration.is_some_and(|m| disabled.contains(m))
+            }
+        })
+        .collect();
+
+    if pending_steps.is_empty() {
+        return Ok(MigrationReport {
+            tracking_table_created,
+            ..MigrationReport::default()
+        });
+    }
+
+    // Seeds may only depend on migrations that are applied or run as part of this pipeline
+    for step in &pending_steps {
+        if let Step::Seed(seed) = step {
+            if let Some(migration) = seed.requires_migration {
+                if !applied_migrations.contains(migration)
+                    && !migrations.iter().any(|m| m.id == migration)
+                {
+                    return Err(Error::SeedDependencyMissing {
+                        seed: seed.id.to_string(),
+                        migration: migration.to_string(),
+                    });
+                }
+            }
+        }
+    }
+
+    lock_database(conn).await?;
+    if let Err(e) = backup(conn, options).await {
+        return unlock_database(conn, Err(e)).await;
+    }
+    let result = execute_steps(conn, &pending_steps, &applied_seeds, &disabled, options).await;
+    let mut report = unlock_database(conn, result)
+        .await
+        .map_err(|e| restore(options, e))?;
+    report.tracking_table_created = tracking_table_created;
+
+    Ok(report)
+}
+
+/// Executes and records pipeline steps in a single transaction.
+///
+/// Migrations in `disabled` are recorded as skipped instead. Runs the checks configured
+/// in `options` before committing, and returns a report of the steps and the checks.
+async fn execute_steps(
+    conn: &mut Connection,
+    steps: &[Step<'_>],
+    applied_seeds: &HashMap<String, Option<String>>,
+    disabled: &HashSet<&str>,
+    options: &MigrateOptions,
+) -> MigrateResult<MigrationReport> {
+    let mut report = MigrationReport::default();
+    let tx = conn.transaction().await?;
+
+    for step in steps {
+        let result = match step {
+            Step::Migration(migration) if disabled.contains(migration.id) => {
+                record_skipped(&tx, migration)
+                    .await
+                    .map(|()| report.skipped.push(migration.id.to_string()))
+            }
+            Step::Migration(migration) => {
+                match execute_migration(&tx, migration, options.vars()).await {
+                    Ok(stats) => record_migration(&tx, migration, options.vars())
+                        .await
+                        .map(|()| report.push_migration(stats)),
+                    Err(e) => Err(e),
+                }
+            }
+            Step::Seed(seed) => execute_seed(&tx, seed, applied_seeds)
+                .await
+                .map(|()| report.seeds.push(seed.id.to_string())),
+        };
+
+        if let Err(e) = result {
+            tx.rollback().await?;
+            return Err(e);
+        }
+    }
+
+    report.maintenance = match check(&tx, options).await {
+        Ok(checks) => checks,
+        Err(e) => {
+            tx.rollback().await?;
+            return Err(e);
+        }
+    };
+
+    tx.commit()
+        .await
+        .map_err(|source| Error::Commit { source })?;
+
+    Ok(report)
+}
+
+/// Inserts a row, or updates it if a row with the same key already exists.
+///
+/// Intended for versioned seeds, which must be safe to re-run. Columns in
+/// `conflict_columns` must be covered by a primary key or unique index and be listed
+/// in `columns`, otherwise [`Error::UnknownConflictColumn`] is returned; all other
+/// columns in `columns` are overwritten with the new values on conflict.
+///
+/// # Arguments
+/// * `conn` - The Turso connection (or transaction) to execute on
+/// * `table` - Name of the table to upsert into
+/// * `conflict_columns` - Columns identifying the row
+/// * `columns` - All columns to insert, in the same order as `params`
+/// * `params` - Values for `columns`
+///
+/// # Returns
+/// The number of rows inserted or updated.
+///
+/// # Example
+/// ```no_run
+/// use turso::Connection;
+/// use ic_sql_migrate::turso::upsert;
+///
+/// async fn seed_currencies(conn: &Connection) -> ic_sql_migrate::MigrateResult<()> {
+///     for (code, name) in [("USD", "US Dollar"), ("EUR", "Euro")] {
+///         upsert(conn, "currency", &["code"], &["code", "name"], [code, name]).await?;
+///     }
+///     Ok(())
+/// }
+/// ```
+pub async fn upsert(
+    conn: &Connection,
+    table: &str,
+    conflict_columns: &[&str],
+    columns: &[&str],
+    params: impl turso::params::IntoParams,
+) -> MigrateResult<u64> {
+    // Turso does not support `ON CONFLICT`, so update the row and insert it if there is
+    // none. Both statements refer to `params` by their position in `columns`.
+    let quote = crate::db::quote_identifier;
+    let assignment = |column: &&str| -> MigrateResult<String> {
+        match columns.iter().position(|c| c == column) {
+            Some(position) => Ok(format!("{} = ?{}", quote(column), position + 1)),
+            None => Err(Error::UnknownConflictColumn {
+                table: table.to_string(),
+                column: column.to_string(),
+            }),
+        }
+    };
+    let condition = conflict_columns
+        .iter()
+        .map(assignment)
+        .collect::<MigrateResult<Vec<_>>>()?
+        .join(" AND ");
+    let updates = columns
+        .iter()
+        .filter(|c| !conflict_columns.contains(c))
+        .map(assignment)
+        .collect::<MigrateResult<Vec<_>>>()?;
+    let params = turso::params::IntoParams::into_params(params)?;
+
+    if updates.is_empty() {
+        let sql = format!("SELECT 1 FROM {} WHERE {condition}", quote(table));
+        if conn
+            .query(&sql, params.clone())
+            .await?
+            .next()
+            .await?
+            .is_some()
+        {
+            return Ok(0);
+        }
+    } else {
+        let sql = format!(
+            "UPDATE {} SET {} WHERE {condition}",
+            quote(table),
+            updates.join(", ")
+        );
+        let updated = conn.execute(&sql, params.clone()).await?;
+        if updated > 0 {
+            return Ok(updated);
+        }
+    }
+
+    let column_list = columns.iter().map(|c| quote(c)).collect::<Vec<_>>();
+    let placeholders = (1..=columns.len())
+        .map(|i| format!("?{i}"))
+        .collect::<Vec<_>>();
+    let sql = format!(
+        "INSERT INTO {} ({}) VALUES ({})",
+        quote(table),
+        column_list.join(", "),
+        placeholders.join(", ")
+    );
+    Ok(conn.execute(&sql, params).await?)
+}
+
+/// Rebuilds a table with a new definition, keeping its rows and indexes.
+///
+/// `ALTER TABLE` cannot change column types or constraints. This performs the
+/// workaround documented by SQLite: it creates a table with the new definition, copies
+/// the rows, drops the old table, renames the new one and recreates the indexes of the
+/// old table. Indexes that refer to removed columns must be dropped beforehand.
+///
+/// Turso does not enforce foreign keys, so unlike the SQLite version the rebuild neither
+/// disables nor checks them. Called outside of a transaction, the rebuild runs in its
+/// own transaction.
+///
+/// # Arguments
+/// * `conn` - The Turso connection (or transaction) to execute on
+/// * `table` - Name of the table to rebuild
+/// * `definition` - The new table definition, as it follows the table name in
+///   `CREATE TABLE`
+/// * `columns` - Columns of the new table, each with the expression over the columns
+///   of the old table that fills it
+///
+/// # Errors
+/// Returns [`Error::Unsupported`] if the table name is not made of ASCII letters, digits
+/// and underscores, or if the database has views, which Turso cannot rename tables
+/// with yet.
+///
+/// # Example
+/// ```no_run
+/// use turso::Connection;
+/// use ic_sql_migrate::turso::rebuild_table;
+///
+/// async fn make_email_required(conn: &Connection) -> ic_sql_migrate::MigrateResult<()> {
+///     rebuild_table(
+///         conn,
+///         "users",
+///         "(id INTEGER PRIMARY KEY, email TEXT NOT NULL UNIQUE)",
+///         &[("id", "id"), ("email", "coalesce(email, id || '@example.com')")],
+///     )
+///     .await
+/// }
+/// ```
+pub async fn rebuild_table(
+    conn: &Connection,
+    table: &str,
+    definition: &str,
+    columns: &[(&str, &str)],
+) -> MigrateResult<()> {
+    // Turso keeps the quotes of quoted table names in the names of automatic indexes
+    // and cannot rename them, so only names that need no quoting are supported
+    let plain_name = table.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && table.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !plain_name {
+        return Err(Error::Unsupported {
+            operation: format!("Rebuilding table '{table}', whose name needs quoting,"),
+        });
+    }
+    if conn
+        .query("SELECT 1 FROM sqlite_master WHERE type = 'view'", ())
+        .await?
+        .next()
+        .await?
+        .is_some()
+    {
+        return Err(Error::Unsupported {
+            operation: "Rebuilding a table in a database with views".to_string(),
+        });
+    }
+
+    if !conn.is_autocommit()? {
+        return execute_rebuild(conn, table, definition, columns).await;
+    }
+
+    conn.execute("BEGIN", ()).await?;
+    let mut result = execute_rebuild(conn, table, definition, columns).await;
+    if result.is_ok() {
+        let commit = conn.execute("COMMIT", ()).await;
+        result = commit.map(drop).map_err(Error::from);
+    }
+    if result.is_err() && !conn.is_autocommit()? {
+        let _ = conn.execute("ROLLBACK", ()).await;
+    }
+    result
+}
+
+/// Runs the statements of [`rebuild_table`].
+async fn execute_rebuild(
+    conn: &Connection,
+    table: &str,
+    definition: &str,
+    columns: &[(&str, &str)],
+) -> MigrateResult<()> {
+    let mut schema = Vec::new();
+    let mut rows = conn.query(TABLE_SCHEMA_SQL, [table]).await?;
+    while let Some(row) = rows.next().await? {
+        if let Some(sql) = row.get_value(0)?.as_text() {
+            schema.push(sql.to_string());
+        }
+    }
+
+    for sql in rebuild_statements(table, definition, columns, str::to_string)
+        .iter()
+        .chain(&schema)
+    {
+        conn.execute(sql, ()).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[tokio::test]
+    async fn test_migration_creation() {
+        let migration = Migration::new("001_test", "CREATE TABLE test (id INTEGER);");
+        assert_eq!(migration.id, "001_test");
+        assert_eq!(migration.sql, "CREATE TABLE test (id INTEGER);");
+    }
+
+    #[tokio::test]
+    async fn test_migration_version_and_name() {
+        let migration = Migration::new("010_add_users", "");
+        assert_eq!(migration.version(), Some(10));
+        assert_eq!(migration.name(), "add_users");
+
+        let migration = Migration::new("V2__legacy", "").with_version(2, "legacy");
+        assert_eq!(migration.version(), Some(2));
+        assert_eq!(migration.name(), "legacy");
+
+        assert_eq!(Migration::new("init", "").version(), None);
+    }
+
+    #[tokio::test]
+    async fn test_ensure_migrations_table() {
         // Create in-memory Turso database
         let db = turso::Builder::new_local(":memory:").build().await.unwrap();
         let conn = db.connect().unwrap();
@@ -437,8 +1747,8 @@ mod tests {
         seed(&mut conn, seeds).await.unwrap();
 
         let applied = get_applied_seeds(&conn).await.unwrap();
-        assert!(applied.contains("001_initial"));
-        assert!(applied.contains("002_more"));
+        assert!(applied.contains_key("001_initial"));
+        assert!(applied.contains_key("002_more"));
 
         let mut rows = conn
             .query("SELECT COUNT(*) FROM test_users", ())
@@ -487,4 +1797,903 @@ mod tests {
             panic!("Expected count result");
         }
     }
+
+    fn seed_currencies(
+        conn: &Connection,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = MigrateResult<()>> + Send>> {
+        let conn = conn.clone();
+        Box::pin(async move {
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS currency (code TEXT PRIMARY KEY, name TEXT NOT NULL)",
+                (),
+            )
+            .await?;
+            for (code, name) in [("USD", "US Dollar"), ("EUR", "Euro")] {
+                upsert(
+                    &conn,
+                    "currency",
+                    &["code"],
+                    &["code", "name"],
+                    [code, name],
+                )
+                .await?;
+            }
+            Ok(())
+        })
+    }
+
+    async fn count(conn: &Connection, sql: &str) -> i64 {
+        let mut rows = conn.query(sql, ()).await.unwrap();
+        let row = rows.next().await.unwrap().expect("Expected count result");
+        *row.get_value(0).unwrap().as_integer().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_versioned_seed_reruns_on_hash_change() {
+        let db = turso::Builder::new_local(":memory:").build().await.unwrap();
+        let mut conn = db.connect().unwrap();
+
+        let v1 = &[Seed::versioned("001_currency", "aaaa", seed_currencies)];
+        let v2 = &[Seed::versioned("001_currency", "bbbb", seed_currencies)];
+        let changed = "SELECT COUNT(*) FROM currency WHERE name = 'Changed'";
+
+        seed(&mut conn, v1).await.unwrap();
+        conn.execute("UPDATE currency SET name = 'Changed'", ())
+            .await
+            .unwrap();
+
+        // Same hash: the seed is not re-run
+        seed(&mut conn, v1).await.unwrap();
+        assert_eq!(count(&conn, changed).await, 2);
+
+        // New hash: the seed is re-run and the recorded hash updated
+        seed(&mut conn, v2).await.unwrap();
+        assert_eq!(count(&conn, changed).await, 0);
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM currency").await, 2);
+
+        let applied = get_applied_seeds(&conn).await.unwrap();
+        assert_eq!(applied["001_currency"].as_deref(), Some("bbbb"));
+    }
+
+    #[tokio::test]
+    async fn test_upsert() {
+        let db = turso::Builder::new_local(":memory:").build().await.unwrap();
+        let conn = db.connect().unwrap();
+        conn.execute("CREATE TABLE tags (name TEXT PRIMARY KEY, color TEXT)", ())
+            .await
+            .unwrap();
+
+        let columns = &["name", "color"];
+        assert_eq!(
+            upsert(&conn, "tags", &["name"], columns, ["a", "red"])
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            upsert(&conn, "tags", &["name"], columns, ["a", "blue"])
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            upsert(&conn, "tags", columns, columns, ["a", "blue"])
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            count(&conn, "SELECT COUNT(*) FROM tags WHERE color = 'blue'").await,
+            1
+        );
+
+        let result = upsert(&conn, "tags", &["id"], columns, ["b", "red"]).await;
+        assert!(matches!(
+            result,
+            Err(Error::UnknownConflictColumn { ref column, .. }) if column == "id"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_seed_dependency_missing() {
+        let db = turso::Builder::new_local(":memory:").build().await.unwrap();
+        let mut conn = db.connect().unwrap();
+
+        let seeds = &[Seed::new("001_initial", seed_test_data).requires("001_create_users")];
+
+        // Migrations have not been run at all
+        let result = seed(&mut conn, seeds).await;
+        assert!(matches!(
+            result,
+            Err(Error::SeedDependencyMissing { ref seed, ref migration })
+                if seed == "001_initial" && migration == "001_create_users"
+        ));
+        assert!(get_applied_seeds(&conn).await.unwrap().is_empty());
+
+        // Once the migration is applied the seed runs
+        let migrations = &[Migration::new(
+            "001_create_users",
+            "CREATE TABLE users (id INTEGER PRIMARY KEY);",
+        )];
+        migrate(&mut conn, migrations).await.unwrap();
+        seed(&mut conn, seeds).await.unwrap();
+        assert!(get_applied_seeds(&conn)
+            .await
+            .unwrap()
+            .contains_key("001_initial"));
+    }
+
+    fn seed_items(
+        conn: &Connection,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = MigrateResult<()>> + Send>> {
+        let conn = conn.clone();
+        Box::pin(async move {
+            conn.execute("INSERT INTO items (name) VALUES ('a'), ('b'), ('c')", ())
+                .await?;
+            Ok(())
+        })
+    }
+
+    #[tokio::test]
+    async fn test_run_interleaves_seeds_and_migrations() {
+        let db = turso::Builder::new_local(":memory:").build().await.unwrap();
+        let mut conn = db.connect().unwrap();
+
+        let migrations = &[
+            Migration::new("001_items", "CREATE TABLE items (name TEXT);"),
+            Migration::new(
+                "002_item_count",
+                "CREATE TABLE item_count (n INTEGER);
+                 INSERT INTO item_count SELECT COUNT(*) FROM items;",
+            ),
+        ];
+        let seeds = &[Seed::new("seed_001_items", seed_items).requires("001_items")];
+
+        run(&mut conn, migrations, seeds).await.unwrap();
+
+        // The seed ran between the two migrations
+        assert_eq!(count(&conn, "SELECT n FROM item_count").await, 3);
+        assert_eq!(get_applied_migrations(&conn).await.unwrap().len(), 2);
+        assert!(get_applied_seeds(&conn)
+            .await
+            .unwrap()
+            .contains_key("seed_001_items"));
+
+        // Nothing is pending on a second run
+        run(&mut conn, migrations, seeds).await.unwrap();
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM items").await, 3);
+    }
+
+    #[tokio::test]
+    async fn test_run_merges_seeds_by_version() {
+        let db = turso::Builder::new_local(":memory:").build().await.unwrap();
+        let mut conn = db.connect().unwrap();
+
+        let migrations = &[
+            Migration::new("9_items", "CREATE TABLE items (name TEXT);"),
+            Migration::new(
+                "10_item_count",
+                "CREATE TABLE item_count (n INTEGER);
+                 INSERT INTO item_count SELECT COUNT(*) FROM items;",
+            ),
+        ];
+        let seeds = &[Seed::new("seed_9_items", seed_items)];
+
+        run(&mut conn, migrations, seeds).await.unwrap();
+
+        // The seed ran after migration 9 and before migration 10
+        assert_eq!(count(&conn, "SELECT n FROM item_count").await, 3);
+    }
+
+    #[tokio::test]
+    async fn test_rebuild_table() {
+        let db = turso::Builder::new_local(":memory:").build().await.unwrap();
+        let conn = db.connect().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE users (id INTEGER PRIMARY KEY, email TEXT, name TEXT);
+             CREATE INDEX idx_users_name ON users (name);
+             INSERT INTO users VALUES (1, 'a@example.com', 'a'), (2, NULL, 'b');",
+        )
+        .await
+        .unwrap();
+
+        rebuild_table(
+            &conn,
+            "users",
+            "(id INTEGER PRIMARY KEY, email TEXT NOT NULL, name TEXT)",
+            &[
+                ("id", "id"),
+                ("email", "coalesce(email, 'user' || id)"),
+                ("name", "name"),
+            ],
+        )
+        .await
+        .unwrap();
+
+        let mut rows = conn
+            .query("SELECT email FROM users ORDER BY id", ())
+            .await
+            .unwrap();
+        let mut emails = Vec::new();
+        while let Some(row) = rows.next().await.unwrap() {
+            emails.push(row.get_value(0).unwrap().as_text().unwrap().clone());
+        }
+        assert_eq!(emails, ["a@example.com", "user2"]);
+        assert!(conn
+            .execute("INSERT INTO users (id, email) VALUES (3, NULL)", ())
+            .await
+            .is_err());
+
+        // Indexes are recreated
+        assert_eq!(
+            count(
+                &conn,
+                "SELECT COUNT(*) FROM sqlite_master WHERE tbl_name = 'users' \
+                 AND name = 'idx_users_name'"
+            )
+            .await,
+            1
+        );
+        assert!(conn.is_autocommit().unwrap());
+
+        // Inside a transaction, the rebuild is part of it
+        conn.execute("BEGIN", ()).await.unwrap();
+        rebuild_table(
+            &conn,
+            "users",
+            "(id INTEGER PRIMARY KEY, email TEXT, name TEXT)",
+            &[("id", "id"), ("email", "email"), ("name", "name")],
+        )
+        .await
+        .unwrap();
+        conn.execute("ROLLBACK", ()).await.unwrap();
+        assert_eq!(
+            count(
+                &conn,
+                "SELECT \"notnull\" FROM pragma_table_info('users') WHERE name = 'email'"
+            )
+            .await,
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rebuild_table_unsupported() {
+        let db = turso::Builder::new_local(":memory:").build().await.unwrap();
+        let conn = db.connect().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE \"user list\" (id INTEGER PRIMARY KEY);
+             CREATE TABLE users (id INTEGER PRIMARY KEY);",
+        )
+        .await
+        .unwrap();
+
+        let result = rebuild_table(&conn, "user list", "(id INTEGER)", &[("id", "id")]).await;
+        assert!(matches!(result, Err(Error::Unsupported { .. })));
+
+        conn.execute("CREATE VIEW user_ids AS SELECT id FROM users", ())
+            .await
+            .unwrap();
+        let result = rebuild_table(&conn, "users", "(id INTEGER)", &[("id", "id")]).await;
+        assert!(matches!(result, Err(Error::Unsupported { .. })));
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM users").await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_modified_migration_detected() {
+        let db = turso::Builder::new_local(":memory:").build().await.unwrap();
+        let mut conn = db.connect().unwrap();
+
+        let sql = "CREATE TABLE users (id INTEGER PRIMARY KEY);";
+        let migrations =
+            &[Migration::new("001_create_users", sql).with_checksum("0123456789abcdef")];
+        migrate(&mut conn, migrations).await.unwrap();
+        assert_eq!(
+            get_migration_checksums(&conn)
+                .await
+                .unwrap()
+                .get("001_create_users"),
+            Some(&"0123456789abcdef".to_string())
+        );
+
+        let migrations =
+            &[Migration::new("001_create_users", sql).with_checksum("fedcba9876543210")];
+        let err = migrate(&mut conn, migrations).await.unwrap_err();
+        assert!(matches!(err, Error::ChecksumMismatch { id, .. } if id == "001_create_users"));
+        assert!(run(&mut conn, migrations, &[]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_failed_statement_reported() {
+        let db = turso::Builder::new_local(":memory:").build().await.unwrap();
+        let mut conn = db.connect().unwrap();
+
+        let sql = "-- Users\nCREATE TABLE users (id INTEGER PRIMARY KEY);\n\n  \
+                   INSERT INTO missing VALUES (1);\n";
+        let migrations = &[Migration::new("001_users", sql)];
+
+        let Err(Error::MigrationFailed {
+            id,
+            statement: Some(statement),
+            ..
+        }) = migrate(&mut conn, migrations).await
+        else {
+            panic!("expected a failed statement");
+        };
+        assert_eq!(id, "001_users");
+        assert_eq!(statement.index, 1);
+        assert_eq!(statement.sql, "INSERT INTO missing VALUES (1);");
+        assert_eq!((statement.line, statement.column), (4, 3));
+    }
+
+    fn seed_missing_table(
+        conn: &Connection,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = MigrateResult<()>> + Send>> {
+        let conn = conn.clone();
+        Box::pin(async move {
+            conn.execute("INSERT INTO missing VALUES (1)", ()).await?;
+            Ok(())
+        })
+    }
+
+    #[tokio::test]
+    async fn test_seed_failure_chains_source() {
+        use std::error::Error as _;
+
+        let db = turso::Builder::new_local(":memory:").build().await.unwrap();
+        let mut conn = db.connect().unwrap();
+        let seeds = &[Seed::new("001_missing", seed_missing_table)];
+
+        let err = seed(&mut conn, seeds).await.unwrap_err();
+        let Error::SeedFailed { id, source } = &err else {
+            panic!("expected a seed failure, got {err:?}");
+        };
+        assert_eq!(id, "001_missing");
+        assert!(matches!(**source, Error::Database(_)));
+        assert!(err.source().unwrap().to_string().contains("missing"));
+        assert!(get_applied_seeds(&conn).await.unwrap().is_empty());
+
+        let err = run(&mut conn, &[], seeds).await.unwrap_err();
+        assert!(matches!(err, Error::SeedFailed { id, .. } if id == "001_missing"));
+    }
+
+    fn reversible_migrations() -> [Migration; 3] {
+        [
+            Migration::new("001_users", "CREATE TABLE users (id INTEGER PRIMARY KEY);")
+                .with_down("DROP TABLE users;"),
+            Migration::new("002_roles", "CREATE TABLE roles (id INTEGER PRIMARY KEY);")
+                .with_down("DROP TABLE roles;"),
+            Migration::new("003_posts", "CREATE TABLE posts (id INTEGER PRIMARY KEY);")
+                .with_down("DROP TABLE posts;"),
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_migrate_to_and_rollback_to() {
+        let db = turso::Builder::new_local(":memory:").build().await.unwrap();
+        let mut conn = db.connect().unwrap();
+        let migrations = &reversible_migrations();
+
+        assert_eq!(
+            migrate_to(&mut conn, migrations, "002_roles")
+                .await
+                .unwrap(),
+            ["001_users", "002_roles"]
+        );
+        assert!(table_exists(&conn, "roles").await.unwrap());
+        assert!(!table_exists(&conn, "posts").await.unwrap());
+        assert!(matches!(
+            migrate_to(&mut conn, migrations, "004_missing").await,
+            Err(Error::UnknownMigration { id }) if id == "004_missing"
+        ));
+
+        migrate(&mut conn, migrations).await.unwrap();
+        assert_eq!(
+            rollback_to(&mut conn, migrations, Some("001_users"))
+                .await
+                .unwrap(),
+            ["003_posts", "002_roles"]
+        );
+        assert!(!table_exists(&conn, "posts").await.unwrap());
+        assert_eq!(
+            get_applied_migrations(&conn).await.unwrap(),
+            HashSet::from(["001_users".to_string()])
+        );
+
+        assert_eq!(
+            rollback_to(&mut conn, migrations, None).await.unwrap(),
+            ["001_users"]
+        );
+        assert!(!table_exists(&conn, "users").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_rollback_requires_down_migrations() {
+        let db = turso::Builder::new_local(":memory:").build().await.unwrap();
+        let mut conn = db.connect().unwrap();
+        let [users, roles, _] = reversible_migrations();
+        let migrations = &[
+            users,
+            roles,
+            Migration::new("003_posts", "CREATE TABLE posts (id INTEGER PRIMARY KEY);"),
+        ];
+        migrate(&mut conn, migrations).await.unwrap();
+
+        let err = rollback_to(&mut conn, migrations, Some("001_users"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::IrreversibleMigration { id } if id == "003_posts"));
+        assert_eq!(get_applied_migrations(&conn).await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_status() {
+        let db = turso::Builder::new_local(":memory:").build().await.unwrap();
+        let mut conn = db.connect().unwrap();
+        let [users, roles, posts] = reversible_migrations();
+        let migrations = &[users, roles, Migration::new("003_posts", posts.sql)];
+
+        migrate_to(&mut conn, migrations, "001_users")
+            .await
+            .unwrap();
+        let status = status(&conn, migrations).await.unwrap();
+        let summary: Vec<_> = status
+            .iter()
+            .map(|s| (s.id.as_str(), s.applied_at.is_some(), s.reversible))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("001_users", true, true),
+                ("002_roles", false, true),
+                ("003_posts", false, false),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_seed() {
+        let db = turso::Builder::new_local(":memory:").build().await.unwrap();
+        let mut conn = db.connect().unwrap();
+        let seeds = &[Seed::new("001_initial", seed_test_data)];
+
+        run_seed(&mut conn, seeds, "001_initial").await.unwrap();
+        run_seed(&mut conn, seeds, "001_initial").await.unwrap();
+
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM test_users").await, 4);
+        assert_eq!(get_applied_seeds(&conn).await.unwrap().len(), 1);
+        assert!(matches!(
+            run_seed(&mut conn, seeds, "002_missing").await,
+            Err(Error::UnknownSeed { id }) if id == "002_missing"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_history() {
+        let db = turso::Builder::new_local(":memory:").build().await.unwrap();
+        let mut conn = db.connect().unwrap();
+        assert!(history(&conn).await.unwrap().migrations.is_empty());
+
+        let [users, roles, posts] = reversible_migrations();
+        let migrations = &[users.with_checksum("aaaa"), roles, posts];
+        migrate_to(&mut conn, migrations, "002_roles")
+            .await
+            .unwrap();
+
+        let history = history(&conn).await.unwrap();
+        assert_eq!(
+            history.encode(),
+            "ic-sql-migrate/1;001_users=aaaa;002_roles"
+        );
+
+        let check = crate::check_upgrade(&history, migrations);
+        assert!(check.is_compatible());
+        assert_eq!(check.pending, ["003_posts"]);
+    }
+
+    /// Creates an empty, uniquely named directory under the system temp dir.
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("ic-sql-migrate-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_backup_before_migrations() {
+        let dir = temp_dir("turso-backup");
+        let database_path = dir.join("main.db");
+        let backup_path = dir.join("backup.db");
+        let options = MigrateOptions::new()
+            .with_database_path(&database_path)
+            .with_backup(&backup_path);
+        let db = turso::Builder::new_local(database_path.to_str().unwrap())
+            .build()
+            .await
+            .unwrap();
+        let mut conn = db.connect().unwrap();
+        let [users, roles, _] = reversible_migrations();
+        let [users2, roles2, _] = reversible_migrations();
+
+        // No backup is taken while another connection holds the lock
+        lock_database(&conn).await.unwrap();
+        let err = migrate_with(&mut conn, std::slice::from_ref(&users), &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::MigrationLocked { .. }));
+        assert!(!backup_path.exists());
+        release_migration_lock(&conn).await.unwrap();
+
+        migrate_with(&mut conn, std::slice::from_ref(&users), &options)
+            .await
+            .unwrap();
+        conn.execute("INSERT INTO users (id) VALUES (1)", ())
+            .await
+            .unwrap();
+
+        let failing = Migration::new("003_fail", "INSERT INTO missing VALUES (1);");
+        let err = migrate_with(&mut conn, &[users, roles, failing], &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::MigrationFailed { id, .. } if id == "003_fail"));
+
+        // The backup holds the state before the failed migrations
+        let backup = turso::Builder::new_local(backup_path.to_str().unwrap())
+            .build()
+            .await
+            .unwrap()
+            .connect()
+            .unwrap();
+        assert_eq!(count(&backup, "SELECT COUNT(*) FROM users").await, 1);
+        assert_eq!(get_applied_migrations(&backup).await.unwrap().len(), 1);
+        assert_eq!(
+            count(&backup, "SELECT COUNT(*) FROM _migrations_lock").await,
+            0
+        );
+
+        // The restored database is not left locked
+        let db = turso::Builder::new_local(database_path.to_str().unwrap())
+            .build()
+            .await
+            .unwrap();
+        let mut restored = db.connect().unwrap();
+        migrate_with(&mut restored, &[users2, roles2], &options)
+            .await
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_backup_requires_database_path() {
+        let db = turso::Builder::new_local(":memory:").build().await.unwrap();
+        let mut conn = db.connect().unwrap();
+        let options = MigrateOptions::new().with_backup("backup.db");
+
+        let err = run_with(&mut conn, &reversible_migrations(), &[], &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::BackupFailed { .. }));
+        assert!(!table_exists(&conn, "users").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_foreign_keys_disabled_is_unsupported() {
+        let db = turso::Builder::new_local(":memory:").build().await.unwrap();
+        let mut conn = db.connect().unwrap();
+        let options = MigrateOptions::new().with_foreign_keys_disabled();
+
+        let err = migrate_with(&mut conn, &reversible_migrations(), &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Unsupported { .. }));
+        let err = run_with(&mut conn, &reversible_migrations(), &[], &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Unsupported { .. }));
+        assert!(!table_exists(&conn, "users").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_migrate_with_maintenance() {
+        let db = turso::Builder::new_local(":memory:").build().await.unwrap();
+        let mut conn = db.connect().unwrap();
+        let create = Migration::new(
+            "001_create",
+            "CREATE TABLE logs (id INTEGER PRIMARY KEY, message TEXT);
+             INSERT INTO logs VALUES (1, 'started');",
+        );
+        let drop = Migration::new("002_drop", "DROP TABLE logs;");
+        let options = MigrateOptions::new().with_integrity_check(IntegrityCheck::Full);
+
+        let report = migrate_with(&mut conn, std::slice::from_ref(&create), &options)
+            .await
+            .unwrap();
+        assert_eq!(report.applied, ["001_create"]);
+        assert_eq!(
+            report.maintenance,
+            [Maintenance::IntegrityCheck(IntegrityCheck::Full)]
+        );
+
+        // Checks and maintenance steps Turso cannot run are refused before migrating
+        for options in [
+            MigrateOptions::new().with_integrity_check(IntegrityCheck::Quick),
+            MigrateOptions::new().with_optimize(),
+            MigrateOptions::new().with_analyze(),
+            MigrateOptions::new().with_vacuum_threshold(0),
+        ] {
+            let err = run_with(&mut conn, &[create.clone(), drop.clone()], &[], &options)
+                .await
+                .unwrap_err();
+            assert!(matches!(err, Error::Unsupported { .. }));
+        }
+        assert!(table_exists(&conn, "logs").await.unwrap());
+
+        let report = run_with(&mut conn, &[create.clone(), drop.clone()], &[], &options)
+            .await
+            .unwrap();
+        assert_eq!(report.applied, ["002_drop"]);
+
+        // Nothing runs when there is nothing to apply
+        let report = migrate_with(&mut conn, &[create, drop], &options)
+            .await
+            .unwrap();
+        assert_eq!(report, MigrationReport::default());
+    }
+
+    #[tokio::test]
+    async fn test_migration_lock() {
+        let db = turso::Builder::new_local(":memory:").build().await.unwrap();
+        let mut conn = db.connect().unwrap();
+        let migrations = &[Migration::new(
+            "001_users",
+            "CREATE TABLE users (id INTEGER PRIMARY KEY);",
+        )];
+
+        let held = lock(&MigrateOptions::new()).await.unwrap();
+        assert!(migration_in_progress());
+        let options = MigrateOptions::new().with_fail_fast();
+        let err = migrate_with(&mut conn, migrations, &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::MigrationInProgress));
+
+        // A waiting call continues once the lock is released
+        let options = MigrateOptions::new();
+        let (report, ()) = tokio::join!(migrate_with(&mut conn, migrations, &options), async {
+            drop(held)
+        });
+        assert_eq!(report.unwrap().applied, ["001_users"]);
+    }
+
+    #[tokio::test]
+    async fn test_migration_lock_row() {
+        let db = turso::Builder::new_local(":memory:").build().await.unwrap();
+        let mut conn = db.connect().unwrap();
+        let migrations = &[Migration::new(
+            "001_users",
+            "CREATE TABLE users (id INTEGER PRIMARY KEY);",
+        )];
+
+        // A lock left behind by another connection makes migrating fail
+        lock_database(&conn).await.unwrap();
+        let err = migrate(&mut conn, migrations).await.unwrap_err();
+        assert!(matches!(err, Error::MigrationLocked { .. }));
+        assert!(get_applied_migrations(&conn).await.unwrap().is_empty());
+
+        release_migration_lock(&conn).await.unwrap();
+        migrate(&mut conn, migrations).await.unwrap();
+        let mut rows = conn
+            .query("SELECT COUNT(*) FROM _migrations_lock", ())
+            .await
+            .unwrap();
+        let row = rows.next().await.unwrap().unwrap();
+        assert_eq!(*row.get_value(0).unwrap().as_integer().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_migration_report() {
+        let db = turso::Builder::new_local(":memory:").build().await.unwrap();
+        let mut conn = db.connect().unwrap();
+        let migrations = [
+            Migration::new(
+                "001_users",
+                "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT);
+                 INSERT INTO users (name) VALUES ('Alice'), ('Bob');",
+            ),
+            Migration::new("002_upper", "UPDATE users SET name = upper(name);"),
+        ];
+        let seeds = [
+            Seed::new("001_initial", seed_test_data),
+            Seed::new("002_more", seed_more_data),
+        ];
+
+        let report = migrate(&mut conn, &migrations[..1]).await.unwrap();
+        assert_eq!(report.applied, ["001_users"]);
+        assert!(report.tracking_table_created);
+        assert_eq!(report.rows_changed, 2);
+        let [stats] = report.stats.as_slice() else {
+            panic!("unexpected stats: {:?}", report.stats);
+        };
+        assert_eq!(stats.id, "001_users");
+        assert_eq!(stats.statements, 2);
+        assert_eq!(stats.rows_changed, 2);
+        assert_eq!(stats.instructions, None);
+
+        // The rows changed by seeds are not counted with Turso
+        let report = seed(&mut conn, &seeds[..1]).await.unwrap();
+        assert_eq!(report.seeds, ["001_initial"]);
+        assert!(report.applied.is_empty());
+        assert!(report.tracking_table_created);
+        assert_eq!(report.rows_changed, 0);
+
+        let report = run(&mut conn, &migrations, &seeds).await.unwrap();
+        assert_eq!(report.applied, ["002_upper"]);
+        assert_eq!(report.seeds, ["002_more"]);
+        assert!(!report.tracking_table_created);
+        assert_eq!(report.rows_changed, 2);
+        assert_eq!(report.stats[0].rows_changed, 2);
+        assert_eq!(report.stats[0].statements, 1);
+
+        // Nothing is reported when there is nothing to apply
+        let report = migrate(&mut conn, &migrations).await.unwrap();
+        assert_eq!(report, MigrationReport::default());
+    }
+
+    #[tokio::test]
+    async fn test_migration_returning_rows() {
+        let db = turso::Builder::new_local(":memory:").build().await.unwrap();
+        let mut conn = db.connect().unwrap();
+        let migration = Migration::new(
+            "001_users",
+            "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT);
+             INSERT INTO users (name) VALUES ('Alice'), ('Bob') RETURNING id;
+             SELECT name FROM users;
+             UPDATE users SET name = upper(name);",
+        );
+
+        // Statements returning rows run once and count as changing no rows
+        let report = migrate(&mut conn, &[migration]).await.unwrap();
+        assert_eq!(report.rows_changed, 2);
+        let mut rows = conn.query("SELECT COUNT(*) FROM users", ()).await.unwrap();
+        let row = rows.next().await.unwrap().unwrap();
+        assert_eq!(*row.get_value(0).unwrap().as_integer().unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_migrate_to_with_runtime_variables() {
+        let db = turso::Builder::new_local(":memory:").build().await.unwrap();
+        let mut conn = db.connect().unwrap();
+        let migrations = [
+            Migration::new("001_admins", "CREATE TABLE admins (principal TEXT);"),
+            Migration::new("002_admin", "INSERT INTO admins VALUES ('${admin}');")
+                .with_vars(&["admin"]),
+        ];
+
+        let err = migrate_to(&mut conn, &migrations, "002_admin")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::UnresolvedVariable { name, .. } if name == "admin"));
+
+        let options = MigrateOptions::new().with_var("admin", "aaaaa-aa");
+        let applied = migrate_to_with(&mut conn, &migrations, "002_admin", &options)
+            .await
+            .unwrap();
+        assert_eq!(applied, ["001_admins", "002_admin"]);
+        assert_eq!(
+            count(
+                &conn,
+                "SELECT COUNT(*) FROM admins WHERE principal = 'aaaaa-aa'"
+            )
+            .await,
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_runtime_variables() {
+        let db = turso::Builder::new_local(":memory:").build().await.unwrap();
+        let mut conn = db.connect().unwrap();
+        let migrations = [Migration::new(
+            "001_admins",
+            "CREATE TABLE admins (principal TEXT, note TEXT);
+             INSERT INTO admins VALUES ('${admin}', '$${admin}');",
+        )
+        .with_checksum("0123456789abcdef")
+        .with_vars(&["admin"])];
+
+        let err = migrate(&mut conn, &migrations).await.unwrap_err();
+        assert!(matches!(
+            err,
+            Error::UnresolvedVariable { id, name } if id == "001_admins" && name == "admin"
+        ));
+
+        let options = MigrateOptions::new().with_var("admin", "aaaaa-aa");
+        migrate_with(&mut conn, &migrations, &options)
+            .await
+            .unwrap();
+        let mut rows = conn
+            .query("SELECT principal, note FROM admins", ())
+            .await
+            .unwrap();
+        let row = rows.next().await.unwrap().unwrap();
+        assert_eq!(row.get_value(0).unwrap().as_text().unwrap(), "aaaaa-aa");
+        assert_eq!(row.get_value(1).unwrap().as_text().unwrap(), "${admin}");
+
+        // The recorded checksum covers the value, which must not change afterwards
+        let checksums = get_migration_checksums(&conn).await.unwrap();
+        assert_ne!(checksums["001_admins"], "0123456789abcdef");
+        assert!(migrate_with(&mut conn, &migrations, &options).await.is_ok());
+        // Without the value the checksum can't be verified, but nothing has to be applied
+        assert!(migrate(&mut conn, &migrations).await.is_ok());
+        let options = MigrateOptions::new().with_var("admin", "bbbbb-bb");
+        let err = migrate_with(&mut conn, &migrations, &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::ChecksumMismatch { id, .. } if id == "001_admins"));
+    }
+
+    static EVENTS_ENABLED: AtomicBool = AtomicBool::new(false);
+
+    fn events_enabled() -> bool {
+        EVENTS_ENABLED.load(Ordering::Relaxed)
+    }
+
+    fn seed_events(
+        conn: &Connection,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = MigrateResult<()>> + Send>> {
+        let conn = conn.clone();
+        Box::pin(async move {
+            conn.execute("INSERT INTO events (name) VALUES ('created')", ())
+                .await?;
+            Ok(())
+        })
+    }
+
+    #[tokio::test]
+    async fn test_conditional_migrations() {
+        let db = turso::Builder::new_local(":memory:").build().await.unwrap();
+        let mut conn = db.connect().unwrap();
+        let migrations = [
+            Migration::new("001_users", "CREATE TABLE users (id INTEGER PRIMARY KEY);"),
+            Migration::new("002_audit", "").when_feature("audit", false),
+            Migration::new("003_events", "CREATE TABLE events (name TEXT);").when(events_enabled),
+            Migration::new("004_posts", "CREATE TABLE posts (id INTEGER PRIMARY KEY);"),
+        ];
+        let seeds = [Seed::new("seed_003_events", seed_events).requires("003_events")];
+
+        let report = run(&mut conn, &migrations, &seeds).await.unwrap();
+        assert_eq!(report.applied, ["001_users", "004_posts"]);
+        assert_eq!(report.skipped, ["002_audit", "003_events"]);
+        assert!(report.seeds.is_empty());
+        assert!(!table_exists(&conn, "events").await.unwrap());
+
+        // Skipped migrations are recorded once, and are not part of the history
+        let report = run(&mut conn, &migrations, &seeds).await.unwrap();
+        assert_eq!(report, MigrationReport::default());
+        assert_eq!(history(&conn).await.unwrap().migrations.len(), 2);
+        let skipped: Vec<_> = status(&conn, &migrations)
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|s| s.skipped)
+            .map(|s| s.id)
+            .collect();
+        assert_eq!(skipped, ["002_audit", "003_events"]);
+
+        // Once the condition is met, the skipped migration and its seed are applied
+        EVENTS_ENABLED.store(true, Ordering::Relaxed);
+        let report = run(&mut conn, &migrations, &seeds).await.unwrap();
+        assert_eq!(report.applied, ["003_events"]);
+        assert_eq!(report.seeds, ["seed_003_events"]);
+        let status = status(&conn, &migrations).await.unwrap();
+        assert!(status[2].applied_at.is_some() && !status[2].skipped);
+        assert!(status[1].skipped);
+        assert_eq!(
+            get_applied_migrations(&conn).await.unwrap().len()
+                + get_skipped_migrations(&conn).await.unwrap().len(),
+            4
+        );
+    }
 }
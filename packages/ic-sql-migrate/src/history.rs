@@ -0,0 +1,395 @@
+//! Applied migration history and upgrade compatibility checks.
+//!
+//! A [`MigrationHistory`] lists the migrations a database has applied, with their
+//! checksums, and encodes to a compact, versioned string that a canister can return from
+//! a query or keep in stable memory. [`check_upgrade`] compares it with the migrations
+//! of a new build before that build is deployed.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::{Error, MigrateResult, Migration};
+
+/// Prefix of encoded histories, followed by the format version.
+const HISTORY_PREFIX: &str = "ic-sql-migrate/";
+
+/// Version of the encoding written by [`MigrationHistory::encode`].
+const HISTORY_VERSION: u32 = 1;
+
+/// The migrations applied to a database, in the order they were applied.
+///
+/// Read it with `sqlite::history` or `turso::history`, and pass it to [`check_upgrade`]
+/// to find out whether a new set of migrations can be applied to the database.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MigrationHistory {
+    pub migrations: Vec<AppliedMigration>,
+}
+
+/// A migration recorded in `_migrations`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppliedMigration {
+    pub id: String,
+    /// Checksum recorded when the migration was applied, `None` for migrations applied
+    /// by earlier versions of the library.
+    pub checksum: Option<String>,
+}
+
+impl MigrationHistory {
+    /// Encodes the history as a single line of text.
+    ///
+    /// The encoding starts with `ic-sql-migrate/` and the format version, followed by
+    /// `;`-separated `id=checksum` entries. Characters other than ASCII letters, digits
+    /// and `_-./` are percent-encoded, so the result contains no whitespace or quotes
+    /// and can be copied from command output as is.
+    ///
+    /// # Example
+    /// ```
+    /// use ic_sql_migrate::{AppliedMigration, MigrationHistory};
+    ///
+    /// let history = MigrationHistory {
+    ///     migrations: vec![AppliedMigration {
+    ///         id: "001_users".to_string(),
+    ///         checksum: Some("9f86d081884c7d65".to_string()),
+    ///     }],
+    /// };
+    /// assert_eq!(history.encode(), "ic-sql-migrate/1;001_users=9f86d081884c7d65");
+    /// assert_eq!(MigrationHistory::decode(&history.encode()).unwrap(), history);
+    /// ```
+    pub fn encode(&self) -> String {
+        let mut encoded = format!("{HISTORY_PREFIX}{HISTORY_VERSION}");
+        for migration in &self.migrations {
+            encoded.push(';');
+            escape_into(&mut encoded, &migration.id);
+            if let Some(checksum) = &migration.checksum {
+                encoded.push('=');
+                escape_into(&mut encoded, checksum);
+            }
+        }
+        encoded
+    }
+
+    /// Decodes a history produced by [`MigrationHistory::encode`].
+    ///
+    /// Surrounding whitespace is ignored.
+    ///
+    /// # Errors
+    /// Returns [`Error::InvalidHistory`] if the text is not an encoded history or was
+    /// written by a newer, unsupported format version.
+    pub fn decode(encoded: &str) -> MigrateResult<Self> {
+        let invalid = |reason: String| Error::InvalidHistory { reason };
+
+        let rest = encoded
+            .trim()
+            .strip_prefix(HISTORY_PREFIX)
+            .ok_or_else(|| invalid(format!("expected the '{HISTORY_PREFIX}' prefix")))?;
+        let mut entries = rest.split(';');
+        let version = entries.next().unwrap_or_default();
+        if version.parse::<u32>().ok() != Some(HISTORY_VERSION) {
+            return Err(invalid(format!("unsupported format version '{version}'")));
+        }
+
+        let migrations = entries
+            .map(|entry| {
+                let (id, checksum) = match entry.split_once('=') {
+                    Some((id, checksum)) => (id, Some(unescape(checksum).map_err(invalid)?)),
+                    None => (entry, None),
+                };
+                if id.is_empty() {
+                    return Err(invalid("empty migration ID".to_string()));
+                }
+                Ok(AppliedMigration {
+                    id: unescape(id).map_err(invalid)?,
+                    checksum,
+                })
+            })
+            .collect::<MigrateResult<_>>()?;
+
+        Ok(Self { migrations })
+    }
+}
+
+/// Appends `text` to `out`, percent-encoding characters outside `[A-Za-z0-9_\-./]`.
+fn escape_into(out: &mut String, text: &str) {
+    for byte in text.bytes() {
+        if byte.is_ascii_alphanumeric() || b"_-./".contains(&byte) {
+            out.push(char::from(byte));
+        } else {
+            out.push_str(&format!("%{byte:02X}"));
+        }
+    }
+}
+
+/// Reverses [`escape_into`].
+fn unescape(text: &str) -> Result<String, String> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let byte = tail
+                .get(..2)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| format!("invalid escape in '{text}'"))?;
+            bytes.push(byte);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| format!("invalid UTF-8 in '{text}'"))
+}
+
+/// Result of [`check_upgrade`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UpgradeCheck {
+    /// Migrations that the upgrade will apply, in order.
+    pub pending: Vec<String>,
+    /// Differences between the history and the new migrations.
+    pub conflicts: Vec<UpgradeConflict>,
+}
+
+impl UpgradeCheck {
+    /// Returns `true` if the new migrations can be applied without conflicts.
+    pub fn is_compatible(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+}
+
+/// A difference between the applied history and the migrations of a new build.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpgradeConflict {
+    /// An applied migration is missing from the new build, e.g. because it was removed
+    /// or renamed.
+    Removed { id: String },
+    /// An applied migration was modified; applying the new build fails with
+    /// [`Error::ChecksumMismatch`].
+    Modified {
+        id: String,
+        recorded: String,
+        embedded: String,
+    },
+    /// A pending migration is ordered before `applied`, which has already been applied,
+    /// so it will run after migrations that were meant to follow it.
+    OutOfOrder { id: String, applied: String },
+}
+
+impl fmt::Display for UpgradeConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Removed { id } => write!(f, "Applied migration '{id}' was removed"),
+            Self::Modified {
+                id,
+                recorded,
+                embedded,
+            } => write!(
+                f,
+                "Applied migration '{id}' was modified (recorded checksum {recorded}, new checksum {embedded})"
+            ),
+            Self::OutOfOrder { id, applied } => write!(
+                f,
+                "Pending migration '{id}' is ordered before applied migration '{applied}'"
+            ),
+        }
+    }
+}
+
+/// Predicts what applying `migrations` to a database with the given history will do.
+///
+/// This runs without a database, e.g. in CI against the history exported from a
+/// deployed canister, to catch an incompatible build before it is installed. The
+/// checksums of migrations with runtime variables are not compared, as they cover values
+/// that are only known when the migrations are applied. Conditional migrations (see
+/// [`Migration::when`]) pending before applied ones are not conflicts, as they are
+/// expected to be applied once their condition is met.
+///
+/// # Example
+/// ```
+/// use ic_sql_migrate::{check_upgrade, Migration, MigrationHistory};
+///
+/// let history = MigrationHistory::decode("ic-sql-migrate/1;001_users").unwrap();
+/// let migrations = [
+///     Migration::new("001_users", "CREATE TABLE users (id INTEGER PRIMARY KEY);"),
+///     Migration::new("002_posts", "CREATE TABLE posts (id INTEGER PRIMARY KEY);"),
+/// ];
+///
+/// let check = check_upgrade(&history, &migrations);
+/// assert!(check.is_compatible());
+/// assert_eq!(check.pending, ["002_posts"]);
+/// ```
+pub fn check_upgrade(history: &MigrationHistory, migrations: &[Migration]) -> UpgradeCheck {
+    let applied: HashMap<&str, Option<&str>> = history
+        .migrations
+        .iter()
+        .map(|m| (m.id.as_str(), m.checksum.as_deref()))
+        .collect();
+    let embedded: HashSet<&str> = migrations.iter().map(|m| m.id).collect();
+
+    let mut check = UpgradeCheck::default();
+    for migration in &history.migrations {
+        if !embedded.contains(migration.id.as_str()) {
+            check.conflicts.push(UpgradeConflict::Removed {
+                id: migration.id.clone(),
+            });
+        }
+    }
+
+    let latest_applied = migrations.iter().rposition(|m| applied.contains_key(m.id));
+    for (index, migration) in migrations.iter().enumerate() {
+        match applied.get(migration.id) {
+            // The recorded checksum of a migration with runtime variables covers their
+            // values, which are not known here
+            Some(_) if !migration.vars().is_empty() => {}
+            Some(recorded) => {
+                if let (Some(recorded), Some(embedded)) = (recorded, migration.checksum()) {
+                    if *recorded != embedded {
+                        check.conflicts.push(UpgradeConflict::Modified {
+                            id: migration.id.to_string(),
+                            recorded: recorded.to_string(),
+                            embedded: embedded.to_string(),
+                        });
+                    }
+                }
+            }
+            None => {
+                let out_of_order = latest_applied
+                    .filter(|&latest| latest > index && migration.condition().is_none());
+                if let Some(latest) = out_of_order {
+                    check.conflicts.push(UpgradeConflict::OutOfOrder {
+                        id: migration.id.to_string(),
+                        applied: migrations[latest].id.to_string(),
+                    });
+                }
+                check.pending.push(migration.id.to_string());
+            }
+        }
+    }
+
+    check
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history(entries: &[(&str, Option<&str>)]) -> MigrationHistory {
+        MigrationHistory {
+            migrations: entries
+                .iter()
+                .map(|(id, checksum)| AppliedMigration {
+                    id: id.to_string(),
+                    checksum: checksum.map(str::to_string),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_encode_decode_roundtrip() {
+        let original = history(&[
+            ("001_users", Some("0123456789abcdef")),
+            ("billing/002_invoices", None),
+            ("003 odd;name=ü", Some("fedcba9876543210")),
+        ]);
+
+        let encoded = original.encode();
+        assert_eq!(
+            encoded,
+            "ic-sql-migrate/1;001_users=0123456789abcdef;billing/002_invoices;\
+             003%20odd%3Bname%3D%C3%BC=fedcba9876543210"
+        );
+        assert!(!encoded.contains(char::is_whitespace));
+        assert_eq!(MigrationHistory::decode(&encoded).unwrap(), original);
+
+        let empty = MigrationHistory::default();
+        assert_eq!(empty.encode(), "ic-sql-migrate/1");
+        assert_eq!(
+            MigrationHistory::decode(" ic-sql-migrate/1\n").unwrap(),
+            empty
+        );
+    }
+
+    #[test]
+    fn test_decode_rejects_invalid_history() {
+        for encoded in [
+            "",
+            "001_users",
+            "ic-sql-migrate/2;001_users",
+            "ic-sql-migrate/1;;001_users",
+            "ic-sql-migrate/1;001%2",
+            "ic-sql-migrate/1;001%ZZ",
+        ] {
+            assert!(
+                matches!(
+                    MigrationHistory::decode(encoded),
+                    Err(Error::InvalidHistory { .. })
+                ),
+                "{encoded}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_check_upgrade() {
+        let migrations = [
+            Migration::new("001_users", "").with_checksum("aaaa"),
+            Migration::new("002_email", "").with_checksum("bbbb"),
+            Migration::new("003_roles", "").with_checksum("cccc"),
+            Migration::new("004_posts", "").with_checksum("dddd"),
+        ];
+
+        let check = check_upgrade(
+            &history(&[("001_users", Some("aaaa")), ("002_email", None)]),
+            &migrations,
+        );
+        assert!(check.is_compatible());
+        assert_eq!(check.pending, ["003_roles", "004_posts"]);
+
+        let check = check_upgrade(
+            &history(&[
+                ("000_legacy", None),
+                ("001_users", Some("0000")),
+                ("003_roles", Some("cccc")),
+            ]),
+            &migrations,
+        );
+        assert!(!check.is_compatible());
+        assert_eq!(check.pending, ["002_email", "004_posts"]);
+        assert_eq!(
+            check.conflicts,
+            [
+                UpgradeConflict::Removed {
+                    id: "000_legacy".to_string()
+                },
+                UpgradeConflict::Modified {
+                    id: "001_users".to_string(),
+                    recorded: "0000".to_string(),
+                    embedded: "aaaa".to_string(),
+                },
+                UpgradeConflict::OutOfOrder {
+                    id: "002_email".to_string(),
+                    applied: "003_roles".to_string(),
+                },
+            ]
+        );
+
+        let migrations = [Migration::new("001_users", "")
+            .with_checksum("aaaa")
+            .with_vars(&["admin"])];
+        let check = check_upgrade(&history(&[("001_users", Some("0000"))]), &migrations);
+        assert!(check.is_compatible());
+
+        // A conditional migration skipped so far is pending, but not out of order
+        let migrations = [
+            Migration::new("001_users", ""),
+            Migration::new("002_analytics", "").when_feature("analytics", false),
+            Migration::new("003_roles", ""),
+        ];
+        let check = check_upgrade(
+            &history(&[("001_users", None), ("003_roles", None)]),
+            &migrations,
+        );
+        assert!(check.is_compatible());
+        assert_eq!(check.pending, ["002_analytics"]);
+    }
+}
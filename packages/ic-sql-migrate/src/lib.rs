@@ -14,6 +14,20 @@
 //! - **Automatic migration execution** on canister `init` and `post_upgrade`
 //! - **Compile-time migration embedding** via `include_migrations!()` macro
 //! - **Transaction-based execution** for atomicity
+//! - **Compressed embedding** of migrations to reduce wasm size (feature: `compress`)
+//! - **Generated canister lifecycle hooks** via the `lifecycle!()` macro (feature: `ic-cdk`)
+//! - **Down migrations** with `migrate_to()` and `rollback_to()`
+//! - **Upgrade compatibility checks** of new migrations against an exported history with
+//!   `check_upgrade()`
+//! - **Template variables** in migration SQL, resolved at build time or when migrations
+//!   are applied
+//! - **Conditional migrations** gated by a cargo feature or a runtime predicate
+//! - **Table rebuilds** for schema changes that `ALTER TABLE` cannot make, with
+//!   `sqlite::rebuild_table()` and `turso::rebuild_table()`
+//! - **Generated admin methods** for managing migrations via the `admin_interface!()` macro
+//!   (feature: `admin`)
+//! - **Migration test harness** for fresh installs, upgrades from every version, down
+//!   migration roundtrips and seed idempotency under `cargo test` (feature: `testing`)
 //!
 //! The library has no default features. Attempting to use it without enabling
 //! either `sqlite` or `turso` will result in compilation errors when trying to
@@ -98,7 +112,21 @@
 //! }
 //! ```
 
+mod build;
+#[cfg(feature = "ic-cdk")]
+pub mod canister;
 mod db;
+mod history;
+mod sql;
+#[cfg(feature = "testing")]
+pub mod testing;
+
+pub use crate::build::{
+    Builder, Layout, MigrationCondition, MigrationFile, MigrationOrder, VersionScheme,
+};
+pub use crate::history::{
+    check_upgrade, AppliedMigration, MigrationHistory, UpgradeCheck, UpgradeConflict,
+};
 
 #[cfg(feature = "turso")]
 pub use crate::db::turso;
@@ -109,31 +137,245 @@ pub use crate::db::sqlite;
 #[cfg(feature = "turso")]
 use ::turso as turso_crate;
 
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use thiserror::Error;
 
+/// The error type of the enabled database driver.
+///
+/// This is `rusqlite::Error` with the `sqlite` feature and `turso::Error` with the
+/// `turso` feature.
+#[cfg(feature = "sqlite")]
+pub type DatabaseError = rusqlite::Error;
+
+/// The error type of the enabled database driver.
+///
+/// This is `rusqlite::Error` with the `sqlite` feature and `turso::Error` with the
+/// `turso` feature.
+#[cfg(feature = "turso")]
+pub type DatabaseError = turso_crate::Error;
+
+/// The error type of the enabled database driver.
+///
+/// Without a database feature no database errors can occur.
+#[cfg(not(any(feature = "sqlite", feature = "turso")))]
+pub type DatabaseError = std::convert::Infallible;
+
 /// Custom error type for migration operations.
 ///
 /// This enum represents all possible errors that can occur during migration operations.
-/// The actual database error variant depends on the feature flag enabled (either `sqlite` or `turso`).
+/// Variants that wrap a database failure keep the driver error as their
+/// [`source`](std::error::Error::source), which is a [`DatabaseError`].
 #[derive(Debug, Error)]
 pub enum Error {
     /// I/O operation failed during build-time migration discovery
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
 
+    /// A migration file does not follow the naming convention of the configured layout
+    #[error("Invalid migration file {}: {reason}", .path.display())]
+    InvalidMigrationFile { path: PathBuf, reason: String },
+
+    /// Two migration files have the same version
+    #[error(
+        "Migrations {} and {} have the same version {version}",
+        .first.display(),
+        .second.display()
+    )]
+    DuplicateVersion {
+        version: String,
+        first: PathBuf,
+        second: PathBuf,
+    },
+
     /// A specific migration failed to execute
     ///
-    /// Contains the migration ID and the error message from the database
-    #[error("Migration '{id}' failed: {message}")]
-    MigrationFailed { id: String, message: String },
+    /// Contains the migration ID, the database error and, if the failure was caused by
+    /// one of the migration's statements, that statement
+    #[error("Migration '{id}' failed{}: {source}", statement_location(.statement))]
+    MigrationFailed {
+        id: String,
+        statement: Option<Box<FailedStatement>>,
+        source: DatabaseError,
+    },
+
+    /// A seed function returned an error
+    #[error("Seed '{id}' failed: {source}")]
+    SeedFailed { id: String, source: Box<Error> },
+
+    /// A seed was run before the migration it requires was applied
+    #[error("Seed '{seed}' requires migration '{migration}', which has not been applied")]
+    SeedDependencyMissing { seed: String, migration: String },
+
+    /// A conflict column passed to `upsert` is not one of the upserted columns
+    #[error("Conflict column '{column}' is not one of the columns upserted into '{table}'")]
+    UnknownConflictColumn { table: String, column: String },
+
+    /// Reading or writing a tracking table (`_migrations` or `_seeds`) failed
+    ///
+    /// Contains the migration or seed ID when recording it as applied failed
+    #[error("Failed to update tracking table {table}{}: {source}", tracked_id(.id))]
+    TrackingTable {
+        table: &'static str,
+        id: Option<String>,
+        source: DatabaseError,
+    },
+
+    /// Committing the transaction that applied migrations or seeds failed
+    #[error("Failed to commit transaction: {source}")]
+    Commit { source: DatabaseError },
+
+    /// An applied migration was modified after it was applied
+    #[error(
+        "Migration '{id}' has changed since it was applied \
+         (recorded checksum {recorded}, embedded checksum {embedded})"
+    )]
+    ChecksumMismatch {
+        id: String,
+        recorded: String,
+        embedded: String,
+    },
+
+    /// A migration ID passed to `migrate_to` or `rollback_to` is not an embedded migration
+    #[error("Unknown migration '{id}'")]
+    UnknownMigration { id: String },
+
+    /// A migration that would have to be rolled back has no down migration
+    #[error("Migration '{id}' cannot be rolled back as it has no down migration")]
+    IrreversibleMigration { id: String },
+
+    /// A seed ID passed to `run_seed` is not a known seed
+    #[error("Unknown seed '{id}'")]
+    UnknownSeed { id: String },
+
+    /// The database could not be backed up before applying migrations
+    #[error("Failed to back up the database to {}: {source}", .path.display())]
+    BackupFailed { path: PathBuf, source: Box<Error> },
+
+    /// Applying migrations failed and the backup taken before could not be restored
+    ///
+    /// `error` is the error that made the migrations fail
+    #[error(
+        "{error}; restoring the backup from {} failed as well: {source}",
+        .path.display()
+    )]
+    RestoreFailed {
+        path: PathBuf,
+        error: Box<Error>,
+        source: Box<Error>,
+    },
+
+    /// Migrations drop schema objects or delete data without acknowledging it
+    ///
+    /// Reported by [`Builder::build`] for migrations without the
+    /// `-- ic-sql-migrate: allow-destructive` directive
+    #[error(
+        "Migrations contain destructive changes; add `-- ic-sql-migrate: allow-destructive` \
+         to the migration to apply them anyway:{}",
+        destructive_report(.changes)
+    )]
+    DestructiveChanges { changes: Vec<DestructiveChange> },
+
+    /// Rows violate foreign key constraints, as reported by `PRAGMA foreign_key_check`
+    /// after a table rebuild or after migrations applied with foreign keys disabled
+    #[error("Foreign key check failed:{}", indented_list(.violations))]
+    ForeignKeyViolations {
+        violations: Vec<ForeignKeyViolation>,
+    },
+
+    /// The integrity check of [`MigrateOptions::with_integrity_check`] found problems
+    #[error("Integrity check failed:{}", indented_list(.problems))]
+    IntegrityCheckFailed { problems: Vec<String> },
+
+    /// Another call is applying migrations in this process
+    ///
+    /// Returned by Turso calls with [`MigrateOptions::with_fail_fast`] and by the
+    /// `canister::not_migrating` guard
+    #[error("Migrations are in progress")]
+    MigrationInProgress,
+
+    /// Another connection holds the `_migrations_lock` row
+    ///
+    /// A lock left behind by an interrupted run is removed with
+    /// `turso::release_migration_lock`
+    #[error("Migrations are locked by another connection since {locked_at}")]
+    MigrationLocked { locked_at: String },
+
+    /// A table cannot be rebuilt by `sqlite::rebuild_table`
+    #[error("Cannot rebuild table '{table}': {reason}")]
+    TableRebuild { table: String, reason: String },
+
+    /// The database backend does not support an operation or option
+    #[error("{operation} is not supported by this database backend")]
+    Unsupported { operation: String },
+    /// A check of `testing::MigrationTest` found a schema that differs from the expected
+    /// one (feature: `testing`)
+    #[error("{check} ended with a different schema:{}", indented_list(.differences))]
+    SchemaMismatch {
+        check: String,
+        differences: Vec<String>,
+    },
+
+    /// Running a seed again changed data, found by `testing::MigrationTest` (feature:
+    /// `testing`)
+    #[error("Seed '{id}' is not idempotent, running it again changed {}", .tables.join(", "))]
+    SeedNotIdempotent { id: String, tables: Vec<String> },
+
+    /// A fixture of `testing::MigrationTest::check_fixture` could not be loaded (feature:
+    /// `testing`)
+    #[error("Invalid fixture {}: {reason}", .path.display())]
+    InvalidFixture { path: PathBuf, reason: String },
+
+    /// The table contents after `testing::MigrationTest::check_fixture` differ from the
+    /// snapshot file (feature: `testing`)
+    ///
+    /// `diff` lists the expected lines that are missing prefixed with `-`, and the
+    /// unexpected lines prefixed with `+`
+    #[error(
+        "Table contents differ from snapshot {}, run with \
+         IC_SQL_MIGRATE_UPDATE_SNAPSHOTS=1 to update it:{}",
+        .path.display(),
+        indented_list(.diff)
+    )]
+    SnapshotMismatch { path: PathBuf, diff: Vec<String> },
+
+    /// An encoded migration history could not be decoded
+    #[error("Invalid migration history: {reason}")]
+    InvalidHistory { reason: String },
+
+    /// Compressed migration SQL could not be decompressed
+    #[error("Failed to decompress migration '{id}': {message}")]
+    Decompression { id: String, message: String },
+
+    /// A migration uses a `${name}` variable that is not set
+    ///
+    /// Reported by [`Builder::build`] for variables that are neither set nor declared
+    /// with [`Builder::with_runtime_var`], and when migrations are applied for runtime
+    /// variables that are not set with [`MigrateOptions::with_var`]
+    #[error("Migration '{id}' uses variable '{name}', which is not set")]
+    UnresolvedVariable { id: String, name: String },
 
     /// Environment variable was not found during build-time processing
     #[error("Environment variable '{0}' not set")]
     EnvVarNotFound(String),
 
-    /// Database error from the underlying database driver
+    /// Any other error from the underlying database driver
     #[error("Database error: {0}")]
-    Database(Box<dyn std::error::Error + Send + Sync>),
+    Database(#[from] DatabaseError),
+}
+
+impl Error {
+    /// Returns a function that wraps a database error as a tracking table failure.
+    #[cfg(any(feature = "sqlite", feature = "turso"))]
+    pub(crate) fn tracking(
+        table: &'static str,
+        id: Option<&str>,
+    ) -> impl FnOnce(DatabaseError) -> Error {
+        let id = id.map(str::to_string);
+        move |source| Error::TrackingTable { table, id, source }
+    }
 }
 
 // IMPORTANT: Users must enable exactly one database feature: either 'sqlite' or 'turso'
@@ -141,18 +383,410 @@ pub enum Error {
 // but actual usage requires selecting a database backend. If no feature is selected,
 // the database modules will not be available and the library cannot be used.
 
-#[cfg(feature = "sqlite")]
-impl From<rusqlite::Error> for Error {
-    fn from(err: rusqlite::Error) -> Self {
-        Error::Database(Box::new(err))
+fn tracked_id(id: &Option<String>) -> String {
+    match id {
+        Some(id) => format!(" for '{id}'"),
+        None => String::new(),
     }
 }
 
-#[cfg(feature = "turso")]
-impl From<turso_crate::Error> for Error {
-    fn from(err: turso_crate::Error) -> Self {
-        Error::Database(Box::new(err))
+/// The statement of a migration that failed to execute.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FailedStatement {
+    /// Zero-based index of the statement within the migration
+    pub index: usize,
+    /// The statement text, as embedded in the canister
+    pub sql: String,
+    /// One-based line of the statement in the original migration file
+    pub line: usize,
+    /// One-based column of the statement in the original migration file
+    pub column: usize,
+}
+
+/// A destructive operation found in a migration by [`Builder::build`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DestructiveChange {
+    /// Path of the migration file
+    pub path: PathBuf,
+    /// One-based line of the statement in the migration file
+    pub line: usize,
+    /// One-based column of the statement in the migration file
+    pub column: usize,
+    pub operation: DestructiveOperation,
+}
+
+/// A statement that drops a schema object or deletes data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DestructiveOperation {
+    /// `DROP TABLE`
+    DropTable { table: String },
+    /// `DROP INDEX`
+    DropIndex { index: String },
+    /// `DROP VIEW`
+    DropView { view: String },
+    /// `ALTER TABLE ... DROP COLUMN`
+    DropColumn { table: String, column: String },
+    /// `DELETE FROM` without a `WHERE` clause
+    DeleteAll { table: String },
+    /// `DROP TABLE` followed by renaming another table to the dropped name, which
+    /// loses the data of columns that were not copied over
+    RebuildTable { table: String },
+}
+
+impl std::fmt::Display for DestructiveOperation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DropTable { table } => write!(f, "drops table '{table}'"),
+            Self::DropIndex { index } => write!(f, "drops index '{index}'"),
+            Self::DropView { view } => write!(f, "drops view '{view}'"),
+            Self::DropColumn { table, column } => {
+                write!(f, "drops column '{column}' of table '{table}'")
+            }
+            Self::DeleteAll { table } => write!(f, "deletes all rows of table '{table}'"),
+            Self::RebuildTable { table } => write!(f, "rebuilds table '{table}'"),
+        }
+    }
+}
+
+/// A row that violates a foreign key constraint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForeignKeyViolation {
+    /// Table containing the row
+    pub table: String,
+    /// Rowid of the row, `None` for `WITHOUT ROWID` tables
+    pub rowid: Option<i64>,
+    /// Table the foreign key refers to
+    pub parent: String,
+    /// Index of the violated foreign key in `PRAGMA foreign_key_list` of the table
+    pub foreign_key: i64,
+}
+
+impl std::fmt::Display for ForeignKeyViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.rowid {
+            Some(rowid) => write!(f, "row {rowid} of table '{}'", self.table)?,
+            None => write!(f, "a row of table '{}'", self.table)?,
+        }
+        write!(f, " references a missing row of table '{}'", self.parent)
+    }
+}
+
+/// The state of a migration, as reported by `status` in the database modules.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "admin", derive(candid::CandidType, serde::Deserialize))]
+pub struct MigrationStatus {
+    /// Migration ID
+    pub id: String,
+    /// When the migration was applied, `None` if it is pending or skipped
+    pub applied_at: Option<String>,
+    /// Whether the migration has a down migration, so that it can be rolled back
+    pub reversible: bool,
+    /// Whether the migration was skipped because its condition was not met
+    pub skipped: bool,
+}
+
+/// Options for applying migrations with `migrate_with` and `run_with` in the database
+/// modules.
+///
+/// # Example
+/// ```
+/// use ic_sql_migrate::MigrateOptions;
+///
+/// let options = MigrateOptions::new().with_backup("/backup/main.db");
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MigrateOptions {
+    backup_path: Option<PathBuf>,
+    database_path: Option<PathBuf>,
+    foreign_keys_disabled: bool,
+    integrity_check: Option<IntegrityCheck>,
+    optimize: bool,
+    analyze: bool,
+    vacuum_threshold: Option<u64>,
+    fail_fast: bool,
+    vars: BTreeMap<String, String>,
+}
+
+impl MigrateOptions {
+    /// Creates options with the default behavior of `migrate` and `run`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Backs up the database to `path` before pending migrations are applied, and
+    /// restores the backup if they fail.
+    ///
+    /// Applying migrations is transactional, but a failure can still leave changes
+    /// behind, e.g. from statements SQLite cannot roll back or from seeds with side
+    /// effects. The backup is only taken if there is something to apply, and is
+    /// overwritten by the next backup.
+    ///
+    /// With SQLite the backup is made with SQLite's online backup API. With Turso the
+    /// database file is copied, which requires [`MigrateOptions::with_database_path`],
+    /// and the connection must be reopened after a restore.
+    pub fn with_backup(mut self, path: impl Into<PathBuf>) -> Self {
+        self.backup_path = Some(path.into());
+        self
+    }
+
+    /// Sets the path of the database file, which Turso needs to back it up.
+    pub fn with_database_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.database_path = Some(path.into());
+        self
+    }
+
+    /// Disables foreign key enforcement while pending migrations are applied.
+    ///
+    /// `PRAGMA foreign_keys` has no effect inside a transaction, so migrations that
+    /// rebuild or reorder referenced tables cannot turn it off themselves. With this
+    /// option foreign keys are disabled before the transaction starts and enabled again
+    /// after it ends, if they were enabled before. Before committing,
+    /// `PRAGMA foreign_key_check` checks the whole database, and any violation fails
+    /// the migrations with [`Error::ForeignKeyViolations`], rolling them back.
+    ///
+    /// Turso supports neither, so there this option fails with [`Error::Unsupported`].
+    pub fn with_foreign_keys_disabled(mut self) -> Self {
+        self.foreign_keys_disabled = true;
+        self
+    }
+
+    /// Checks the integrity of the database before committing applied migrations.
+    ///
+    /// Problems found by the check fail the migrations with
+    /// [`Error::IntegrityCheckFailed`], rolling them back. Turso only supports
+    /// [`IntegrityCheck::Full`].
+    pub fn with_integrity_check(mut self, check: IntegrityCheck) -> Self {
+        self.integrity_check = Some(check);
+        self
+    }
+
+    /// Runs `PRAGMA optimize` after migrations were applied, which refreshes the query
+    /// planner statistics that need it. Not supported by Turso.
+    pub fn with_optimize(mut self) -> Self {
+        self.optimize = true;
+        self
+    }
+
+    /// Runs `ANALYZE` after migrations were applied, which refreshes all query planner
+    /// statistics. Not supported by Turso.
+    pub fn with_analyze(mut self) -> Self {
+        self.analyze = true;
+        self
+    }
+
+    /// Runs `VACUUM` after migrations were applied if the database has more than
+    /// `free_pages` unused pages, e.g. after dropping a large table.
+    ///
+    /// `VACUUM` rewrites the whole database, so the threshold should be high enough
+    /// that it only runs when it reclaims a significant amount of space. Not supported
+    /// by Turso.
+    pub fn with_vacuum_threshold(mut self, free_pages: u64) -> Self {
+        self.vacuum_threshold = Some(free_pages);
+        self
+    }
+
+    /// Fails with [`Error::MigrationInProgress`] instead of waiting if another call is
+    /// already applying migrations (Turso only).
+    ///
+    /// Turso is async, so in a canister another message can start migrating while a
+    /// migration is suspended at an await point. By default the second call waits for
+    /// the first one to finish.
+    pub fn with_fail_fast(mut self) -> Self {
+        self.fail_fast = true;
+        self
+    }
+
+    /// Sets the value of a runtime variable, substituted for `${name}` in migrations.
+    ///
+    /// Runtime variables are declared with [`Builder::with_runtime_var`] and resolved
+    /// when migrations are applied, e.g. for the principal of the canister. Their values
+    /// are included in the recorded checksums, so later calls with different values
+    /// fail with [`Error::ChecksumMismatch`].
+    pub fn with_var(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.vars.insert(name.into(), value.into());
+        self
+    }
+
+    /// Sets the values of several runtime variables, see [`MigrateOptions::with_var`].
+    pub fn with_vars<K, V>(mut self, vars: impl IntoIterator<Item = (K, V)>) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.vars.extend(
+            vars.into_iter()
+                .map(|(name, value)| (name.into(), value.into())),
+        );
+        self
+    }
+
+    /// Returns the path backups are written to, if backups are enabled.
+    pub fn backup_path(&self) -> Option<&Path> {
+        self.backup_path.as_deref()
+    }
+
+    /// Returns the path of the database file, if it was set.
+    pub fn database_path(&self) -> Option<&Path> {
+        self.database_path.as_deref()
+    }
+
+    /// Returns whether foreign keys are disabled while migrations are applied.
+    pub fn foreign_keys_disabled(&self) -> bool {
+        self.foreign_keys_disabled
+    }
+
+    /// Returns the integrity check run before committing, if any.
+    pub fn integrity_check(&self) -> Option<IntegrityCheck> {
+        self.integrity_check
+    }
+
+    /// Returns whether `PRAGMA optimize` runs after migrations were applied.
+    pub fn optimize(&self) -> bool {
+        self.optimize
+    }
+
+    /// Returns whether `ANALYZE` runs after migrations were applied.
+    pub fn analyze(&self) -> bool {
+        self.analyze
+    }
+
+    /// Returns the number of free pages above which `VACUUM` runs, if set.
+    pub fn vacuum_threshold(&self) -> Option<u64> {
+        self.vacuum_threshold
     }
+
+    /// Returns whether a call fails instead of waiting while migrations are in progress.
+    pub fn fail_fast(&self) -> bool {
+        self.fail_fast
+    }
+
+    /// Returns the values of the runtime variables.
+    pub fn vars(&self) -> &BTreeMap<String, String> {
+        &self.vars
+    }
+}
+
+/// The integrity check run by [`MigrateOptions::with_integrity_check`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntegrityCheck {
+    /// `PRAGMA quick_check`, which skips the checks of indexes against table contents
+    Quick,
+    /// `PRAGMA integrity_check`
+    Full,
+}
+
+impl IntegrityCheck {
+    #[cfg(any(feature = "sqlite", feature = "turso"))]
+    fn pragma(self) -> &'static str {
+        match self {
+            Self::Quick => "PRAGMA quick_check",
+            Self::Full => "PRAGMA integrity_check",
+        }
+    }
+}
+
+/// The result of `migrate`, `run` and `seed` and their `_with` variants in the database
+/// modules, e.g. for canister logs and upgrade audit trails.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MigrationReport {
+    /// IDs of the migrations that were applied, in order
+    pub applied: Vec<String>,
+    /// IDs of the migrations that were skipped because their condition was not met, in
+    /// order. They are applied by a later call once their condition is met.
+    pub skipped: Vec<String>,
+    /// IDs of the seeds that were applied, in order
+    pub seeds: Vec<String>,
+    /// Statistics of each applied migration, in the order of `applied`
+    pub stats: Vec<MigrationStats>,
+    /// Rows inserted, updated or deleted by the applied migrations and seeds
+    ///
+    /// Turso cannot count the rows changed by seeds, so there only migrations count.
+    pub rows_changed: u64,
+    /// Whether the tracking table (`_migrations`, or `_seeds` for `seed`) was created
+    /// by this call, i.e. nothing had been applied to the database before
+    pub tracking_table_created: bool,
+    /// The checks and maintenance steps configured in [`MigrateOptions`] that ran, in
+    /// order. They only run if migrations or seeds were applied. Maintenance steps run
+    /// after committing, so their failures are recorded as [`Maintenance::Failed`].
+    pub maintenance: Vec<Maintenance>,
+}
+
+impl MigrationReport {
+    /// Adds an applied migration to the report.
+    #[cfg(any(feature = "sqlite", feature = "turso"))]
+    pub(crate) fn push_migration(&mut self, stats: MigrationStats) {
+        self.applied.push(stats.id.clone());
+        self.rows_changed += stats.rows_changed;
+        self.stats.push(stats);
+    }
+}
+
+/// Statistics of an applied migration, see [`MigrationReport::stats`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationStats {
+    /// The migration ID
+    pub id: String,
+    /// Time the migration took to execute
+    ///
+    /// The clock does not advance during a canister message, so in a canister this is
+    /// usually zero; see `instructions` instead.
+    pub elapsed: Duration,
+    /// Instructions the migration took to execute, counted in canisters built with the
+    /// `ic-cdk` feature
+    pub instructions: Option<u64>,
+    /// Number of statements executed
+    pub statements: usize,
+    /// Rows inserted, updated or deleted by the migration
+    pub rows_changed: u64,
+}
+
+/// A check or maintenance step that ran after migrations were applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Maintenance {
+    /// `PRAGMA foreign_key_check` found no violations
+    ForeignKeyCheck,
+    /// The integrity check found no problems
+    IntegrityCheck(IntegrityCheck),
+    /// `PRAGMA optimize` ran
+    Optimize,
+    /// `ANALYZE` ran
+    Analyze,
+    /// `VACUUM` ran, reclaiming `free_pages` unused pages
+    Vacuum { free_pages: u64 },
+    /// A maintenance step failed after the migrations were committed
+    Failed { statement: String, error: String },
+}
+
+fn statement_location(statement: &Option<Box<FailedStatement>>) -> String {
+    match statement {
+        Some(statement) => format!(
+            " at statement {} (line {}, column {})",
+            statement.index + 1,
+            statement.line,
+            statement.column
+        ),
+        None => String::new(),
+    }
+}
+
+/// Formats items as an indented list, each on its own line.
+fn indented_list<T: std::fmt::Display>(items: &[T]) -> String {
+    items.iter().map(|item| format!("\n  {item}")).collect()
+}
+
+fn destructive_report(changes: &[DestructiveChange]) -> String {
+    changes
+        .iter()
+        .map(|change| {
+            format!(
+                "\n  {}:{}:{}: {}",
+                change.path.display(),
+                change.line,
+                change.column,
+                change.operation
+            )
+        })
+        .collect()
 }
 
 /// Type alias for `Result<T, Error>` used throughout the library.
@@ -180,6 +814,16 @@ pub type TursoSeedFn =
 /// Seeds are typically created at compile time and executed after migrations
 /// to populate initial or test data using Rust code rather than SQL.
 ///
+/// A regular seed runs exactly once. A versioned seed (see [`Seed::versioned`]) carries
+/// a content hash and is re-run whenever that hash changes, which makes it a good fit
+/// for reference data that evolves over time. The build script creates versioned seeds
+/// for seed modules declaring `pub const VERSIONED: bool = true;`, hashing the module
+/// source. Versioned seeds should be written with upserts so that re-runs are idempotent.
+///
+/// A seed can also declare the migration it depends on (see [`Seed::requires`]). The build
+/// script picks this up from a `pub const REQUIRES_MIGRATION: &str = "...";` declaration
+/// in the seed module.
+///
 /// # Example
 /// ```
 /// use ic_sql_migrate::Seed;
@@ -198,12 +842,51 @@ pub type TursoSeedFn =
 pub struct Seed {
     pub id: &'static str,
     pub seed_fn: SqliteSeedFn,
+    /// Content hash of a versioned seed, `None` for run-once seeds.
+    pub hash: Option<&'static str>,
+    /// ID of a migration that must be applied before this seed can run.
+    pub requires_migration: Option<&'static str>,
 }
 
 #[cfg(feature = "sqlite")]
 impl Seed {
     pub const fn new(id: &'static str, seed_fn: SqliteSeedFn) -> Self {
-        Self { id, seed_fn }
+        Self {
+            id,
+            seed_fn,
+            hash: None,
+            requires_migration: None,
+        }
+    }
+
+    /// Creates a versioned seed that is re-run whenever `hash` differs from
+    /// the hash recorded in the `_seeds` table.
+    pub const fn versioned(id: &'static str, hash: &'static str, seed_fn: SqliteSeedFn) -> Self {
+        Self {
+            id,
+            seed_fn,
+            hash: Some(hash),
+            requires_migration: None,
+        }
+    }
+
+    /// Declares a migration that must be applied before this seed runs.
+    ///
+    /// The seed runners fail with [`Error::SeedDependencyMissing`] instead of running
+    /// the seed against a schema that is not ready for it.
+    pub const fn requires(mut self, migration_id: &'static str) -> Self {
+        self.requires_migration = Some(migration_id);
+        self
+    }
+
+    /// Returns the version of the seed, the number following the `seed_` prefix of its
+    /// ID, e.g. `1` for `seed_001_currency`.
+    ///
+    /// IDs without the prefix use their leading digits. Returns `None` if there are
+    /// none, e.g. for `currency`.
+    pub fn version(&self) -> Option<u64> {
+        let id = self.id.strip_prefix("seed_").unwrap_or(self.id);
+        split_version_prefix(id).0.parse().ok()
     }
 }
 
@@ -212,12 +895,51 @@ impl Seed {
 pub struct Seed {
     pub id: &'static str,
     pub seed_fn: TursoSeedFn,
+    /// Content hash of a versioned seed, `None` for run-once seeds.
+    pub hash: Option<&'static str>,
+    /// ID of a migration that must be applied before this seed can run.
+    pub requires_migration: Option<&'static str>,
 }
 
 #[cfg(feature = "turso")]
 impl Seed {
     pub const fn new(id: &'static str, seed_fn: TursoSeedFn) -> Self {
-        Self { id, seed_fn }
+        Self {
+            id,
+            seed_fn,
+            hash: None,
+            requires_migration: None,
+        }
+    }
+
+    /// Creates a versioned seed that is re-run whenever `hash` differs from
+    /// the hash recorded in the `_seeds` table.
+    pub const fn versioned(id: &'static str, hash: &'static str, seed_fn: TursoSeedFn) -> Self {
+        Self {
+            id,
+            seed_fn,
+            hash: Some(hash),
+            requires_migration: None,
+        }
+    }
+
+    /// Declares a migration that must be applied before this seed runs.
+    ///
+    /// The seed runners fail with [`Error::SeedDependencyMissing`] instead of running
+    /// the seed against a schema that is not ready for it.
+    pub const fn requires(mut self, migration_id: &'static str) -> Self {
+        self.requires_migration = Some(migration_id);
+        self
+    }
+
+    /// Returns the version of the seed, the number following the `seed_` prefix of its
+    /// ID, e.g. `1` for `seed_001_currency`.
+    ///
+    /// IDs without the prefix use their leading digits. Returns `None` if there are
+    /// none, e.g. for `currency`.
+    pub fn version(&self) -> Option<u64> {
+        let id = self.id.strip_prefix("seed_").unwrap_or(self.id);
+        split_version_prefix(id).0.parse().ok()
     }
 }
 
@@ -228,6 +950,10 @@ impl Seed {
 /// - An identifier (usually the filename without extension)
 /// - The SQL statements to execute
 ///
+/// The identifier is made up of a numeric version and a descriptive name, e.g. version
+/// `1` and name `create_users` for `001_create_users`. See [`Migration::version`] and
+/// [`Migration::name`].
+///
 /// # Example in ICP Canister
 /// ```
 /// use ic_sql_migrate::Migration;
@@ -250,8 +976,26 @@ pub struct Migration {
     /// This ID is stored in the `_migrations` table to track which migrations have been applied.
     pub id: &'static str,
     /// SQL statements to execute for this migration.
-    /// Can contain multiple statements separated by semicolons.
+    /// Can contain multiple statements separated by semicolons. Empty for compressed
+    /// migrations; use [`Migration::load_sql`] to read the SQL of any migration.
     pub sql: &'static str,
+    /// Version parsed at build time, overriding the version derived from `id`.
+    version: Option<u64>,
+    /// Name parsed at build time, overriding the name derived from `id`.
+    name: Option<&'static str>,
+    /// Checksum of the migration file, computed at build time.
+    checksum: Option<&'static str>,
+    /// Line and column of each statement in the original file, if the SQL was minified.
+    statement_positions: Option<&'static [(usize, usize)]>,
+    /// SQL that reverts the migration, if it is reversible.
+    down: Option<&'static str>,
+    /// Names of the runtime variables in the SQL.
+    vars: &'static [&'static str],
+    /// Condition under which the migration is applied, `None` if it is always applied.
+    condition: Option<Condition>,
+    /// Deflate-compressed SQL, decompressed when the migration is applied.
+    #[cfg(feature = "compress")]
+    compressed: Option<&'static [u8]>,
 }
 
 impl Migration {
@@ -274,280 +1018,318 @@ impl Migration {
     /// );
     /// ```
     pub const fn new(id: &'static str, sql: &'static str) -> Self {
-        Self { id, sql }
+        Self {
+            id,
+            sql,
+            version: None,
+            name: None,
+            checksum: None,
+            statement_positions: None,
+            down: None,
+            vars: &[],
+            condition: None,
+            #[cfg(feature = "compress")]
+            compressed: None,
+        }
     }
-}
-
-/// Includes all migration files discovered by the Builder at compile time.
-///
-/// This macro expands to a static slice of `Migration` structs containing
-/// all SQL files found in the migrations directory. The migrations are ordered
-/// alphabetically by filename, so it's recommended to prefix them with numbers
-/// (e.g., `001_initial.sql`, `002_add_users.sql`).
-///
-/// # Prerequisites
-/// You must call `ic_sql_migrate::Builder::new().build()` in your `build.rs` file to generate
-/// the migration data that this macro includes.
-///
-/// # Example in ICP Canister
-/// ```ignore
-/// // In your canister lib.rs
-/// use ic_cdk::{init, post_upgrade};
-/// use ic_rusqlite::{with_connection, Connection};
-///
-/// static MIGRATIONS: &[ic_sql_migrate::Migration] = ic_sql_migrate::include_migrations!();
-///
-/// fn run_migrations() {
-///     with_connection(|mut conn| {
-///         let conn: &mut Connection = &mut conn;
-///         ic_sql_migrate::sqlite::migrate(conn, MIGRATIONS).unwrap();
-///     });
-/// }
-///
-/// #[init]
-/// fn init() {
-///     run_migrations();
-/// }
-///
-/// #[post_upgrade]
-/// fn post_upgrade() {
-///     run_migrations();
-/// }
-/// ```
-#[macro_export]
-macro_rules! include_migrations {
-    () => {
-        include!(concat!(env!("OUT_DIR"), "/migrations_gen.rs"))
-    };
-}
-
-/// Builder for configuring migration and seed discovery at compile time.
-///
-/// This builder allows you to customize the directories where migrations and seeds
-/// are located. By default, it looks for migrations in `migrations/` and seeds in `src/seeds/`.
-///
-/// # Example in build.rs
-/// ```no_run
-/// // Use defaults (migrations/ and src/seeds/)
-/// // If either directory doesn't exist, it will be skipped automatically
-/// ic_sql_migrate::Builder::new().build().unwrap();
-///
-/// // Custom directories
-/// ic_sql_migrate::Builder::new()
-///     .with_migrations_dir("db/migrations")
-///     .with_seeds_dir("src/db/seeds")
-///     .build()
-///     .unwrap();
-/// ```
-pub struct Builder {
-    migrations_dir: String,
-    seeds_dir: String,
-}
 
-impl Builder {
-    /// Creates a new builder with default settings.
+    /// Creates a migration from deflate-compressed SQL.
     ///
-    /// Defaults:
-    /// - Migrations directory: `migrations/`
-    /// - Seeds directory: `src/seeds/`
-    pub fn new() -> Self {
+    /// The build script generates these when compression is enabled with
+    /// [`Builder::compress`]. The SQL is only decompressed when the migration is
+    /// applied, so migrations that have already been applied are never decompressed.
+    #[cfg(feature = "compress")]
+    pub const fn compressed(id: &'static str, compressed_sql: &'static [u8]) -> Self {
         Self {
-            migrations_dir: "migrations".to_string(),
-            seeds_dir: "src/seeds".to_string(),
+            id,
+            sql: "",
+            version: None,
+            name: None,
+            checksum: None,
+            statement_positions: None,
+            down: None,
+            vars: &[],
+            condition: None,
+            compressed: Some(compressed_sql),
         }
     }
 
-    /// Sets the directory where migration SQL files are located.
+    /// Returns the SQL of the migration, decompressing it if it was embedded compressed.
     ///
-    /// # Arguments
-    /// * `dir` - Path relative to `Cargo.toml`
-    pub fn with_migrations_dir(mut self, dir: impl Into<String>) -> Self {
-        self.migrations_dir = dir.into();
-        self
+    /// # Errors
+    /// Returns [`Error::Decompression`] if compressed SQL is corrupt or not valid UTF-8.
+    pub fn load_sql(&self) -> MigrateResult<Cow<'static, str>> {
+        #[cfg(feature = "compress")]
+        if let Some(compressed_sql) = self.compressed {
+            let decompression_error = |message: String| Error::Decompression {
+                id: self.id.to_string(),
+                message,
+            };
+            let bytes = miniz_oxide::inflate::decompress_to_vec(compressed_sql)
+                .map_err(|e| decompression_error(e.to_string()))?;
+            let sql = String::from_utf8(bytes).map_err(|e| decompression_error(e.to_string()))?;
+            return Ok(Cow::Owned(sql));
+        }
+
+        Ok(Cow::Borrowed(self.sql))
     }
 
-    /// Sets the directory where seed Rust files are located.
+    /// Sets the version and name explicitly instead of deriving them from the ID.
     ///
-    /// # Arguments
-    /// * `dir` - Path relative to `Cargo.toml`
-    pub fn with_seeds_dir(mut self, dir: impl Into<String>) -> Self {
-        self.seeds_dir = dir.into();
+    /// The build script uses this to embed the values it parsed from the file name.
+    pub const fn with_version(mut self, version: u64, name: &'static str) -> Self {
+        self.version = Some(version);
+        self.name = Some(name);
         self
     }
 
-    /// Executes the builder, discovering and generating code for migrations and seeds.
-    ///
-    /// This method automatically handles missing directories by generating empty arrays.
-    /// You don't need to specify whether directories exist or not.
+    /// Sets the checksum of the original migration file.
     ///
-    /// # Errors
-    /// Returns an I/O error if file system operations fail or required environment
-    /// variables are not set.
-    pub fn build(self) -> std::io::Result<()> {
-        use std::env;
-        use std::fs;
-        use std::path::Path;
-
-        let manifest_dir = env::var("CARGO_MANIFEST_DIR").map_err(|_| {
-            std::io::Error::new(std::io::ErrorKind::NotFound, "CARGO_MANIFEST_DIR not set")
-        })?;
-
-        let out_dir = env::var("OUT_DIR")
-            .map_err(|_| std::io::Error::new(std::io::ErrorKind::NotFound, "OUT_DIR not set"))?;
-
-        // Process migrations
-        let migrations_dir = Path::new(&manifest_dir).join(&self.migrations_dir);
-        println!("cargo:rerun-if-changed={}", migrations_dir.display());
-
-        let migrations_dest = Path::new(&out_dir).join("migrations_gen.rs");
-
-        if !migrations_dir.exists() {
-            fs::write(migrations_dest, "&[]")?;
-        } else {
-            let migration_files = collect_migration_files(&migrations_dir)?;
-            let generated_code = generate_migrations_code(&migration_files);
-            fs::write(migrations_dest, generated_code)?;
-        }
+    /// The build script embeds the checksum of every migration file. It is recorded in
+    /// `_migrations` when the migration is applied, and later runs fail with
+    /// [`Error::ChecksumMismatch`] if the embedded checksum no longer matches.
+    pub const fn with_checksum(mut self, checksum: &'static str) -> Self {
+        self.checksum = Some(checksum);
+        self
+    }
 
-        // Process seeds - generate mod.rs in the seeds directory
-        let seeds_dir = Path::new(&manifest_dir).join(&self.seeds_dir);
-        println!("cargo:rerun-if-changed={}", seeds_dir.display());
+    /// Returns the checksum of the original migration file, if it was set.
+    pub fn checksum(&self) -> Option<&'static str> {
+        self.checksum
+    }
 
-        if seeds_dir.exists() {
-            let seed_files = collect_seed_files(&seeds_dir)?;
-            if !seed_files.is_empty() {
-                let generated_code = generate_seeds_code(&seed_files);
-                let mod_file = seeds_dir.join("mod.rs");
-                fs::write(mod_file, generated_code)?;
-            }
-        }
+    /// Sets the line and column of each statement in the original migration file.
+    ///
+    /// The build script sets these for minified migrations, whose statements are at
+    /// other positions than in the original file, so that errors can point to the
+    /// original location.
+    pub const fn with_statement_positions(mut self, positions: &'static [(usize, usize)]) -> Self {
+        self.statement_positions = Some(positions);
+        self
+    }
 
-        Ok(())
+    /// Returns the line and column of a statement in the original file, if they were set.
+    #[cfg(any(feature = "sqlite", feature = "turso"))]
+    pub(crate) fn statement_position(&self, index: usize) -> Option<(usize, usize)> {
+        self.statement_positions?.get(index).copied()
     }
-}
 
-impl Default for Builder {
-    fn default() -> Self {
-        Self::new()
+    /// Sets the SQL that reverts the migration, making it reversible.
+    ///
+    /// The build script embeds down migrations found next to the migration files, e.g.
+    /// `001_create_users.down.sql`. They are executed by `rollback_to` in the database
+    /// modules.
+    pub const fn with_down(mut self, sql: &'static str) -> Self {
+        self.down = Some(sql);
+        self
     }
-}
 
-/// Collects all SQL migration files from the specified directory.
-///
-/// Returns a sorted list of (migration_id, file_path) tuples.
-fn collect_migration_files(
-    migrations_dir: &std::path::Path,
-) -> std::io::Result<Vec<(String, String)>> {
-    use std::fs;
-
-    let mut migration_files = Vec::new();
-
-    let entries = fs::read_dir(migrations_dir)?;
-    for entry in entries {
-        let entry = entry?;
-        let path = entry.path();
-
-        // Only process .sql files
-        if path.extension().and_then(|s| s.to_str()) != Some("sql") {
-            continue;
-        }
+    /// Returns the SQL that reverts the migration, if it has a down migration.
+    pub fn down(&self) -> Option<&'static str> {
+        self.down
+    }
 
-        if let Some(file_stem) = path.file_stem().and_then(|s| s.to_str()) {
-            let absolute_path = path.to_string_lossy().to_string();
-            migration_files.push((file_stem.to_string(), absolute_path));
+    /// Declares the runtime variables of the migration.
+    ///
+    /// The build script declares the variables set with [`Builder::with_runtime_var`]
+    /// that the migration uses. Their `${name}` placeholders are substituted with the
+    /// values of [`MigrateOptions::with_var`] when the migration is applied, and the
+    /// values are included in the checksum recorded in `_migrations`.
+    pub const fn with_vars(mut self, vars: &'static [&'static str]) -> Self {
+        self.vars = vars;
+        self
+    }
 
-            // Ensure cargo rebuilds when this specific file changes
-            println!("cargo:rerun-if-changed={}", path.display());
-        }
+    /// Returns the names of the runtime variables of the migration.
+    pub fn vars(&self) -> &'static [&'static str] {
+        self.vars
     }
 
-    // Sort migration files by name to ensure consistent ordering
-    migration_files.sort_by(|a, b| a.0.cmp(&b.0));
+    /// Returns the SQL of the migration with its runtime variables substituted.
+    ///
+    /// # Errors
+    /// Returns [`Error::UnresolvedVariable`] if the SQL uses a variable missing from
+    /// `vars`, and the errors of [`Migration::load_sql`].
+    #[cfg(any(feature = "sqlite", feature = "turso"))]
+    pub(crate) fn resolve_sql(
+        &self,
+        vars: &BTreeMap<String, String>,
+    ) -> MigrateResult<Cow<'static, str>> {
+        let sql = self.load_sql()?;
+        if self.vars.is_empty() {
+            return Ok(sql);
+        }
+        if let Some(name) = sql::variables(&sql)
+            .into_iter()
+            .find(|name| !vars.contains_key(*name))
+        {
+            return Err(self.unresolved_variable(name));
+        }
+        let resolved =
+            sql::substitute_variables(&sql, |name| vars.get(name).map(String::as_str), true);
+        Ok(Cow::Owned(resolved.into_owned()))
+    }
 
-    Ok(migration_files)
-}
+    /// Returns the checksum of the migration including the values of its runtime
+    /// variables, as recorded in `_migrations`.
+    ///
+    /// # Errors
+    /// Returns [`Error::UnresolvedVariable`] if a runtime variable is missing from `vars`.
+    #[cfg(any(feature = "sqlite", feature = "turso"))]
+    pub(crate) fn resolved_checksum(
+        &self,
+        vars: &BTreeMap<String, String>,
+    ) -> MigrateResult<Option<String>> {
+        let Some(checksum) = self.checksum else {
+            return Ok(None);
+        };
+        let values = self
+            .vars
+            .iter()
+            .map(|name| match vars.get(*name) {
+                Some(value) => Ok((*name, value.as_str())),
+                None => Err(self.unresolved_variable(name)),
+            })
+            .collect::<MigrateResult<Vec<_>>>()?;
+        Ok(Some(build::checksum_with_vars(checksum, &values)))
+    }
 
-/// Generates Rust code for including migration files.
-///
-/// Creates a static array initialization with all migration files.
-fn generate_migrations_code(migration_files: &[(String, String)]) -> String {
-    let mut code = String::from("&[\n");
+    #[cfg(any(feature = "sqlite", feature = "turso"))]
+    fn unresolved_variable(&self, name: &str) -> Error {
+        Error::UnresolvedVariable {
+            id: self.id.to_string(),
+            name: name.to_string(),
+        }
+    }
 
-    for (migration_id, file_path) in migration_files {
-        code.push_str(&format!(
-            "    ic_sql_migrate::Migration::new(\"{migration_id}\", include_str!(\"{file_path}\")),\n"
-        ));
+    /// Applies the migration only if `predicate` returns `true`.
+    ///
+    /// The predicate is evaluated whenever pending migrations are applied. While it
+    /// returns `false` the migration is recorded as skipped, and it is applied by the
+    /// first call for which it returns `true`, even if later migrations were applied
+    /// since. The build script adds predicates declared with the
+    /// `-- ic-sql-migrate: when = path::to::predicate` directive.
+    pub const fn when(mut self, predicate: fn() -> bool) -> Self {
+        self.condition = Some(Condition::Predicate(predicate));
+        self
     }
 
-    code.push_str("]\n");
-    code
-}
+    /// Applies the migration only if the cargo feature `feature` is enabled.
+    ///
+    /// `enabled` is whether the feature was enabled when the canister was built, e.g.
+    /// `cfg!(feature = "analytics")`. A skipped migration is applied once the canister
+    /// is built with the feature, like one skipped by [`Migration::when`]. The build
+    /// script adds features declared with the `-- ic-sql-migrate: feature = analytics`
+    /// directive, and only embeds the SQL if the feature is enabled.
+    pub const fn when_feature(mut self, feature: &'static str, enabled: bool) -> Self {
+        self.condition = Some(Condition::Feature {
+            name: feature,
+            enabled,
+        });
+        self
+    }
 
-/// Collects all Rust seed files from the specified directory.
-///
-/// Returns a sorted list of (seed_id, module_path) tuples.
-/// Excludes mod.rs as it's the module declaration file.
-fn collect_seed_files(seeds_dir: &std::path::Path) -> std::io::Result<Vec<(String, String)>> {
-    use std::fs;
+    /// Returns the condition under which the migration is applied, if it has one.
+    pub fn condition(&self) -> Option<Condition> {
+        self.condition
+    }
 
-    let mut seed_files = Vec::new();
+    /// Returns whether the migration is applied, evaluating its condition.
+    pub fn is_enabled(&self) -> bool {
+        self.condition.is_none_or(|condition| condition.is_met())
+    }
 
-    let entries = fs::read_dir(seeds_dir)?;
-    for entry in entries {
-        let entry = entry?;
-        let path = entry.path();
+    /// Returns the numeric version of the migration.
+    ///
+    /// Unless set with [`Migration::with_version`], this is the number formed by the
+    /// leading digits of the ID, so both `001_init` and `20250101120000_init` have a
+    /// version. Returns `None` if the ID does not start with a digit.
+    pub fn version(&self) -> Option<u64> {
+        self.version.or_else(|| {
+            let (digits, _) = split_version_prefix(self.id);
+            digits.parse().ok()
+        })
+    }
 
-        if path.extension().and_then(|s| s.to_str()) != Some("rs") {
-            continue;
+    /// Returns the descriptive name of the migration.
+    ///
+    /// Unless set with [`Migration::with_version`], this is the ID without its version
+    /// prefix and separator, e.g. `create_users` for `001_create_users`.
+    pub fn name(&self) -> &str {
+        match self.name {
+            Some(name) => name,
+            None => split_version_prefix(self.id).1,
         }
+    }
+}
 
-        if let Some(file_stem) = path.file_stem().and_then(|s| s.to_str()) {
-            // Skip mod.rs as it's the generated module file
-            if file_stem == "mod" {
-                continue;
-            }
-
-            let absolute_path = path.to_string_lossy().to_string();
-            seed_files.push((file_stem.to_string(), absolute_path));
+/// The condition under which a conditional migration is applied, see [`Migration::when`]
+/// and [`Migration::when_feature`].
+#[derive(Debug, Clone, Copy)]
+pub enum Condition {
+    /// A cargo feature, and whether it was enabled when the canister was built
+    Feature { name: &'static str, enabled: bool },
+    /// A predicate evaluated when migrations are applied
+    Predicate(fn() -> bool),
+}
 
-            println!("cargo:rerun-if-changed={}", path.display());
+impl Condition {
+    /// Returns whether the condition is met, calling the predicate if it is one.
+    pub fn is_met(&self) -> bool {
+        match self {
+            Self::Feature { enabled, .. } => *enabled,
+            Self::Predicate(predicate) => predicate(),
         }
     }
+}
 
-    seed_files.sort_by(|a, b| a.0.cmp(&b.0));
-
-    Ok(seed_files)
+/// Splits a migration ID into its leading digits and the name after the `_` separator.
+fn split_version_prefix(id: &str) -> (&str, &str) {
+    let end = id.find(|c: char| !c.is_ascii_digit()).unwrap_or(id.len());
+    let (digits, rest) = id.split_at(end);
+    (digits, rest.strip_prefix('_').unwrap_or(rest))
 }
 
-/// Generates a mod.rs file for the seeds module.
+/// Includes all migration files discovered by the Builder at compile time.
 ///
-/// Creates a module file that:
-/// 1. Declares all seed submodules in alphabetical order
-/// 2. Exports a SEEDS constant with all seed functions in order
+/// This macro expands to a static slice of `Migration` structs containing
+/// all SQL files found in the migrations directory. Every file name must start with
+/// a numeric version followed by `_` and a name (e.g., `001_initial.sql`,
+/// `002_add_users.sql` or `20250101120000_add_users.sql`), and the migrations are
+/// ordered by that version.
 ///
-/// This function is feature-agnostic and generates generic code.
-/// The actual type checking happens at compile time when the user's
-/// crate is built with the appropriate feature.
-fn generate_seeds_code(seed_files: &[(String, String)]) -> String {
-    let mut code = String::new();
-
-    code.push_str("// This file is auto-generated by ic-sql-migrate\n");
-    code.push_str("// Do not edit manually\n\n");
-
-    // Declare all submodules
-    for (seed_id, _) in seed_files {
-        code.push_str(&format!("pub mod {seed_id};\n"));
-    }
-
-    code.push('\n');
-    code.push_str("use ic_sql_migrate::Seed;\n\n");
-
-    // Create the SEEDS array
-    code.push_str("pub static SEEDS: &[Seed] = &[\n");
-    for (seed_id, _) in seed_files {
-        code.push_str(&format!("    Seed::new(\"{seed_id}\", {seed_id}::seed),\n"));
-    }
-    code.push_str("];\n");
-
-    code
+/// # Prerequisites
+/// You must call `ic_sql_migrate::Builder::new().build()` in your `build.rs` file to generate
+/// the migration data that this macro includes.
+///
+/// # Example in ICP Canister
+/// ```ignore
+/// // In your canister lib.rs
+/// use ic_cdk::{init, post_upgrade};
+/// use ic_rusqlite::{with_connection, Connection};
+///
+/// static MIGRATIONS: &[ic_sql_migrate::Migration] = ic_sql_migrate::include_migrations!();
+///
+/// fn run_migrations() {
+///     with_connection(|mut conn| {
+///         let conn: &mut Connection = &mut conn;
+///         ic_sql_migrate::sqlite::migrate(conn, MIGRATIONS).unwrap();
+///     });
+/// }
+///
+/// #[init]
+/// fn init() {
+///     run_migrations();
+/// }
+///
+/// #[post_upgrade]
+/// fn post_upgrade() {
+///     run_migrations();
+/// }
+/// ```
+#[macro_export]
+macro_rules! include_migrations {
+    () => {
+        include!(concat!(env!("OUT_DIR"), "/migrations_gen.rs"))
+    };
 }
@@ -0,0 +1,674 @@
+//! A small tokenizer for SQLite SQL.
+//!
+//! It understands just enough of the lexical structure to find comments, whitespace and
+//! statement boundaries without being confused by string literals, quoted identifiers
+//! or the bodies of `CREATE TRIGGER` statements.
+
+use std::borrow::Cow;
+use std::ops::Range;
+
+use crate::DestructiveOperation;
+
+/// The kind of a [`Token`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TokenKind {
+    Whitespace,
+    /// A `-- line comment` or `/* block comment */`
+    Comment,
+    /// A string literal in single quotes
+    String,
+    /// An identifier quoted with double quotes, backticks or square brackets
+    QuotedIdentifier,
+    /// A keyword, unquoted identifier or number
+    Word,
+    Semicolon,
+    /// Any other character, such as an operator or parenthesis
+    Punctuation,
+}
+
+/// A token of SQL text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Token<'a> {
+    pub kind: TokenKind,
+    pub text: &'a str,
+    /// Byte offset of the token in the tokenized SQL
+    pub offset: usize,
+}
+
+impl Token<'_> {
+    /// Whether the token is whitespace or a comment, which SQLite ignores.
+    pub fn is_trivia(&self) -> bool {
+        matches!(self.kind, TokenKind::Whitespace | TokenKind::Comment)
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        self.kind == TokenKind::Word && self.text.eq_ignore_ascii_case(keyword)
+    }
+}
+
+/// Splits SQL text into tokens.
+///
+/// Unterminated comments, strings and quoted identifiers extend to the end of the text,
+/// leaving it to SQLite to report the syntax error.
+pub(crate) fn tokenize(sql: &str) -> Vec<Token<'_>> {
+    let bytes = sql.as_bytes();
+    let mut tokens = Vec::new();
+    let mut offset = 0;
+
+    while offset < bytes.len() {
+        let rest = &bytes[offset..];
+        let (kind, len) = match rest[0] {
+            b if b.is_ascii_whitespace() => (
+                TokenKind::Whitespace,
+                span(rest, |b| b.is_ascii_whitespace()),
+            ),
+            b'-' if rest.get(1) == Some(&b'-') => (
+                TokenKind::Comment,
+                rest.iter().position(|&b| b == b'\n').unwrap_or(rest.len()),
+            ),
+            b'/' if rest.get(1) == Some(&b'*') => (
+                TokenKind::Comment,
+                rest.windows(2)
+                    .skip(2)
+                    .position(|w| w == b"*/")
+                    .map_or(rest.len(), |i| i + 4),
+            ),
+            b'\'' => (TokenKind::String, quoted_len(rest, b'\'')),
+            b'"' => (TokenKind::QuotedIdentifier, quoted_len(rest, b'"')),
+            b'`' => (TokenKind::QuotedIdentifier, quoted_len(rest, b'`')),
+            b'[' => (
+                TokenKind::QuotedIdentifier,
+                rest.iter()
+                    .position(|&b| b == b']')
+                    .map_or(rest.len(), |i| i + 1),
+            ),
+            b';' => (TokenKind::Semicolon, 1),
+            b if is_word_byte(b) => (TokenKind::Word, span(rest, is_word_byte)),
+            _ => (TokenKind::Punctuation, 1),
+        };
+
+        tokens.push(Token {
+            kind,
+            text: &sql[offset..offset + len],
+            offset,
+        });
+        offset += len;
+    }
+
+    tokens
+}
+
+/// Non-ASCII bytes are treated as part of words, so tokens never split a UTF-8 character.
+fn is_word_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_' || b == b'$' || !b.is_ascii()
+}
+
+fn span(bytes: &[u8], matches: impl Fn(u8) -> bool) -> usize {
+    bytes
+        .iter()
+        .position(|&b| !matches(b))
+        .unwrap_or(bytes.len())
+}
+
+/// Returns the length of a quoted token, where a doubled quote is an escaped quote.
+fn quoted_len(bytes: &[u8], quote: u8) -> usize {
+    let mut i = 1;
+    while i < bytes.len() {
+        if bytes[i] == quote {
+            if bytes.get(i + 1) == Some(&quote) {
+                i += 2;
+                continue;
+            }
+            return i + 1;
+        }
+        i += 1;
+    }
+    bytes.len()
+}
+
+/// A statement found by [`split_statements`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Statement<'a> {
+    /// The tokens of the statement, including its terminating semicolon if it has one,
+    /// without leading and trailing whitespace and comments
+    pub tokens: Vec<Token<'a>>,
+}
+
+impl<'a> Statement<'a> {
+    /// Returns the statement text as written in the SQL it was split from.
+    #[cfg(any(test, feature = "sqlite", feature = "turso"))]
+    pub fn text(&self, sql: &'a str) -> &'a str {
+        let last = self.tokens[self.tokens.len() - 1];
+        &sql[self.offset()..last.offset + last.text.len()]
+    }
+
+    /// Returns the byte offset of the statement in the SQL it was split from.
+    pub fn offset(&self) -> usize {
+        self.tokens[0].offset
+    }
+}
+
+/// Returns the one-based line and column of a byte offset in SQL text.
+///
+/// Columns count characters, not bytes.
+pub(crate) fn line_column(sql: &str, offset: usize) -> (usize, usize) {
+    let before = &sql[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let line = before.matches('\n').count() + 1;
+    let column = before[line_start..].chars().count() + 1;
+    (line, column)
+}
+
+/// Splits SQL text into statements.
+///
+/// Semicolons inside the `BEGIN ... END` body of a `CREATE TRIGGER` statement don't end
+/// the statement. Text consisting only of whitespace and comments is not a statement.
+pub(crate) fn split_statements(sql: &str) -> Vec<Statement<'_>> {
+    let mut statements = Vec::new();
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut is_trigger = false;
+    let mut depth = 0usize;
+
+    for token in tokenize(sql) {
+        if tokens.is_empty() && token.is_trivia() {
+            continue;
+        }
+        tokens.push(token);
+
+        if token.kind == TokenKind::Word {
+            // CREATE [TEMP | TEMPORARY] TRIGGER
+            let words = tokens.iter().filter(|t| t.kind == TokenKind::Word).count();
+            if words <= 3 && token.is_keyword("TRIGGER") && tokens[0].is_keyword("CREATE") {
+                is_trigger = true;
+            }
+            if is_trigger {
+                if token.is_keyword("BEGIN") || token.is_keyword("CASE") {
+                    depth += 1;
+                } else if token.is_keyword("END") {
+                    depth = depth.saturating_sub(1);
+                }
+            }
+        }
+
+        if token.kind == TokenKind::Semicolon && depth == 0 {
+            statements.push(Statement {
+                tokens: std::mem::take(&mut tokens),
+            });
+            is_trigger = false;
+        }
+    }
+
+    while tokens.last().is_some_and(Token::is_trivia) {
+        tokens.pop();
+    }
+    if !tokens.is_empty() {
+        statements.push(Statement { tokens });
+    }
+
+    statements
+}
+
+/// Removes comments and redundant whitespace from SQL text.
+///
+/// Each statement is put on its own line, and whitespace between tokens is reduced to a
+/// single space, or removed next to parentheses, commas and semicolons. String literals
+/// and quoted identifiers are kept verbatim.
+pub(crate) fn minify(sql: &str) -> String {
+    let mut minified = String::with_capacity(sql.len());
+
+    for statement in split_statements(sql) {
+        if !minified.is_empty() {
+            minified.push('\n');
+        }
+
+        let mut gap = false;
+        let mut previous: Option<&str> = None;
+        for token in &statement.tokens {
+            if token.is_trivia() {
+                gap = true;
+                continue;
+            }
+            if let Some(previous) = previous {
+                if gap && needs_space(previous, token.text) {
+                    minified.push(' ');
+                }
+            }
+            minified.push_str(token.text);
+            previous = Some(token.text);
+            gap = false;
+        }
+    }
+
+    minified
+}
+
+fn needs_space(before: &str, after: &str) -> bool {
+    let is_separator = |c: Option<char>| matches!(c, Some('(' | ')' | ',' | ';'));
+    !is_separator(before.chars().last()) && !is_separator(after.chars().next())
+}
+
+/// Prefix of directives, comments that tell the library how to handle a migration.
+const DIRECTIVE_PREFIX: &str = "ic-sql-migrate:";
+
+/// Returns the directives in the comments of SQL text.
+///
+/// A directive is a comment starting with `ic-sql-migrate:`, e.g.
+/// `-- ic-sql-migrate: allow-destructive`. The text after the prefix is returned
+/// trimmed.
+pub(crate) fn directives(sql: &str) -> Vec<&str> {
+    tokenize(sql)
+        .into_iter()
+        .filter(|t| t.kind == TokenKind::Comment)
+        .filter_map(|t| {
+            let text = match t.text.strip_prefix("--") {
+                Some(text) => text,
+                None => t.text[2..].strip_suffix("*/").unwrap_or(&t.text[2..]),
+            };
+            text.trim().strip_prefix(DIRECTIVE_PREFIX).map(str::trim)
+        })
+        .collect()
+}
+
+/// A placeholder found by [`placeholders`].
+enum Placeholder<'a> {
+    /// A `${name}` variable
+    Variable(&'a str),
+    /// `$${`, an escaped `${` that is not a variable
+    Escape,
+}
+
+/// Finds the placeholders of SQL text, with their byte ranges.
+///
+/// Placeholders are found anywhere in the text, including string literals and comments.
+/// A `$` that doesn't start a placeholder, such as that of the `$name` parameter syntax,
+/// is left alone.
+fn placeholders(sql: &str) -> Vec<(Range<usize>, Placeholder<'_>)> {
+    let mut placeholders = Vec::new();
+    let mut offset = 0;
+
+    while let Some(position) = sql[offset..].find('$') {
+        let start = offset + position;
+        let rest = &sql[start + 1..];
+        if rest.starts_with("${") {
+            placeholders.push((start..start + 3, Placeholder::Escape));
+            offset = start + 3;
+        } else if let Some(name) = rest
+            .strip_prefix('{')
+            .and_then(|rest| rest.split_once('}'))
+            .map(|(name, _)| name)
+            .filter(|name| is_variable_name(name))
+        {
+            let end = start + name.len() + 3;
+            placeholders.push((start..end, Placeholder::Variable(name)));
+            offset = end;
+        } else {
+            offset = start + 1;
+        }
+    }
+
+    placeholders
+}
+
+fn is_variable_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+}
+
+/// Returns the names of the `${name}` variables in SQL text, in order of first use.
+pub(crate) fn variables(sql: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    for (_, placeholder) in placeholders(sql) {
+        if let Placeholder::Variable(name) = placeholder {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    names
+}
+
+/// Replaces the `${name}` variables of SQL text with their values.
+///
+/// Variables for which `value` returns `None` are kept. Escaped `$${` is replaced with
+/// `${` if `unescape` is set, and kept otherwise, so that it is only unescaped when the
+/// kept variables are substituted.
+///
+/// Values substituted inside a string literal or a quoted identifier have their quote
+/// character doubled, so they can't end the literal. Values substituted anywhere else
+/// are inserted as they are.
+pub(crate) fn substitute_variables<'a, 'v>(
+    sql: &'a str,
+    value: impl Fn(&str) -> Option<&'v str>,
+    unescape: bool,
+) -> Cow<'a, str> {
+    let tokens = tokenize(sql);
+    let mut substituted = String::new();
+    let mut copied = 0;
+
+    for (range, placeholder) in placeholders(sql) {
+        let replacement = match placeholder {
+            Placeholder::Variable(name) => {
+                value(name).map(|value| match enclosing_quote(&tokens, range.start) {
+                    Some(quote) => Cow::Owned(value.replace(quote, &quote.repeat(2))),
+                    None => Cow::Borrowed(value),
+                })
+            }
+            Placeholder::Escape => unescape.then_some(Cow::Borrowed("${")),
+        };
+        if let Some(replacement) = replacement {
+            substituted.push_str(&sql[copied..range.start]);
+            substituted.push_str(&replacement);
+            copied = range.end;
+        }
+    }
+
+    if copied == 0 {
+        Cow::Borrowed(sql)
+    } else {
+        substituted.push_str(&sql[copied..]);
+        Cow::Owned(substituted)
+    }
+}
+
+/// Returns the quote character of the string literal or quoted identifier containing
+/// the given byte offset, if it is quoted with a character that can be escaped by
+/// doubling it.
+fn enclosing_quote(tokens: &[Token], offset: usize) -> Option<&'static str> {
+    let index = tokens
+        .partition_point(|t| t.offset <= offset)
+        .checked_sub(1)?;
+    match tokens[index].text.as_bytes()[0] {
+        b'\'' if tokens[index].kind == TokenKind::String => Some("'"),
+        b'"' if tokens[index].kind == TokenKind::QuotedIdentifier => Some("\""),
+        b'`' if tokens[index].kind == TokenKind::QuotedIdentifier => Some("`"),
+        _ => None,
+    }
+}
+
+/// Finds the statements of SQL text that drop schema objects or delete data.
+///
+/// Returns each operation with the byte offset of its statement. A `DROP TABLE` that is
+/// followed by renaming another table to the dropped name is reported as a table
+/// rebuild. Statements inside trigger bodies only run when the trigger fires, so they
+/// are not reported.
+pub(crate) fn destructive_operations(sql: &str) -> Vec<(usize, DestructiveOperation)> {
+    let statements = split_statements(sql);
+    let mut operations = Vec::new();
+    let mut renamed_to = Vec::new();
+
+    for statement in &statements {
+        let tokens: Vec<Token> = statement
+            .tokens
+            .iter()
+            .copied()
+            .filter(|t| !t.is_trivia())
+            .collect();
+        let keyword =
+            |i: usize, keyword: &str| tokens.get(i).is_some_and(|t| t.is_keyword(keyword));
+
+        let operation = if keyword(0, "DROP") {
+            let name = |i: usize| {
+                let i = if keyword(i, "IF") && keyword(i + 1, "EXISTS") {
+                    i + 2
+                } else {
+                    i
+                };
+                object_name(&tokens, i)
+            };
+            if keyword(1, "TABLE") {
+                name(2).map(|table| DestructiveOperation::DropTable { table })
+            } else if keyword(1, "INDEX") {
+                name(2).map(|index| DestructiveOperation::DropIndex { index })
+            } else if keyword(1, "VIEW") {
+                name(2).map(|view| DestructiveOperation::DropView { view })
+            } else {
+                None
+            }
+        } else if keyword(0, "ALTER") && keyword(1, "TABLE") {
+            let table = object_name(&tokens, 2);
+            // The name can be qualified with a schema, so find the action after it
+            let action = tokens
+                .iter()
+                .position(|t| t.is_keyword("DROP") || t.is_keyword("RENAME"))
+                .unwrap_or(tokens.len());
+            if keyword(action, "DROP") {
+                let column = if keyword(action + 1, "COLUMN") {
+                    action + 2
+                } else {
+                    action + 1
+                };
+                table
+                    .zip(object_name(&tokens, column))
+                    .map(|(table, column)| DestructiveOperation::DropColumn { table, column })
+            } else {
+                if keyword(action, "RENAME") && keyword(action + 1, "TO") {
+                    renamed_to.extend(object_name(&tokens, action + 2));
+                }
+                None
+            }
+        } else if keyword(0, "DELETE") && keyword(1, "FROM") {
+            if tokens.iter().any(|t| t.is_keyword("WHERE")) {
+                None
+            } else {
+                object_name(&tokens, 2).map(|table| DestructiveOperation::DeleteAll { table })
+            }
+        } else {
+            None
+        };
+
+        if let Some(operation) = operation {
+            operations.push((statement.offset(), operation, renamed_to.len()));
+        }
+    }
+
+    operations
+        .into_iter()
+        .map(|(offset, operation, renames_before)| match operation {
+            DestructiveOperation::DropTable { table }
+                if renamed_to[renames_before..]
+                    .iter()
+                    .any(|renamed| renamed.eq_ignore_ascii_case(&table)) =>
+            {
+                (offset, DestructiveOperation::RebuildTable { table })
+            }
+            operation => (offset, operation),
+        })
+        .collect()
+}
+
+/// Returns the unquoted name of the object starting at token `i`.
+///
+/// A name qualified with a schema, such as `main.users`, is returned without the schema.
+fn object_name(tokens: &[Token], mut i: usize) -> Option<String> {
+    while tokens.get(i + 1).is_some_and(|t| t.text == ".") {
+        i += 2;
+    }
+    let token = tokens.get(i)?;
+    match token.kind {
+        TokenKind::Word => Some(token.text.to_string()),
+        TokenKind::QuotedIdentifier | TokenKind::String => {
+            let inner = &token.text[1..token.text.len().saturating_sub(1).max(1)];
+            Some(match token.text.as_bytes()[0] {
+                b'[' => inner.to_string(),
+                quote => {
+                    let quote = char::from(quote).to_string();
+                    inner.replace(&quote.repeat(2), &quote)
+                }
+            })
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(sql: &str) -> Vec<(TokenKind, &str)> {
+        tokenize(sql)
+            .into_iter()
+            .filter(|t| t.kind != TokenKind::Whitespace)
+            .map(|t| (t.kind, t.text))
+            .collect()
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            kinds("SELECT 'it''s; -- not a comment', \"a\"\"b\", [c d] -- note\n/* x; */;"),
+            [
+                (TokenKind::Word, "SELECT"),
+                (TokenKind::String, "'it''s; -- not a comment'"),
+                (TokenKind::Punctuation, ","),
+                (TokenKind::QuotedIdentifier, "\"a\"\"b\""),
+                (TokenKind::Punctuation, ","),
+                (TokenKind::QuotedIdentifier, "[c d]"),
+                (TokenKind::Comment, "-- note"),
+                (TokenKind::Comment, "/* x; */"),
+                (TokenKind::Semicolon, ";"),
+            ]
+        );
+
+        // Unterminated tokens extend to the end
+        assert_eq!(
+            kinds("SELECT 'abc"),
+            [(TokenKind::Word, "SELECT"), (TokenKind::String, "'abc")]
+        );
+        assert_eq!(kinds("/* a"), [(TokenKind::Comment, "/* a")]);
+        assert_eq!(
+            kinds("/**/x"),
+            [(TokenKind::Comment, "/**/"), (TokenKind::Word, "x")]
+        );
+    }
+
+    #[test]
+    fn test_split_statements() {
+        let sql = "-- header\nCREATE TABLE a (x TEXT DEFAULT ';');\n\n\
+                   CREATE TEMP TRIGGER t AFTER INSERT ON a BEGIN\n\
+                     UPDATE a SET x = CASE WHEN x IS NULL THEN 'y' END;\n\
+                     DELETE FROM a;\n\
+                   END;\n\
+                   INSERT INTO a VALUES ('z') -- no semicolon\n";
+        let statements: Vec<_> = split_statements(sql).iter().map(|s| s.text(sql)).collect();
+        assert_eq!(
+            statements,
+            [
+                "CREATE TABLE a (x TEXT DEFAULT ';');",
+                "CREATE TEMP TRIGGER t AFTER INSERT ON a BEGIN\n\
+                   UPDATE a SET x = CASE WHEN x IS NULL THEN 'y' END;\n\
+                   DELETE FROM a;\n\
+                 END;",
+                "INSERT INTO a VALUES ('z')",
+            ]
+        );
+
+        assert!(split_statements("  -- only a comment\n").is_empty());
+        assert_eq!(split_statements("BEGIN; SELECT 1; END;").len(), 3);
+    }
+
+    #[test]
+    fn test_line_column() {
+        let sql = "SELECT 1;\n  SELECT 'é';\nSELECT 3;";
+        let offsets: Vec<_> = split_statements(sql)
+            .iter()
+            .map(|s| line_column(sql, s.offset()))
+            .collect();
+        assert_eq!(offsets, [(1, 1), (2, 3), (3, 1)]);
+        assert_eq!(line_column(sql, sql.find('\'').unwrap() + 3), (2, 12));
+    }
+
+    #[test]
+    fn test_minify() {
+        let sql =
+            "-- Create users\nCREATE TABLE users (\n    id INTEGER PRIMARY KEY, -- the id\n    \
+                   name TEXT NOT NULL DEFAULT '  two  spaces -- kept'\n);\n\n\
+                   /* seed */ INSERT INTO users (name) VALUES ('a'),  ('b');\n";
+        assert_eq!(
+            minify(sql),
+            "CREATE TABLE users(id INTEGER PRIMARY KEY,name TEXT NOT NULL DEFAULT '  two  spaces -- kept');\n\
+             INSERT INTO users(name)VALUES('a'),('b');"
+        );
+
+        // Comments between words still separate them
+        assert_eq!(minify("SELECT/**/1 - -1"), "SELECT 1 - -1");
+    }
+
+    #[test]
+    fn test_directives() {
+        let sql = "-- ic-sql-migrate: allow-destructive\n\
+                   /* ic-sql-migrate:  other */ SELECT '-- ic-sql-migrate: not a comment';\n\
+                   -- a regular comment";
+        assert_eq!(directives(sql), ["allow-destructive", "other"]);
+    }
+
+    #[test]
+    fn test_variables() {
+        let sql = "INSERT INTO admins VALUES ('${admin}', '${env}'); -- ${admin}\n\
+                   SELECT '$${admin}', $param, ${not a variable}, ${1x}, ${}";
+        assert_eq!(variables(sql), ["admin", "env"]);
+
+        let value = |name: &str| (name == "admin").then_some("aaaaa-aa");
+        assert_eq!(
+            substitute_variables(sql, value, true),
+            "INSERT INTO admins VALUES ('aaaaa-aa', '${env}'); -- aaaaa-aa\n\
+             SELECT '${admin}', $param, ${not a variable}, ${1x}, ${}"
+        );
+        // Escapes are kept for a later substitution of the kept variables
+        assert!(substitute_variables(sql, value, false).contains("SELECT '$${admin}'"));
+
+        // Values are escaped inside string literals and quoted identifiers only
+        let sql = "SELECT '${v}', \"${v}\", `${v}`, [${v}], ${v} -- '${v}'";
+        let value = |_: &str| Some("it's \"x\"");
+        assert_eq!(
+            substitute_variables(sql, value, true),
+            "SELECT 'it''s \"x\"', \"it's \"\"x\"\"\", `it's \"x\"`, [it's \"x\"], \
+             it's \"x\" -- 'it's \"x\"'"
+        );
+
+        let sql = "SELECT $1, '${}'";
+        assert!(matches!(
+            substitute_variables(sql, value, true),
+            Cow::Borrowed(s) if s == sql
+        ));
+    }
+
+    #[test]
+    fn test_destructive_operations() {
+        let sql = "DROP TABLE IF EXISTS [old users];\n\
+                   drop index main.idx_email;\n\
+                   DROP VIEW \"active\"\"users\";\n\
+                   ALTER TABLE users DROP COLUMN email;\n\
+                   ALTER TABLE posts DROP body;\n\
+                   DELETE FROM sessions;\n\
+                   DELETE FROM tokens WHERE expired = 1;\n\
+                   DROP TRIGGER audit;\n\
+                   CREATE TRIGGER t AFTER INSERT ON users BEGIN DELETE FROM log; END;\n\
+                   CREATE TABLE new_users (id INTEGER PRIMARY KEY);\n\
+                   DROP TABLE users;\n\
+                   ALTER TABLE new_users RENAME TO users;";
+        let operations: Vec<_> = destructive_operations(sql)
+            .into_iter()
+            .map(|(offset, operation)| (line_column(sql, offset).0, operation.to_string()))
+            .collect();
+        assert_eq!(
+            operations,
+            [
+                (1, "drops table 'old users'".to_string()),
+                (2, "drops index 'idx_email'".to_string()),
+                (3, "drops view 'active\"users'".to_string()),
+                (4, "drops column 'email' of table 'users'".to_string()),
+                (5, "drops column 'body' of table 'posts'".to_string()),
+                (6, "deletes all rows of table 'sessions'".to_string()),
+                (11, "rebuilds table 'users'".to_string()),
+            ]
+        );
+
+        // A table renamed before it is dropped is not rebuilt
+        assert_eq!(
+            destructive_operations("ALTER TABLE a RENAME TO b; DROP TABLE b;")[0].1,
+            DestructiveOperation::DropTable {
+                table: "b".to_string()
+            }
+        );
+    }
+}
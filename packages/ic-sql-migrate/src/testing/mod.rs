@@ -0,0 +1,446 @@
+//! Test harness for migrations under `cargo test` (feature: `testing`).
+//!
+//! Migrations usually only run when a canister is installed or upgraded, which is too
+//! late to find out that they fail. A [`MigrationTest`] runs them against in-memory
+//! databases instead, natively and without a replica:
+//! - [`MigrationTest::fresh_apply`] applies all migrations and seeds to an empty database
+//! - [`MigrationTest::check_upgrades`] upgrades from every intermediate version and checks
+//!   that each upgrade ends with the schema of a fresh install
+//! - [`MigrationTest::check_roundtrips`] rolls back each reversible migration and checks
+//!   that its down migration restores the exact previous schema
+//! - [`MigrationTest::check_seeds_idempotent`] runs each seed again and checks that it
+//!   does not change any data
+//! - [`MigrationTest::check_fixture`] loads a fixture into a database at a given version,
+//!   applies the remaining migrations and compares the table contents to a snapshot file
+//!
+//! Schemas are compared by the SQL recorded in `sqlite_master`, ignoring the tracking
+//! tables; [`Schema::differences`] describes what differs. With Turso, tables are
+//! compared by their columns instead, see [`schema`].
+//!
+//! # Example
+//! ```ignore
+//! static MIGRATIONS: &[ic_sql_migrate::Migration] = ic_sql_migrate::include_migrations!();
+//!
+//! #[test]
+//! fn migrations() {
+//!     ic_sql_migrate::testing::MigrationTest::new(MIGRATIONS)
+//!         .with_seeds(seeds::SEEDS)
+//!         .check_all()
+//!         .unwrap();
+//! }
+//! ```
+//!
+//! With the `turso` feature the checks are `async`, e.g. in a `#[tokio::test]`.
+//!
+//! # Fixtures and snapshots
+//! A fixture is either an `.sql` file of statements, e.g. the output of
+//! `sqlite3 app.db '.dump --data-only'`, or a `.json` file that maps table names to
+//! arrays of rows:
+//! ```json
+//! { "users": [{ "id": 1, "name": "admin", "active": true }] }
+//! ```
+//! Snapshots list the rows of every table as SQL literals, sorted:
+//! ```text
+//! -- users (id, name, active)
+//! 1, 'admin', 1
+//! ```
+//! A missing or outdated snapshot fails the check with [`Error::SnapshotMismatch`]. Run
+//! the tests with `IC_SQL_MIGRATE_UPDATE_SNAPSHOTS=1` to write the actual contents to the
+//! snapshot files instead, and review the changes before committing them.
+
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(feature = "turso")]
+mod turso;
+
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::{schema, MigrationTest};
+#[cfg(feature = "turso")]
+pub use self::turso::{schema, MigrationTest};
+
+use std::collections::{BTreeMap, BTreeSet};
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+use std::path::Path;
+
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+use crate::db::quote_identifier;
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+use crate::sql::split_statements;
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+use crate::{Error, MigrateResult};
+
+/// The environment variable that makes [`MigrationTest::check_fixture`] write snapshot
+/// files instead of comparing against them.
+pub const UPDATE_SNAPSHOTS_VAR: &str = "IC_SQL_MIGRATE_UPDATE_SNAPSHOTS";
+
+/// Lists the schema objects and the automatic indexes of constraints, without SQLite's
+/// internal tables and the tracking tables.
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+const SCHEMA_SQL: &str = "SELECT type, name, sql FROM sqlite_master \
+     WHERE (name NOT LIKE 'sqlite_%' OR name LIKE 'sqlite_autoindex_%') \
+     AND tbl_name NOT IN ('_migrations', '_seeds', '_migrations_lock') \
+     ORDER BY type, name";
+
+/// Lists the columns of a table in order.
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+const COLUMNS_SQL: &str = "SELECT name FROM pragma_table_info(?1) ORDER BY cid";
+
+/// A table, index, view or trigger, as recorded in `sqlite_master`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaObject {
+    /// `table`, `index`, `view` or `trigger`
+    pub kind: String,
+    pub name: String,
+    /// The SQL that creates the object, `None` for automatic indexes of constraints
+    pub sql: Option<String>,
+}
+
+/// The schema of a database, see [`schema`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Schema {
+    /// The schema objects, sorted by kind and name
+    pub objects: Vec<SchemaObject>,
+}
+
+impl Schema {
+    /// Returns the names of the tables.
+    pub fn tables(&self) -> impl Iterator<Item = &str> {
+        self.objects
+            .iter()
+            .filter(|object| object.kind == "table")
+            .map(|object| object.name.as_str())
+    }
+
+    /// Describes how `actual` differs from this schema, one line per object that is
+    /// missing, unexpected or created by different SQL. Empty if the schemas are equal.
+    pub fn differences(&self, actual: &Schema) -> Vec<String> {
+        let objects = |schema: &Schema| -> BTreeMap<(String, String), Option<String>> {
+            schema
+                .objects
+                .iter()
+                .map(|o| ((o.kind.clone(), o.name.clone()), o.sql.clone()))
+                .collect()
+        };
+        let expected = objects(self);
+        let actual = objects(actual);
+
+        let keys: BTreeSet<_> = expected.keys().chain(actual.keys()).collect();
+        keys.into_iter()
+            .filter_map(
+                |key @ (kind, name)| match (expected.get(key), actual.get(key)) {
+                    (Some(_), None) => Some(format!("{kind} {name} is missing")),
+                    (None, Some(_)) => Some(format!("{kind} {name} is unexpected")),
+                    (Some(expected), Some(actual)) if expected != actual => Some(format!(
+                        "{kind} {name} differs: expected {}, found {}",
+                        expected.as_deref().unwrap_or("no SQL"),
+                        actual.as_deref().unwrap_or("no SQL")
+                    )),
+                    _ => None,
+                },
+            )
+            .collect()
+    }
+}
+
+/// The columns of a table and its rows, each row as the SQL literals of its values.
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+#[derive(Debug, PartialEq, Eq)]
+struct Rows {
+    columns: Vec<String>,
+    rows: Vec<String>,
+}
+
+/// The rows of each table.
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+type Data = BTreeMap<String, Rows>;
+
+/// Returns the query that selects the rows of a table as text, sorted so that tables
+/// with the same rows compare equal regardless of their rowids.
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+fn rows_sql(table: &str, columns: &[String]) -> String {
+    let values: Vec<_> = columns
+        .iter()
+        .map(|column| format!("quote({})", quote_identifier(column)))
+        .collect();
+    format!(
+        "SELECT {} FROM {} ORDER BY 1",
+        values.join(" || ', ' || "),
+        quote_identifier(table)
+    )
+}
+
+/// Returns the tables whose rows differ between two snapshots.
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+fn changed_tables(before: &Data, after: &Data) -> Vec<String> {
+    before
+        .keys()
+        .chain(after.keys())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .filter(|table| before.get(*table) != after.get(*table))
+        .cloned()
+        .collect()
+}
+
+/// Renders the rows of every table as the text of a snapshot file.
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+fn render_snapshot(data: &Data) -> String {
+    data.iter()
+        .map(|(table, rows)| {
+            let mut text = format!("-- {table} ({})\n", rows.columns.join(", "));
+            for row in &rows.rows {
+                text.push_str(row);
+                text.push('\n');
+            }
+            text
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Returns the statements that load a fixture, by the extension of its path: `.sql`
+/// files are split into their statements, `.json` files become `INSERT` statements.
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+fn fixture_statements(path: &Path) -> MigrateResult<Vec<String>> {
+    let invalid = |reason: String| Error::InvalidFixture {
+        path: path.to_path_buf(),
+        reason,
+    };
+    let text = std::fs::read_to_string(path)?;
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("sql") => Ok(split_statements(&text)
+            .iter()
+            .map(|statement| statement.text(&text).to_string())
+            .collect()),
+        Some("json") => {
+            let json: serde_json::Value =
+                serde_json::from_str(&text).map_err(|e| invalid(e.to_string()))?;
+            json_statements(&json).map_err(invalid)
+        }
+        _ => Err(invalid(
+            "unsupported extension, expected .sql or .json".to_string(),
+        )),
+    }
+}
+
+/// Returns an `INSERT` statement for each row of a JSON fixture.
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+fn json_statements(json: &serde_json::Value) -> Result<Vec<String>, String> {
+    let tables = json
+        .as_object()
+        .ok_or("expected an object that maps table names to arrays of rows")?;
+    let mut statements = Vec::new();
+    for (table, rows) in tables {
+        let rows = rows
+            .as_array()
+            .ok_or_else(|| format!("expected an array of rows for table '{table}'"))?;
+        for row in rows {
+            let row = row
+                .as_object()
+                .ok_or_else(|| format!("expected the rows of table '{table}' to be objects"))?;
+            let columns: Vec<_> = row.keys().map(|c| quote_identifier(c)).collect();
+            let values: Vec<_> = row.values().map(json_literal).collect();
+            statements.push(format!(
+                "INSERT INTO {} ({}) VALUES ({})",
+                quote_identifier(table),
+                columns.join(", "),
+                values.join(", ")
+            ));
+        }
+    }
+    Ok(statements)
+}
+
+/// Returns the SQL literal of a JSON value; arrays and objects are stored as JSON text.
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+fn json_literal(value: &serde_json::Value) -> String {
+    use serde_json::Value;
+
+    let text = |text: &str| format!("'{}'", text.replace('\'', "''"));
+    match value {
+        Value::Null => "NULL".to_string(),
+        Value::Bool(value) => u8::from(*value).to_string(),
+        Value::Number(number) => number.to_string(),
+        Value::String(string) => text(string),
+        Value::Array(_) | Value::Object(_) => text(&value.to_string()),
+    }
+}
+
+/// Compares `actual` to the snapshot file at `path`, or writes it to the file in update
+/// mode.
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+fn check_snapshot(path: &Path, actual: &str, update: bool) -> MigrateResult<()> {
+    if update {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, actual)?;
+        return Ok(());
+    }
+
+    let expected = match std::fs::read_to_string(path) {
+        Ok(expected) => expected,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    if expected == actual {
+        Ok(())
+    } else {
+        Err(Error::SnapshotMismatch {
+            path: path.to_path_buf(),
+            diff: diff_lines(&expected, actual),
+        })
+    }
+}
+
+/// Returns whether [`UPDATE_SNAPSHOTS_VAR`] is set to anything but `0`.
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+fn update_snapshots() -> bool {
+    std::env::var(UPDATE_SNAPSHOTS_VAR).is_ok_and(|value| !value.is_empty() && value != "0")
+}
+
+/// Returns the lines removed from `expected` prefixed with `-`, and the lines added in
+/// `actual` prefixed with `+`, in order of the longest common subsequence.
+#[cfg(any(feature = "sqlite", feature = "turso"))]
+fn diff_lines(expected: &str, actual: &str) -> Vec<String> {
+    let expected: Vec<_> = expected.lines().collect();
+    let actual: Vec<_> = actual.lines().collect();
+
+    // common[i][j] is the length of the longest common subsequence of the lines from
+    // expected[i] and actual[j] on
+    let mut common = vec![vec![0usize; actual.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            common[i][j] = if expected[i] == actual[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    let mut diff = Vec::new();
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+            i += 1;
+            j += 1;
+        } else if j == actual.len() || (i < expected.len() && common[i + 1][j] >= common[i][j + 1])
+        {
+            diff.push(format!("- {}", expected[i]));
+            i += 1;
+        } else {
+            diff.push(format!("+ {}", actual[j]));
+            j += 1;
+        }
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object(kind: &str, name: &str, sql: Option<&str>) -> SchemaObject {
+        SchemaObject {
+            kind: kind.to_string(),
+            name: name.to_string(),
+            sql: sql.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_schema_differences() {
+        let expected = Schema {
+            objects: vec![
+                object(
+                    "index",
+                    "idx_users_email",
+                    Some("CREATE INDEX idx_users_email ON users (email)"),
+                ),
+                object(
+                    "table",
+                    "users",
+                    Some("CREATE TABLE users (id INTEGER, email TEXT)"),
+                ),
+            ],
+        };
+        assert!(expected.differences(&expected).is_empty());
+        assert_eq!(expected.tables().collect::<Vec<_>>(), ["users"]);
+
+        let actual = Schema {
+            objects: vec![
+                object("table", "posts", Some("CREATE TABLE posts (id INTEGER)")),
+                object("table", "users", Some("CREATE TABLE users (id INTEGER)")),
+            ],
+        };
+        assert_eq!(
+            expected.differences(&actual),
+            [
+                "index idx_users_email is missing",
+                "table posts is unexpected",
+                "table users differs: expected CREATE TABLE users (id INTEGER, email TEXT), \
+                 found CREATE TABLE users (id INTEGER)",
+            ]
+        );
+    }
+
+    #[cfg(any(feature = "sqlite", feature = "turso"))]
+    #[test]
+    fn test_json_statements() {
+        let json = serde_json::json!({
+            "users": [{ "id": 1, "name": "O'Brien", "active": true, "tags": ["a"], "bio": null }],
+        });
+        assert_eq!(
+            json_statements(&json).unwrap(),
+            [
+                r#"INSERT INTO "users" ("active", "bio", "id", "name", "tags") VALUES (1, NULL, 1, 'O''Brien', '["a"]')"#
+            ]
+        );
+        assert_eq!(
+            json_statements(&serde_json::json!({ "users": {} })).unwrap_err(),
+            "expected an array of rows for table 'users'"
+        );
+    }
+
+    #[cfg(any(feature = "sqlite", feature = "turso"))]
+    #[test]
+    fn test_snapshots() {
+        let data = Data::from([
+            (
+                "posts".to_string(),
+                Rows {
+                    columns: vec!["id".to_string()],
+                    rows: vec![],
+                },
+            ),
+            (
+                "users".to_string(),
+                Rows {
+                    columns: vec!["id".to_string(), "name".to_string()],
+                    rows: vec!["1, 'Ada'".to_string(), "2, 'Bob'".to_string()],
+                },
+            ),
+        ]);
+        let actual = render_snapshot(&data);
+        assert_eq!(
+            actual,
+            "-- posts (id)\n\n-- users (id, name)\n1, 'Ada'\n2, 'Bob'\n"
+        );
+        assert_eq!(
+            diff_lines("-- users (id, name)\n1, 'Ada'\n3, 'Cy'\n", &actual),
+            ["+ -- posts (id)", "+ ", "- 3, 'Cy'", "+ 2, 'Bob'"]
+        );
+
+        let path = std::env::temp_dir()
+            .join(format!("ic-sql-migrate-snapshots-{}", std::process::id()))
+            .join("data.snap");
+        assert!(matches!(
+            check_snapshot(&path, &actual, false),
+            Err(Error::SnapshotMismatch { diff, .. }) if diff.len() == 5
+        ));
+        check_snapshot(&path, &actual, true).unwrap();
+        check_snapshot(&path, &actual, false).unwrap();
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,425 @@
+use std::path::Path;
+
+use rusqlite::Connection;
+
+use super::{
+    changed_tables, check_snapshot, fixture_statements, render_snapshot, rows_sql,
+    update_snapshots, Data, Rows, Schema, SchemaObject, COLUMNS_SQL, SCHEMA_SQL,
+};
+use crate::{sqlite, Error, MigrateOptions, MigrateResult, Migration, MigrationReport, Seed};
+
+/// Checks migrations and seeds against in-memory databases, see the
+/// [module documentation](super).
+///
+/// # Example
+/// ```ignore
+/// use ic_sql_migrate::testing::MigrationTest;
+///
+/// #[test]
+/// fn migrations() {
+///     let test = MigrationTest::new(MIGRATIONS).with_seeds(seeds::SEEDS);
+///     test.check_upgrades().unwrap();
+///     test.check_roundtrips().unwrap();
+///     test.check_seeds_idempotent().unwrap();
+/// }
+/// ```
+#[derive(Clone)]
+pub struct MigrationTest<'a> {
+    migrations: &'a [Migration],
+    seeds: &'a [Seed],
+    options: MigrateOptions,
+}
+
+impl<'a> MigrationTest<'a> {
+    pub fn new(migrations: &'a [Migration]) -> Self {
+        Self {
+            migrations,
+            seeds: &[],
+            options: MigrateOptions::default(),
+        }
+    }
+
+    /// Sets the seeds to apply together with the migrations, see [`sqlite::run`].
+    pub fn with_seeds(mut self, seeds: &'a [Seed]) -> Self {
+        self.seeds = seeds;
+        self
+    }
+
+    /// Sets the options to apply migrations with, e.g. the values of runtime variables.
+    pub fn with_options(mut self, options: MigrateOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Applies all migrations and seeds to an empty in-memory database, and returns the
+    /// database for further assertions.
+    ///
+    /// # Errors
+    /// Returns an error if a migration or seed fails, see [`sqlite::migrate`] and
+    /// [`sqlite::run`].
+    pub fn fresh_apply(&self) -> MigrateResult<Connection> {
+        let mut conn = Connection::open_in_memory()?;
+        self.apply(&mut conn)?;
+        Ok(conn)
+    }
+
+    /// Checks that upgrading from every intermediate version ends with the schema of a
+    /// fresh install.
+    ///
+    /// For each version a database is migrated up to it, and then the remaining
+    /// migrations and the seeds are applied.
+    ///
+    /// # Errors
+    /// Returns [`Error::SchemaMismatch`] for the first version whose upgrade ends with a
+    /// different schema, and an error if a migration or seed fails.
+    pub fn check_upgrades(&self) -> MigrateResult<()> {
+        let expected = schema(&self.fresh_apply()?)?;
+        for version in 1..self.migrations.len() {
+            let mut conn = Connection::open_in_memory()?;
+            sqlite::migrate_with(&mut conn, &self.migrations[..version], &self.options)?;
+            self.apply(&mut conn)?;
+            let from = self.migrations[version - 1].id;
+            compare(&expected, &conn, || format!("Upgrading from '{from}'"))?;
+        }
+        Ok(())
+    }
+
+    /// Checks that the down migration of each reversible migration restores the exact
+    /// schema from before the migration, and that the migration can be applied again.
+    ///
+    /// Migrations are applied one at a time; each reversible one is rolled back with
+    /// [`sqlite::rollback_to`] and applied again before moving on. Migrations without a
+    /// down migration are only applied.
+    ///
+    /// # Errors
+    /// Returns [`Error::SchemaMismatch`] for the first migration whose rollback or
+    /// reapplication ends with a different schema, and an error if a migration or down
+    /// migration fails.
+    pub fn check_roundtrips(&self) -> MigrateResult<()> {
+        let mut conn = Connection::open_in_memory()?;
+        for (index, migration) in self.migrations.iter().enumerate() {
+            let before = schema(&conn)?;
+            let migrations = &self.migrations[..=index];
+            sqlite::migrate_with(&mut conn, migrations, &self.options)?;
+            if migration.down().is_none() {
+                continue;
+            }
+
+            let after = schema(&conn)?;
+            let previous = index.checked_sub(1).map(|i| self.migrations[i].id);
+            sqlite::rollback_to(&mut conn, migrations, previous)?;
+            compare(&before, &conn, || {
+                format!("Rolling back '{}'", migration.id)
+            })?;
+            sqlite::migrate_with(&mut conn, migrations, &self.options)?;
+            compare(&after, &conn, || {
+                format!("Applying '{}' after rolling it back", migration.id)
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Checks that running each seed again does not change any data.
+    ///
+    /// Versioned seeds run again whenever their content changes, and [`sqlite::run_seed`]
+    /// re-runs any seed, so seeds should be safe to re-run, e.g. with [`sqlite::upsert`].
+    /// Each seed applied to a fresh install runs again in a transaction that is rolled
+    /// back afterwards.
+    ///
+    /// # Errors
+    /// Returns [`Error::SeedNotIdempotent`] for the first seed that changes data, and an
+    /// error if a migration or seed fails.
+    pub fn check_seeds_idempotent(&self) -> MigrateResult<()> {
+        let mut conn = Connection::open_in_memory()?;
+        let report = self.apply(&mut conn)?;
+        for seed in self
+            .seeds
+            .iter()
+            .filter(|s| report.seeds.iter().any(|id| id == s.id))
+        {
+            let tx = conn.transaction()?;
+            let before = data(&tx)?;
+            (seed.seed_fn)(&tx).map_err(|e| Error::SeedFailed {
+                id: seed.id.to_string(),
+                source: Box::new(e),
+            })?;
+            let tables = changed_tables(&before, &data(&tx)?);
+            if !tables.is_empty() {
+                return Err(Error::SeedNotIdempotent {
+                    id: seed.id.to_string(),
+                    tables,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that migrating data from the fixture at `fixture` ends with the table
+    /// contents of the snapshot file at `snapshot`.
+    ///
+    /// A database is migrated up to and including the migration `version`, the fixture is
+    /// loaded into it, and then the remaining migrations and the seeds are applied. See the
+    /// [module documentation](super#fixtures-and-snapshots) for the file formats. With
+    /// `IC_SQL_MIGRATE_UPDATE_SNAPSHOTS=1` the table contents are written to `snapshot`
+    /// instead.
+    ///
+    /// # Errors
+    /// Returns [`Error::UnknownMigration`] if `version` is not one of the migrations,
+    /// [`Error::InvalidFixture`] if the fixture cannot be loaded,
+    /// [`Error::SnapshotMismatch`] if the table contents differ from the snapshot, and an
+    /// error if a migration or seed fails.
+    pub fn check_fixture(
+        &self,
+        version: &str,
+        fixture: impl AsRef<Path>,
+        snapshot: impl AsRef<Path>,
+    ) -> MigrateResult<()> {
+        let position = self
+            .migrations
+            .iter()
+            .position(|m| m.id == version)
+            .ok_or_else(|| Error::UnknownMigration {
+                id: version.to_string(),
+            })?;
+        let mut conn = Connection::open_in_memory()?;
+        sqlite::migrate_with(&mut conn, &self.migrations[..=position], &self.options)?;
+
+        let fixture = fixture.as_ref();
+        for statement in fixture_statements(fixture)? {
+            conn.execute_batch(&statement)
+                .map_err(|e| Error::InvalidFixture {
+                    path: fixture.to_path_buf(),
+                    reason: e.to_string(),
+                })?;
+        }
+        self.apply(&mut conn)?;
+
+        let actual = render_snapshot(&data(&conn)?);
+        check_snapshot(snapshot.as_ref(), &actual, update_snapshots())
+    }
+
+    /// Runs all checks: [`MigrationTest::check_upgrades`], which includes a fresh install,
+    /// [`MigrationTest::check_roundtrips`] and [`MigrationTest::check_seeds_idempotent`].
+    ///
+    /// # Errors
+    /// Returns the error of the first check that fails.
+    pub fn check_all(&self) -> MigrateResult<()> {
+        self.check_upgrades()?;
+        self.check_roundtrips()?;
+        self.check_seeds_idempotent()
+    }
+
+    fn apply(&self, conn: &mut Connection) -> MigrateResult<MigrationReport> {
+        if self.seeds.is_empty() {
+            sqlite::migrate_with(conn, self.migrations, &self.options)
+        } else {
+            sqlite::run_with(conn, self.migrations, self.seeds, &self.options)
+        }
+    }
+}
+
+/// Returns the schema of a database, without the tracking tables.
+///
+/// # Errors
+/// Returns an error if `sqlite_master` cannot be read.
+pub fn schema(conn: &Connection) -> MigrateResult<Schema> {
+    let mut statement = conn.prepare(SCHEMA_SQL)?;
+    let objects = statement
+        .query_map([], |row| {
+            Ok(SchemaObject {
+                kind: row.get(0)?,
+                name: row.get(1)?,
+                sql: row.get(2)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(Schema { objects })
+}
+
+/// Fails with [`Error::SchemaMismatch`] if the schema of `conn` differs from `expected`.
+fn compare(
+    expected: &Schema,
+    conn: &Connection,
+    check: impl FnOnce() -> String,
+) -> MigrateResult<()> {
+    let differences = expected.differences(&schema(conn)?);
+    if differences.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::SchemaMismatch {
+            check: check(),
+            differences,
+        })
+    }
+}
+
+/// Returns the rows of every table, without the tracking tables.
+fn data(conn: &Connection) -> MigrateResult<Data> {
+    let schema = schema(conn)?;
+    let mut data = Data::new();
+    for table in schema.tables() {
+        let columns = conn
+            .prepare(COLUMNS_SQL)?
+            .query_map([table], |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        let rows = conn
+            .prepare(&rows_sql(table, &columns))?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        data.insert(table.to_string(), Rows { columns, rows });
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static MIGRATIONS: &[Migration] = &[
+        Migration::new(
+            "001_users",
+            "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT NOT NULL UNIQUE);",
+        )
+        .with_down("DROP TABLE users;"),
+        Migration::new("002_email", "ALTER TABLE users ADD COLUMN email TEXT;")
+            .with_down("ALTER TABLE users DROP COLUMN email;"),
+        Migration::new(
+            "003_posts",
+            "CREATE TABLE posts (id INTEGER PRIMARY KEY, user_id INTEGER);
+             CREATE INDEX idx_posts_user ON posts (user_id);",
+        ),
+    ];
+
+    fn seed_admin(conn: &Connection) -> MigrateResult<()> {
+        sqlite::upsert(
+            conn,
+            "users",
+            &["name"],
+            &["name", "email"],
+            rusqlite::params!["admin", "admin@example.com"],
+        )?;
+        Ok(())
+    }
+
+    fn seed_welcome_post(conn: &Connection) -> MigrateResult<()> {
+        conn.execute("INSERT INTO posts (user_id) VALUES (1)", [])?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_migration_test() {
+        let seeds = [Seed::new("001_admin", seed_admin).requires("002_email")];
+        let test = MigrationTest::new(MIGRATIONS).with_seeds(&seeds);
+        test.check_all().unwrap();
+
+        let conn = test.fresh_apply().unwrap();
+        let tables: Vec<_> = schema(&conn)
+            .unwrap()
+            .tables()
+            .map(str::to_string)
+            .collect();
+        assert_eq!(tables, ["posts", "users"]);
+    }
+
+    #[test]
+    fn test_check_fixture() {
+        let migrations = [
+            Migration::new(
+                "001_users",
+                "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT NOT NULL);",
+            ),
+            Migration::new(
+                "002_email",
+                "ALTER TABLE users ADD COLUMN email TEXT;
+                 UPDATE users SET email = lower(name) || '@example.com';",
+            ),
+        ];
+        let dir = std::env::temp_dir().join(format!(
+            "ic-sql-migrate-check-fixture-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let json = dir.join("users.json");
+        std::fs::write(
+            &json,
+            r#"{"users": [{"id": 1, "name": "Ada"}, {"id": 2, "name": "Bob"}]}"#,
+        )
+        .unwrap();
+        let sql = dir.join("users.sql");
+        std::fs::write(
+            &sql,
+            "INSERT INTO users VALUES (1, 'Ada');\nINSERT INTO users VALUES (2, 'Bob');\n",
+        )
+        .unwrap();
+        let snapshot = dir.join("users.snap");
+        let test = MigrationTest::new(&migrations);
+
+        let err = test
+            .check_fixture("001_users", &json, &snapshot)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::SnapshotMismatch { path, diff } if path == snapshot && diff.len() == 3
+        ));
+
+        std::fs::write(
+            &snapshot,
+            "-- users (id, name, email)\n\
+             1, 'Ada', 'ada@example.com'\n\
+             2, 'Bob', 'bob@example.com'\n",
+        )
+        .unwrap();
+        test.check_fixture("001_users", &json, &snapshot).unwrap();
+        test.check_fixture("001_users", &sql, &snapshot).unwrap();
+
+        let err = test
+            .check_fixture("002_email", &json, &snapshot)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::SnapshotMismatch { diff, .. }
+                if diff == ["- 1, 'Ada', 'ada@example.com'", "- 2, 'Bob', 'bob@example.com'", "+ 1, 'Ada', NULL", "+ 2, 'Bob', NULL"]
+        ));
+        assert!(matches!(
+            test.check_fixture("003_posts", &json, &snapshot),
+            Err(Error::UnknownMigration { id }) if id == "003_posts"
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_roundtrip_must_restore_schema() {
+        let migrations = [
+            Migration::new("001_users", "CREATE TABLE users (id INTEGER PRIMARY KEY);"),
+            Migration::new(
+                "002_index",
+                "CREATE INDEX idx_users ON users (id); CREATE TABLE audit (id INTEGER);",
+            )
+            .with_down("DROP INDEX idx_users;"),
+        ];
+        let err = MigrationTest::new(&migrations)
+            .check_roundtrips()
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::SchemaMismatch { check, differences }
+                if check == "Rolling back '002_index'" && differences == ["table audit is unexpected"]
+        ));
+    }
+
+    #[test]
+    fn test_seeds_must_be_idempotent() {
+        let seeds = [
+            Seed::new("001_admin", seed_admin).requires("002_email"),
+            Seed::new("002_welcome_post", seed_welcome_post).requires("003_posts"),
+        ];
+        let err = MigrationTest::new(MIGRATIONS)
+            .with_seeds(&seeds)
+            .check_seeds_idempotent()
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::SeedNotIdempotent { id, tables } if id == "002_welcome_post" && tables == ["posts"]
+        ));
+    }
+}
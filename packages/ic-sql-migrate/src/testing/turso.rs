@@ -0,0 +1,520 @@
+use std::path::Path;
+
+use turso_crate::Connection;
+
+use super::{
+    changed_tables, check_snapshot, fixture_statements, quote_identifier, render_snapshot,
+    rows_sql, update_snapshots, Data, Rows, Schema, SchemaObject, COLUMNS_SQL, SCHEMA_SQL,
+};
+use crate::{
+    turso, turso_crate, Error, MigrateOptions, MigrateResult, Migration, MigrationReport, Seed,
+};
+
+/// Describes the columns of a table in order.
+const TABLE_INFO_SQL: &str =
+    "SELECT name, type, \"notnull\", dflt_value, pk FROM pragma_table_info(?1) ORDER BY cid";
+
+/// Checks migrations and seeds against in-memory databases, see the
+/// [module documentation](super).
+///
+/// # Example
+/// ```ignore
+/// use ic_sql_migrate::testing::MigrationTest;
+///
+/// #[tokio::test]
+/// async fn migrations() {
+///     let test = MigrationTest::new(MIGRATIONS).with_seeds(seeds::SEEDS);
+///     test.check_upgrades().await.unwrap();
+///     test.check_roundtrips().await.unwrap();
+///     test.check_seeds_idempotent().await.unwrap();
+/// }
+/// ```
+#[derive(Clone)]
+pub struct MigrationTest<'a> {
+    migrations: &'a [Migration],
+    seeds: &'a [Seed],
+    options: MigrateOptions,
+}
+
+impl<'a> MigrationTest<'a> {
+    pub fn new(migrations: &'a [Migration]) -> Self {
+        Self {
+            migrations,
+            seeds: &[],
+            options: MigrateOptions::default(),
+        }
+    }
+
+    /// Sets the seeds to apply together with the migrations, see [`turso::run`].
+    pub fn with_seeds(mut self, seeds: &'a [Seed]) -> Self {
+        self.seeds = seeds;
+        self
+    }
+
+    /// Sets the options to apply migrations with, e.g. the values of runtime variables.
+    pub fn with_options(mut self, options: MigrateOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Applies all migrations and seeds to an empty in-memory database, and returns the
+    /// database for further assertions.
+    ///
+    /// # Errors
+    /// Returns an error if a migration or seed fails, see [`turso::migrate`] and
+    /// [`turso::run`].
+    pub async fn fresh_apply(&self) -> MigrateResult<Connection> {
+        let mut conn = open_in_memory().await?;
+        self.apply(&mut conn).await?;
+        Ok(conn)
+    }
+
+    /// Checks that upgrading from every intermediate version ends with the schema of a
+    /// fresh install.
+    ///
+    /// For each version a database is migrated up to it, and then the remaining
+    /// migrations and the seeds are applied.
+    ///
+    /// # Errors
+    /// Returns [`Error::SchemaMismatch`] for the first version whose upgrade ends with a
+    /// different schema, and an error if a migration or seed fails.
+    pub async fn check_upgrades(&self) -> MigrateResult<()> {
+        let expected = schema(&self.fresh_apply().await?).await?;
+        for version in 1..self.migrations.len() {
+            let mut conn = open_in_memory().await?;
+            turso::migrate_with(&mut conn, &self.migrations[..version], &self.options).await?;
+            self.apply(&mut conn).await?;
+            let from = self.migrations[version - 1].id;
+            compare(&expected, &conn, || format!("Upgrading from '{from}'")).await?;
+        }
+        Ok(())
+    }
+
+    /// Checks that the down migration of each reversible migration restores the exact
+    /// schema from before the migration, and that the migration can be applied again.
+    ///
+    /// Migrations are applied one at a time; each reversible one is rolled back with
+    /// [`turso::rollback_to`] and applied again before moving on. Migrations without a
+    /// down migration are only applied.
+    ///
+    /// # Errors
+    /// Returns [`Error::SchemaMismatch`] for the first migration whose rollback or
+    /// reapplication ends with a different schema, and an error if a migration or down
+    /// migration fails.
+    pub async fn check_roundtrips(&self) -> MigrateResult<()> {
+        let mut conn = open_in_memory().await?;
+        for (index, migration) in self.migrations.iter().enumerate() {
+            let before = schema(&conn).await?;
+            let migrations = &self.migrations[..=index];
+            turso::migrate_with(&mut conn, migrations, &self.options).await?;
+            if migration.down().is_none() {
+                continue;
+            }
+
+            let after = schema(&conn).await?;
+            let previous = index.checked_sub(1).map(|i| self.migrations[i].id);
+            turso::rollback_to(&mut conn, migrations, previous).await?;
+            compare(&before, &conn, || {
+                format!("Rolling back '{}'", migration.id)
+            })
+            .await?;
+            turso::migrate_with(&mut conn, migrations, &self.options).await?;
+            compare(&after, &conn, || {
+                format!("Applying '{}' after rolling it back", migration.id)
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Checks that running each seed again does not change any data.
+    ///
+    /// Versioned seeds run again whenever their content changes, and [`turso::run_seed`]
+    /// re-runs any seed, so seeds should be safe to re-run, e.g. with [`turso::upsert`].
+    /// Each seed applied to a fresh install runs again in a transaction that is rolled
+    /// back afterwards.
+    ///
+    /// # Errors
+    /// Returns [`Error::SeedNotIdempotent`] for the first seed that changes data, and an
+    /// error if a migration or seed fails.
+    pub async fn check_seeds_idempotent(&self) -> MigrateResult<()> {
+        let mut conn = open_in_memory().await?;
+        let report = self.apply(&mut conn).await?;
+        for seed in self
+            .seeds
+            .iter()
+            .filter(|s| report.seeds.iter().any(|id| id == s.id))
+        {
+            let tx = conn.transaction().await?;
+            let before = data(&tx).await?;
+            let result = (seed.seed_fn)(&tx).await;
+            let after = data(&tx).await;
+            tx.rollback().await?;
+
+            result.map_err(|e| Error::SeedFailed {
+                id: seed.id.to_string(),
+                source: Box::new(e),
+            })?;
+            let tables = changed_tables(&before, &after?);
+            if !tables.is_empty() {
+                return Err(Error::SeedNotIdempotent {
+                    id: seed.id.to_string(),
+                    tables,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that migrating data from the fixture at `fixture` ends with the table
+    /// contents of the snapshot file at `snapshot`.
+    ///
+    /// A database is migrated up to and including the migration `version`, the fixture is
+    /// loaded into it, and then the remaining migrations and the seeds are applied. See the
+    /// [module documentation](super#fixtures-and-snapshots) for the file formats. With
+    /// `IC_SQL_MIGRATE_UPDATE_SNAPSHOTS=1` the table contents are written to `snapshot`
+    /// instead.
+    ///
+    /// # Errors
+    /// Returns [`Error::UnknownMigration`] if `version` is not one of the migrations,
+    /// [`Error::InvalidFixture`] if the fixture cannot be loaded,
+    /// [`Error::SnapshotMismatch`] if the table contents differ from the snapshot, and an
+    /// error if a migration or seed fails.
+    pub async fn check_fixture(
+        &self,
+        version: &str,
+        fixture: impl AsRef<Path>,
+        snapshot: impl AsRef<Path>,
+    ) -> MigrateResult<()> {
+        let position = self
+            .migrations
+            .iter()
+            .position(|m| m.id == version)
+            .ok_or_else(|| Error::UnknownMigration {
+                id: version.to_string(),
+            })?;
+        let mut conn = open_in_memory().await?;
+        turso::migrate_with(&mut conn, &self.migrations[..=position], &self.options).await?;
+
+        let fixture = fixture.as_ref();
+        for statement in fixture_statements(fixture)? {
+            conn.execute_batch(&statement)
+                .await
+                .map_err(|e| Error::InvalidFixture {
+                    path: fixture.to_path_buf(),
+                    reason: e.to_string(),
+                })?;
+        }
+        self.apply(&mut conn).await?;
+
+        let actual = render_snapshot(&data(&conn).await?);
+        check_snapshot(snapshot.as_ref(), &actual, update_snapshots())
+    }
+
+    /// Runs all checks: [`MigrationTest::check_upgrades`], which includes a fresh install,
+    /// [`MigrationTest::check_roundtrips`] and [`MigrationTest::check_seeds_idempotent`].
+    ///
+    /// # Errors
+    /// Returns the error of the first check that fails.
+    pub async fn check_all(&self) -> MigrateResult<()> {
+        self.check_upgrades().await?;
+        self.check_roundtrips().await?;
+        self.check_seeds_idempotent().await
+    }
+
+    async fn apply(&self, conn: &mut Connection) -> MigrateResult<MigrationReport> {
+        if self.seeds.is_empty() {
+            turso::migrate_with(conn, self.migrations, &self.options).await
+        } else {
+            turso::run_with(conn, self.migrations, self.seeds, &self.options).await
+        }
+    }
+}
+
+async fn open_in_memory() -> MigrateResult<Connection> {
+    let db = turso_crate::Builder::new_local(":memory:").build().await?;
+    Ok(db.connect()?)
+}
+
+/// Returns the schema of a database, without the tracking tables.
+///
+/// Turso rewrites the `CREATE TABLE` statement of a table when one of its columns is
+/// dropped, leaving out the `NOT NULL`, `CHECK` and `COLLATE` clauses and the table
+/// constraints it still enforces. The `sql` of tables is therefore a `CREATE TABLE`
+/// statement rebuilt from `pragma_table_info`, with the type, `NOT NULL`, default and
+/// primary key of each column, and unique constraints show as their automatic indexes.
+/// Differences in `CHECK`, `COLLATE` and foreign key clauses and in the columns of
+/// unique constraints are not detected.
+///
+/// # Errors
+/// Returns an error if `sqlite_master` or `pragma_table_info` cannot be read.
+pub async fn schema(conn: &Connection) -> MigrateResult<Schema> {
+    let mut rows = conn.query(SCHEMA_SQL, ()).await?;
+    let mut objects = Vec::new();
+    while let Some(row) = rows.next().await? {
+        let text = |index| -> MigrateResult<Option<String>> {
+            Ok(row.get_value(index)?.as_text().cloned())
+        };
+        objects.push(SchemaObject {
+            kind: text(0)?.unwrap_or_default(),
+            name: text(1)?.unwrap_or_default(),
+            sql: text(2)?,
+        });
+    }
+    drop(rows);
+
+    for object in objects.iter_mut().filter(|object| object.kind == "table") {
+        object.sql = Some(table_definition(conn, &object.name).await?);
+    }
+    Ok(Schema { objects })
+}
+
+/// Rebuilds the `CREATE TABLE` statement of a table from `pragma_table_info`.
+async fn table_definition(conn: &Connection, table: &str) -> MigrateResult<String> {
+    let mut rows = conn.query(TABLE_INFO_SQL, [table]).await?;
+    let mut columns = Vec::new();
+    let mut primary_key = Vec::new();
+    while let Some(row) = rows.next().await? {
+        let name = row.get_value(0)?.as_text().cloned().unwrap_or_default();
+        let mut column = quote_identifier(&name);
+        if let Some(kind) = row.get_value(1)?.as_text().filter(|kind| !kind.is_empty()) {
+            column.push_str(&format!(" {kind}"));
+        }
+        if row.get_value(2)?.as_integer() == Some(&1) {
+            column.push_str(" NOT NULL");
+        }
+        if let Some(default) = row.get_value(3)?.as_text() {
+            column.push_str(&format!(" DEFAULT {default}"));
+        }
+        if let Some(&position) = row.get_value(4)?.as_integer().filter(|&&pk| pk > 0) {
+            primary_key.push((position, quote_identifier(&name)));
+        }
+        columns.push(column);
+    }
+
+    primary_key.sort();
+    if !primary_key.is_empty() {
+        let key: Vec<_> = primary_key.into_iter().map(|(_, name)| name).collect();
+        columns.push(format!("PRIMARY KEY ({})", key.join(", ")));
+    }
+    Ok(format!(
+        "CREATE TABLE {} ({})",
+        quote_identifier(table),
+        columns.join(", ")
+    ))
+}
+
+/// Fails with [`Error::SchemaMismatch`] if the schema of `conn` differs from `expected`.
+async fn compare(
+    expected: &Schema,
+    conn: &Connection,
+    check: impl FnOnce() -> String,
+) -> MigrateResult<()> {
+    let differences = expected.differences(&schema(conn).await?);
+    if differences.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::SchemaMismatch {
+            check: check(),
+            differences,
+        })
+    }
+}
+
+/// Returns the rows of every table, without the tracking tables.
+async fn data(conn: &Connection) -> MigrateResult<Data> {
+    let schema = schema(conn).await?;
+    let mut data = Data::new();
+    for table in schema.tables() {
+        let columns = texts(conn, COLUMNS_SQL, [table]).await?;
+        let rows = texts(conn, &rows_sql(table, &columns), ()).await?;
+        data.insert(table.to_string(), Rows { columns, rows });
+    }
+    Ok(data)
+}
+
+/// Returns the first column of the rows returned by `sql`, as text.
+async fn texts(
+    conn: &Connection,
+    sql: &str,
+    params: impl turso_crate::params::IntoParams,
+) -> MigrateResult<Vec<String>> {
+    let mut rows = conn.query(sql, params).await?;
+    let mut texts = Vec::new();
+    while let Some(row) = rows.next().await? {
+        if let Some(text) = row.get_value(0)?.as_text() {
+            texts.push(text.to_string());
+        }
+    }
+    Ok(texts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static MIGRATIONS: &[Migration] = &[
+        Migration::new(
+            "001_users",
+            "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT NOT NULL UNIQUE);",
+        )
+        .with_down("DROP TABLE users;"),
+        Migration::new("002_email", "ALTER TABLE users ADD COLUMN email TEXT;")
+            .with_down("ALTER TABLE users DROP COLUMN email;"),
+        Migration::new(
+            "003_posts",
+            "CREATE TABLE posts (id INTEGER PRIMARY KEY, user_id INTEGER);
+             CREATE INDEX idx_posts_user ON posts (user_id);",
+        ),
+    ];
+
+    fn seed_admin(
+        conn: &Connection,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = MigrateResult<()>> + Send>> {
+        let conn = conn.clone();
+        Box::pin(async move {
+            turso::upsert(
+                &conn,
+                "users",
+                &["name"],
+                &["name", "email"],
+                ["admin", "admin@example.com"],
+            )
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn seed_welcome_post(
+        conn: &Connection,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = MigrateResult<()>> + Send>> {
+        let conn = conn.clone();
+        Box::pin(async move {
+            conn.execute("INSERT INTO posts (user_id) VALUES (1)", ())
+                .await?;
+            Ok(())
+        })
+    }
+
+    #[tokio::test]
+    async fn test_migration_test() {
+        let seeds = [Seed::new("001_admin", seed_admin).requires("002_email")];
+        let test = MigrationTest::new(MIGRATIONS).with_seeds(&seeds);
+        test.check_all().await.unwrap();
+
+        let conn = test.fresh_apply().await.unwrap();
+        let schema = schema(&conn).await.unwrap();
+        assert_eq!(schema.tables().collect::<Vec<_>>(), ["posts", "users"]);
+    }
+
+    #[tokio::test]
+    async fn test_check_fixture() {
+        let migrations = [
+            Migration::new(
+                "001_users",
+                "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT NOT NULL);",
+            ),
+            Migration::new(
+                "002_email",
+                "ALTER TABLE users ADD COLUMN email TEXT;
+                 UPDATE users SET email = lower(name) || '@example.com';",
+            ),
+        ];
+        let dir = std::env::temp_dir().join(format!(
+            "ic-sql-migrate-check-fixture-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let json = dir.join("users.json");
+        std::fs::write(
+            &json,
+            r#"{"users": [{"id": 1, "name": "Ada"}, {"id": 2, "name": "Bob"}]}"#,
+        )
+        .unwrap();
+        let sql = dir.join("users.sql");
+        std::fs::write(
+            &sql,
+            "INSERT INTO users VALUES (1, 'Ada');\nINSERT INTO users VALUES (2, 'Bob');\n",
+        )
+        .unwrap();
+        let snapshot = dir.join("users.snap");
+        let test = MigrationTest::new(&migrations);
+
+        let err = test
+            .check_fixture("001_users", &json, &snapshot)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::SnapshotMismatch { path, diff } if path == snapshot && diff.len() == 3
+        ));
+
+        std::fs::write(
+            &snapshot,
+            "-- users (id, name, email)\n\
+             1, 'Ada', 'ada@example.com'\n\
+             2, 'Bob', 'bob@example.com'\n",
+        )
+        .unwrap();
+        test.check_fixture("001_users", &json, &snapshot)
+            .await
+            .unwrap();
+        test.check_fixture("001_users", &sql, &snapshot)
+            .await
+            .unwrap();
+
+        let err = test
+            .check_fixture("002_email", &json, &snapshot)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::SnapshotMismatch { diff, .. }
+                if diff == ["- 1, 'Ada', 'ada@example.com'", "- 2, 'Bob', 'bob@example.com'", "+ 1, 'Ada', NULL", "+ 2, 'Bob', NULL"]
+        ));
+        assert!(matches!(
+            test.check_fixture("003_posts", &json, &snapshot).await,
+            Err(Error::UnknownMigration { id }) if id == "003_posts"
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_must_restore_schema() {
+        let migrations = [
+            Migration::new("001_users", "CREATE TABLE users (id INTEGER PRIMARY KEY);"),
+            Migration::new(
+                "002_index",
+                "CREATE INDEX idx_users ON users (id); CREATE TABLE audit (id INTEGER);",
+            )
+            .with_down("DROP INDEX idx_users;"),
+        ];
+        let err = MigrationTest::new(&migrations)
+            .check_roundtrips()
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::SchemaMismatch { check, differences }
+                if check == "Rolling back '002_index'" && differences == ["table audit is unexpected"]
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_seeds_must_be_idempotent() {
+        let seeds = [
+            Seed::new("001_admin", seed_admin).requires("002_email"),
+            Seed::new("002_welcome_post", seed_welcome_post).requires("003_posts"),
+        ];
+        let err = MigrationTest::new(MIGRATIONS)
+            .with_seeds(&seeds)
+            .check_seeds_idempotent()
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::SeedNotIdempotent { id, tables }
+                if id == "002_welcome_post" && tables == ["posts"]
+        ));
+    }
+}